@@ -0,0 +1,133 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use utils::Tags;
+
+/// Minutes since midnight
+pub type Time = u16;
+
+const MINUTES_PER_DAY: Time = 24 * 60;
+
+/// A window of time in one day. If `end` is before `start`, it wraps past midnight.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TimeRange {
+    start: Time,
+    end: Time,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+enum AccessRule {
+    /// The road is closed outside of these windows
+    OpenDuring(Vec<TimeRange>),
+    ClosedDuring(Vec<TimeRange>),
+}
+
+/// Times when a road can't be walked along, from `opening_hours` and `access:conditional` on the
+/// way itself or on gates along it. Days of the week and seasons are ignored; only the times of
+/// day matter.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ConditionalAccess {
+    rules: Vec<AccessRule>,
+}
+
+impl ConditionalAccess {
+    pub fn parse(tags: &Tags) -> Self {
+        let mut rules = Vec::new();
+
+        if let Some(hours) = tags.get("opening_hours") {
+            // Something like "24/7" or unparseable values don't restrict anything
+            let windows = parse_time_ranges(hours);
+            if !windows.is_empty() {
+                rules.push(AccessRule::OpenDuring(windows));
+            }
+        }
+
+        for key in ["access:conditional", "foot:conditional"] {
+            if let Some(value) = tags.get(key) {
+                // Like "no @ (19:00-07:00); yes @ (Sa 10:00-12:00)"
+                for condition in value.split(';') {
+                    let Some((access, when)) = condition.split_once('@') else {
+                        continue;
+                    };
+                    if !matches!(access.trim(), "no" | "private") {
+                        continue;
+                    }
+                    let windows = parse_time_ranges(when);
+                    if !windows.is_empty() {
+                        rules.push(AccessRule::ClosedDuring(windows));
+                    }
+                }
+            }
+        }
+
+        Self { rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn is_closed_at(&self, time: Time) -> bool {
+        self.rules.iter().any(|rule| match rule {
+            AccessRule::OpenDuring(windows) => !windows.iter().any(|w| w.contains(time)),
+            AccessRule::ClosedDuring(windows) => windows.iter().any(|w| w.contains(time)),
+        })
+    }
+
+    /// Restrictions from a gate along a road also apply to the road
+    pub fn extend(&mut self, other: &ConditionalAccess) {
+        self.rules.extend(other.rules.iter().cloned());
+    }
+}
+
+impl TimeRange {
+    fn contains(&self, time: Time) -> bool {
+        // Like "00:00-24:00"
+        if self.start == self.end {
+            return true;
+        }
+        if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// Parses a time of day in "HH:MM" format
+pub fn parse_time(input: &str) -> Result<Time> {
+    match parse_time_of_day(input) {
+        Some(time) => Ok(time),
+        None => bail!("Can't parse time {input}; expecting HH:MM"),
+    }
+}
+
+/// Find every "HH:MM-HH:MM" range, ignoring weekdays, months, and anything else
+fn parse_time_ranges(input: &str) -> Vec<TimeRange> {
+    let mut ranges = Vec::new();
+    for token in input.split(|c: char| c.is_whitespace() || matches!(c, ',' | ';' | '(' | ')')) {
+        if let Some((start, end)) = token.split_once('-') {
+            if let (Some(start), Some(end)) = (parse_time_of_day(start), parse_time_of_day(end)) {
+                ranges.push(TimeRange { start, end });
+            }
+        }
+    }
+    ranges
+}
+
+fn parse_time_of_day(input: &str) -> Option<Time> {
+    // The real times depend on the location and date; these are rough guesses
+    match input {
+        "sunrise" | "dawn" => return Some(6 * 60),
+        "sunset" | "dusk" => return Some(19 * 60),
+        _ => {}
+    }
+
+    let (hours, minutes) = input.split_once(':')?;
+    let hours: Time = hours.parse().ok()?;
+    let minutes: Time = minutes.parse().ok()?;
+    if minutes >= 60 || hours > 24 {
+        return None;
+    }
+    // Treat 24:00 as midnight
+    Some((hours * 60 + minutes) % MINUTES_PER_DAY)
+}
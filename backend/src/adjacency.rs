@@ -0,0 +1,55 @@
+use std::collections::HashMap;
+
+use crate::costs::CostConfig;
+use crate::{IntersectionID, Road, RoadID};
+
+/// Looks up the road between two intersections without scanning either one's roads
+#[derive(Default)]
+pub struct EdgeIndex {
+    // Keyed with the smaller ID first, with every road connecting the pair in increasing order
+    edges: HashMap<(IntersectionID, IntersectionID), Vec<RoadID>>,
+}
+
+impl EdgeIndex {
+    pub fn new(roads: &[Road]) -> Self {
+        let mut edges: HashMap<_, Vec<RoadID>> = HashMap::new();
+        for r in roads {
+            edges.entry(key(r.src_i, r.dst_i)).or_default().push(r.id);
+        }
+        Self { edges }
+    }
+
+    /// Some road between two intersections in either direction, if there is one
+    pub fn find(&self, i1: IntersectionID, i2: IntersectionID) -> Option<RoadID> {
+        self.edges.get(&key(i1, i2)).map(|roads| roads[0])
+    }
+
+    /// The road from `i1` to `i2` that's cheapest to walk in that direction under `costs`, like the
+    /// one a route found with those costs used. Ties go to the smallest road ID.
+    pub fn cheapest(
+        &self,
+        roads: &[Road],
+        costs: &CostConfig,
+        i1: IntersectionID,
+        i2: IntersectionID,
+    ) -> Option<RoadID> {
+        self.edges
+            .get(&key(i1, i2))?
+            .iter()
+            .filter_map(|r| {
+                let road = &roads[r.0];
+                let forwards = road.src_i == i1;
+                Some((costs.cost_in_direction(road, forwards)?, *r))
+            })
+            .min()
+            .map(|(_, r)| r)
+    }
+}
+
+fn key(i1: IntersectionID, i2: IntersectionID) -> (IntersectionID, IntersectionID) {
+    if i1 <= i2 {
+        (i1, i2)
+    } else {
+        (i2, i1)
+    }
+}
@@ -0,0 +1,81 @@
+use std::collections::BTreeMap;
+
+use geojson::GeoJson;
+use serde::Deserialize;
+
+use crate::{crossings, heatmap, MapModel};
+
+/// Options for `analyzeArea` and the `MapModel` constructor. All are optional.
+#[derive(Default, Deserialize)]
+pub struct AnalyzeOptions {
+    #[serde(default)]
+    pub import_streets_without_sidewalk_tagging: bool,
+    #[serde(default)]
+    pub traffic_counts: Option<String>,
+    #[serde(default)]
+    pub severance_aadt: Option<f64>,
+    #[serde(default)]
+    pub skip_contraction_hierarchy: bool,
+    /// Meters; see `setBoundaryBuffer`
+    #[serde(default)]
+    pub boundary_buffer: Option<f64>,
+    /// GeoJSON with a polygon to use as the boundary; see `MapModel::new`
+    #[serde(default)]
+    pub boundary: Option<String>,
+    /// Meters; see `MapModel::new`
+    #[serde(default)]
+    pub min_component_length: Option<f64>,
+    /// Meters; see `MapModel::new`
+    #[serde(default)]
+    pub snap_tolerance: Option<f64>,
+    #[serde(default, flatten)]
+    pub bounds: heatmap::DesireLineBounds,
+}
+
+/// Everything a simple severance widget needs, from a model that's already built
+pub fn bundle(map: &mut MapModel, bounds: &heatmap::DesireLineBounds) -> serde_json::Value {
+    let network = GeoJson::from(
+        map.roads
+            .iter()
+            .map(|r| r.to_gj(&map.mercator))
+            .collect::<Vec<_>>(),
+    );
+    let heatmap = heatmap::along_severances(map, bounds, &heatmap::DesireLineSampling::default());
+    let gaps = crossings::crossing_gaps(map);
+
+    let mut road_kinds: BTreeMap<String, usize> = BTreeMap::new();
+    for r in &map.roads {
+        *road_kinds.entry(format!("{:?}", r.kind)).or_insert(0) += 1;
+    }
+    let scores: Vec<f64> = heatmap
+        .features
+        .iter()
+        .filter_map(|f| f.property("score").and_then(|x| x.as_f64()))
+        .collect();
+    let mean_score = if scores.is_empty() {
+        None
+    } else {
+        Some(scores.iter().sum::<f64>() / scores.len() as f64)
+    };
+    let max_score = scores.iter().cloned().reduce(f64::max);
+    let max_gap = gaps
+        .foreign_members
+        .as_ref()
+        .and_then(|x| x.get("max_gap"))
+        .cloned();
+
+    serde_json::json!({
+        "network": network,
+        "heatmap": heatmap,
+        "boundary": map.inverted_boundary(),
+        "bounds": map.get_bounds(),
+        "provenance": map.provenance,
+        "stats": {
+            "road_kinds": road_kinds,
+            "desire_lines": scores.len(),
+            "mean_score": mean_score,
+            "max_score": max_score,
+            "max_gap": max_gap,
+        },
+    })
+}
@@ -0,0 +1,127 @@
+use geo::{Coord, Intersects, LineString, MultiPolygon, Polygon};
+use geojson::{Feature, FeatureCollection, Geometry};
+use osm_reader::OsmID;
+use serde::{Deserialize, Serialize};
+use utils::Tags;
+
+use crate::MapModel;
+
+/// An area from a closed way or a `type=multipolygon` relation
+#[derive(Serialize, Deserialize)]
+pub struct Area {
+    pub osm_id: OsmID,
+    pub kind: AreaKind,
+    pub polygon: MultiPolygon,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum AreaKind {
+    /// A pedestrian plaza or square that can be walked across freely
+    Pedestrian,
+    /// A park or other green space open to walking
+    Park,
+    /// Water that can't be walked across
+    Water,
+}
+
+/// Classifies OSM areas that matter for walking, or returns `None`
+pub fn kind(tags: &Tags) -> Option<AreaKind> {
+    let no_access = tags.is_any("foot", vec!["no", "private"])
+        || (tags.is_any("access", vec!["no", "private"])
+            && !tags.is_any("foot", vec!["yes", "designated", "permissive"]));
+
+    if tags.is_any("natural", vec!["water", "bay"])
+        || tags.is("waterway", "riverbank")
+        || tags.is_any("landuse", vec!["reservoir", "basin"])
+    {
+        return Some(AreaKind::Water);
+    }
+    if no_access {
+        return None;
+    }
+    if (tags.is_any("highway", vec!["pedestrian", "footway"]) && tags.is("area", "yes"))
+        || tags.is_any("area:highway", vec!["pedestrian", "footway"])
+        || tags.is("place", "square")
+    {
+        return Some(AreaKind::Pedestrian);
+    }
+    if tags.is_any(
+        "leisure",
+        vec!["park", "garden", "common", "nature_reserve"],
+    ) {
+        return Some(AreaKind::Park);
+    }
+    // Other green spaces only count when walking is explicitly allowed
+    if (tags.has("leisure") || tags.is_any("landuse", vec!["grass", "meadow", "recreation_ground"]))
+        && tags.is_any("foot", vec!["yes", "designated", "permissive"])
+    {
+        return Some(AreaKind::Park);
+    }
+    None
+}
+
+/// Joins the member ways of a multipolygon into rings and nests the inner rings in the outer ones.
+/// Rings that can't be closed are skipped. Returns `None` if no outer ring closes.
+pub fn assemble_multipolygon(
+    outers: Vec<Vec<Coord>>,
+    inners: Vec<Vec<Coord>>,
+) -> Option<MultiPolygon> {
+    let mut polygons: Vec<Polygon> = join_rings(outers)
+        .into_iter()
+        .map(|ring| Polygon::new(ring, Vec::new()))
+        .collect();
+    if polygons.is_empty() {
+        return None;
+    }
+    for ring in join_rings(inners) {
+        // Inner rings may touch the outer ring, so test against the whole outer area
+        if let Some(polygon) = polygons
+            .iter_mut()
+            .find(|p| Polygon::new(p.exterior().clone(), Vec::new()).intersects(&ring.0[0]))
+        {
+            polygon.interiors_push(ring);
+        }
+    }
+    Some(MultiPolygon(polygons))
+}
+
+/// Chains pieces sharing endpoints into closed rings
+fn join_rings(mut pieces: Vec<Vec<Coord>>) -> Vec<LineString> {
+    pieces.retain(|pts| pts.len() >= 2);
+    let mut rings = Vec::new();
+    while let Some(mut current) = pieces.pop() {
+        loop {
+            if current.len() >= 4 && current[0] == *current.last().unwrap() {
+                rings.push(LineString::new(current));
+                break;
+            }
+            let end = *current.last().unwrap();
+            let Some(idx) = pieces
+                .iter()
+                .position(|pts| pts[0] == end || *pts.last().unwrap() == end)
+            else {
+                break;
+            };
+            let mut next = pieces.swap_remove(idx);
+            if next[0] != end {
+                next.reverse();
+            }
+            current.extend(next.into_iter().skip(1));
+        }
+    }
+    rings
+}
+
+/// Every area, with its `kind` and the OSM `way` or `relation` it came from
+pub fn render(map: &MapModel) -> FeatureCollection {
+    FeatureCollection::from_iter(map.areas.iter().map(|area| {
+        let mut f = Feature::from(Geometry::from(&map.mercator.to_wgs84(&area.polygon)));
+        f.set_property("kind", format!("{:?}", area.kind));
+        match &area.osm_id {
+            OsmID::Way(way) => f.set_property("way", way.0),
+            OsmID::Relation(relation) => f.set_property("relation", relation.0),
+            OsmID::Node(_) => {}
+        }
+        f
+    }))
+}
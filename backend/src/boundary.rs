@@ -0,0 +1,59 @@
+use anyhow::{bail, Result};
+use geo::{Coord, EuclideanDistance, Geometry, Point, Polygon};
+use geojson::GeoJson;
+
+use crate::{IntersectionID, MapModel};
+
+/// Dead-ends within this many meters of the boundary are probably artifacts of clipping the input
+/// extract
+pub const DEFAULT_BOUNDARY_BUFFER: f64 = 30.0;
+
+/// Parses GeoJSON with a single WGS84 polygon, as a bare geometry, a feature, or the first feature
+/// in a collection
+pub fn parse_boundary(input: &str) -> Result<Polygon> {
+    let geometry = match input.parse::<GeoJson>()? {
+        GeoJson::Geometry(geometry) => Some(geometry),
+        GeoJson::Feature(f) => f.geometry,
+        GeoJson::FeatureCollection(fc) => fc.features.into_iter().next().and_then(|f| f.geometry),
+    };
+    let Some(geometry) = geometry else {
+        bail!("The boundary GeoJSON has no geometry");
+    };
+    match Geometry::try_from(geometry.value)? {
+        Geometry::Polygon(polygon) => Ok(polygon),
+        Geometry::MultiPolygon(mut mp) if mp.0.len() == 1 => Ok(mp.0.remove(0)),
+        _ => bail!("The boundary must be a single polygon"),
+    }
+}
+
+/// Is the point within the boundary buffer distance of where the input was clipped?
+pub fn near_boundary(map: &MapModel, pt: Coord) -> bool {
+    Point::from(pt).euclidean_distance(map.clip_polygon.exterior()) < map.boundary_buffer
+}
+
+/// Is the intersection a dead-end that's probably only there because the road was clipped?
+pub fn is_truncated_dead_end(map: &MapModel, i: IntersectionID) -> bool {
+    let intersection = &map.intersections[i.0];
+    intersection.roads.len() == 1 && near_boundary(map, intersection.point.into())
+}
+
+/// Recalculate which roads lead to a clipped dead-end, after the boundary buffer changes
+pub fn mark_truncated_roads(map: &mut MapModel) {
+    let truncated: Vec<bool> = map
+        .roads
+        .iter()
+        .map(|r| is_truncated_dead_end(map, r.src_i) || is_truncated_dead_end(map, r.dst_i))
+        .collect();
+    let version = map.render_version + 1;
+    let mut changed = false;
+    for (road, truncated) in map.roads.iter_mut().zip(truncated) {
+        if road.truncated != truncated {
+            road.truncated = truncated;
+            road.version = version;
+            changed = true;
+        }
+    }
+    if changed {
+        map.render_version = version;
+    }
+}
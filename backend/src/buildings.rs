@@ -0,0 +1,89 @@
+use geo::{Coord, Densify, Point, Polygon};
+use geojson::{Feature, FeatureCollection, Geometry};
+use osm_reader::WayID;
+use serde::{Deserialize, Serialize};
+
+use crate::route::walking_cost;
+use crate::{MapModel, RoadID};
+
+#[derive(Serialize, Deserialize)]
+pub struct Building {
+    pub way: WayID,
+    pub polygon: Polygon,
+    /// Mapped entrances along the building outline
+    pub entrances: Vec<Coord>,
+}
+
+/// How far apart to sample points along building outlines, in meters
+const SAMPLE_SPACING: f64 = 10.0;
+/// Points along the outline within this many meters of the closest point to a walkable road are
+/// part of the frontage
+const FRONTAGE_TOLERANCE: f64 = 3.0;
+/// Ignore buildings farther than this from any walkable road
+const MAX_DISTANCE_TO_ROAD: f64 = 100.0;
+
+/// Returns points where people can get in and out of buildings. Mapped entrances are used when
+/// they exist. Otherwise, points are spread along the side of the building facing the nearest
+/// walkable road, which is more realistic than the centroid for big buildings in dense blocks.
+pub fn access_points(map: &MapModel) -> FeatureCollection {
+    let mut features = Vec::new();
+    for b in &map.buildings {
+        let (source, points) = if b.entrances.is_empty() {
+            ("frontage", frontage_points(map, &b.polygon))
+        } else {
+            (
+                "entrance",
+                b.entrances
+                    .iter()
+                    .filter_map(|pt| closest_road(map, *pt).map(|(r, _)| (*pt, r)))
+                    .collect(),
+            )
+        };
+
+        for (pt, road) in points {
+            let mut f = Feature::from(Geometry::from(&map.mercator.to_wgs84(&Point::from(pt))));
+            f.set_property("building_way", b.way.to_string());
+            f.set_property("source", source);
+            f.set_property("road", road.0);
+            features.push(f);
+        }
+    }
+
+    FeatureCollection {
+        features,
+        bbox: None,
+        foreign_members: None,
+    }
+}
+
+/// Points along the building outline nearest to a walkable road, and that road
+fn frontage_points(map: &MapModel, polygon: &Polygon) -> Vec<(Coord, RoadID)> {
+    let mut candidates: Vec<(Coord, RoadID, f64)> = Vec::new();
+    let ring = polygon.exterior().densify(SAMPLE_SPACING);
+    // The ring is closed, so skip the repeated last point
+    for pt in ring.0.iter().skip(1) {
+        if let Some((road, dist)) = closest_road(map, *pt) {
+            if dist <= MAX_DISTANCE_TO_ROAD {
+                candidates.push((*pt, road, dist));
+            }
+        }
+    }
+
+    let Some(min_dist) = candidates
+        .iter()
+        .map(|(_, _, dist)| *dist)
+        .min_by(|a, b| a.total_cmp(b))
+    else {
+        return Vec::new();
+    };
+    candidates
+        .into_iter()
+        .filter(|(_, _, dist)| *dist <= min_dist + FRONTAGE_TOLERANCE)
+        .map(|(pt, road, _)| (pt, road))
+        .collect()
+}
+
+fn closest_road(map: &MapModel, pt: Coord) -> Option<(RoadID, f64)> {
+    map.nearest_road(pt, |r| walking_cost(r).is_some())
+        .map(|(road, dist)| (road.id, dist))
+}
@@ -0,0 +1,45 @@
+use std::collections::HashMap;
+
+use geojson::FeatureCollection;
+
+use crate::{MapModel, RoadKind};
+
+/// Given two models imported from the same extract with different classification options, find
+/// every road where the two disagree. Roads only present in one model are included, with the
+/// other kind missing.
+pub fn classification_disagreements(model1: &MapModel, model2: &MapModel) -> FeatureCollection {
+    // Classification happens per way, so every edge split from a way has the same kind
+    let kinds1 = kinds_per_way(model1);
+    let kinds2 = kinds_per_way(model2);
+
+    let mut features = Vec::new();
+    for r in &model1.roads {
+        let other = kinds2.get(&r.way);
+        if other == Some(&&r.kind) {
+            continue;
+        }
+        let mut f = r.to_gj(&model1.mercator);
+        f.set_property("kind1", format!("{:?}", r.kind));
+        f.set_property("kind2", other.map(|k| format!("{:?}", k)));
+        features.push(f);
+    }
+    for r in &model2.roads {
+        if kinds1.contains_key(&r.way) {
+            continue;
+        }
+        let mut f = r.to_gj(&model2.mercator);
+        f.set_property("kind1", None::<String>);
+        f.set_property("kind2", format!("{:?}", r.kind));
+        features.push(f);
+    }
+
+    FeatureCollection {
+        features,
+        bbox: None,
+        foreign_members: None,
+    }
+}
+
+fn kinds_per_way(map: &MapModel) -> HashMap<osm_reader::WayID, &RoadKind> {
+    map.roads.iter().map(|r| (r.way, &r.kind)).collect()
+}
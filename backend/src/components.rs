@@ -0,0 +1,109 @@
+use geo::EuclideanLength;
+use geojson::FeatureCollection;
+use serde::Serialize;
+
+use crate::route::walking_cost;
+use crate::{Intersection, MapModel, Road, RoadID};
+
+/// A piece of the walking network with no walkable connection to the rest
+pub struct Component {
+    pub roads: Vec<RoadID>,
+    /// Total length of the roads, in meters
+    pub length: f64,
+}
+
+/// Finds the connected components of walkable roads, from longest to shortest
+pub fn find_components(intersections: &[Intersection], roads: &[Road]) -> Vec<Component> {
+    let mut seen = vec![false; roads.len()];
+    let mut components = Vec::new();
+    for start in roads {
+        if seen[start.id.0] || walking_cost(start).is_none() {
+            continue;
+        }
+        seen[start.id.0] = true;
+        let mut component = Component {
+            roads: Vec::new(),
+            length: 0.0,
+        };
+        let mut queue = vec![start.id];
+        while let Some(r) = queue.pop() {
+            let road = &roads[r.0];
+            component.roads.push(r);
+            component.length += road.linestring.euclidean_length();
+            for i in [road.src_i, road.dst_i] {
+                for next in &intersections[i.0].roads {
+                    if !seen[next.0] && walking_cost(&roads[next.0]).is_some() {
+                        seen[next.0] = true;
+                        queue.push(*next);
+                    }
+                }
+            }
+        }
+        components.push(component);
+    }
+    components.sort_by(|a, b| b.length.total_cmp(&a.length));
+    components
+}
+
+/// Drops walkable roads in components shorter than `min_length` meters, like paths around a
+/// parking lot or on an island, which otherwise cause failed routes and tiny isochrones. Roads
+/// that aren't walkable are kept.
+pub fn prune(intersections: &mut Vec<Intersection>, roads: &mut Vec<Road>, min_length: f64) {
+    let mut remove = vec![false; roads.len()];
+    for component in find_components(intersections, roads) {
+        if component.length < min_length {
+            for r in component.roads {
+                remove[r.0] = true;
+            }
+        }
+    }
+    let dropped = crate::scrape::retain_roads(intersections, roads, |r| !remove[r.id.0]);
+    info!("Dropped {dropped} roads in disconnected pieces under {min_length}m long");
+}
+
+#[derive(Serialize)]
+struct ComponentStats {
+    roads: usize,
+    length: f64,
+}
+
+/// Every walkable road, with the `component` it belongs to, numbered from the longest. The
+/// collection has `components` with the number of roads and length of each, and the fraction of
+/// the total length in the largest one.
+pub fn connectivity(map: &MapModel) -> FeatureCollection {
+    let components = find_components(&map.intersections, &map.roads);
+    let total_length: f64 = components.iter().map(|c| c.length).sum();
+
+    let mut features = Vec::new();
+    for (idx, component) in components.iter().enumerate() {
+        for r in &component.roads {
+            let mut f = map.roads[r.0].to_gj(&map.mercator);
+            f.set_property("component", idx);
+            features.push(f);
+        }
+    }
+
+    let stats: Vec<ComponentStats> = components
+        .iter()
+        .map(|c| ComponentStats {
+            roads: c.roads.len(),
+            length: c.length,
+        })
+        .collect();
+    let largest_share = match components.first() {
+        Some(c) if total_length > 0.0 => c.length / total_length,
+        _ => 0.0,
+    };
+
+    let mut gj = FeatureCollection::from_iter(features);
+    gj.foreign_members = Some(
+        serde_json::json!({
+            "components": stats,
+            "largest_share": largest_share,
+        })
+        .as_object()
+        .unwrap()
+        .clone(),
+    );
+    gj
+}
@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+
+use geo::{Area, Contains, Coord, LineString, MultiPolygon, Point, Polygon, Rect};
+
+/// Values sampled on a regular grid, like the cost to reach each cell
+pub struct Grid {
+    /// The minimum corner of the first cell
+    origin: Coord,
+    cell_size: f64,
+    width: usize,
+    height: usize,
+    /// Row-major, starting from `origin`. Cells never reached are infinite.
+    values: Vec<f64>,
+}
+
+impl Grid {
+    /// Covers `bounds` plus `padding` meters on each side
+    pub fn new(bounds: Rect, cell_size: f64, padding: f64) -> Self {
+        let origin = Coord {
+            x: bounds.min().x - padding,
+            y: bounds.min().y - padding,
+        };
+        let width = ((bounds.width() + 2.0 * padding) / cell_size).ceil() as usize + 1;
+        let height = ((bounds.height() + 2.0 * padding) / cell_size).ceil() as usize + 1;
+        Self {
+            origin,
+            cell_size,
+            width,
+            height,
+            values: vec![f64::INFINITY; width * height],
+        }
+    }
+
+    /// Lowers every cell within `radius` of `pt` to at most `value`, plus `per_meter` for every
+    /// meter between `pt` and the cell's center
+    pub fn splat(&mut self, pt: Coord, radius: f64, value: f64, per_meter: f64) {
+        let to_cell = |v: f64, origin: f64, max: usize| {
+            (((v - origin) / self.cell_size).floor().max(0.0) as usize).min(max - 1)
+        };
+        let (x1, x2) = (
+            to_cell(pt.x - radius, self.origin.x, self.width),
+            to_cell(pt.x + radius, self.origin.x, self.width),
+        );
+        let (y1, y2) = (
+            to_cell(pt.y - radius, self.origin.y, self.height),
+            to_cell(pt.y + radius, self.origin.y, self.height),
+        );
+        for y in y1..=y2 {
+            for x in x1..=x2 {
+                let center = self.cell_center(x, y);
+                let dist = (center.x - pt.x).hypot(center.y - pt.y);
+                if dist > radius {
+                    continue;
+                }
+                let idx = y * self.width + x;
+                self.values[idx] = self.values[idx].min(value + dist * per_meter);
+            }
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// The area covered by every cell
+    pub fn bounds(&self) -> Rect {
+        Rect::new(
+            self.origin,
+            Coord {
+                x: self.origin.x + self.width as f64 * self.cell_size,
+                y: self.origin.y + self.height as f64 * self.cell_size,
+            },
+        )
+    }
+
+    /// The value of a cell, counting from `origin`
+    pub fn get(&self, x: usize, y: usize) -> f64 {
+        self.values[y * self.width + x]
+    }
+
+    fn cell_center(&self, x: usize, y: usize) -> Coord {
+        Coord {
+            x: self.origin.x + (x as f64 + 0.5) * self.cell_size,
+            y: self.origin.y + (y as f64 + 0.5) * self.cell_size,
+        }
+    }
+
+    /// The outline of every cell with a value at most `threshold`, with holes. Contours for
+    /// increasing thresholds nest inside each other.
+    pub fn contour(&self, threshold: f64) -> MultiPolygon {
+        let inside = |x: isize, y: isize| {
+            x >= 0
+                && y >= 0
+                && (x as usize) < self.width
+                && (y as usize) < self.height
+                && self.values[y as usize * self.width + x as usize] <= threshold
+        };
+
+        // Directed edges between grid corners along the boundary, with the inside on the left
+        let mut edges: HashMap<(isize, isize), Vec<(isize, isize)>> = HashMap::new();
+        for y in 0..self.height as isize {
+            for x in 0..self.width as isize {
+                if !inside(x, y) {
+                    continue;
+                }
+                let mut add = |from: (isize, isize), to: (isize, isize)| {
+                    edges.entry(from).or_default().push(to);
+                };
+                if !inside(x, y - 1) {
+                    add((x, y), (x + 1, y));
+                }
+                if !inside(x + 1, y) {
+                    add((x + 1, y), (x + 1, y + 1));
+                }
+                if !inside(x, y + 1) {
+                    add((x + 1, y + 1), (x, y + 1));
+                }
+                if !inside(x - 1, y) {
+                    add((x, y + 1), (x, y));
+                }
+            }
+        }
+
+        // Chain the edges into rings. Where two cells only touch at a corner, turn left, so they
+        // stay as separate rings.
+        let mut rings = Vec::new();
+        while let Some(start) = edges.keys().next().cloned() {
+            let mut ring = vec![start];
+            let mut current = start;
+            let mut direction: Option<(isize, isize)> = None;
+            loop {
+                let outgoing = edges.get_mut(&current).unwrap();
+                let idx = direction
+                    .and_then(|(dx, dy)| {
+                        [(-dy, dx), (dx, dy), (dy, -dx)]
+                            .into_iter()
+                            .find_map(|(tx, ty)| {
+                                outgoing
+                                    .iter()
+                                    .position(|next| *next == (current.0 + tx, current.1 + ty))
+                            })
+                    })
+                    .unwrap_or(0);
+                let next = outgoing.swap_remove(idx);
+                if outgoing.is_empty() {
+                    edges.remove(&current);
+                }
+                let next_direction = (next.0 - current.0, next.1 - current.1);
+                // Only keep corners
+                if direction == Some(next_direction) {
+                    ring.pop();
+                }
+                ring.push(next);
+                direction = Some(next_direction);
+                current = next;
+                if current == start {
+                    break;
+                }
+            }
+            rings.push(ring);
+        }
+
+        // In grid coordinates, outer rings go counter-clockwise and holes clockwise
+        let mut exteriors: Vec<(Polygon, Vec<LineString>)> = Vec::new();
+        let mut holes = Vec::new();
+        for ring in rings {
+            let grid_ring = LineString::from(
+                ring.iter()
+                    .map(|(x, y)| (*x as f64, *y as f64))
+                    .collect::<Vec<_>>(),
+            );
+            let polygon = Polygon::new(grid_ring, Vec::new());
+            if polygon.signed_area() > 0.0 {
+                exteriors.push((polygon, Vec::new()));
+            } else {
+                holes.push(polygon.into_inner().0);
+            }
+        }
+        for hole in holes {
+            // The cell just left of the first edge is filled, so its center is inside the right
+            // exterior
+            let (p1, p2) = (hole.0[0], hole.0[1]);
+            let length = (p2.x - p1.x).hypot(p2.y - p1.y);
+            let (dx, dy) = ((p2.x - p1.x) / length, (p2.y - p1.y) / length);
+            let test = Point::new(p1.x + 0.5 * dx - 0.5 * dy, p1.y + 0.5 * dy + 0.5 * dx);
+            if let Some((_, interiors)) = exteriors
+                .iter_mut()
+                .filter(|(exterior, _)| exterior.contains(&test))
+                .min_by(|(a, _), (b, _)| a.unsigned_area().total_cmp(&b.unsigned_area()))
+            {
+                interiors.push(hole);
+            }
+        }
+
+        let to_map = |ring: LineString| {
+            LineString::new(
+                ring.0
+                    .into_iter()
+                    .map(|pt| Coord {
+                        x: self.origin.x + pt.x * self.cell_size,
+                        y: self.origin.y + pt.y * self.cell_size,
+                    })
+                    .collect(),
+            )
+        };
+        MultiPolygon::new(
+            exteriors
+                .into_iter()
+                .map(|(exterior, interiors)| {
+                    Polygon::new(
+                        to_map(exterior.into_inner().0),
+                        interiors.into_iter().map(to_map).collect(),
+                    )
+                })
+                .collect(),
+        )
+    }
+}
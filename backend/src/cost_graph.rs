@@ -0,0 +1,143 @@
+use std::hash::Hash;
+
+use fast_paths::{FastGraph, InputGraph};
+use utils::NodeMap;
+
+/// Weighs the edges of a graph for routing, so the same builder can prepare contraction hierarchies
+/// for walking profiles and for driving
+pub trait CostFunction {
+    type Edge;
+    type Node: Copy + Eq + Hash;
+
+    /// The nodes at the start and end of an edge
+    fn endpoints(&self, edge: &Self::Edge) -> (Self::Node, Self::Node);
+
+    /// The cost of traversing an edge from its start to its end, or `None` if that's not allowed
+    fn forwards_cost(&self, edge: &Self::Edge) -> Option<usize>;
+
+    /// The cost of traversing an edge from its end to its start, or `None` if that's not allowed
+    fn backwards_cost(&self, edge: &Self::Edge) -> Option<usize> {
+        self.forwards_cost(edge)
+    }
+
+    /// The extra cost of a turn, or `None` if it's banned. Only graphs built with
+    /// `build_turn_graph` use this.
+    fn turn_cost(&self, _edges: &[Self::Edge], _turn: &Turn<Self::Node>) -> Option<usize> {
+        Some(0)
+    }
+}
+
+/// One edge traversed in one direction
+pub struct DirectedEdge<N> {
+    /// Index into the edges the graph was built from
+    pub edge: usize,
+    /// True if this goes from the edge's start to its end
+    pub forwards: bool,
+    pub src: N,
+    pub dst: N,
+    pub cost: usize,
+}
+
+/// Continuing from one directed edge onto another at `via`
+pub struct Turn<'a, N> {
+    pub from: &'a DirectedEdge<N>,
+    pub via: N,
+    pub to: &'a DirectedEdge<N>,
+    /// How many directed edges leave `via`, including going back along `from`
+    pub options: usize,
+}
+
+/// Builds a graph where nodes are the endpoints of edges, without turn costs. Graphs for different
+/// costs can share one `node_map`, built from the union of edges any of those costs allow. Edges
+/// with an endpoint missing from `node_map` are skipped.
+pub fn build_graph<C: CostFunction>(
+    edges: &[C::Edge],
+    cost: &C,
+    node_map: &NodeMap<C::Node>,
+) -> FastGraph {
+    let mut input_graph = InputGraph::new();
+    for edge in edges {
+        let (src, dst) = cost.endpoints(edge);
+        let (Some(node1), Some(node2)) = (node_map.get(src), node_map.get(dst)) else {
+            continue;
+        };
+        if let Some(weight) = cost.forwards_cost(edge) {
+            input_graph.add_edge(node1, node2, weight);
+        }
+        if let Some(weight) = cost.backwards_cost(edge) {
+            input_graph.add_edge(node2, node1, weight);
+        }
+    }
+    input_graph.freeze();
+    fast_paths::prepare(&input_graph)
+}
+
+/// A graph with turn costs, where each node of the contraction hierarchy is a directed edge
+pub struct TurnGraph<N> {
+    pub ch: FastGraph,
+    /// Indexed by the contraction hierarchy's node ID. Each edge appears forwards, then backwards,
+    /// in the order of the input edges.
+    pub directed_edges: Vec<DirectedEdge<N>>,
+}
+
+/// Builds a graph where moving between edges costs `turn_cost`, and banned turns are impossible
+pub fn build_turn_graph<C: CostFunction>(edges: &[C::Edge], cost: &C) -> TurnGraph<C::Node> {
+    let mut directed_edges = Vec::new();
+    for (idx, edge) in edges.iter().enumerate() {
+        let (src, dst) = cost.endpoints(edge);
+        if let Some(weight) = cost.forwards_cost(edge) {
+            directed_edges.push(DirectedEdge {
+                edge: idx,
+                forwards: true,
+                src,
+                dst,
+                cost: weight,
+            });
+        }
+        if let Some(weight) = cost.backwards_cost(edge) {
+            directed_edges.push(DirectedEdge {
+                edge: idx,
+                forwards: false,
+                src: dst,
+                dst: src,
+                cost: weight,
+            });
+        }
+    }
+
+    let mut outgoing: NodeMap<C::Node> = NodeMap::new();
+    let mut outgoing_edges: Vec<Vec<usize>> = Vec::new();
+    for (idx, directed) in directed_edges.iter().enumerate() {
+        let node = outgoing.get_or_insert(directed.src);
+        if node == outgoing_edges.len() {
+            outgoing_edges.push(Vec::new());
+        }
+        outgoing_edges[node].push(idx);
+    }
+
+    let mut input_graph = InputGraph::new();
+    for (from, from_edge) in directed_edges.iter().enumerate() {
+        let Some(node) = outgoing.get(from_edge.dst) else {
+            continue;
+        };
+        let turns = &outgoing_edges[node];
+        for to in turns {
+            let to_edge = &directed_edges[*to];
+            let turn = Turn {
+                from: from_edge,
+                via: from_edge.dst,
+                to: to_edge,
+                options: turns.len(),
+            };
+            if let Some(extra) = cost.turn_cost(edges, &turn) {
+                input_graph.add_edge(from, *to, to_edge.cost + extra);
+            }
+        }
+    }
+    input_graph.freeze();
+
+    TurnGraph {
+        ch: fast_paths::prepare(&input_graph),
+        directed_edges,
+    }
+}
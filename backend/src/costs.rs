@@ -0,0 +1,123 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+use serde::Deserialize;
+
+use crate::cost_graph::CostFunction;
+use crate::crossings::crossing_quality;
+use crate::route::{is_steps, walkable_in_direction, walking_cost};
+use crate::{IntersectionID, Road};
+
+/// Adjusts walking costs to model preferences, like people walking 100m further to avoid an
+/// unmarked crossing. The default is the plain distance.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct CostConfig {
+    /// Multiply the cost of walking along a kind of road, like "WithTraffic"
+    #[serde(default)]
+    pub kind_multipliers: HashMap<String, f64>,
+    /// An extra fixed cost in meters for using a crossing of some quality, like "unmarked"
+    #[serde(default)]
+    pub crossing_penalties: HashMap<String, f64>,
+    /// Multiply the cost of roads by `1 + hill_penalty * gradient`. Only matters when elevation
+    /// data was provided.
+    #[serde(default)]
+    pub hill_penalty: f64,
+    /// Treat steps as impassable
+    #[serde(default)]
+    pub avoid_steps: bool,
+}
+
+impl CostConfig {
+    /// The cost of walking along a road in either direction, in the same units as
+    /// `walking_cost`, or `None` if it's not walkable
+    pub fn cost(&self, road: &Road) -> Option<usize> {
+        if self.avoid_steps && is_steps(road) {
+            return None;
+        }
+        let mut cost = walking_cost(road)? as f64;
+        if let Some(factor) = self.kind_multipliers.get(&format!("{:?}", road.kind)) {
+            cost *= factor.max(0.0);
+        }
+        cost *= hill_factor(road, self.hill_penalty);
+        let quality = crossing_quality(road);
+        if quality != "none" {
+            if let Some(meters) = self.crossing_penalties.get(quality) {
+                cost += 100.0 * meters.max(0.0);
+            }
+        }
+        Some(cost.round() as usize)
+    }
+
+    /// Like `cost`, but `None` for one-way footpaths walked the wrong way, with forwards meaning
+    /// from `src_i` to `dst_i`
+    pub fn cost_in_direction(&self, road: &Road, forwards: bool) -> Option<usize> {
+        if !walkable_in_direction(road, forwards) {
+            return None;
+        }
+        self.cost(road)
+    }
+
+    /// Identifies these costs, so routers prepared for them can be reused. Equal configs have
+    /// equal hashes.
+    pub fn profile_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for map in [&self.kind_multipliers, &self.crossing_penalties] {
+            let mut entries: Vec<(&String, u64)> =
+                map.iter().map(|(k, v)| (k, v.to_bits())).collect();
+            entries.sort();
+            entries.hash(&mut hasher);
+        }
+        self.hill_penalty.to_bits().hash(&mut hasher);
+        self.avoid_steps.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// One of the common profiles in `PROFILES`
+    pub fn profile(name: &str) -> Option<Self> {
+        match name {
+            "default" => Some(Self::default()),
+            "wheelchair" => Some(Self {
+                kind_multipliers: HashMap::new(),
+                crossing_penalties: HashMap::from([
+                    ("unmarked".to_string(), 100.0),
+                    ("marked".to_string(), 20.0),
+                ]),
+                hill_penalty: 20.0,
+                avoid_steps: true,
+            }),
+            // Prefer streets over footpaths, which are often unlit and isolated
+            "night" => Some(Self {
+                kind_multipliers: HashMap::from([("Footway".to_string(), 2.0)]),
+                crossing_penalties: HashMap::new(),
+                hill_penalty: 0.0,
+                avoid_steps: false,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl CostFunction for CostConfig {
+    type Edge = Road;
+    type Node = IntersectionID;
+
+    fn endpoints(&self, road: &Road) -> (IntersectionID, IntersectionID) {
+        (road.src_i, road.dst_i)
+    }
+
+    fn forwards_cost(&self, road: &Road) -> Option<usize> {
+        self.cost_in_direction(road, true)
+    }
+
+    fn backwards_cost(&self, road: &Road) -> Option<usize> {
+        self.cost_in_direction(road, false)
+    }
+}
+
+pub const PROFILES: [&str; 3] = ["default", "wheelchair", "night"];
+
+/// How much harder a road is to walk along because of its gradient
+pub fn hill_factor(road: &Road, hill_penalty: f64) -> f64 {
+    1.0 + hill_penalty.max(0.0) * road.gradient.unwrap_or(0.0)
+}
@@ -0,0 +1,428 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use geo::{Coord, EuclideanLength, LineString};
+use geojson::{feature::Id, Feature, FeatureCollection, Geometry};
+use serde::{Deserialize, Serialize};
+
+use crate::boundary::is_truncated_dead_end;
+use crate::heatmap::severance_requests;
+use crate::route::route_pieces;
+use crate::{IntersectionID, MapModel, Road, RoadID, RoadKind};
+
+/// Split severances into stretches between consecutive crossings. Each stretch is as long as
+/// somebody might have to walk along the severance before being able to cross it. Stretches also
+/// end where severances branch or dead-end.
+pub fn crossing_gaps(map: &MapModel) -> FeatureCollection {
+    let crossing_nodes = crossing_nodes(map);
+
+    let mut visited: HashSet<RoadID> = HashSet::new();
+    let mut features = Vec::new();
+    let mut max_gap = 0.0_f64;
+    for r in &map.roads {
+        if r.kind != RoadKind::Severance || visited.contains(&r.id) {
+            continue;
+        }
+        visited.insert(r.id);
+
+        let mut pts: VecDeque<Coord> = r.linestring.0.iter().cloned().collect();
+        let mut ways = vec![r.way.0];
+        let end = extend_gap(map, &crossing_nodes, &mut visited, r.dst_i, |pts_along, way| {
+            pts.extend(pts_along.into_iter().skip(1));
+            ways.push(way);
+        });
+        let start = extend_gap(map, &crossing_nodes, &mut visited, r.src_i, |pts_along, way| {
+            for pt in pts_along.into_iter().skip(1) {
+                pts.push_front(pt);
+            }
+            ways.push(way);
+        });
+        ways.sort();
+        ways.dedup();
+
+        let linestring = LineString::new(pts.into_iter().collect());
+        let gap = linestring.euclidean_length();
+        // If the severance was clipped by the boundary, the real gap is unknown
+        let truncated = is_truncated_dead_end(map, start) || is_truncated_dead_end(map, end);
+        if !truncated {
+            max_gap = max_gap.max(gap);
+        }
+
+        let mut f = Feature::from(Geometry::from(&map.mercator.to_wgs84(&linestring)));
+        f.set_property("gap_length", gap);
+        f.set_property("crossing_at_start", crossing_nodes.contains(&start));
+        f.set_property("crossing_at_end", crossing_nodes.contains(&end));
+        f.set_property("truncated", truncated);
+        f.set_property(
+            "ways",
+            ways.into_iter().map(|w| w.to_string()).collect::<Vec<_>>(),
+        );
+        features.push(f);
+    }
+
+    FeatureCollection {
+        features,
+        bbox: None,
+        foreign_members: Some(
+            serde_json::json!({
+                "max_gap": max_gap,
+            })
+            .as_object()
+            .unwrap()
+            .clone(),
+        ),
+    }
+}
+
+/// For every severance road, the length of the stretch between crossings that it's part of, as in
+/// `crossing_gaps`
+pub fn gap_per_road(map: &MapModel) -> HashMap<RoadID, f64> {
+    let crossing_nodes = crossing_nodes(map);
+    let mut gaps = HashMap::new();
+    for r in &map.roads {
+        if r.kind != RoadKind::Severance || gaps.contains_key(&r.id) {
+            continue;
+        }
+        let mut stretch: HashSet<RoadID> = HashSet::from([r.id]);
+        let mut gap = r.linestring.euclidean_length();
+        for i in [r.dst_i, r.src_i] {
+            extend_gap(map, &crossing_nodes, &mut stretch, i, |pts, _| {
+                gap += LineString::new(pts).euclidean_length();
+            });
+        }
+        for road in stretch {
+            gaps.insert(road, gap);
+        }
+    }
+    gaps
+}
+
+/// Intersections where a crossing meets something
+pub fn crossing_nodes(map: &MapModel) -> HashSet<IntersectionID> {
+    let mut nodes = HashSet::new();
+    for r in &map.roads {
+        if r.kind == RoadKind::Crossing {
+            nodes.insert(r.src_i);
+            nodes.insert(r.dst_i);
+        }
+    }
+    nodes
+}
+
+/// Starting from the end of a severance, keep following the severance until reaching a crossing
+/// or a place where severances branch. Calls `add` with the points of each road followed, oriented
+/// away from `i`. Returns the last intersection reached.
+fn extend_gap<F: FnMut(Vec<Coord>, i64)>(
+    map: &MapModel,
+    crossing_nodes: &HashSet<IntersectionID>,
+    visited: &mut HashSet<RoadID>,
+    mut i: IntersectionID,
+    mut add: F,
+) -> IntersectionID {
+    loop {
+        if crossing_nodes.contains(&i) {
+            return i;
+        }
+        let severances: Vec<RoadID> = map.intersections[i.0]
+            .roads
+            .iter()
+            .filter(|r| map.roads[r.0].kind == RoadKind::Severance)
+            .cloned()
+            .collect();
+        if severances.len() != 2 {
+            return i;
+        }
+        let Some(next) = severances.into_iter().find(|r| !visited.contains(r)) else {
+            return i;
+        };
+        visited.insert(next);
+
+        let road = &map.roads[next.0];
+        let mut pts = road.linestring.0.clone();
+        if road.src_i == i {
+            i = road.dst_i;
+        } else {
+            pts.reverse();
+            i = road.src_i;
+        }
+        add(pts, road.way.0);
+    }
+}
+
+/// How safe and convenient a crossing is, based on OSM tagging
+pub fn crossing_quality(road: &Road) -> &'static str {
+    match road.kind {
+        RoadKind::Bridge | RoadKind::Tunnel => "grade-separated",
+        RoadKind::Crossing => {
+            if road.tags.is("crossing", "traffic_signals")
+                || road.tags.is("crossing_ref", "pelican")
+                || road.tags.is("crossing_ref", "toucan")
+            {
+                "signalized"
+            } else if road
+                .tags
+                .is_any("crossing", vec!["marked", "zebra", "uncontrolled"])
+                || road.tags.is("crossing_ref", "zebra")
+            {
+                "marked"
+            } else if road.tags.is("crossing", "unmarked") {
+                "unmarked"
+            } else {
+                "unknown"
+            }
+        }
+        // Walking across a severance without any crossing mapped
+        _ => "none",
+    }
+}
+
+/// Crossings this many degrees away from perpendicular are skewed. Long diagonal crossings mean more
+/// time exposed to traffic.
+pub const SKEWED_CROSSING_DEGREES: f64 = 30.0;
+
+/// How many degrees a crossing deviates from perpendicular to the severance it crosses, from 0 to
+/// 90. `None` if the crossing doesn't meet a severance.
+pub fn crossing_skew(map: &MapModel, road: &Road) -> Option<f64> {
+    if road.kind != RoadKind::Crossing {
+        return None;
+    }
+    for (i, pts) in [
+        (road.src_i, road.linestring.0.clone()),
+        (
+            road.dst_i,
+            road.linestring.0.iter().rev().cloned().collect(),
+        ),
+    ] {
+        let Some(severance) = map.intersections[i.0]
+            .roads
+            .iter()
+            .map(|r| &map.roads[r.0])
+            .find(|r| r.kind == RoadKind::Severance)
+        else {
+            continue;
+        };
+        // The severance's direction right at the intersection
+        let sev_pts = &severance.linestring.0;
+        let (a, b) = if severance.src_i == i {
+            (sev_pts[0], sev_pts[1])
+        } else {
+            (sev_pts[sev_pts.len() - 2], sev_pts[sev_pts.len() - 1])
+        };
+        let crossing_angle = bearing(pts[0], *pts.last().unwrap());
+        let severance_angle = bearing(a, b);
+        // The acute angle between the two lines, ignoring direction
+        let mut diff = (crossing_angle - severance_angle).abs() % 180.0;
+        if diff > 90.0 {
+            diff = 180.0 - diff;
+        }
+        return Some(90.0 - diff);
+    }
+    None
+}
+
+fn bearing(from: Coord, to: Coord) -> f64 {
+    (to.y - from.y).atan2(to.x - from.x).to_degrees()
+}
+
+/// A score from 0 (worst) to 1 (best) for how safe and convenient a crossing is, from its quality
+/// and skew
+pub fn crossing_score(map: &MapModel, road: &Road) -> f64 {
+    let base = match crossing_quality(road) {
+        "signalized" => 1.0,
+        "grade-separated" => 0.7,
+        "marked" => 0.8,
+        "unknown" => 0.6,
+        "unmarked" => 0.5,
+        _ => 0.0,
+    };
+    // A crossing parallel to the road would be half as good
+    let skew = crossing_skew(map, road).unwrap_or(0.0);
+    base * (1.0 - 0.5 * skew / 90.0)
+}
+
+/// Every crossing with its quality, skew, and score
+pub fn crossing_audit(map: &MapModel) -> FeatureCollection {
+    let mut features = Vec::new();
+    let mut skewed = 0;
+    for r in &map.roads {
+        if r.kind != RoadKind::Crossing {
+            continue;
+        }
+        let skew = crossing_skew(map, r);
+        let is_skewed = skew
+            .map(|skew| skew > SKEWED_CROSSING_DEGREES)
+            .unwrap_or(false);
+        if is_skewed {
+            skewed += 1;
+        }
+        let mut f = r.to_gj(&map.mercator);
+        f.set_property("quality", crossing_quality(r));
+        f.set_property("skew_degrees", skew);
+        f.set_property("skewed", is_skewed);
+        f.set_property("score", crossing_score(map, r));
+        features.push(f);
+    }
+
+    FeatureCollection {
+        features,
+        bbox: None,
+        foreign_members: Some(
+            serde_json::json!({
+                "skewed_crossings": skewed,
+            })
+            .as_object()
+            .unwrap()
+            .clone(),
+        ),
+    }
+}
+
+/// Just the properties needed to style and inspect a severance or crossing, instead of every tag
+fn slim_feature(map: &MapModel, road: &Road) -> Feature {
+    let mut f = Feature::from(Geometry::from(&map.mercator.to_wgs84(&road.linestring)));
+    f.id = Some(Id::Number(road.id.0.into()));
+    f.set_property("way", road.way.to_string());
+    for key in ["name", "highway"] {
+        if let Some(value) = road.tags.get(key) {
+            f.set_property(key, value.clone());
+        }
+    }
+    f.set_property("gradient", road.gradient);
+    f
+}
+
+/// Every severance with its `aadt`, `gradient`, and the `gap_length` of the stretch between
+/// crossings it's part of
+pub fn severance_layer(map: &MapModel) -> FeatureCollection {
+    let gaps = gap_per_road(map);
+    let mut features = Vec::new();
+    for r in &map.roads {
+        if r.kind != RoadKind::Severance {
+            continue;
+        }
+        let mut f = slim_feature(map, r);
+        f.set_property("aadt", r.aadt);
+        f.set_property("truncated", r.truncated);
+        f.set_property("gap_length", gaps.get(&r.id).copied());
+        features.push(f);
+    }
+    FeatureCollection::from_iter(features)
+}
+
+/// Every crossing with its `quality`, `skew_degrees`, `score`, `gradient`, and
+/// `gap_to_next_crossing`, the longest stretch of severance without a crossing on either side of
+/// this one, or null if it doesn't touch a severance
+pub fn crossing_layer(map: &MapModel) -> FeatureCollection {
+    let gaps = gap_per_road(map);
+    let mut features = Vec::new();
+    for r in &map.roads {
+        if r.kind != RoadKind::Crossing {
+            continue;
+        }
+        let gap = [r.src_i, r.dst_i]
+            .into_iter()
+            .flat_map(|i| &map.intersections[i.0].roads)
+            .filter_map(|road| gaps.get(road).copied())
+            .reduce(f64::max);
+
+        let mut f = slim_feature(map, r);
+        f.set_property("quality", crossing_quality(r));
+        f.set_property("skew_degrees", crossing_skew(map, r));
+        f.set_property("score", crossing_score(map, r));
+        f.set_property("gap_to_next_crossing", gap);
+        features.push(f);
+    }
+    FeatureCollection::from_iter(features)
+}
+
+#[derive(Deserialize)]
+pub struct DelayBudgetRequest {
+    /// How many people per day are assumed to make each trip in the heatmap
+    #[serde(default = "default_trips_per_desire_line")]
+    trips_per_desire_line: f64,
+    /// Override the average delay in seconds for a crossing quality, like "signalized"
+    #[serde(default)]
+    delays: HashMap<String, f64>,
+}
+
+fn default_trips_per_desire_line() -> f64 {
+    10.0
+}
+
+/// The average time in seconds lost using a crossing of some quality, from waiting for a signal or
+/// a gap in traffic, or climbing up and down
+pub fn default_crossing_delay(quality: &str) -> f64 {
+    crossing_delay_range(quality).typical
+}
+
+/// How long somebody might wait at a crossing, in seconds
+#[derive(Clone, Copy, Serialize)]
+pub struct DelayRange {
+    pub min: f64,
+    pub typical: f64,
+    pub max: f64,
+}
+
+/// The range of time in seconds lost using a crossing of some quality. Signals might be green on
+/// arrival or just turned red, and gaps in traffic at busy roads can take a long time to appear.
+pub fn crossing_delay_range(quality: &str) -> DelayRange {
+    let (min, typical, max) = match quality {
+        "signalized" => (0.0, 30.0, 90.0),
+        "marked" => (0.0, 5.0, 20.0),
+        "unmarked" => (0.0, 15.0, 60.0),
+        "grade-separated" => (15.0, 20.0, 30.0),
+        _ => (0.0, 10.0, 30.0),
+    };
+    DelayRange { min, typical, max }
+}
+
+/// Estimates person-minutes of delay per day at each crossing. Flows come from routing every
+/// desire line in the heatmap, each assumed to carry a fixed number of trips per day.
+pub fn delay_budget(map: &mut MapModel, req: DelayBudgetRequest) -> FeatureCollection {
+    let mut flows: HashMap<RoadID, usize> = HashMap::new();
+    for route_req in severance_requests(map) {
+        if let Ok(pieces) = route_pieces(map, &route_req) {
+            for (r, _) in pieces {
+                if crossing_quality(&map.roads[r.0]) != "none" {
+                    *flows.entry(r).or_insert(0) += 1;
+                }
+            }
+        }
+    }
+
+    let mut features = Vec::new();
+    let mut total = 0.0;
+    let mut flows: Vec<(RoadID, usize)> = flows.into_iter().collect();
+    flows.sort();
+    for (r, routes) in flows {
+        let road = &map.roads[r.0];
+        let quality = crossing_quality(road);
+        let delay = req
+            .delays
+            .get(quality)
+            .cloned()
+            .unwrap_or_else(|| default_crossing_delay(quality));
+        let people_per_day = routes as f64 * req.trips_per_desire_line;
+        let person_minutes = people_per_day * delay / 60.0;
+        total += person_minutes;
+
+        let mut f = road.to_gj(&map.mercator);
+        f.set_property("quality", quality);
+        f.set_property("people_per_day", people_per_day);
+        f.set_property("delay_seconds", delay);
+        f.set_property("person_minutes_per_day", person_minutes);
+        features.push(f);
+    }
+
+    FeatureCollection {
+        features,
+        bbox: None,
+        foreign_members: Some(
+            serde_json::json!({
+                "total_person_minutes_per_day": total,
+            })
+            .as_object()
+            .unwrap()
+            .clone(),
+        ),
+    }
+}
@@ -0,0 +1,561 @@
+use std::cmp::Ordering;
+
+use anyhow::{bail, Result};
+use geo::LineString;
+use osm_reader::WayID;
+use serde::{Deserialize, Serialize};
+use utils::Tags;
+
+use crate::access::ConditionalAccess;
+use crate::route::snap_crossing_end;
+use crate::{IntersectionID, MapModel, Road, RoadID, RoadKind};
+
+/// A change to the network. Roads are identified by `stable_id`, so a list of edits can be saved
+/// and applied to another import of the same area.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Edit {
+    /// A new crossing between the intersections nearest each end of a WGS84 line
+    AddCrossing {
+        x1: f64,
+        y1: f64,
+        x2: f64,
+        y2: f64,
+    },
+    DeleteRoad {
+        stable_id: String,
+    },
+    SetKind {
+        stable_id: String,
+        kind: RoadKind,
+    },
+}
+
+/// How to reverse an applied edit. Edits are undone in reverse order, so IDs line up.
+enum Undo {
+    /// The added road is the last one
+    RemoveLast,
+    /// Put back the deleted road at its old ID
+    Restore(Road),
+    SetKind(RoadID, RoadKind),
+}
+
+/// Edits applied to the network so far, and those undone that can be redone
+#[derive(Default)]
+pub struct EditHistory {
+    applied: Vec<(Edit, Undo)>,
+    undone: Vec<Edit>,
+}
+
+impl EditHistory {
+    pub fn list(&self) -> Vec<Edit> {
+        self.applied.iter().map(|(edit, _)| edit.clone()).collect()
+    }
+}
+
+/// Where each road and intersection went after a change, indexed by the old ID, so state kept
+/// elsewhere can be updated. Anything removed maps to `None`.
+#[derive(Serialize)]
+pub struct IdMapping {
+    pub roads: Vec<Option<RoadID>>,
+    pub intersections: Vec<Option<IntersectionID>>,
+}
+
+impl IdMapping {
+    /// Nothing has moved yet
+    fn identity(map: &MapModel) -> Self {
+        Self {
+            roads: (0..map.roads.len()).map(|r| Some(RoadID(r))).collect(),
+            intersections: (0..map.intersections.len())
+                .map(|i| Some(IntersectionID(i)))
+                .collect(),
+        }
+    }
+
+    /// Roads were renumbered, and intersections didn't change
+    fn with_roads(map: &MapModel, roads: Vec<Option<RoadID>>) -> Self {
+        Self {
+            roads,
+            ..Self::identity(map)
+        }
+    }
+
+    /// Follows this mapping, then `next`
+    pub fn then(self, next: &IdMapping) -> Self {
+        Self {
+            roads: self
+                .roads
+                .into_iter()
+                .map(|r| r.and_then(|r| next.roads[r.0]))
+                .collect(),
+            intersections: self
+                .intersections
+                .into_iter()
+                .map(|i| i.and_then(|i| next.intersections[i.0]))
+                .collect(),
+        }
+    }
+}
+
+/// Changes the network, so every analysis afterwards uses the edited graph
+pub fn apply(map: &mut MapModel, edit: Edit) -> Result<IdMapping> {
+    let mapping = apply_without_history(map, edit)?;
+    map.edits.undone.clear();
+    finish(map);
+    Ok(mapping)
+}
+
+/// Reverses the last edit, returning `None` if there's nothing to undo
+pub fn undo(map: &mut MapModel) -> Option<IdMapping> {
+    let mapping = undo_without_finish(map)?;
+    finish(map);
+    Some(mapping)
+}
+
+/// Applies the last undone edit again, returning `None` if there's nothing to redo
+pub fn redo(map: &mut MapModel) -> Result<Option<IdMapping>> {
+    let Some(edit) = map.edits.undone.pop() else {
+        return Ok(None);
+    };
+    let mapping = apply_without_history(map, edit)?;
+    finish(map);
+    Ok(Some(mapping))
+}
+
+/// Undoes everything, then applies a saved list of edits. If any edit fails, the previous edits
+/// are restored and nothing changes.
+pub fn replace_all(map: &mut MapModel, edits: Vec<Edit>) -> Result<IdMapping> {
+    let previous = map.edits.list();
+    let undone = std::mem::take(&mut map.edits.undone);
+    let mut mapping = undo_all(map);
+    for edit in edits {
+        match apply_without_history(map, edit) {
+            Ok(next) => {
+                mapping = mapping.then(&next);
+            }
+            Err(err) => {
+                // The previous edits applied cleanly before, so they'll produce the same IDs
+                undo_all(map);
+                for edit in previous {
+                    apply_without_history(map, edit)
+                        .expect("previous edits should apply again");
+                }
+                map.edits.undone = undone;
+                finish(map);
+                return Err(err);
+            }
+        }
+    }
+    finish(map);
+    Ok(mapping)
+}
+
+/// Drops intersections left without any road by deleted roads, and renumbers the rest to be
+/// contiguous. Deleted roads that can still be undone keep their intersections. Roads are always
+/// contiguous, because deleting one shifts the IDs of later roads. Returns where everything went.
+pub fn compact(map: &mut MapModel) -> IdMapping {
+    let mut used = vec![false; map.intersections.len()];
+    let restorable = map.edits.applied.iter().filter_map(|(_, undo)| match undo {
+        Undo::Restore(road) => Some(road),
+        _ => None,
+    });
+    for road in map.roads.iter().chain(restorable) {
+        used[road.src_i.0] = true;
+        used[road.dst_i.0] = true;
+    }
+    if used.iter().all(|x| *x) {
+        return IdMapping::identity(map);
+    }
+
+    let mut intersections = Vec::new();
+    let mut next = 0;
+    for keep in &used {
+        if *keep {
+            intersections.push(Some(IntersectionID(next)));
+            next += 1;
+        } else {
+            intersections.push(None);
+        }
+    }
+    let mapping = IdMapping {
+        intersections,
+        ..IdMapping::identity(map)
+    };
+    let new_id = |i: IntersectionID| mapping.intersections[i.0].unwrap();
+
+    map.intersections.retain(|i| used[i.id.0]);
+    for i in &mut map.intersections {
+        i.id = new_id(i.id);
+    }
+    for road in &mut map.roads {
+        road.src_i = new_id(road.src_i);
+        road.dst_i = new_id(road.dst_i);
+    }
+    for (_, undo) in &mut map.edits.applied {
+        if let Undo::Restore(road) = undo {
+            road.src_i = new_id(road.src_i);
+            road.dst_i = new_id(road.dst_i);
+        }
+    }
+    finish(map);
+    mapping
+}
+
+/// Undoes every edit, so the underlying network can change, returning them to pass to `reapply`
+/// afterwards. Doesn't call `finish`.
+pub fn take_all(map: &mut MapModel) -> (Vec<Edit>, IdMapping) {
+    let list = map.edits.list();
+    (list, undo_all(map))
+}
+
+/// Applies edits again after the underlying network changed, skipping any that no longer apply.
+/// Returns how many were skipped.
+pub fn reapply(map: &mut MapModel, edits: Vec<Edit>) -> (usize, IdMapping) {
+    let mut mapping = IdMapping::identity(map);
+    let mut skipped = 0;
+    for edit in edits {
+        match apply_without_history(map, edit) {
+            Ok(next) => {
+                mapping = mapping.then(&next);
+            }
+            Err(err) => {
+                warn!("Skipping an edit that no longer applies: {err}");
+                skipped += 1;
+            }
+        }
+    }
+    finish(map);
+    (skipped, mapping)
+}
+
+/// Undoes everything, forgetting what was undone. Doesn't call `finish`.
+fn undo_all(map: &mut MapModel) -> IdMapping {
+    let mut mapping = IdMapping::identity(map);
+    while let Some(next) = undo_without_finish(map) {
+        mapping = mapping.then(&next);
+    }
+    map.edits.undone.clear();
+    mapping
+}
+
+fn undo_without_finish(map: &mut MapModel) -> Option<IdMapping> {
+    let (edit, undo) = map.edits.applied.pop()?;
+    let mapping = match undo {
+        Undo::RemoveLast => {
+            let road = map.roads.pop().unwrap();
+            for i in [road.src_i, road.dst_i] {
+                map.intersections[i.0].roads.retain(|r| *r != road.id);
+            }
+            let mut roads = IdMapping::identity(map).roads;
+            roads.push(None);
+            IdMapping::with_roads(map, roads)
+        }
+        Undo::Restore(road) => insert_road(map, road),
+        Undo::SetKind(r, kind) => {
+            map.roads[r.0].kind = kind;
+            touch(map, r);
+            IdMapping::identity(map)
+        }
+    };
+    map.edits.undone.push(edit);
+    Some(mapping)
+}
+
+/// Applies one edit and remembers how to undo it. Doesn't call `finish`, so the indices can be
+/// stale, but only roads are changed, so snapping to intersections still works.
+fn apply_without_history(map: &mut MapModel, edit: Edit) -> Result<IdMapping> {
+    let (undo, mapping) = match edit {
+        Edit::AddCrossing { x1, y1, x2, y2 } => {
+            let i1 = snap_crossing_end(map, x1, y1)?;
+            let i2 = snap_crossing_end(map, x2, y2)?;
+            if i1 == i2 {
+                bail!("Both ends of a new crossing snap to the same intersection");
+            }
+            let mapping = IdMapping::identity(map);
+            let id = RoadID(map.roads.len());
+            let road = Road {
+                id,
+                src_i: i1,
+                dst_i: i2,
+                // Negative IDs can't clash with OSM, and are the same when replaying edits
+                way: WayID(-(map.edits.applied.len() as i64) - 1),
+                node1: map.intersections[i1.0].node,
+                node2: map.intersections[i2.0].node,
+                linestring: LineString::new(vec![
+                    map.intersections[i1.0].point.into(),
+                    map.intersections[i2.0].point.into(),
+                ]),
+                tags: Tags(
+                    [("highway", "footway"), ("footway", "crossing")]
+                        .into_iter()
+                        .map(|(k, v)| (k.to_string(), v.to_string()))
+                        .collect(),
+                ),
+                kind: RoadKind::Crossing,
+                shared_with_cyclists: false,
+                layer: 0,
+                access: ConditionalAccess::default(),
+                aadt: None,
+                truncated: false,
+                gradient: None,
+                version: 0,
+            };
+            map.intersections[i1.0].roads.push(id);
+            map.intersections[i2.0].roads.push(id);
+            map.roads.push(road);
+            touch(map, id);
+            (Undo::RemoveLast, mapping)
+        }
+        Edit::DeleteRoad { ref stable_id } => {
+            let r = find(map, stable_id)?;
+            let (road, mapping) = remove_road(map, r);
+            (Undo::Restore(road), mapping)
+        }
+        Edit::SetKind {
+            ref stable_id,
+            ref kind,
+        } => {
+            let r = find(map, stable_id)?;
+            let old = std::mem::replace(&mut map.roads[r.0].kind, kind.clone());
+            touch(map, r);
+            (Undo::SetKind(r, old), IdMapping::identity(map))
+        }
+    };
+    map.edits.applied.push((edit, undo));
+    Ok(mapping)
+}
+
+fn find(map: &MapModel, stable_id: &str) -> Result<RoadID> {
+    match map.roads.iter().find(|r| r.stable_id() == stable_id) {
+        Some(r) => Ok(r.id),
+        None => bail!("No road {stable_id}"),
+    }
+}
+
+/// Removes a road, shifting down the IDs of later roads
+fn remove_road(map: &mut MapModel, id: RoadID) -> (Road, IdMapping) {
+    let roads = (0..map.roads.len())
+        .map(|r| match r.cmp(&id.0) {
+            Ordering::Less => Some(RoadID(r)),
+            Ordering::Equal => None,
+            Ordering::Greater => Some(RoadID(r - 1)),
+        })
+        .collect();
+    let mapping = IdMapping::with_roads(map, roads);
+
+    let road = map.roads.remove(id.0);
+    for r in &mut map.roads[id.0..] {
+        r.id.0 -= 1;
+    }
+    for i in &mut map.intersections {
+        i.roads.retain(|r| *r != id);
+        for r in &mut i.roads {
+            if *r > id {
+                r.0 -= 1;
+            }
+        }
+    }
+    (road, mapping)
+}
+
+/// The opposite of `remove_road`
+fn insert_road(map: &mut MapModel, road: Road) -> IdMapping {
+    let id = road.id;
+    let roads = (0..map.roads.len())
+        .map(|r| Some(RoadID(if r < id.0 { r } else { r + 1 })))
+        .collect();
+    let mapping = IdMapping::with_roads(map, roads);
+
+    for r in &mut map.roads[id.0..] {
+        r.id.0 += 1;
+    }
+    for i in &mut map.intersections {
+        for r in &mut i.roads {
+            if *r >= id {
+                r.0 += 1;
+            }
+        }
+    }
+    for i in [road.src_i, road.dst_i] {
+        let roads = &mut map.intersections[i.0].roads;
+        if !roads.contains(&id) {
+            roads.push(id);
+            roads.sort();
+        }
+    }
+    map.roads.insert(id.0, road);
+    touch(map, id);
+    mapping
+}
+
+/// Marks a road to be redrawn by `renderChangedSince` after `finish`
+fn touch(map: &mut MapModel, r: RoadID) {
+    map.roads[r.0].version = map.render_version + 1;
+}
+
+/// Only roads touched by edits are redrawn. Others may have new IDs, which callers map with the
+/// returned `IdMapping`.
+fn finish(map: &mut MapModel) {
+    map.render_version += 1;
+    crate::boundary::mark_truncated_roads(map);
+    map.rebuild_indices();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scrape::{scrape_osm, ImportOptions};
+
+    /// Four corners of a block, joined by footways on three sides and a street on the fourth
+    const BLOCK: &str = r#"<osm version="0.6">
+        <bounds minlon="-0.101" minlat="51.499" maxlon="-0.097" maxlat="51.502"/>
+        <node id="1" lon="-0.100" lat="51.500"/>
+        <node id="2" lon="-0.099" lat="51.500"/>
+        <node id="3" lon="-0.098" lat="51.500"/>
+        <node id="4" lon="-0.100" lat="51.501"/>
+        <node id="5" lon="-0.098" lat="51.501"/>
+        <way id="10"><nd ref="1"/><nd ref="2"/><nd ref="3"/><tag k="highway" v="footway"/></way>
+        <way id="11"><nd ref="1"/><nd ref="4"/><tag k="highway" v="footway"/></way>
+        <way id="12"><nd ref="3"/><nd ref="5"/><tag k="highway" v="footway"/></way>
+        <way id="13"><nd ref="4"/><nd ref="5"/><tag k="highway" v="residential"/><tag k="sidewalk" v="both"/></way>
+    </osm>"#;
+
+    fn block() -> MapModel {
+        let opts = ImportOptions {
+            import_streets_without_sidewalk_tagging: false,
+            traffic_counts: Default::default(),
+            severance_aadt: crate::traffic::DEFAULT_SEVERANCE_AADT,
+            skip_contraction_hierarchy: true,
+            boundary: None,
+            min_component_length: None,
+            snap_tolerance: None,
+        };
+        scrape_osm(BLOCK.as_bytes(), opts, &mut |_: &str, _: f64| {}).unwrap()
+    }
+
+    fn stable_ids(map: &MapModel) -> Vec<String> {
+        map.roads.iter().map(|r| r.stable_id()).collect()
+    }
+
+    fn delete(map: &MapModel, r: usize) -> Edit {
+        Edit::DeleteRoad {
+            stable_id: map.roads[r].stable_id(),
+        }
+    }
+
+    #[test]
+    fn test_add_crossing() {
+        let mut map = block();
+        let before = map.roads.len();
+        // Diagonally across the block, between nodes 1 and 5
+        let mapping = apply(
+            &mut map,
+            Edit::AddCrossing {
+                x1: -0.100,
+                y1: 51.500,
+                x2: -0.098,
+                y2: 51.501,
+            },
+        )
+        .unwrap();
+
+        assert_eq!(map.roads.len(), before + 1);
+        let road = map.roads.last().unwrap();
+        assert_eq!(road.kind, RoadKind::Crossing);
+        assert_eq!(road.node1, osm_reader::NodeID(1));
+        assert_eq!(road.node2, osm_reader::NodeID(5));
+        for i in [road.src_i, road.dst_i] {
+            assert!(map.intersections[i.0].roads.contains(&road.id));
+        }
+        // Nothing existing moved
+        assert_eq!(
+            mapping.roads,
+            (0..before).map(|r| Some(RoadID(r))).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_delete_road() {
+        let mut map = block();
+        let before = stable_ids(&map);
+        let mapping = apply(&mut map, delete(&map, 1)).unwrap();
+
+        assert_eq!(map.roads.len(), before.len() - 1);
+        assert!(!stable_ids(&map).contains(&before[1]));
+        assert_eq!(mapping.roads[0], Some(RoadID(0)));
+        assert_eq!(mapping.roads[1], None);
+        assert_eq!(mapping.roads[2], Some(RoadID(1)));
+        for (idx, road) in map.roads.iter().enumerate() {
+            assert_eq!(road.id, RoadID(idx));
+        }
+        for i in &map.intersections {
+            assert!(i.roads.iter().all(|r| r.0 < map.roads.len()));
+        }
+    }
+
+    #[test]
+    fn test_undo_restores_ids() {
+        let mut map = block();
+        let before = stable_ids(&map);
+        let intersection_roads: Vec<Vec<RoadID>> =
+            map.intersections.iter().map(|i| i.roads.clone()).collect();
+        apply(&mut map, delete(&map, 1)).unwrap();
+        let mapping = undo(&mut map).unwrap();
+
+        assert_eq!(stable_ids(&map), before);
+        assert_eq!(
+            map.intersections
+                .iter()
+                .map(|i| i.roads.clone())
+                .collect::<Vec<_>>(),
+            intersection_roads
+        );
+        // Roads after the restored one shift back up
+        assert_eq!(mapping.roads[0], Some(RoadID(0)));
+        assert_eq!(mapping.roads[1], Some(RoadID(2)));
+        assert!(undo(&mut map).is_none());
+        assert!(redo(&mut map).unwrap().is_some());
+        assert_eq!(map.roads.len(), before.len() - 1);
+    }
+
+    #[test]
+    fn test_failed_replace_all_changes_nothing() {
+        let mut map = block();
+        let set_kind = Edit::SetKind {
+            stable_id: map.roads[0].stable_id(),
+            kind: RoadKind::Severance,
+        };
+        apply(&mut map, set_kind).unwrap();
+        let before = stable_ids(&map);
+
+        let result = replace_all(
+            &mut map,
+            vec![
+                delete(&map, 2),
+                Edit::DeleteRoad {
+                    stable_id: "no such road".to_string(),
+                },
+            ],
+        );
+
+        assert!(result.is_err());
+        assert_eq!(stable_ids(&map), before);
+        assert_eq!(map.roads[0].kind, RoadKind::Severance);
+        assert_eq!(map.edits.list().len(), 1);
+    }
+
+    #[test]
+    fn test_id_mapping_then() {
+        let first = IdMapping {
+            roads: vec![Some(RoadID(1)), None, Some(RoadID(0))],
+            intersections: vec![Some(IntersectionID(0)), Some(IntersectionID(1))],
+        };
+        let second = IdMapping {
+            roads: vec![Some(RoadID(0)), None],
+            intersections: vec![None, Some(IntersectionID(0))],
+        };
+        let combined = first.then(&second);
+        assert_eq!(combined.roads, vec![None, None, Some(RoadID(0))]);
+        assert_eq!(combined.intersections, vec![None, Some(IntersectionID(0))]);
+    }
+}
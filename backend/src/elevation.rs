@@ -0,0 +1,33 @@
+use geo::{Coord, Densify, EuclideanLength};
+
+use crate::MapModel;
+
+/// How often to sample elevation along roads, in meters
+const SAMPLE_SPACING: f64 = 10.0;
+
+/// Samples elevation along every road using `lookup`, which takes a WGS84 point and returns meters
+/// above sea level, or `None` if unknown. Sets each road's `gradient` to the total climb and
+/// descent over its length, so a footbridge with steep ramps on both sides counts as hilly.
+pub fn set_gradients<F: Fn(Coord) -> Option<f64>>(map: &mut MapModel, lookup: F) {
+    for road in &mut map.roads {
+        let length = road.linestring.euclidean_length();
+        if length == 0.0 {
+            continue;
+        }
+        let samples: Option<Vec<f64>> = map
+            .mercator
+            .to_wgs84(&road.linestring.densify(SAMPLE_SPACING))
+            .0
+            .into_iter()
+            .map(&lookup)
+            .collect();
+        let Some(samples) = samples else {
+            continue;
+        };
+        let change: f64 = samples
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]).abs())
+            .sum();
+        road.gradient = Some(change / length);
+    }
+}
@@ -0,0 +1,451 @@
+use std::io::Write;
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use arrow_array::{ArrayRef, Float64Array, Int64Array, RecordBatch, UInt64Array};
+use arrow_ipc::writer::FileWriter;
+use arrow_schema::{DataType, Field, Schema};
+use geo::{Coord, Geometry, LineString, MapCoords, Simplify};
+use geojson::{feature::Id, Feature, FeatureCollection};
+use serde::Deserialize;
+use utils::Mercator;
+
+use crate::fgb::{ColumnType, ColumnValue};
+use crate::heatmap::HeatmapRow;
+use crate::provenance::Provenance;
+use crate::Road;
+
+/// Somewhere to write exported results: a string for the wasm API, bytes, or a file on native
+/// builds. Exporters write here, so every target shares the same serialization.
+pub trait ExportSink {
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<()>;
+}
+
+impl ExportSink for String {
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        self.push_str(std::str::from_utf8(bytes)?);
+        Ok(())
+    }
+}
+
+impl ExportSink for Vec<u8> {
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        self.extend_from_slice(bytes);
+        Ok(())
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl ExportSink for std::fs::File {
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        self.write_all(bytes)?;
+        Ok(())
+    }
+}
+
+/// Adapts a sink for anything expecting `std::io::Write`, so output can be streamed
+struct SinkWriter<'a>(&'a mut dyn ExportSink);
+
+impl Write for SinkWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .write_bytes(buf)
+            .map_err(|err| std::io::Error::other(err.to_string()))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// How to export a table
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TableFormat {
+    Csv,
+    /// An Apache Arrow IPC file, readable by pandas, R, and others
+    Arrow,
+}
+
+const HEATMAP_COLUMNS: [&str; 9] = [
+    "id",
+    "way",
+    "x1",
+    "y1",
+    "x2",
+    "y2",
+    "score",
+    "direct_length",
+    "route_length",
+];
+
+/// Writes scored desire lines as CSV or Arrow, with one row per line
+pub fn write_heatmap_table(
+    rows: &[HeatmapRow],
+    format: TableFormat,
+    provenance: &Provenance,
+    sink: &mut dyn ExportSink,
+) -> Result<()> {
+    let provenance = serde_json::to_string(provenance)?;
+    match format {
+        TableFormat::Csv => {
+            let mut out = format!("# provenance: {provenance}\n");
+            out.push_str(&HEATMAP_COLUMNS.join(","));
+            out.push('\n');
+            for r in rows {
+                out.push_str(&format!(
+                    "{},{},{},{},{},{},{},{},{}\n",
+                    r.id,
+                    r.way.0,
+                    r.line.start.x,
+                    r.line.start.y,
+                    r.line.end.x,
+                    r.line.end.y,
+                    r.score,
+                    r.direct_length,
+                    r.route_length
+                ));
+            }
+            sink.write_bytes(out.as_bytes())
+        }
+        TableFormat::Arrow => {
+            let mut fields = vec![
+                Field::new(HEATMAP_COLUMNS[0], DataType::UInt64, false),
+                Field::new(HEATMAP_COLUMNS[1], DataType::Int64, false),
+            ];
+            for name in &HEATMAP_COLUMNS[2..] {
+                fields.push(Field::new(*name, DataType::Float64, false));
+            }
+            let metadata = [("provenance".to_string(), provenance)]
+                .into_iter()
+                .collect();
+            let schema = Arc::new(Schema::new(fields).with_metadata(metadata));
+
+            let floats = |f: fn(&HeatmapRow) -> f64| -> ArrayRef {
+                Arc::new(Float64Array::from_iter_values(rows.iter().map(f)))
+            };
+            let columns = vec![
+                Arc::new(UInt64Array::from_iter_values(
+                    rows.iter().map(|r| r.id as u64),
+                )) as ArrayRef,
+                Arc::new(Int64Array::from_iter_values(rows.iter().map(|r| r.way.0))),
+                floats(|r| r.line.start.x),
+                floats(|r| r.line.start.y),
+                floats(|r| r.line.end.x),
+                floats(|r| r.line.end.y),
+                floats(|r| r.score),
+                floats(|r| r.direct_length),
+                floats(|r| r.route_length),
+            ];
+            let batch = RecordBatch::try_new(schema.clone(), columns)?;
+            let mut writer = FileWriter::try_new(SinkWriter(sink), &schema)?;
+            writer.write(&batch)?;
+            writer.finish()?;
+            Ok(())
+        }
+    }
+}
+
+/// A layer that can be exported as FlatGeobuf
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Layer {
+    Network,
+    Crossings,
+    Heatmap,
+    Neighbourhoods,
+}
+
+const NETWORK_COLUMNS: [(&str, ColumnType); 15] = [
+    ("id", ColumnType::ULong),
+    ("stable_id", ColumnType::String),
+    ("kind", ColumnType::String),
+    ("way", ColumnType::String),
+    ("node1", ColumnType::String),
+    ("node2", ColumnType::String),
+    ("highway", ColumnType::String),
+    ("name", ColumnType::String),
+    ("shared_with_cyclists", ColumnType::Bool),
+    ("layer", ColumnType::Long),
+    ("time_restricted", ColumnType::Bool),
+    ("aadt", ColumnType::Double),
+    ("truncated", ColumnType::Bool),
+    ("gradient", ColumnType::Double),
+    ("footway", ColumnType::String),
+];
+
+const CROSSING_COLUMNS: [(&str, ColumnType); 4] = [
+    ("quality", ColumnType::String),
+    ("skew_degrees", ColumnType::Double),
+    ("skewed", ColumnType::Bool),
+    ("score", ColumnType::Double),
+];
+
+const HEATMAP_LAYER_COLUMNS: [(&str, ColumnType); 10] = [
+    ("id", ColumnType::ULong),
+    ("severance_way", ColumnType::String),
+    ("score", ColumnType::Double),
+    ("direct_length", ColumnType::Double),
+    ("route_length", ColumnType::Double),
+    ("extra_length", ColumnType::Double),
+    ("route_score", ColumnType::Double),
+    ("alongside_severance", ColumnType::Double),
+    ("demand", ColumnType::Double),
+    ("weighted_score", ColumnType::Double),
+];
+
+const NEIGHBOURHOOD_COLUMNS: [(&str, ColumnType); 3] = [
+    ("id", ColumnType::ULong),
+    ("area", ColumnType::Double),
+    ("population", ColumnType::Double),
+];
+
+impl Layer {
+    fn name(self) -> &'static str {
+        match self {
+            Layer::Network => "network",
+            Layer::Crossings => "crossings",
+            Layer::Heatmap => "heatmap",
+            Layer::Neighbourhoods => "neighbourhoods",
+        }
+    }
+
+    /// The attributes written for each feature. Properties not listed here are dropped, so every
+    /// export of a layer has the same schema. All columns are nullable.
+    fn columns(self) -> Vec<(&'static str, ColumnType)> {
+        match self {
+            Layer::Network => NETWORK_COLUMNS.to_vec(),
+            Layer::Crossings => [&NETWORK_COLUMNS[..], &CROSSING_COLUMNS[..]].concat(),
+            Layer::Heatmap => HEATMAP_LAYER_COLUMNS.to_vec(),
+            Layer::Neighbourhoods => NEIGHBOURHOOD_COLUMNS.to_vec(),
+        }
+    }
+}
+
+/// Writes one layer of WGS84 GeoJSON as FlatGeobuf, for loading straight into QGIS. There's no
+/// spatial index. A numeric feature ID is used for the `id` column if there's no `id` property.
+pub fn write_flatgeobuf(
+    layer: Layer,
+    gj: &FeatureCollection,
+    sink: &mut dyn ExportSink,
+) -> Result<()> {
+    let columns = layer.columns();
+    let mut features = Vec::new();
+    for f in &gj.features {
+        let Some(ref geometry) = f.geometry else {
+            continue;
+        };
+        let geometry: Geometry = geometry.value.clone().try_into()?;
+        let feature_id = match f.id {
+            Some(Id::Number(ref n)) => Some(serde_json::Value::Number(n.clone())),
+            _ => None,
+        };
+        let mut properties = Vec::new();
+        for (idx, (name, column_type)) in columns.iter().enumerate() {
+            let value = match f.property(name) {
+                Some(value) if !value.is_null() => value.clone(),
+                _ if *name == "id" => match feature_id {
+                    Some(ref id) => id.clone(),
+                    None => continue,
+                },
+                _ => continue,
+            };
+            let column_value = match column_type {
+                ColumnType::ULong => value.as_u64().map(ColumnValue::ULong),
+                ColumnType::Long => value.as_i64().map(ColumnValue::Long),
+                ColumnType::Double => value.as_f64().map(ColumnValue::Double),
+                ColumnType::Bool => value.as_bool().map(ColumnValue::Bool),
+                ColumnType::String => Some(ColumnValue::String(match value {
+                    serde_json::Value::String(x) => x,
+                    other => other.to_string(),
+                })),
+            };
+            if let Some(column_value) = column_value {
+                properties.push((idx, column_value));
+            }
+        }
+        features.extend(crate::fgb::feature(&geometry, &properties));
+    }
+
+    sink.write_bytes(&crate::fgb::MAGIC)?;
+    sink.write_bytes(&crate::fgb::header(
+        layer.name(),
+        &columns,
+        features.len() as u64,
+    ))?;
+    for feature in features {
+        sink.write_bytes(&feature)?;
+    }
+    Ok(())
+}
+
+/// Controls how much detail `render` includes, since every OSM tag on every road adds up
+#[derive(Default, Deserialize)]
+pub struct RenderOptions {
+    /// Which properties to keep. Defaults to all of them.
+    #[serde(default)]
+    properties: PropertyFilter,
+    /// Round coordinates to this many decimal places. 6 is about 10cm.
+    #[serde(default)]
+    precision: Option<u32>,
+    /// Simplify geometry, keeping it within this many meters of the original
+    #[serde(default)]
+    simplify_meters: Option<f64>,
+}
+
+/// Which properties to keep on each feature
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PropertyFilter {
+    /// Every OSM tag and derived property
+    #[default]
+    All,
+    /// Just the `id` and `kind`
+    KindOnly,
+    /// The `id` and `kind`, plus the listed tags or properties
+    Tags(Vec<String>),
+}
+
+impl RenderOptions {
+    pub fn road(&self, road: &Road, mercator: &Mercator) -> Feature {
+        let mut f = road.to_gj(mercator);
+
+        let linestring = match self.simplify_meters {
+            Some(tolerance) => road.linestring.simplify(&tolerance),
+            None => road.linestring.clone(),
+        };
+        let mut linestring = mercator.to_wgs84(&linestring);
+        if let Some(precision) = self.precision {
+            let factor = 10_f64.powi(precision as i32);
+            linestring = linestring.map_coords(|c| Coord {
+                x: (c.x * factor).round() / factor,
+                y: (c.y * factor).round() / factor,
+            });
+        }
+        f.geometry = Some(geojson::Geometry::from(&linestring));
+
+        if let Some(ref mut properties) = f.properties {
+            match self.properties {
+                PropertyFilter::All => {}
+                PropertyFilter::KindOnly => {
+                    properties.retain(|key, _| key == "id" || key == "kind");
+                }
+                PropertyFilter::Tags(ref keep) => {
+                    properties.retain(|key, _| key == "id" || key == "kind" || keep.contains(key));
+                }
+            }
+        }
+        f
+    }
+}
+
+/// Streams GeoJSON to a sink
+pub fn write_geojson(gj: &FeatureCollection, sink: &mut dyn ExportSink) -> Result<()> {
+    serde_json::to_writer(SinkWriter(sink), gj)?;
+    Ok(())
+}
+
+/// How to return a route
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RouteFormat {
+    /// Every piece of the route as a feature, with details
+    #[default]
+    GeoJson,
+    /// A Google encoded polyline of the whole route, with 5 decimal places
+    Polyline,
+    /// A GPX track of the whole route
+    Gpx,
+}
+
+/// Joins the WGS84 pieces of a route into one line. Pieces may point either way.
+pub fn route_linestring(gj: &FeatureCollection) -> Result<LineString> {
+    let mut pts: Vec<Coord> = Vec::new();
+    for f in &gj.features {
+        let Some(ref geometry) = f.geometry else {
+            continue;
+        };
+        let mut piece: LineString = geometry.value.clone().try_into()?;
+        if let (Some(last), Some(first), Some(end)) = (pts.last(), piece.0.first(), piece.0.last())
+        {
+            if first != last && end == last {
+                piece.0.reverse();
+            }
+        }
+        for pt in piece.0 {
+            if pts.last() != Some(&pt) {
+                pts.push(pt);
+            }
+        }
+    }
+    if pts.len() < 2 {
+        bail!("Route has no geometry");
+    }
+    Ok(LineString::new(pts))
+}
+
+/// Writes a WGS84 line in Google's encoded polyline format
+pub fn write_polyline(line: &LineString, sink: &mut dyn ExportSink) -> Result<()> {
+    let mut out = String::new();
+    let (mut prev_lat, mut prev_lon) = (0, 0);
+    for pt in &line.0 {
+        let lat = (pt.y * 1e5).round() as i64;
+        let lon = (pt.x * 1e5).round() as i64;
+        encode_polyline_value(lat - prev_lat, &mut out);
+        encode_polyline_value(lon - prev_lon, &mut out);
+        (prev_lat, prev_lon) = (lat, lon);
+    }
+    sink.write_bytes(out.as_bytes())
+}
+
+fn encode_polyline_value(value: i64, out: &mut String) {
+    let mut value = if value < 0 { !(value << 1) } else { value << 1 };
+    while value >= 0x20 {
+        out.push(char::from((((value & 0x1f) | 0x20) + 63) as u8));
+        value >>= 5;
+    }
+    out.push(char::from((value + 63) as u8));
+}
+
+/// Writes a WGS84 line as a GPX track
+pub fn write_gpx(line: &LineString, name: &str, sink: &mut dyn ExportSink) -> Result<()> {
+    let mut writer = SinkWriter(sink);
+    writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        writer,
+        r#"<gpx version="1.1" creator="severance_snape" xmlns="http://www.topografix.com/GPX/1/1">"#
+    )?;
+    writeln!(writer, "  <trk>")?;
+    writeln!(writer, "    <name>{}</name>", crate::merge::escape(name))?;
+    writeln!(writer, "    <trkseg>")?;
+    for pt in &line.0 {
+        writeln!(writer, r#"      <trkpt lat="{}" lon="{}"/>"#, pt.y, pt.x)?;
+    }
+    writeln!(writer, "    </trkseg>")?;
+    writeln!(writer, "  </trk>")?;
+    writeln!(writer, "</gpx>")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_polyline() {
+        // The example from Google's documentation of the format
+        let line = LineString::from(vec![(-120.2, 38.5), (-120.95, 40.7), (-126.453, 43.252)]);
+        let mut out = String::new();
+        write_polyline(&line, &mut out).unwrap();
+        assert_eq!(out, "_p~iF~ps|U_ulLnnqC_mqNvxq`@");
+    }
+
+    #[test]
+    fn test_gpx_escapes_name() {
+        let line = LineString::from(vec![(-0.1, 51.5), (-0.2, 51.6)]);
+        let mut out = String::new();
+        write_gpx(&line, "Fish & <Chips>", &mut out).unwrap();
+        assert!(out.contains("<name>Fish &amp; &lt;Chips&gt;</name>"));
+    }
+}
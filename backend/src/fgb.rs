@@ -0,0 +1,308 @@
+use geo::{Coord, Geometry, LineString, Polygon};
+
+// Just enough FlatGeobuf to write a layer without a spatial index, entirely in memory. The
+// official writer sorts features through a temporary file, which isn't available in the browser.
+// See https://flatgeobuf.org for the format.
+
+/// Every file starts with this: "fgb", the major version, "fgb", the patch version
+pub const MAGIC: [u8; 8] = [b'f', b'g', b'b', 3, b'f', b'g', b'b', 1];
+
+/// The subset of FlatGeobuf column types used by exports
+#[derive(Clone, Copy)]
+pub enum ColumnType {
+    Bool = 2,
+    Long = 7,
+    ULong = 8,
+    Double = 10,
+    String = 11,
+}
+
+pub enum ColumnValue {
+    Bool(bool),
+    Long(i64),
+    ULong(u64),
+    Double(f64),
+    String(String),
+}
+
+/// The size-prefixed header, for WGS84 features of any geometry type. All columns are nullable.
+pub fn header(name: &str, columns: &[(&str, ColumnType)], features_count: u64) -> Vec<u8> {
+    let mut b = Builder::new();
+    let root = b.table(&[
+        (0, Field::Offset),
+        (7, Field::Offset),
+        (8, Field::Scalar(features_count.to_le_bytes().to_vec())),
+        // No spatial index
+        (9, Field::Scalar(0_u16.to_le_bytes().to_vec())),
+        (10, Field::Offset),
+    ]);
+    b.set_offset(4, root.pos);
+
+    let name = b.string(name);
+    b.set_offset(root.slots[0], name);
+
+    let (pos, column_slots) = b.offsets_vector(columns.len());
+    b.set_offset(root.slots[1], pos);
+    for ((name, column_type), slot) in columns.iter().zip(column_slots) {
+        let column = b.table(&[
+            (0, Field::Offset),
+            (1, Field::Scalar(vec![*column_type as u8])),
+        ]);
+        b.set_offset(slot, column.pos);
+        let name = b.string(name);
+        b.set_offset(column.slots[0], name);
+    }
+
+    let crs = b.table(&[(1, Field::Scalar(4326_i32.to_le_bytes().to_vec()))]);
+    b.set_offset(root.slots[2], crs.pos);
+    b.finish()
+}
+
+/// One size-prefixed feature, with values for some columns by index. Returns `None` for
+/// geometry FlatGeobuf can't represent.
+pub fn feature(geometry: &Geometry, properties: &[(usize, ColumnValue)]) -> Option<Vec<u8>> {
+    let geometry = Parts::new(geometry)?;
+
+    let mut bytes = Vec::new();
+    for (idx, value) in properties {
+        bytes.extend_from_slice(&(*idx as u16).to_le_bytes());
+        match value {
+            ColumnValue::Bool(x) => bytes.push(*x as u8),
+            ColumnValue::Long(x) => bytes.extend_from_slice(&x.to_le_bytes()),
+            ColumnValue::ULong(x) => bytes.extend_from_slice(&x.to_le_bytes()),
+            ColumnValue::Double(x) => bytes.extend_from_slice(&x.to_le_bytes()),
+            ColumnValue::String(x) => {
+                bytes.extend_from_slice(&(x.len() as u32).to_le_bytes());
+                bytes.extend_from_slice(x.as_bytes());
+            }
+        }
+    }
+
+    let mut b = Builder::new();
+    let root = b.table(&[(0, Field::Offset), (1, Field::Offset)]);
+    b.set_offset(4, root.pos);
+    geometry.write(&mut b, root.slots[0]);
+    let properties = b.vector(1, &bytes);
+    b.set_offset(root.slots[1], properties);
+    Some(b.finish())
+}
+
+/// A geometry laid out the way FlatGeobuf stores it. Multi-part polygons and collections nest
+/// their parts; everything else is one flat list of coordinates, with the end of each line or
+/// ring if there's more than one.
+struct Parts {
+    geometry_type: u8,
+    xy: Vec<f64>,
+    ends: Vec<u32>,
+    parts: Vec<Parts>,
+}
+
+impl Parts {
+    fn new(geometry: &Geometry) -> Option<Self> {
+        Some(match geometry {
+            Geometry::Point(pt) => Self::points(1, vec![pt.0]),
+            Geometry::LineString(ls) => Self::lines(2, vec![ls]),
+            Geometry::Polygon(p) => Self::polygon(p),
+            Geometry::MultiPoint(mp) => Self::points(4, mp.iter().map(|pt| pt.0).collect()),
+            Geometry::MultiLineString(mls) => Self::lines(5, mls.iter().collect()),
+            Geometry::MultiPolygon(mp) => Self::nested(6, mp.iter().map(Self::polygon).collect()),
+            Geometry::GeometryCollection(gc) => {
+                Self::nested(7, gc.iter().map(Self::new).collect::<Option<_>>()?)
+            }
+            _ => return None,
+        })
+    }
+
+    fn points(geometry_type: u8, pts: Vec<Coord>) -> Self {
+        Self {
+            geometry_type,
+            xy: pts.into_iter().flat_map(|c| [c.x, c.y]).collect(),
+            ends: Vec::new(),
+            parts: Vec::new(),
+        }
+    }
+
+    fn lines(geometry_type: u8, lines: Vec<&LineString>) -> Self {
+        let mut xy = Vec::new();
+        let mut ends = Vec::new();
+        for line in lines {
+            xy.extend(line.coords().flat_map(|c| [c.x, c.y]));
+            ends.push((xy.len() / 2) as u32);
+        }
+        if ends.len() == 1 {
+            ends.clear();
+        }
+        Self {
+            geometry_type,
+            xy,
+            ends,
+            parts: Vec::new(),
+        }
+    }
+
+    fn polygon(polygon: &Polygon) -> Self {
+        let mut rings = vec![polygon.exterior()];
+        rings.extend(polygon.interiors());
+        Self::lines(3, rings)
+    }
+
+    fn nested(geometry_type: u8, parts: Vec<Parts>) -> Self {
+        Self {
+            geometry_type,
+            xy: Vec::new(),
+            ends: Vec::new(),
+            parts,
+        }
+    }
+
+    fn write(&self, b: &mut Builder, slot: usize) {
+        let mut fields = Vec::new();
+        if !self.ends.is_empty() {
+            fields.push((0, Field::Offset));
+        }
+        if !self.xy.is_empty() {
+            fields.push((1, Field::Offset));
+        }
+        fields.push((6, Field::Scalar(vec![self.geometry_type])));
+        if !self.parts.is_empty() {
+            fields.push((7, Field::Offset));
+        }
+        let table = b.table(&fields);
+        b.set_offset(slot, table.pos);
+
+        let mut slots = table.slots.into_iter();
+        if !self.ends.is_empty() {
+            let bytes: Vec<u8> = self.ends.iter().flat_map(|x| x.to_le_bytes()).collect();
+            let pos = b.vector(4, &bytes);
+            b.set_offset(slots.next().unwrap(), pos);
+        }
+        if !self.xy.is_empty() {
+            let bytes: Vec<u8> = self.xy.iter().flat_map(|x| x.to_le_bytes()).collect();
+            let pos = b.vector(8, &bytes);
+            b.set_offset(slots.next().unwrap(), pos);
+        }
+        if !self.parts.is_empty() {
+            let (pos, part_slots) = b.offsets_vector(self.parts.len());
+            b.set_offset(slots.next().unwrap(), pos);
+            for (part, slot) in self.parts.iter().zip(part_slots) {
+                part.write(b, slot);
+            }
+        }
+    }
+}
+
+enum Field {
+    /// Little-endian bytes, aligned to their own size
+    Scalar(Vec<u8>),
+    /// To a string, vector, or table written later
+    Offset,
+}
+
+struct Table {
+    pos: usize,
+    /// Where to write each `Field::Offset`, in order
+    slots: Vec<usize>,
+}
+
+/// Lays out one size-prefixed flatbuffer front to back. Offsets to other objects have to point
+/// forwards, so each table's vtable comes just before it, and everything it refers to comes
+/// after. The buffer starts with the size, then the offset to the root table.
+struct Builder {
+    buf: Vec<u8>,
+}
+
+impl Builder {
+    fn new() -> Self {
+        Self { buf: vec![0; 8] }
+    }
+
+    /// Pads with zeroes until `extra` more bytes would end on a multiple of `align`
+    fn pad(&mut self, align: usize, extra: usize) {
+        while (self.buf.len() + extra) % align != 0 {
+            self.buf.push(0);
+        }
+    }
+
+    fn set_offset(&mut self, slot: usize, target: usize) {
+        let offset = (target - slot) as u32;
+        self.buf[slot..slot + 4].copy_from_slice(&offset.to_le_bytes());
+    }
+
+    /// Fields are identified by their position in the schema
+    fn table(&mut self, fields: &[(u16, Field)]) -> Table {
+        // Place the biggest fields first, after the offset to the vtable, to avoid padding
+        let size = |field: &Field| match field {
+            Field::Scalar(bytes) => bytes.len(),
+            Field::Offset => 4,
+        };
+        let mut order: Vec<usize> = (0..fields.len()).collect();
+        order.sort_by_key(|idx| std::cmp::Reverse(size(&fields[*idx].1)));
+        let mut field_offsets = vec![0; fields.len()];
+        let mut table_size = 4;
+        for idx in order {
+            let size = size(&fields[idx].1);
+            table_size = table_size.next_multiple_of(size);
+            field_offsets[idx] = table_size;
+            table_size += size;
+        }
+
+        let num_slots = fields.iter().map(|(id, _)| *id as usize + 1).max().unwrap_or(0);
+        let vtable_size = 4 + 2 * num_slots;
+        let mut vtable = vec![0_u16; num_slots + 2];
+        vtable[0] = vtable_size as u16;
+        vtable[1] = table_size as u16;
+        for ((id, _), offset) in fields.iter().zip(&field_offsets) {
+            vtable[*id as usize + 2] = *offset as u16;
+        }
+        // Start the table on a multiple of 8, so every field is aligned
+        self.pad(8, vtable_size);
+        let vtable_pos = self.buf.len();
+        for x in vtable {
+            self.buf.extend_from_slice(&x.to_le_bytes());
+        }
+
+        let pos = self.buf.len();
+        let mut table = vec![0; table_size];
+        table[0..4].copy_from_slice(&((pos - vtable_pos) as i32).to_le_bytes());
+        let mut slots = Vec::new();
+        for ((_, field), offset) in fields.iter().zip(field_offsets) {
+            match field {
+                Field::Scalar(bytes) => {
+                    table[offset..offset + bytes.len()].copy_from_slice(bytes);
+                }
+                Field::Offset => slots.push(pos + offset),
+            }
+        }
+        self.buf.extend(table);
+        Table { pos, slots }
+    }
+
+    fn string(&mut self, x: &str) -> usize {
+        let pos = self.vector(1, x.as_bytes());
+        self.buf.push(0);
+        pos
+    }
+
+    /// A vector of `bytes.len() / element_size` little-endian scalars
+    fn vector(&mut self, element_size: usize, bytes: &[u8]) -> usize {
+        // The length and the elements both have to be aligned
+        self.pad(element_size.max(4), 4);
+        let pos = self.buf.len();
+        let len = (bytes.len() / element_size) as u32;
+        self.buf.extend_from_slice(&len.to_le_bytes());
+        self.buf.extend_from_slice(bytes);
+        pos
+    }
+
+    /// A vector of offsets to tables, returning where to write each one
+    fn offsets_vector(&mut self, len: usize) -> (usize, Vec<usize>) {
+        let pos = self.vector(4, &vec![0; 4 * len]);
+        (pos, (0..len).map(|idx| pos + 4 + 4 * idx).collect())
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let size = (self.buf.len() - 4) as u32;
+        self.buf[0..4].copy_from_slice(&size.to_le_bytes());
+        self.buf
+    }
+}
@@ -0,0 +1,913 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+use geo::{BoundingRect, Coord, Geometry, LineString, Polygon, Rect};
+use geojson::FeatureCollection;
+use serde_json::Value;
+
+use crate::export::ExportSink;
+
+// There's no SQLite in the wasm build, so this writes the database file format directly. Only
+// what's needed for a fresh, read-only database is supported: table b-trees with overflow pages,
+// and no indices. The GeoPackage tables leave out their key constraints, since those would need
+// index b-trees too; GDAL and QGIS don't rely on them.
+
+/// Big pages mean geometry rarely spills onto overflow pages
+const PAGE_SIZE: usize = 65536;
+/// "GPKG", marking the file as a GeoPackage
+const APPLICATION_ID: u32 = 0x4750_4B47;
+/// GeoPackage 1.4.0
+const USER_VERSION: u32 = 10400;
+const WGS84: i64 = 4326;
+
+const WGS84_DEFINITION: &str = r#"GEOGCS["WGS 84",DATUM["WGS_1984",SPHEROID["WGS 84",6378137,298.257223563,AUTHORITY["EPSG","7030"]],AUTHORITY["EPSG","6326"]],PRIMEM["Greenwich",0,AUTHORITY["EPSG","8901"]],UNIT["degree",0.0174532925199433,AUTHORITY["EPSG","9122"]],AUTHORITY["EPSG","4326"]]"#;
+
+/// Writes WGS84 GeoJSON layers into one GeoPackage. Each layer becomes a table named after it,
+/// with a column per property. `timestamp` is an ISO 8601 time recorded as when the layers last
+/// changed.
+pub fn write_geopackage(
+    layers: &[(&str, &FeatureCollection)],
+    timestamp: &str,
+    sink: &mut dyn ExportSink,
+) -> Result<()> {
+    let mut tables = vec![
+        Table {
+            name: "gpkg_spatial_ref_sys".to_string(),
+            sql: "CREATE TABLE gpkg_spatial_ref_sys (srs_name TEXT NOT NULL, srs_id INTEGER PRIMARY KEY, organization TEXT NOT NULL, organization_coordsys_id INTEGER NOT NULL, definition TEXT NOT NULL, description TEXT)".to_string(),
+            rows: [
+                (-1, "Undefined cartesian SRS", "undefined"),
+                (0, "Undefined geographic SRS", "undefined"),
+                (WGS84, "WGS 84 geodetic", WGS84_DEFINITION),
+            ]
+            .into_iter()
+            .map(|(id, name, definition)| {
+                let organization = if id == WGS84 { "EPSG" } else { "NONE" };
+                (
+                    id,
+                    vec![
+                        SqlValue::Text(name.to_string()),
+                        SqlValue::Null,
+                        SqlValue::Text(organization.to_string()),
+                        SqlValue::Integer(id),
+                        SqlValue::Text(definition.to_string()),
+                        SqlValue::Null,
+                    ],
+                )
+            })
+            .collect(),
+        },
+        Table {
+            name: "gpkg_contents".to_string(),
+            sql: "CREATE TABLE gpkg_contents (table_name TEXT NOT NULL, data_type TEXT NOT NULL, identifier TEXT, description TEXT DEFAULT '', last_change DATETIME NOT NULL, min_x DOUBLE, min_y DOUBLE, max_x DOUBLE, max_y DOUBLE, srs_id INTEGER)".to_string(),
+            rows: Vec::new(),
+        },
+        Table {
+            name: "gpkg_geometry_columns".to_string(),
+            sql: "CREATE TABLE gpkg_geometry_columns (table_name TEXT NOT NULL, column_name TEXT NOT NULL, geometry_type_name TEXT NOT NULL, srs_id INTEGER NOT NULL, z TINYINT NOT NULL, m TINYINT NOT NULL)".to_string(),
+            rows: Vec::new(),
+        },
+    ];
+
+    for (idx, (name, gj)) in layers.iter().enumerate() {
+        let rowid = idx as i64 + 1;
+        let layer = feature_table(name, gj);
+        let extent = |f: fn(&Rect) -> f64| match layer.bounds {
+            Some(ref rect) => SqlValue::Real(f(rect)),
+            None => SqlValue::Null,
+        };
+        tables[1].rows.push((
+            rowid,
+            vec![
+                SqlValue::Text(name.to_string()),
+                SqlValue::Text("features".to_string()),
+                SqlValue::Text(name.to_string()),
+                SqlValue::Text(String::new()),
+                SqlValue::Text(timestamp.to_string()),
+                extent(|r| r.min().x),
+                extent(|r| r.min().y),
+                extent(|r| r.max().x),
+                extent(|r| r.max().y),
+                SqlValue::Integer(WGS84),
+            ],
+        ));
+        tables[2].rows.push((
+            rowid,
+            vec![
+                SqlValue::Text(name.to_string()),
+                SqlValue::Text("geom".to_string()),
+                SqlValue::Text("GEOMETRY".to_string()),
+                SqlValue::Integer(WGS84),
+                SqlValue::Integer(0),
+                SqlValue::Integer(0),
+            ],
+        ));
+        tables.push(layer.table);
+    }
+
+    let mut pager = Pager::default();
+    // The schema table always starts on page 1
+    pager.allocate();
+    let mut schema_rows = Vec::new();
+    for (idx, table) in tables.into_iter().enumerate() {
+        let root = pager.build_table(&table.rows, None);
+        schema_rows.push((
+            idx as i64 + 1,
+            vec![
+                SqlValue::Text("table".to_string()),
+                SqlValue::Text(table.name.clone()),
+                SqlValue::Text(table.name),
+                SqlValue::Integer(root as i64),
+                SqlValue::Text(table.sql),
+            ],
+        ));
+    }
+    pager.build_table(&schema_rows, Some(1));
+
+    let num_pages = pager.pages.len() as u32;
+    let header = &mut pager.pages[0][0..100];
+    header[0..16].copy_from_slice(b"SQLite format 3\0");
+    // 65536 is written as 1
+    header[16..18].copy_from_slice(&1_u16.to_be_bytes());
+    // Legacy journal mode for reading and writing
+    header[18] = 1;
+    header[19] = 1;
+    // Payload fractions, which must be these values
+    header[21] = 64;
+    header[22] = 32;
+    header[23] = 32;
+    header[24..28].copy_from_slice(&1_u32.to_be_bytes());
+    header[28..32].copy_from_slice(&num_pages.to_be_bytes());
+    // Schema cookie and format
+    header[40..44].copy_from_slice(&1_u32.to_be_bytes());
+    header[44..48].copy_from_slice(&4_u32.to_be_bytes());
+    // UTF-8
+    header[56..60].copy_from_slice(&1_u32.to_be_bytes());
+    header[60..64].copy_from_slice(&USER_VERSION.to_be_bytes());
+    header[68..72].copy_from_slice(&APPLICATION_ID.to_be_bytes());
+    header[92..96].copy_from_slice(&1_u32.to_be_bytes());
+    header[96..100].copy_from_slice(&3_045_000_u32.to_be_bytes());
+
+    for page in pager.pages {
+        sink.write_bytes(&page)?;
+    }
+    Ok(())
+}
+
+struct Table {
+    name: String,
+    sql: String,
+    /// Rowid and values, sorted by rowid
+    rows: Vec<(i64, Vec<SqlValue>)>,
+}
+
+struct FeatureTable {
+    table: Table,
+    bounds: Option<Rect>,
+}
+
+/// Makes a table for a layer, with `fid` and `geom` columns followed by every property. Column
+/// types come from the values: all booleans, all integers, all numbers, or otherwise text.
+fn feature_table(name: &str, gj: &FeatureCollection) -> FeatureTable {
+    // Column names are case insensitive, and OSM tags sometimes differ only by case
+    let mut seen: HashSet<String> = ["fid".to_string(), "geom".to_string()].into();
+    let mut columns: Vec<(String, &'static str)> = Vec::new();
+    for f in &gj.features {
+        for (key, value) in f.properties.iter().flatten() {
+            if value.is_null() || !seen.insert(key.to_lowercase()) {
+                continue;
+            }
+            columns.push((key.clone(), column_type(gj, key)));
+        }
+    }
+
+    let mut sql = format!(
+        "CREATE TABLE {} (fid INTEGER PRIMARY KEY, geom GEOMETRY",
+        quote(name)
+    );
+    for (key, column_type) in &columns {
+        sql.push_str(&format!(", {} {}", quote(key), column_type));
+    }
+    sql.push(')');
+
+    let mut rows = Vec::new();
+    let mut bounds: Option<Rect> = None;
+    for (idx, f) in gj.features.iter().enumerate() {
+        let geometry: Option<Geometry> = f
+            .geometry
+            .as_ref()
+            .and_then(|g| g.value.clone().try_into().ok());
+        if let Some(rect) = geometry.as_ref().and_then(|g| g.bounding_rect()) {
+            bounds = Some(match bounds {
+                Some(b) => Rect::new(
+                    Coord {
+                        x: b.min().x.min(rect.min().x),
+                        y: b.min().y.min(rect.min().y),
+                    },
+                    Coord {
+                        x: b.max().x.max(rect.max().x),
+                        y: b.max().y.max(rect.max().y),
+                    },
+                ),
+                None => rect,
+            });
+        }
+
+        let mut values = vec![
+            SqlValue::Null,
+            geometry
+                .map(|g| SqlValue::Blob(gpkg_geometry(&g)))
+                .unwrap_or(SqlValue::Null),
+        ];
+        for (key, column_type) in &columns {
+            values.push(match f.property(key) {
+                None | Some(Value::Null) => SqlValue::Null,
+                Some(Value::Bool(x)) => SqlValue::Integer(*x as i64),
+                Some(Value::Number(x)) if *column_type == "INTEGER" => {
+                    SqlValue::Integer(x.as_i64().unwrap_or(0))
+                }
+                Some(Value::Number(x)) if *column_type == "REAL" => {
+                    SqlValue::Real(x.as_f64().unwrap_or(0.0))
+                }
+                Some(Value::String(x)) => SqlValue::Text(x.clone()),
+                Some(other) => SqlValue::Text(other.to_string()),
+            });
+        }
+        rows.push((idx as i64 + 1, values));
+    }
+
+    FeatureTable {
+        table: Table {
+            name: name.to_string(),
+            sql,
+            rows,
+        },
+        bounds,
+    }
+}
+
+fn column_type(gj: &FeatureCollection, key: &str) -> &'static str {
+    let values: Vec<&Value> = gj
+        .features
+        .iter()
+        .filter_map(|f| f.property(key))
+        .filter(|v| !v.is_null())
+        .collect();
+    if values.iter().all(|v| v.is_boolean()) {
+        "BOOLEAN"
+    } else if values.iter().all(|v| v.is_i64()) {
+        "INTEGER"
+    } else if values.iter().all(|v| v.is_number()) {
+        "REAL"
+    } else {
+        "TEXT"
+    }
+}
+
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// The GeoPackage binary header, with an envelope, followed by little-endian WKB
+fn gpkg_geometry(geometry: &Geometry) -> Vec<u8> {
+    let mut out = b"GP".to_vec();
+    // Version 1
+    out.push(0);
+    let bounds = geometry.bounding_rect();
+    // Little-endian, with an xy envelope, or flagged as empty
+    out.push(match bounds {
+        Some(_) => 0b0000_0011,
+        None => 0b0001_0001,
+    });
+    out.extend_from_slice(&(WGS84 as i32).to_le_bytes());
+    if let Some(rect) = bounds {
+        for x in [rect.min().x, rect.max().x, rect.min().y, rect.max().y] {
+            out.extend_from_slice(&x.to_le_bytes());
+        }
+    }
+    write_wkb(&mut out, geometry);
+    out
+}
+
+fn write_wkb(out: &mut Vec<u8>, geometry: &Geometry) {
+    let start = |out: &mut Vec<u8>, kind: u32| {
+        out.push(1);
+        out.extend_from_slice(&kind.to_le_bytes());
+    };
+    let coord = |out: &mut Vec<u8>, pt: Coord| {
+        out.extend_from_slice(&pt.x.to_le_bytes());
+        out.extend_from_slice(&pt.y.to_le_bytes());
+    };
+    let coords = |out: &mut Vec<u8>, ls: &LineString| {
+        out.extend_from_slice(&(ls.0.len() as u32).to_le_bytes());
+        for pt in &ls.0 {
+            coord(out, *pt);
+        }
+    };
+    let rings = |out: &mut Vec<u8>, polygon: &Polygon| {
+        out.extend_from_slice(&(1 + polygon.interiors().len() as u32).to_le_bytes());
+        coords(out, polygon.exterior());
+        for ring in polygon.interiors() {
+            coords(out, ring);
+        }
+    };
+    let count = |out: &mut Vec<u8>, n: usize| out.extend_from_slice(&(n as u32).to_le_bytes());
+
+    match geometry {
+        Geometry::Point(pt) => {
+            start(out, 1);
+            coord(out, pt.0);
+        }
+        Geometry::Line(line) => {
+            start(out, 2);
+            coords(out, &LineString::new(vec![line.start, line.end]));
+        }
+        Geometry::LineString(ls) => {
+            start(out, 2);
+            coords(out, ls);
+        }
+        Geometry::Polygon(polygon) => {
+            start(out, 3);
+            rings(out, polygon);
+        }
+        Geometry::Rect(rect) => {
+            start(out, 3);
+            rings(out, &rect.to_polygon());
+        }
+        Geometry::Triangle(triangle) => {
+            start(out, 3);
+            rings(out, &triangle.to_polygon());
+        }
+        Geometry::MultiPoint(pts) => {
+            start(out, 4);
+            count(out, pts.0.len());
+            for pt in &pts.0 {
+                write_wkb(out, &Geometry::Point(*pt));
+            }
+        }
+        Geometry::MultiLineString(mls) => {
+            start(out, 5);
+            count(out, mls.0.len());
+            for ls in &mls.0 {
+                start(out, 2);
+                coords(out, ls);
+            }
+        }
+        Geometry::MultiPolygon(mp) => {
+            start(out, 6);
+            count(out, mp.0.len());
+            for polygon in &mp.0 {
+                start(out, 3);
+                rings(out, polygon);
+            }
+        }
+        Geometry::GeometryCollection(gc) => {
+            start(out, 7);
+            count(out, gc.0.len());
+            for g in &gc.0 {
+                write_wkb(out, g);
+            }
+        }
+    }
+}
+
+enum SqlValue {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+/// Encodes a row in SQLite's record format
+fn record(values: &[SqlValue]) -> Vec<u8> {
+    let mut types = Vec::new();
+    let mut body = Vec::new();
+    for value in values {
+        match value {
+            SqlValue::Null => write_varint(&mut types, 0),
+            SqlValue::Integer(x) => {
+                write_varint(&mut types, 6);
+                body.extend_from_slice(&x.to_be_bytes());
+            }
+            SqlValue::Real(x) => {
+                write_varint(&mut types, 7);
+                body.extend_from_slice(&x.to_be_bytes());
+            }
+            SqlValue::Text(x) => {
+                write_varint(&mut types, 13 + 2 * x.len() as u64);
+                body.extend_from_slice(x.as_bytes());
+            }
+            SqlValue::Blob(x) => {
+                write_varint(&mut types, 12 + 2 * x.len() as u64);
+                body.extend_from_slice(x);
+            }
+        }
+    }
+    // The header size includes its own varint
+    let mut header_size = types.len() + 1;
+    while varint_len(header_size as u64) + types.len() != header_size {
+        header_size = types.len() + varint_len(header_size as u64);
+    }
+    let mut out = Vec::new();
+    write_varint(&mut out, header_size as u64);
+    out.extend(types);
+    out.extend(body);
+    out
+}
+
+#[derive(Default)]
+struct Pager {
+    /// Page 1 is at index 0
+    pages: Vec<Vec<u8>>,
+}
+
+impl Pager {
+    fn allocate(&mut self) -> u32 {
+        self.pages.push(vec![0; PAGE_SIZE]);
+        self.pages.len() as u32
+    }
+
+    /// Writes a table b-tree, returning the root page. The root can be placed on an
+    /// already-allocated page.
+    fn build_table(&mut self, rows: &[(i64, Vec<SqlValue>)], root: Option<u32>) -> u32 {
+        // Page 1 starts with the file header. Leave room for it on every page of that b-tree, so
+        // the root fits no matter which level it's at.
+        let offset = if root == Some(1) { 100 } else { 0 };
+        let capacity = PAGE_SIZE - offset - 12;
+
+        // Pack rows into leaves, remembering the last rowid in each
+        let mut leaves: Vec<(Vec<Vec<u8>>, i64)> = Vec::new();
+        let mut cells = Vec::new();
+        let mut used = 0;
+        let mut last_rowid = 0;
+        for (rowid, values) in rows {
+            let cell = self.leaf_cell(*rowid, &record(values));
+            if used + cell.len() + 2 > capacity && !cells.is_empty() {
+                leaves.push((std::mem::take(&mut cells), last_rowid));
+                used = 0;
+            }
+            used += cell.len() + 2;
+            cells.push(cell);
+            last_rowid = *rowid;
+        }
+        leaves.push((cells, last_rowid));
+
+        if leaves.len() == 1 {
+            let page = root.unwrap_or_else(|| self.allocate());
+            self.write_page(page, &leaves[0].0, None, offset);
+            return page;
+        }
+        let mut children = Vec::new();
+        for (cells, last_rowid) in leaves {
+            let page = self.allocate();
+            self.write_page(page, &cells, None, 0);
+            children.push((page, last_rowid));
+        }
+
+        // Interior cells are a 4 byte page number and a rowid varint, plus a 2 byte pointer
+        let per_page = capacity / (4 + 9 + 2);
+        loop {
+            let groups: Vec<&[(u32, i64)]> = children.chunks(per_page + 1).collect();
+            if groups.len() == 1 {
+                let page = root.unwrap_or_else(|| self.allocate());
+                self.write_interior(page, groups[0], offset);
+                return page;
+            }
+            let mut parents = Vec::new();
+            for group in groups {
+                let page = self.allocate();
+                self.write_interior(page, group, 0);
+                parents.push((page, group.last().unwrap().1));
+            }
+            children = parents;
+        }
+    }
+
+    /// Every child but the last gets a cell keyed by its last rowid; the last is the right pointer
+    fn write_interior(&mut self, page: u32, children: &[(u32, i64)], offset: usize) {
+        let (last, rest) = children.split_last().unwrap();
+        let cells: Vec<Vec<u8>> = rest
+            .iter()
+            .map(|(child, rowid)| {
+                let mut cell = child.to_be_bytes().to_vec();
+                write_varint(&mut cell, *rowid as u64);
+                cell
+            })
+            .collect();
+        self.write_page(page, &cells, Some(last.0), offset);
+    }
+
+    fn write_page(
+        &mut self,
+        page: u32,
+        cells: &[Vec<u8>],
+        right_child: Option<u32>,
+        offset: usize,
+    ) {
+        let bytes = &mut self.pages[page as usize - 1];
+        let header_len = if right_child.is_some() { 12 } else { 8 };
+        let mut content_start = PAGE_SIZE;
+        for (idx, cell) in cells.iter().enumerate() {
+            content_start -= cell.len();
+            bytes[content_start..content_start + cell.len()].copy_from_slice(cell);
+            let pointer = offset + header_len + 2 * idx;
+            bytes[pointer..pointer + 2].copy_from_slice(&(content_start as u16).to_be_bytes());
+        }
+
+        let header = &mut bytes[offset..offset + header_len];
+        header[0] = if right_child.is_some() { 0x05 } else { 0x0D };
+        header[3..5].copy_from_slice(&(cells.len() as u16).to_be_bytes());
+        // An empty content area starts at 65536, written as 0
+        header[5..7].copy_from_slice(&((content_start % PAGE_SIZE) as u16).to_be_bytes());
+        if let Some(child) = right_child {
+            header[8..12].copy_from_slice(&child.to_be_bytes());
+        }
+    }
+
+    /// A table leaf cell, spilling the end of big payloads onto overflow pages
+    fn leaf_cell(&mut self, rowid: i64, payload: &[u8]) -> Vec<u8> {
+        let mut cell = Vec::new();
+        write_varint(&mut cell, payload.len() as u64);
+        write_varint(&mut cell, rowid as u64);
+
+        // How much of the payload stays in the cell, following the file format spec
+        let usable = PAGE_SIZE;
+        let max_local = usable - 35;
+        if payload.len() <= max_local {
+            cell.extend_from_slice(payload);
+            return cell;
+        }
+        let min_local = (usable - 12) * 32 / 255 - 23;
+        let k = min_local + (payload.len() - min_local) % (usable - 4);
+        let local = if k <= max_local { k } else { min_local };
+        cell.extend_from_slice(&payload[..local]);
+
+        let chunks: Vec<&[u8]> = payload[local..].chunks(usable - 4).collect();
+        let pages: Vec<u32> = chunks.iter().map(|_| self.allocate()).collect();
+        cell.extend_from_slice(&pages[0].to_be_bytes());
+        for (idx, chunk) in chunks.into_iter().enumerate() {
+            let next = pages.get(idx + 1).copied().unwrap_or(0);
+            let bytes = &mut self.pages[pages[idx] as usize - 1];
+            bytes[0..4].copy_from_slice(&next.to_be_bytes());
+            bytes[4..4 + chunk.len()].copy_from_slice(chunk);
+        }
+        cell
+    }
+}
+
+/// SQLite's big-endian varint, using all 8 bits of the 9th byte
+fn write_varint(out: &mut Vec<u8>, mut x: u64) {
+    if x > 0x00ff_ffff_ffff_ffff {
+        let mut bytes = [0; 9];
+        bytes[8] = x as u8;
+        x >>= 8;
+        for byte in bytes[0..8].iter_mut().rev() {
+            *byte = (x as u8 & 0x7f) | 0x80;
+            x >>= 7;
+        }
+        out.extend_from_slice(&bytes);
+        return;
+    }
+    let mut groups = vec![x as u8 & 0x7f];
+    x >>= 7;
+    while x > 0 {
+        groups.push((x as u8 & 0x7f) | 0x80);
+        x >>= 7;
+    }
+    groups.reverse();
+    out.extend(groups);
+}
+
+fn varint_len(x: u64) -> usize {
+    let mut out = Vec::new();
+    write_varint(&mut out, x);
+    out.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use geojson::{Feature, Geometry as GeoJsonGeometry, Value as GeoJsonValue};
+    use rusqlite::Connection;
+
+    use super::*;
+
+    fn feature(value: GeoJsonValue, properties: Value) -> Feature {
+        let mut f = Feature::from(GeoJsonGeometry::new(value));
+        for (key, value) in properties.as_object().unwrap() {
+            f.set_property(key.clone(), value.clone());
+        }
+        f
+    }
+
+    /// Writes the layers to a temporary file and opens it with SQLite
+    fn round_trip(name: &str, layers: &[(&str, &FeatureCollection)]) -> Connection {
+        let mut out = Vec::new();
+        write_geopackage(layers, "2024-01-01T00:00:00.000Z", &mut out).unwrap();
+        assert_eq!(out.len() % PAGE_SIZE, 0);
+
+        let path =
+            std::env::temp_dir().join(format!("severance-{}-{}.gpkg", name, std::process::id()));
+        std::fs::write(&path, out).unwrap();
+        let conn = Connection::open(&path).unwrap();
+        let integrity: String = conn
+            .query_row("PRAGMA integrity_check", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(integrity, "ok");
+        // The open connection can still read an unlinked file on Unix
+        let _ = std::fs::remove_file(&path);
+        conn
+    }
+
+    #[test]
+    fn metadata_tables() {
+        let points = FeatureCollection::from_iter(vec![
+            feature(GeoJsonValue::Point(vec![-0.1, 51.5]), serde_json::json!({})),
+            feature(GeoJsonValue::Point(vec![0.2, 51.7]), serde_json::json!({})),
+        ]);
+        let empty = FeatureCollection::from_iter(Vec::new());
+        let conn = round_trip("metadata", &[("points", &points), ("empty", &empty)]);
+
+        let application_id: u32 = conn
+            .query_row("PRAGMA application_id", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(application_id, APPLICATION_ID);
+        let user_version: u32 = conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(user_version, USER_VERSION);
+
+        let srs: Vec<(i64, String)> = conn
+            .prepare("SELECT srs_id, organization FROM gpkg_spatial_ref_sys ORDER BY srs_id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            srs,
+            vec![
+                (-1, "NONE".to_string()),
+                (0, "NONE".to_string()),
+                (WGS84, "EPSG".to_string())
+            ]
+        );
+
+        let contents: Vec<(String, String, Option<f64>, Option<f64>, i64)> = conn
+            .prepare("SELECT table_name, data_type, min_x, max_y, srs_id FROM gpkg_contents")
+            .unwrap()
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            contents,
+            vec![
+                (
+                    "points".to_string(),
+                    "features".to_string(),
+                    Some(-0.1),
+                    Some(51.7),
+                    WGS84
+                ),
+                (
+                    "empty".to_string(),
+                    "features".to_string(),
+                    None,
+                    None,
+                    WGS84
+                ),
+            ]
+        );
+
+        let geometry_columns: Vec<(String, String)> = conn
+            .prepare("SELECT table_name, column_name FROM gpkg_geometry_columns")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            geometry_columns,
+            vec![
+                ("points".to_string(), "geom".to_string()),
+                ("empty".to_string(), "geom".to_string())
+            ]
+        );
+
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM empty", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn property_columns() {
+        let gj = FeatureCollection::from_iter(vec![
+            feature(
+                GeoJsonValue::Point(vec![1.0, 2.0]),
+                serde_json::json!({
+                    "flag": true,
+                    "count": 3,
+                    "score": 1,
+                    "name": "a \"quoted\" name",
+                    "Mixed": 1,
+                    "nested": {"x": 1},
+                }),
+            ),
+            feature(
+                GeoJsonValue::Point(vec![3.0, 4.0]),
+                serde_json::json!({
+                    "flag": false,
+                    "count": null,
+                    "score": 2.5,
+                    "name": "b",
+                    "Mixed": "text",
+                    // Differs from "name" only by case, so it's dropped
+                    "NAME": "ignored",
+                }),
+            ),
+        ]);
+        let conn = round_trip("properties", &[("layer", &gj)]);
+
+        let columns: Vec<(String, String)> = conn
+            .prepare("SELECT name, type FROM pragma_table_info('layer')")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        let columns: Vec<(&str, &str)> = columns
+            .iter()
+            .map(|(name, kind)| (name.as_str(), kind.as_str()))
+            .collect();
+        assert!(columns.contains(&("fid", "INTEGER")));
+        assert!(columns.contains(&("geom", "GEOMETRY")));
+        assert!(columns.contains(&("flag", "BOOLEAN")));
+        assert!(columns.contains(&("count", "INTEGER")));
+        assert!(columns.contains(&("score", "REAL")));
+        assert!(columns.contains(&("Mixed", "TEXT")));
+        assert!(columns.contains(&("nested", "TEXT")));
+        assert!(!columns.iter().any(|(name, _)| *name == "NAME"));
+
+        type Row = (i64, bool, Option<i64>, f64, String, String, Option<String>);
+        let rows: Vec<Row> = conn
+            .prepare("SELECT fid, flag, count, score, name, Mixed, nested FROM layer ORDER BY fid")
+            .unwrap()
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                    row.get(5)?,
+                    row.get(6)?,
+                ))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                (
+                    1,
+                    true,
+                    Some(3),
+                    1.0,
+                    "a \"quoted\" name".to_string(),
+                    "1".to_string(),
+                    Some(r#"{"x":1}"#.to_string())
+                ),
+                (
+                    2,
+                    false,
+                    None,
+                    2.5,
+                    "b".to_string(),
+                    "text".to_string(),
+                    None
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn geometry_blobs() {
+        let gj = FeatureCollection::from_iter(vec![
+            feature(GeoJsonValue::Point(vec![1.0, 2.0]), serde_json::json!({})),
+            feature(
+                GeoJsonValue::LineString(vec![vec![0.0, 0.0], vec![3.0, 4.0]]),
+                serde_json::json!({}),
+            ),
+            feature(
+                GeoJsonValue::Polygon(vec![vec![
+                    vec![0.0, 0.0],
+                    vec![1.0, 0.0],
+                    vec![1.0, 1.0],
+                    vec![0.0, 0.0],
+                ]]),
+                serde_json::json!({}),
+            ),
+        ]);
+        let conn = round_trip("geometry", &[("layer", &gj)]);
+
+        let blobs: Vec<Vec<u8>> = conn
+            .prepare("SELECT geom FROM layer ORDER BY fid")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(blobs.len(), 3);
+
+        let f64_at =
+            |blob: &[u8], pos: usize| f64::from_le_bytes(blob[pos..pos + 8].try_into().unwrap());
+        let u32_at =
+            |blob: &[u8], pos: usize| u32::from_le_bytes(blob[pos..pos + 4].try_into().unwrap());
+        for (blob, wkb_type) in blobs.iter().zip([1, 2, 3]) {
+            assert_eq!(&blob[0..2], b"GP");
+            assert_eq!(blob[2], 0);
+            assert_eq!(blob[3], 0b0000_0011);
+            assert_eq!(u32_at(blob, 4), WGS84 as u32);
+            // The header is 8 bytes, then a 32 byte envelope
+            assert_eq!(blob[40], 1);
+            assert_eq!(u32_at(blob, 41), wkb_type);
+        }
+
+        // The line's envelope is minx, maxx, miny, maxy
+        let line = &blobs[1];
+        assert_eq!(
+            [
+                f64_at(line, 8),
+                f64_at(line, 16),
+                f64_at(line, 24),
+                f64_at(line, 32)
+            ],
+            [0.0, 3.0, 0.0, 4.0]
+        );
+        // Then the number of points and each coordinate
+        assert_eq!(u32_at(line, 45), 2);
+        assert_eq!(
+            [
+                f64_at(line, 49),
+                f64_at(line, 57),
+                f64_at(line, 65),
+                f64_at(line, 73)
+            ],
+            [0.0, 0.0, 3.0, 4.0]
+        );
+
+        // The polygon has one ring of four points
+        let polygon = &blobs[2];
+        assert_eq!(u32_at(polygon, 45), 1);
+        assert_eq!(u32_at(polygon, 49), 4);
+    }
+
+    #[test]
+    fn many_pages() {
+        // Enough rows to need interior pages, and values too big for one page to need overflow
+        let long_name = "x".repeat(3 * PAGE_SIZE);
+        let gj = FeatureCollection::from_iter((0..20_000).map(|idx| {
+            let name = if idx % 5000 == 0 {
+                long_name.clone()
+            } else {
+                format!("feature {idx}")
+            };
+            feature(
+                GeoJsonValue::Point(vec![idx as f64 / 1000.0, 0.0]),
+                serde_json::json!({ "idx": idx, "name": name }),
+            )
+        }));
+        let conn = round_trip("pages", &[("layer", &gj)]);
+
+        let (count, sum): (i64, i64) = conn
+            .query_row("SELECT COUNT(*), SUM(idx) FROM layer", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(count, 20_000);
+        assert_eq!(sum, (0..20_000).sum::<i64>());
+
+        let name: String = conn
+            .query_row("SELECT name FROM layer WHERE fid = 5001", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(name, long_name);
+        let name: String = conn
+            .query_row("SELECT name FROM layer WHERE fid = 12345", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(name, "feature 12344");
+    }
+}
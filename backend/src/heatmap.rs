@@ -1,38 +1,539 @@
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashMap, HashSet};
 
-use geo::{Coord, Densify, Line, LineString};
-use geojson::FeatureCollection;
+use anyhow::{bail, Result};
+use geo::{BoundingRect, Coord, Densify, EuclideanLength, Line, LineString};
+use geojson::{feature::Id, Feature, FeatureCollection, Geometry};
+use osm_reader::WayID;
 use rstar::{primitives::GeomWithData, RTree};
+use serde::{Deserialize, Serialize};
 
-use crate::{CompareRouteRequest, IntersectionID, MapModel, RoadKind};
+use crate::boundary::{is_truncated_dead_end, near_boundary};
+use crate::crossings::crossing_quality;
+use crate::route::{
+    crossing_edit_edges, do_route_with_extra_edges, length_alongside_severance, route_pieces,
+    step_free_comparison, CrossingEdit,
+};
+use crate::{CompareRouteRequest, IntersectionID, MapModel, RoadID, RoadKind};
 
+/// Only desire lines with a snapped straight-line length in this range (in meters) are scored.
+/// Very long desire lines measure trip planning more than severance.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct DesireLineBounds {
+    #[serde(default)]
+    min_length: Option<f64>,
+    #[serde(default)]
+    max_length: Option<f64>,
+}
+
+impl DesireLineBounds {
+    fn contains(&self, length: f64) -> bool {
+        self.min_length.map(|min| length >= min).unwrap_or(true)
+            && self.max_length.map(|max| length <= max).unwrap_or(true)
+    }
+}
+
+/// Where to put desire lines along severances. Dense urban streets need closely spaced, short
+/// lines, while rural dual carriageways need longer lines to reach the network on the other side.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DesireLineSampling {
+    /// How often to try crossing severances, in meters along them. Smaller values make more desire
+    /// lines, so the heatmap is more detailed but slower.
+    #[serde(default = "default_spacing")]
+    pub spacing: f64,
+    /// The straight-line length of each desire line, in meters, centered on the severance. Lines
+    /// too short may snap to the same side; lines too long measure more than the severance.
+    #[serde(default = "default_line_length")]
+    pub line_length: f64,
+}
+
+fn default_spacing() -> f64 {
+    25.0
+}
+
+fn default_line_length() -> f64 {
+    30.0
+}
+
+impl Default for DesireLineSampling {
+    fn default() -> Self {
+        Self {
+            spacing: default_spacing(),
+            line_length: default_line_length(),
+        }
+    }
+}
+
+/// Which desire lines to score in a heatmap
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HeatmapStrategy {
+    /// Perpendicular to severances, placed by `DesireLineSampling`
+    #[default]
+    AlongSeverances,
+    /// Between footway intersections near each other on opposite sides of a severance
+    NearbyFootways,
+}
+
+/// The input to `makeHeatmap`
+#[derive(Clone, Default, Deserialize)]
+pub struct HeatmapRequest {
+    #[serde(default)]
+    pub strategy: HeatmapStrategy,
+    #[serde(flatten)]
+    pub bounds: DesireLineBounds,
+    #[serde(flatten)]
+    pub sampling: DesireLineSampling,
+    /// For `NearbyFootways`, how far apart intersections can be, in meters. Defaults to 200.
+    #[serde(default)]
+    pub nearby_radius: Option<f64>,
+}
+
+impl HeatmapRequest {
+    fn nearby_radius(&self) -> f64 {
+        self.nearby_radius.unwrap_or(200.0)
+    }
+}
+
+/// Scores the desire lines picked by the request's strategy
+pub fn make_heatmap(map: &mut MapModel, req: &HeatmapRequest) -> FeatureCollection {
+    match req.strategy {
+        HeatmapStrategy::AlongSeverances => along_severances(map, &req.bounds, &req.sampling),
+        HeatmapStrategy::NearbyFootways => {
+            let requests = nearby_footway_requests(map, req.nearby_radius());
+            calculate(map, requests, &req.bounds)
+        }
+    }
+}
+
+/// The desire lines scored by `make_heatmap`, with IDs
+pub fn heatmap_requests(map: &MapModel, req: &HeatmapRequest) -> Vec<(usize, CompareRouteRequest)> {
+    match req.strategy {
+        HeatmapStrategy::AlongSeverances => severance_requests_with_ids(map, &req.sampling),
+        HeatmapStrategy::NearbyFootways => nearby_footway_requests(map, req.nearby_radius()),
+    }
+}
+
+/// Desire lines with midpoints this close, in meters, and pointing the same or opposite way are
+/// duplicates, like those from both carriageways of a dual carriageway
+const DUPLICATE_DISTANCE: f64 = 10.0;
+
+/// One place to try crossing a severance, from one side to the other
+pub struct DesireLine {
+    /// Stays the same as long as the severances don't change, even if some lines are skipped
+    pub id: usize,
+    /// The severance being crossed
+    pub road: RoadID,
+    pub way: WayID,
+    pub line: Line,
+}
+
+/// Each line has the `score`, the ratio of the route length to the straight line, the
+/// `extra_length` walked in meters, and the `severance_way` crossed. Lines are also weighted by
+/// demand when population or POIs are loaded; see `calculate`. The `sampling` used and the number
+/// of `desire_lines` tried are returned as foreign members.
 // Walk along severances. Every X meters, try to cross from one side to the other.
 //
 // We could focus where footways connect to severances, but that's probably a crossing. Ideally we
 // want to find footpaths parallel(ish) to severances. If we had some kind of generalized edge
 // bundling...
-pub fn along_severances(map: &mut MapModel) -> FeatureCollection {
-    let mut requests = Vec::new();
+pub fn along_severances(
+    map: &mut MapModel,
+    bounds: &DesireLineBounds,
+    sampling: &DesireLineSampling,
+) -> FeatureCollection {
+    let lines = sampled_desire_lines(map, sampling);
+    let desire_lines = lines.len();
+    let ways: HashMap<usize, WayID> = lines.iter().map(|d| (d.id, d.way)).collect();
+    let mut fc = calculate(
+        map,
+        lines.into_iter().map(|d| (d.id, d.line.into())).collect(),
+        bounds,
+    );
+    for f in &mut fc.features {
+        if let Some(Id::Number(id)) = &f.id {
+            if let Some(way) = id.as_u64().and_then(|id| ways.get(&(id as usize))) {
+                f.set_property("severance_way", way.to_string());
+            }
+        }
+    }
+    let foreign_members = fc.foreign_members.get_or_insert_with(Default::default);
+    foreign_members.insert(
+        "sampling".to_string(),
+        serde_json::to_value(sampling).unwrap(),
+    );
+    foreign_members.insert("desire_lines".to_string(), desire_lines.into());
+    fc
+}
+
+pub fn severance_requests(map: &MapModel) -> Vec<CompareRouteRequest> {
+    severance_requests_with_ids(map, &DesireLineSampling::default())
+        .into_iter()
+        .map(|(_, req)| req)
+        .collect()
+}
+
+/// Each desire line has an ID that stays the same as long as the severances don't change, even if
+/// some lines are skipped
+pub fn severance_requests_with_ids(
+    map: &MapModel,
+    sampling: &DesireLineSampling,
+) -> Vec<(usize, CompareRouteRequest)> {
+    sampled_desire_lines(map, sampling)
+        .into_iter()
+        .map(|d| (d.id, d.line.into()))
+        .collect()
+}
+
+/// Every candidate place to cross a severance. Lines near the boundary, where the network has been
+/// clipped and routes are misleading, are skipped. When two severances run alongside each other,
+/// only the first of each pair of duplicate lines is kept, whichever way it points.
+pub fn severance_desire_lines(map: &MapModel) -> Vec<DesireLine> {
+    sampled_desire_lines(map, &DesireLineSampling::default())
+}
+
+/// Like `severance_desire_lines`, with control over where lines are placed
+pub fn sampled_desire_lines(map: &MapModel, sampling: &DesireLineSampling) -> Vec<DesireLine> {
+    let mut lines: Vec<DesireLine> = Vec::new();
+    let mut midpoints: RTree<GeomWithData<[f64; 2], usize>> = RTree::new();
+    let mut id = 0;
     for r in &map.roads {
         if r.kind != RoadKind::Severance {
             continue;
         }
-        for line in make_perpendicular_offsets(&r.linestring, 25.0, 15.0) {
-            requests.push(line.into());
+        for line in make_perpendicular_offsets(
+            &r.linestring,
+            sampling.spacing.max(1.0),
+            sampling.line_length / 2.0,
+        ) {
+            id += 1;
+            if near_boundary(map, line.start) || near_boundary(map, line.end) {
+                continue;
+            }
+            let midpoint = [
+                (line.start.x + line.end.x) / 2.0,
+                (line.start.y + line.end.y) / 2.0,
+            ];
+            let duplicate = midpoints
+                .locate_within_distance(midpoint, DUPLICATE_DISTANCE * DUPLICATE_DISTANCE)
+                .any(|obj| is_parallel(&lines[obj.data].line, &line));
+            if duplicate {
+                continue;
+            }
+            midpoints.insert(GeomWithData::new(midpoint, lines.len()));
+            lines.push(DesireLine {
+                id,
+                road: r.id,
+                way: r.way,
+                line,
+            });
+        }
+    }
+    lines
+}
+
+/// True if the lines point within about 20 degrees of the same or opposite direction
+fn is_parallel(line1: &Line, line2: &Line) -> bool {
+    let (dx1, dy1) = (line1.dx(), line1.dy());
+    let (dx2, dy2) = (line2.dx(), line2.dy());
+    let lengths = dx1.hypot(dy1) * dx2.hypot(dy2);
+    lengths > 0.0 && ((dx1 * dx2 + dy1 * dy2) / lengths).abs() > 20.0_f64.to_radians().cos()
+}
+
+#[derive(Deserialize)]
+pub struct GridDesireLines {
+    /// The distance between grid points, in meters
+    #[serde(default = "default_grid_spacing")]
+    spacing: f64,
+    /// The straight-line length of every desire line, in meters
+    #[serde(default = "default_grid_line_length")]
+    line_length: f64,
+    #[serde(flatten)]
+    bounds: DesireLineBounds,
+}
+
+fn default_grid_spacing() -> f64 {
+    50.0
+}
+
+fn default_grid_line_length() -> f64 {
+    100.0
+}
+
+/// Like `along_severances`, but starts desire lines from every point on a regular grid, going the
+/// same distance east, north, northeast, and southeast. Only lines crossing a severance are
+/// scored. Unlike lines perpendicular to severances, every area is sampled at the same density, so
+/// scores are more comparable between places. Each line also has the `extra_length` in meters.
+pub fn grid_across_severances(
+    map: &mut MapModel,
+    req: GridDesireLines,
+) -> Result<FeatureCollection> {
+    let requests = grid_requests(map, &req)?;
+    Ok(calculate(map, requests, &req.bounds))
+}
+
+/// The desire lines used by `grid_across_severances`, with IDs
+fn grid_requests(
+    map: &MapModel,
+    req: &GridDesireLines,
+) -> Result<Vec<(usize, CompareRouteRequest)>> {
+    if req.spacing < 1.0 || req.line_length < 1.0 {
+        bail!("The spacing and line length must be at least 1m");
+    }
+    let bbox = map.boundary_polygon.bounding_rect().unwrap();
+    let offsets = [
+        (req.line_length, 0.0),
+        (0.0, req.line_length),
+        (
+            req.line_length / 2.0_f64.sqrt(),
+            req.line_length / 2.0_f64.sqrt(),
+        ),
+        (
+            req.line_length / 2.0_f64.sqrt(),
+            -req.line_length / 2.0_f64.sqrt(),
+        ),
+    ];
+
+    let mut requests = Vec::new();
+    let mut id = 0;
+    let mut y = bbox.min().y;
+    while y <= bbox.max().y {
+        let mut x = bbox.min().x;
+        while x <= bbox.max().x {
+            for (dx, dy) in offsets {
+                id += 1;
+                let line = Line::new(
+                    Coord { x, y },
+                    Coord {
+                        x: x + dx,
+                        y: y + dy,
+                    },
+                );
+                if near_boundary(map, line.start)
+                    || near_boundary(map, line.end)
+                    || !crosses_severance(map, &line)
+                {
+                    continue;
+                }
+                requests.push((id, line.into()));
+            }
+            x += req.spacing;
+        }
+        y += req.spacing;
+    }
+    Ok(requests)
+}
+
+#[derive(Deserialize)]
+pub struct ExposureRequest {
+    /// Route desire lines on this grid, instead of those perpendicular to severances
+    #[serde(default)]
+    grid: Option<GridDesireLines>,
+}
+
+/// Routes every desire line, then finds how much of that walking happens alongside or across
+/// severances. Each road used by some route has the number of `routes` using it, the
+/// `alongside_severance` length in meters, and the `exposure`: the number of routes times the
+/// fraction of the road alongside a severance, or the number of routes for crossings. Footways
+/// with high exposure absorb the detours that severances cause.
+pub fn severance_exposure(map: &mut MapModel, req: ExposureRequest) -> Result<FeatureCollection> {
+    let requests = match req.grid {
+        Some(ref grid) => grid_requests(map, grid)?,
+        None => severance_requests_with_ids(map, &DesireLineSampling::default()),
+    };
+    let mut routes: HashMap<RoadID, usize> = HashMap::new();
+    for (_, route_req) in requests {
+        if let Ok(pieces) = route_pieces(map, &route_req) {
+            // Count a road only once per route
+            let roads: HashSet<RoadID> = pieces.into_iter().map(|(r, _)| r).collect();
+            for r in roads {
+                *routes.entry(r).or_insert(0) += 1;
+            }
+        }
+    }
+
+    let mut features = Vec::new();
+    let mut max_exposure = 0.0_f64;
+    let mut routes: Vec<(RoadID, usize)> = routes.into_iter().collect();
+    routes.sort();
+    for (r, count) in routes {
+        let road = &map.roads[r.0];
+        let length = road.linestring.euclidean_length();
+        let alongside = length_alongside_severance(map, &road.linestring);
+        let fraction = if crossing_quality(road) != "none"
+            || matches!(road.kind, RoadKind::Bridge | RoadKind::Tunnel)
+        {
+            1.0
+        } else if length > 0.0 {
+            alongside / length
+        } else {
+            0.0
+        };
+        let exposure = count as f64 * fraction;
+        max_exposure = max_exposure.max(exposure);
+
+        let mut f = road.to_gj(&map.mercator);
+        f.set_property("routes", count);
+        f.set_property("alongside_severance", alongside);
+        f.set_property("exposure", exposure);
+        features.push(f);
+    }
+
+    Ok(FeatureCollection {
+        features,
+        bbox: None,
+        foreign_members: Some(
+            serde_json::json!({
+                "max_exposure": max_exposure,
+            })
+            .as_object()
+            .unwrap()
+            .clone(),
+        ),
+    })
+}
+
+fn crosses_severance(map: &MapModel, line: &Line) -> bool {
+    map.roads_crossing(line)
+        .any(|road| road.kind == RoadKind::Severance)
+}
+
+/// Scores for the same desire lines at different times of day, for animating
+#[derive(Serialize)]
+pub struct HeatmapOverTime {
+    /// The desire lines, with no scores
+    lines: FeatureCollection,
+    times: Vec<String>,
+    /// One frame per time. The first frame has a score for every line, in the same order as
+    /// `lines`. Later frames only have the `(line index, score)` pairs that changed from the
+    /// previous frame. The score is null when there's no route at that time.
+    frames: Vec<Vec<(usize, Option<f64>)>>,
+}
+
+/// Like `along_severances`, but scores every desire line at each time of day ("HH:MM")
+pub fn along_severances_over_time(
+    map: &mut MapModel,
+    times: Vec<String>,
+) -> Result<HeatmapOverTime> {
+    for time in &times {
+        crate::access::parse_time(time)?;
+    }
+
+    let requests = severance_requests(map);
+    let mut lines = Vec::new();
+    for req in &requests {
+        let line = LineString::new(vec![
+            Coord {
+                x: req.x1,
+                y: req.y1,
+            },
+            Coord {
+                x: req.x2,
+                y: req.y2,
+            },
+        ]);
+        lines.push(Feature::from(Geometry::from(&map.mercator.to_wgs84(&line))));
+    }
+
+    let mut frames = Vec::new();
+    let mut previous: Vec<Option<f64>> = Vec::new();
+    for time in &times {
+        let mut current = Vec::new();
+        for req in &requests {
+            let mut req = req.clone();
+            req.time = Some(time.clone());
+            current.push(
+                score_request(map, req, &DesireLineBounds::default()).map(|(_, score)| score),
+            );
+        }
+
+        let frame = current
+            .iter()
+            .enumerate()
+            .filter(|(idx, score)| previous.is_empty() || previous[*idx] != **score)
+            .map(|(idx, score)| (idx, *score))
+            .collect();
+        frames.push(frame);
+        previous = current;
+    }
+
+    Ok(HeatmapOverTime {
+        lines: FeatureCollection {
+            features: lines,
+            bbox: None,
+            foreign_members: None,
+        },
+        times,
+        frames,
+    })
+}
+
+/// For every desire line along severances, compares the best route with the best step-free route.
+/// Each line has `extra_length` in meters and `score`, the ratio of the step-free route length to
+/// the normal route, both null when no step-free route exists. Lines where steps don't matter are
+/// omitted.
+pub fn step_free(map: &mut MapModel) -> FeatureCollection {
+    let mut features = Vec::new();
+    let mut unreachable = 0;
+    for (id, req) in severance_requests_with_ids(map, &DesireLineSampling::default()) {
+        let Ok((length, step_free)) = step_free_comparison(map, &req) else {
+            continue;
+        };
+        if step_free == Some(length) {
+            continue;
+        }
+        if step_free.is_none() {
+            unreachable += 1;
         }
+
+        let line = LineString::new(vec![
+            Coord {
+                x: req.x1,
+                y: req.y1,
+            },
+            Coord {
+                x: req.x2,
+                y: req.y2,
+            },
+        ]);
+        let mut f = Feature::from(Geometry::from(&map.mercator.to_wgs84(&line)));
+        f.id = Some(Id::Number(id.into()));
+        f.set_property("route_length", length);
+        f.set_property("step_free_length", step_free);
+        f.set_property("extra_length", step_free.map(|x| x - length));
+        f.set_property("score", step_free.map(|x| x / length));
+        features.push(f);
+    }
+
+    FeatureCollection {
+        features,
+        bbox: None,
+        foreign_members: Some(
+            serde_json::json!({
+                "unreachable": unreachable,
+            })
+            .as_object()
+            .unwrap()
+            .clone(),
+        ),
     }
-    calculate(map, requests)
 }
 
-// For every intersection involving a footway, look for any other nearby intersection and see how
-// hard it is to walk there.
-pub fn nearby_footway_intersections(map: &mut MapModel, dist_meters: f64) -> FeatureCollection {
+/// Pairs of footway intersections within `dist_meters` of each other, on opposite sides of a
+/// severance. Intersections already connected by a road are skipped. IDs are stable as long as the
+/// network doesn't change.
+pub fn nearby_footway_requests(
+    map: &MapModel,
+    dist_meters: f64,
+) -> Vec<(usize, CompareRouteRequest)> {
     // Look for intersections we want to connect
-    let mut footway_intersections = HashSet::new();
+    let mut footway_intersections = BTreeSet::new();
     for r in &map.roads {
         if r.kind == RoadKind::Footway {
-            footway_intersections.insert(r.src_i);
-            footway_intersections.insert(r.dst_i);
+            for i in [r.src_i, r.dst_i] {
+                if !is_truncated_dead_end(map, i) {
+                    footway_intersections.insert(i);
+                }
+            }
         }
     }
 
@@ -43,60 +544,418 @@ pub fn nearby_footway_intersections(map: &mut MapModel, dist_meters: f64) -> Fea
     }
     let rtree = RTree::bulk_load(points);
 
-    // For every intersection, try to go to every nearby intersection
+    // For every intersection, try to go to every nearby intersection across a severance
     let mut requests = Vec::new();
+    let mut id = 0;
     for i1 in &footway_intersections {
         let i1_pt = map.intersections[i1.0].point;
-        for i2 in rtree.locate_within_distance(i1_pt.into(), dist_meters) {
-            // TODO Skip trivial things connected by a road
-            let i2_pt = map.intersections[i2.data.0].point;
-            requests.push(CompareRouteRequest {
-                x1: i1_pt.x(),
-                y1: i1_pt.y(),
-                x2: i2_pt.x(),
-                y2: i2_pt.y(),
-            });
+        let mut nearby: Vec<IntersectionID> = rtree
+            .locate_within_distance(i1_pt.into(), dist_meters * dist_meters)
+            .map(|obj| obj.data)
+            .filter(|i2| i2 > i1)
+            .collect();
+        nearby.sort();
+        for i2 in nearby {
+            id += 1;
+            let directly_connected = map.edge_index.find(*i1, i2).is_some();
+            let line = Line::new(i1_pt.0, map.intersections[i2.0].point.0);
+            if directly_connected || !crosses_severance(map, &line) {
+                continue;
+            }
+            requests.push((id, line.into()));
         }
     }
-    calculate(map, requests)
+    requests
+}
+
+/// People and places within this many meters of a desire line's midpoint count as its demand
+const DEMAND_RADIUS: f64 = 400.0;
+/// How many residents one POI counts as when weighting by demand
+const PEOPLE_PER_POI: f64 = 50.0;
+
+/// Population cell centers and POIs, indexed to look up the demand near many desire lines
+struct Demand {
+    people: RTree<GeomWithData<[f64; 2], f64>>,
+    pois: RTree<[f64; 2]>,
 }
 
-fn calculate(map: &mut MapModel, requests: Vec<CompareRouteRequest>) -> FeatureCollection {
+impl Demand {
+    /// Returns `None` if neither layer is loaded
+    fn new(map: &MapModel) -> Option<Self> {
+        if map.population.is_none() && map.pois.is_empty() {
+            return None;
+        }
+        let people = match map.population {
+            Some(ref population) => population
+                .cell_centers()
+                .map(|(pt, count)| GeomWithData::new([pt.x, pt.y], count))
+                .collect(),
+            None => Vec::new(),
+        };
+        Some(Self {
+            people: RTree::bulk_load(people),
+            pois: RTree::bulk_load(
+                map.pois
+                    .iter()
+                    .map(|poi| [poi.point.x, poi.point.y])
+                    .collect(),
+            ),
+        })
+    }
+
+    /// How many people might want to cross near `pt`, from the population and POIs within
+    /// `DEMAND_RADIUS`
+    fn near(&self, pt: Coord) -> f64 {
+        let radius_squared = DEMAND_RADIUS.powi(2);
+        let people: f64 = self
+            .people
+            .locate_within_distance([pt.x, pt.y], radius_squared)
+            .map(|cell| cell.data)
+            .sum();
+        let pois = self
+            .pois
+            .locate_within_distance([pt.x, pt.y], radius_squared)
+            .count() as f64;
+        people + PEOPLE_PER_POI * pois
+    }
+}
+
+/// Each line has the raw `score`. When population or POIs are loaded, lines also have their
+/// `demand` and a `weighted_score`, the score scaled by demand relative to the average line, so a
+/// bad severance through empty land ranks below a milder one next to a school.
+fn calculate(
+    map: &mut MapModel,
+    requests: Vec<(usize, CompareRouteRequest)>,
+    bounds: &DesireLineBounds,
+) -> FeatureCollection {
     let mut samples = Vec::new();
-    let mut max_score = 0.0_f64;
-    for req in requests {
-        if let Ok((mut f, fc)) = crate::route::do_route(map, req) {
-            let direct = fc
-                .foreign_members
-                .as_ref()
-                .unwrap()
-                .get("direct_length")
-                .unwrap()
-                .as_f64()
-                .unwrap();
-            let route = fc
-                .foreign_members
-                .unwrap()
-                .get("route_length")
-                .unwrap()
-                .as_f64()
-                .unwrap();
-            let score = route / direct;
-            max_score = max_score.max(score);
+    let mut scores = Vec::new();
+    let mut demands = Vec::new();
+    let demand_index = Demand::new(map);
+    for (id, req) in requests {
+        let midpoint = Coord {
+            x: (req.x1 + req.x2) / 2.0,
+            y: (req.y1 + req.y2) / 2.0,
+        };
+        if let Some((mut f, score)) = score_request(map, req, bounds) {
+            scores.push(score);
+            f.id = Some(Id::Number(id.into()));
             f.set_property("score", score);
+            if let Some(ref index) = demand_index {
+                let demand = index.near(midpoint);
+                f.set_property("demand", demand);
+                demands.push(demand);
+            }
             samples.push(f);
         }
     }
-    info!("Max score is {max_score}");
+
+    let stats = ScoreStats::new(scores);
+    info!("Max score is {}", stats.max);
+    let mut foreign_members = serde_json::json!({
+        "score_stats": stats,
+    })
+    .as_object()
+    .unwrap()
+    .clone();
+
+    if !demands.is_empty() {
+        let mean_demand = demands.iter().sum::<f64>() / demands.len() as f64;
+        let mut weighted_scores = Vec::new();
+        for f in &mut samples {
+            let score = f.property("score").unwrap().as_f64().unwrap();
+            let demand = f.property("demand").unwrap().as_f64().unwrap();
+            let weighted = if mean_demand > 0.0 {
+                score * demand / mean_demand
+            } else {
+                score
+            };
+            f.set_property("weighted_score", weighted);
+            weighted_scores.push(weighted);
+        }
+        foreign_members.insert(
+            "weighted_score_stats".to_string(),
+            serde_json::to_value(ScoreStats::new(weighted_scores)).unwrap(),
+        );
+    }
+
     FeatureCollection {
         features: samples,
         bbox: None,
+        foreign_members: Some(foreign_members),
+    }
+}
+
+/// Scores the heatmap before and after adding hypothetical crossings. Each desire line has the
+/// usual properties for the route before, plus `score_before`, `score_after`, and `improvement`,
+/// the drop in score. Lines the new crossings don't help have an `improvement` of 0. Routes are
+/// compared under the active costs, so a cheaper route using a new crossing can occasionally be
+/// longer, with a negative `improvement`. The `improved` foreign member counts the lines that got
+/// better.
+pub fn heatmap_with_edit(
+    map: &mut MapModel,
+    req: &HeatmapRequest,
+    edits: &[CrossingEdit],
+) -> Result<FeatureCollection> {
+    let extra = crossing_edit_edges(map, edits)?;
+    let mut features = Vec::new();
+    let mut improved = 0;
+    let mut max_improvement: f64 = 0.0;
+    for (id, line) in heatmap_requests(map, req) {
+        let Ok(((f, fc), after)) = do_route_with_extra_edges(map, line, &extra) else {
+            continue;
+        };
+        let Some((mut f, score_before)) = score_route(f, &fc, &req.bounds) else {
+            continue;
+        };
+        let direct = f.property("direct_length").unwrap().as_f64().unwrap();
+        // Both routes use the same costs, so the route after is only different when it's cheaper
+        let score_after = after.map_or(score_before, |after| after.length / direct);
+        let improvement = score_before - score_after;
+        if improvement > 0.0 {
+            improved += 1;
+            max_improvement = max_improvement.max(improvement);
+        }
+
+        f.id = Some(Id::Number(id.into()));
+        f.set_property("score_before", score_before);
+        f.set_property("score_after", score_after);
+        f.set_property("improvement", improvement);
+        features.push(f);
+    }
+
+    Ok(FeatureCollection {
+        features,
+        bbox: None,
+        foreign_members: Some(
+            serde_json::json!({
+                "improved": improved,
+                "max_improvement": max_improvement,
+            })
+            .as_object()
+            .unwrap()
+            .clone(),
+        ),
+    })
+}
+
+/// One scored desire line along a severance, for tabular export
+pub struct HeatmapRow {
+    pub id: usize,
+    pub way: WayID,
+    /// The requested desire line in WGS84, before snapping
+    pub line: Line,
+    pub score: f64,
+    /// In meters, between the snapped endpoints
+    pub direct_length: f64,
+    pub route_length: f64,
+}
+
+/// Scores every desire line along severances, like `along_severances`, with the bounds and
+/// sampling from the request. Its strategy is ignored, since every row is along a severance.
+pub fn table_rows(map: &mut MapModel, req: &HeatmapRequest) -> Vec<HeatmapRow> {
+    let mut rows = Vec::new();
+    for d in sampled_desire_lines(map, &req.sampling) {
+        let Some((f, score)) = score_request(map, d.line.into(), &req.bounds) else {
+            continue;
+        };
+        let get = |key: &str| f.property(key).and_then(|x| x.as_f64()).unwrap_or(0.0);
+        rows.push(HeatmapRow {
+            id: d.id,
+            way: d.way,
+            line: Line::new(
+                map.mercator.pt_to_wgs84(d.line.start),
+                map.mercator.pt_to_wgs84(d.line.end),
+            ),
+            score,
+            direct_length: get("direct_length"),
+            route_length: get("route_length"),
+        });
+    }
+    rows
+}
+
+/// The `n` desire lines along severances with the most extra walking, worst first. Ratios
+/// exaggerate short lines, so this ranks by the absolute `extra_length` in meters. Each desire line
+/// is followed by its route, both with the same `rank` (starting from 1) and a `kind` of
+/// "desire_line" or "route".
+pub fn worst_detours(map: &mut MapModel, n: usize) -> FeatureCollection {
+    // Keep each route from scoring, instead of finding it again
+    let mut scored = Vec::new();
+    for (id, req) in severance_requests_with_ids(map, &DesireLineSampling::default()) {
+        let Ok((f, fc)) = crate::route::do_route(map, req) else {
+            continue;
+        };
+        let Some((mut f, score)) = score_route(f, &fc, &DesireLineBounds::default()) else {
+            continue;
+        };
+        let extra = f.property("extra_length").unwrap().as_f64().unwrap();
+        f.id = Some(Id::Number(id.into()));
+        f.set_property("score", score);
+        // The pieces are already in WGS84
+        let route: Vec<_> = fc
+            .features
+            .into_iter()
+            .filter_map(|piece| match piece.geometry?.value {
+                geojson::Value::LineString(pts) => Some(pts),
+                _ => None,
+            })
+            .collect();
+        scored.push((extra, f, route));
+
+        // Only the worst routes are needed, so don't hold onto every one
+        if scored.len() >= 2 * n.max(1) {
+            scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+            scored.truncate(n);
+        }
+    }
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    scored.truncate(n);
+
+    let mut features = Vec::new();
+    for (idx, (_, mut f, route)) in scored.into_iter().enumerate() {
+        let rank = idx + 1;
+        f.set_property("kind", "desire_line");
+        f.set_property("rank", rank);
+        features.push(f);
+
+        let mut f = Feature::from(Geometry::new(geojson::Value::MultiLineString(route)));
+        f.set_property("kind", "route");
+        f.set_property("rank", rank);
+        features.push(f);
+    }
+
+    FeatureCollection {
+        features,
+        bbox: None,
         foreign_members: None,
     }
 }
 
+/// Number of equal-width bins in `ScoreStats::histogram`
+const HISTOGRAM_BINS: usize = 10;
+
+/// Describes the distribution of heatmap scores, so legends can be built consistently and cities
+/// compared
+#[derive(Serialize)]
+pub struct ScoreStats {
+    count: usize,
+    min: f64,
+    max: f64,
+    mean: f64,
+    /// The scores at every 10th percentile, from the 0th to the 100th
+    deciles: Vec<f64>,
+    /// `HISTOGRAM_BINS + 1` edges of equal-width bins from `min` to `max`
+    histogram_breaks: Vec<f64>,
+    /// The number of scores in each bin. The last bin includes `max`.
+    histogram_counts: Vec<usize>,
+}
+
+impl ScoreStats {
+    fn new(mut scores: Vec<f64>) -> Self {
+        scores.sort_by(f64::total_cmp);
+        let count = scores.len();
+        if count == 0 {
+            return Self {
+                count,
+                min: 0.0,
+                max: 0.0,
+                mean: 0.0,
+                deciles: Vec::new(),
+                histogram_breaks: Vec::new(),
+                histogram_counts: Vec::new(),
+            };
+        }
+        let (min, max) = (scores[0], scores[count - 1]);
+
+        // Linearly interpolate between the closest ranks
+        let quantile = |q: f64| {
+            let rank = q * (count - 1) as f64;
+            let (lower, upper) = (rank.floor() as usize, rank.ceil() as usize);
+            scores[lower] + (rank - lower as f64) * (scores[upper] - scores[lower])
+        };
+        let deciles = (0..=10).map(|i| quantile(i as f64 / 10.0)).collect();
+
+        let width = (max - min) / HISTOGRAM_BINS as f64;
+        let histogram_breaks = (0..=HISTOGRAM_BINS)
+            .map(|i| min + i as f64 * width)
+            .collect();
+        let mut histogram_counts = vec![0; HISTOGRAM_BINS];
+        for score in &scores {
+            let bin = if width > 0.0 {
+                (((score - min) / width) as usize).min(HISTOGRAM_BINS - 1)
+            } else {
+                0
+            };
+            histogram_counts[bin] += 1;
+        }
+
+        Self {
+            count,
+            min,
+            max,
+            mean: scores.iter().sum::<f64>() / count as f64,
+            deciles,
+            histogram_breaks,
+            histogram_counts,
+        }
+    }
+}
+
+/// Returns the snapped desire line with its `extra_length` in meters, and the ratio of the route
+/// length to the straight line, or `None` if there's no route or the snapped line is out of bounds.
+/// The `direct_length` and `route_length` in meters are also set.
+pub fn score_request(
+    map: &mut MapModel,
+    req: CompareRouteRequest,
+    bounds: &DesireLineBounds,
+) -> Option<(Feature, f64)> {
+    let (f, fc) = crate::route::do_route(map, req).ok()?;
+    score_route(f, &fc, bounds)
+}
+
+/// Scores a `do_route` response, like `score_request`
+fn score_route(
+    mut f: Feature,
+    fc: &FeatureCollection,
+    bounds: &DesireLineBounds,
+) -> Option<(Feature, f64)> {
+    let direct = fc
+        .foreign_members
+        .as_ref()
+        .unwrap()
+        .get("direct_length")
+        .unwrap()
+        .as_f64()
+        .unwrap();
+    // The detour is meaningless when both ends snap to the same point
+    if !bounds.contains(direct) || direct <= 0.0 {
+        return None;
+    }
+    let route = fc
+        .foreign_members
+        .as_ref()
+        .unwrap()
+        .get("route_length")
+        .unwrap()
+        .as_f64()
+        .unwrap();
+    f.set_property("direct_length", direct);
+    f.set_property("route_length", route);
+    f.set_property("extra_length", route - direct);
+    if let Some(route_score) = fc.foreign_members.as_ref().unwrap().get("route_score") {
+        if let Some(score) = route_score.get("score") {
+            f.set_property("route_score", score.clone());
+        }
+        if let Some(meters) = route_score.get("alongside_severance") {
+            f.set_property("alongside_severance", meters.clone());
+        }
+    }
+    Some((f, route / direct))
+}
+
 // TODO canvas_geometry needs this too
-fn make_perpendicular_offsets(
+pub fn make_perpendicular_offsets(
     linestring: &LineString,
     walk_every_m: f64,
     project_away_m: f64,
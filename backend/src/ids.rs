@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use geo::{Coord, LineInterpolatePoint};
+use serde::{Deserialize, Serialize};
+
+use crate::{MapModel, Road, RoadID};
+
+/// Any of these identifiers may be used to look up roads in bulk
+#[derive(Deserialize)]
+pub struct ResolveIdsRequest {
+    #[serde(default)]
+    way_ids: Vec<i64>,
+    #[serde(default)]
+    stable_ids: Vec<String>,
+    #[serde(default)]
+    road_ids: Vec<usize>,
+}
+
+/// Every identifier for one road
+#[derive(Serialize)]
+pub struct ResolvedRoad {
+    road_id: usize,
+    way_id: i64,
+    stable_id: String,
+}
+
+/// One entry per input identifier, in the same order. One way can be split into many roads. Unknown
+/// identifiers resolve to an empty list.
+#[derive(Serialize)]
+pub struct ResolveIdsResponse {
+    way_ids: Vec<Vec<ResolvedRoad>>,
+    stable_ids: Vec<Vec<ResolvedRoad>>,
+    road_ids: Vec<Vec<ResolvedRoad>>,
+}
+
+pub fn resolve_ids(map: &MapModel, req: ResolveIdsRequest) -> ResolveIdsResponse {
+    let mut by_way: HashMap<i64, Vec<RoadID>> = HashMap::new();
+    let mut by_stable_id: HashMap<String, RoadID> = HashMap::new();
+    for r in &map.roads {
+        by_way.entry(r.way.0).or_default().push(r.id);
+        by_stable_id.insert(r.stable_id(), r.id);
+    }
+
+    let resolve = |r: RoadID| ResolvedRoad::new(&map.roads[r.0]);
+    ResolveIdsResponse {
+        way_ids: req
+            .way_ids
+            .iter()
+            .map(|w| {
+                by_way
+                    .get(w)
+                    .map(|roads| roads.iter().cloned().map(resolve).collect())
+                    .unwrap_or_default()
+            })
+            .collect(),
+        stable_ids: req
+            .stable_ids
+            .iter()
+            .map(|id| by_stable_id.get(id).cloned().map(resolve).into_iter().collect())
+            .collect(),
+        road_ids: req
+            .road_ids
+            .iter()
+            .map(|id| {
+                if *id < map.roads.len() {
+                    vec![resolve(RoadID(*id))]
+                } else {
+                    Vec::new()
+                }
+            })
+            .collect(),
+    }
+}
+
+impl ResolvedRoad {
+    fn new(road: &Road) -> Self {
+        Self {
+            road_id: road.id.0,
+            way_id: road.way.0,
+            stable_id: road.stable_id(),
+        }
+    }
+}
+
+/// Where an OSM node is in the walking network. Only nodes at intersections or the ends of roads
+/// are known.
+pub fn node_point(map: &MapModel, node: i64) -> Result<Coord> {
+    match map.intersections.iter().find(|i| i.node.0 == node) {
+        Some(i) => Ok(i.point.into()),
+        None => bail!("OSM node {node} isn't an intersection in the walking network"),
+    }
+}
+
+/// The middle of an OSM way in the walking network
+pub fn way_point(map: &MapModel, way: i64) -> Result<Coord> {
+    let roads: Vec<&Road> = map.roads.iter().filter(|r| r.way.0 == way).collect();
+    if roads.is_empty() {
+        bail!("OSM way {way} isn't in the walking network");
+    }
+    let road = roads[roads.len() / 2];
+    Ok(road
+        .linestring
+        .line_interpolate_point(0.5)
+        .map(|pt| pt.into())
+        .unwrap_or(road.linestring.0[0]))
+}
@@ -0,0 +1,27 @@
+use std::collections::BTreeMap;
+
+use geojson::{feature::Id, Feature, FeatureCollection, Geometry};
+
+use crate::{MapModel, RoadKind};
+
+/// Every intersection as a point with its `degree` (number of roads), the `road_kinds` connected
+/// and how many of each, whether it's `on_severance`, and whether it has a `crossing`
+pub fn render_intersections(map: &MapModel) -> FeatureCollection {
+    FeatureCollection::from_iter(map.intersections.iter().map(|i| {
+        let mut road_kinds: BTreeMap<String, usize> = BTreeMap::new();
+        for (road, _) in map.neighbors(i.id) {
+            *road_kinds.entry(format!("{:?}", road.kind)).or_insert(0) += 1;
+        }
+        let has_kind = |kind: RoadKind| road_kinds.contains_key(&format!("{kind:?}"));
+
+        let mut f = Feature::from(Geometry::from(&map.mercator.to_wgs84(&i.point)));
+        f.id = Some(Id::Number(i.id.0.into()));
+        f.set_property("id", i.id.0);
+        f.set_property("node", i.node.to_string());
+        f.set_property("degree", i.roads.len());
+        f.set_property("on_severance", has_kind(RoadKind::Severance));
+        f.set_property("crossing", has_kind(RoadKind::Crossing));
+        f.set_property("road_kinds", serde_json::to_value(&road_kinds).unwrap());
+        f
+    }))
+}
@@ -0,0 +1,571 @@
+use anyhow::{bail, Result};
+use geo::{
+    Area, BooleanOps, BoundingRect, Centroid, Contains, Coord, Densify, EuclideanDistance,
+    EuclideanLength, LineString, MultiLineString, MultiPoint, MultiPolygon, Polygon,
+};
+use geojson::{Feature, FeatureCollection, Geometry};
+use serde::{Deserialize, Serialize};
+
+use crate::access::{parse_time, Time};
+use crate::contours::Grid;
+use crate::costs::hill_factor;
+use crate::crossings::{crossing_delay_range, crossing_quality};
+use crate::pois::count_within;
+use crate::route::{
+    crossing_edit_edges, dijkstra_directed, is_steps, slice_linestring, walkable_in_direction,
+    walking_cost, Costs, CrossingEdit, ExtraEdges,
+};
+use crate::{IntersectionID, MapModel, Road};
+
+/// Assumed walking speed in meters per second
+pub const WALKING_SPEED: f64 = 1.34;
+
+#[derive(Deserialize)]
+pub struct IsochroneRequest {
+    // WGS84
+    x: f64,
+    y: f64,
+    /// More `[x, y]` starting points in WGS84, like the other entrances of a station. Everywhere is
+    /// reached from whichever origin is closest.
+    #[serde(default)]
+    other_origins: Vec<[f64; 2]>,
+    /// How long to walk for
+    #[serde(default = "default_limit_minutes")]
+    limit_minutes: f64,
+    /// Instead of a time limit, how far to walk along the network. No walking speed, hills, or
+    /// crossing delays are assumed, and `cutoffs_meters` is used instead of `cutoffs_minutes`.
+    #[serde(default)]
+    limit_meters: Option<f64>,
+    /// Time of day as "HH:MM". If specified, roads closed at this time are avoided.
+    #[serde(default)]
+    time: Option<String>,
+    /// Walking up and down hills takes longer; see `CostConfig`
+    #[serde(default)]
+    hill_penalty: f64,
+    /// Assume the longest wait at every crossing
+    #[serde(default)]
+    pessimistic: bool,
+    /// In meters per second, to model people who walk more slowly, like children or the elderly
+    #[serde(default = "default_walking_speed")]
+    walking_speed: f64,
+    /// Multiply the time to walk along steps by `1 + steps_penalty`
+    #[serde(default)]
+    steps_penalty: f64,
+    /// Extra seconds spent at every crossing, on top of any waiting from `pessimistic`
+    #[serde(default)]
+    crossing_delay_seconds: f64,
+    /// Never walk along these kinds of road, like "WithTraffic" or "Tunnel", to compare the reach
+    /// of a more pleasant network against the full one
+    #[serde(default)]
+    exclude_kinds: Vec<String>,
+    /// Instead of reachable roads, return one polygon for each of these limits in minutes, like 5,
+    /// 10, and 15, covering everywhere reachable within that time. The largest comes first. If
+    /// population data is loaded, each has a `population`, and `population_cells` has the
+    /// populated cells within the largest. Each also has `amenities`, counting the points of
+    /// interest of each category inside it.
+    #[serde(default)]
+    cutoffs_minutes: Vec<f64>,
+    /// Like `cutoffs_minutes`, when the limit is a distance
+    #[serde(default)]
+    cutoffs_meters: Vec<f64>,
+    /// Instead of reachable roads, return the area reachable over the network and the circle
+    /// reachable walking in a straight line, with the ratio of their areas. Ignores
+    /// `cutoffs_minutes`.
+    #[serde(default)]
+    crow_flies: bool,
+    /// Find the time to walk from everywhere to the origins, instead of from the origins. This
+    /// differs where footpaths are one-way.
+    #[serde(default)]
+    reverse: bool,
+}
+
+fn default_limit_minutes() -> f64 {
+    15.0
+}
+
+fn default_walking_speed() -> f64 {
+    WALKING_SPEED
+}
+
+impl IsochroneRequest {
+    fn speed(&self) -> f64 {
+        // Avoid dividing by zero
+        self.walking_speed.max(0.01)
+    }
+
+    /// Costs are in cm, so convert the time to the distance walked at this speed
+    fn minutes_to_cost(&self, minutes: f64) -> f64 {
+        100.0 * self.speed() * 60.0 * minutes
+    }
+
+    fn by_distance(&self) -> bool {
+        self.limit_meters.is_some()
+    }
+
+    fn limit_cost(&self) -> f64 {
+        match self.limit_meters {
+            Some(meters) => 100.0 * meters,
+            None => self.minutes_to_cost(self.limit_minutes),
+        }
+    }
+
+    /// Each contour cutoff with its property name and cost
+    fn cutoffs(&self) -> Vec<(&'static str, f64, f64)> {
+        if self.by_distance() {
+            self.cutoffs_meters
+                .iter()
+                .map(|meters| ("cutoff_meters", *meters, 100.0 * meters))
+                .collect()
+        } else {
+            self.cutoffs_minutes
+                .iter()
+                .map(|minutes| ("cutoff_minutes", *minutes, self.minutes_to_cost(*minutes)))
+                .collect()
+        }
+    }
+
+    /// How far someone could get within the limit walking in a straight line, in meters
+    fn straight_line_reach(&self) -> f64 {
+        self.limit_meters
+            .unwrap_or(self.speed() * 60.0 * self.limit_minutes)
+    }
+
+    /// Converts a cost to seconds, or meters when the limit is a distance
+    fn cost_to_output(&self, cost: f64) -> f64 {
+        if self.by_distance() {
+            cost / 100.0
+        } else {
+            cost / 100.0 / self.speed()
+        }
+    }
+}
+
+/// Walking this far from a reached road, like across a park or up to a front door, still counts as
+/// reachable in contours
+pub const OFF_NETWORK_METERS: f64 = 50.0;
+/// The resolution of contours, in meters
+pub const CONTOUR_CELL_SIZE: f64 = 20.0;
+
+/// Returns every road reachable from the start within the time limit, with the time in seconds to
+/// reach it. Roads only partly reachable are cut off where time runs out. See `IsochroneRequest`
+/// for the other outputs, and for limiting by distance instead.
+pub fn calculate(map: &MapModel, req: IsochroneRequest) -> Result<FeatureCollection> {
+    let origins = snap_origins(map, &req);
+    let time = req.time.as_deref().map(parse_time).transpose()?;
+
+    let cutoffs = if req.crow_flies {
+        Vec::new()
+    } else {
+        req.cutoffs()
+    };
+    let limit = cutoffs
+        .iter()
+        .map(|(_, _, cost)| *cost)
+        .reduce(f64::max)
+        .unwrap_or(req.limit_cost())
+        .round() as usize;
+    let cost_fn = |r: &Road| edge_cost(r, time, &req);
+    let starts = origins.iter().map(|(_, i)| (*i, 0)).collect();
+    let costs = search(map, starts, limit, &ExtraEdges::new(), &req, &cost_fn);
+
+    if req.crow_flies {
+        let radius = req.straight_line_reach();
+        let circle = origins
+            .iter()
+            .map(|(pt, _)| MultiPolygon::from(circle(*pt, radius)))
+            .reduce(|a, b| a.union(&b))
+            .unwrap();
+        let network = cost_grid(map, &costs, &cost_fn, CONTOUR_CELL_SIZE)
+            .contour(req.limit_cost())
+            .intersection(&circle);
+        let (network_area, circle_area) = (network.unsigned_area(), circle.unsigned_area());
+
+        let mut network_f = Feature::from(Geometry::from(&map.mercator.to_wgs84(&network)));
+        network_f.set_property("kind", "network");
+        let mut circle_f = Feature::from(Geometry::from(&map.mercator.to_wgs84(&circle)));
+        circle_f.set_property("kind", "crow_flies");
+        return Ok(FeatureCollection {
+            features: vec![circle_f, network_f],
+            bbox: None,
+            foreign_members: Some(
+                serde_json::json!({
+                    // In square meters
+                    "network_area": network_area,
+                    "crow_flies_area": circle_area,
+                    "area_ratio": network_area / circle_area,
+                })
+                .as_object()
+                .unwrap()
+                .clone(),
+            ),
+        });
+    }
+
+    if !cutoffs.is_empty() {
+        let grid = cost_grid(map, &costs, &cost_fn, CONTOUR_CELL_SIZE);
+        let mut cutoffs = cutoffs;
+        cutoffs.sort_by(|a, b| b.2.total_cmp(&a.2));
+        let mut features = Vec::new();
+        let mut population_cells = None;
+        for (key, value, cost) in cutoffs {
+            let contour = grid.contour(cost);
+            let mut f = Feature::from(Geometry::from(&map.mercator.to_wgs84(&contour)));
+            f.set_property(key, value);
+            f.set_property(
+                "amenities",
+                serde_json::to_value(count_within(&map.pois, &contour))?,
+            );
+            if let Some(ref population) = map.population {
+                f.set_property("population", population.within(&contour));
+                if population_cells.is_none() {
+                    population_cells = Some(population.cells_within(map, &contour));
+                }
+            }
+            features.push(f);
+        }
+        return Ok(FeatureCollection {
+            features,
+            bbox: None,
+            foreign_members: population_cells.map(|cells| {
+                serde_json::json!({
+                    "population_cells": cells,
+                })
+                .as_object()
+                .unwrap()
+                .clone()
+            }),
+        });
+    }
+
+    let mut features = Vec::new();
+    for r in &map.roads {
+        let Some(edge_cost) = cost_fn(r) else {
+            continue;
+        };
+        let cost1 = costs.get(&r.src_i).map(|(c, _)| *c);
+        let cost2 = costs.get(&r.dst_i).map(|(c, _)| *c);
+        let cost = match (cost1, cost2) {
+            (Some(c1), Some(c2)) => c1.min(c2),
+            (Some(c), None) | (None, Some(c)) => c,
+            (None, None) => continue,
+        };
+
+        // How far along the road is reachable from each end, before running out of time
+        let reach = |cost: Option<usize>, forwards: bool| {
+            if !walkable_in_direction(r, forwards != req.reverse) {
+                return 0.0;
+            }
+            cost.map(|c| (limit.saturating_sub(c) as f64 / edge_cost.max(1) as f64).min(1.0))
+                .unwrap_or(0.0)
+        };
+        let (from_src, from_dst) = (reach(cost1, true), 1.0 - reach(cost2, false));
+        let pieces = if from_src >= from_dst {
+            vec![r.linestring.clone()]
+        } else {
+            [(0.0, from_src), (from_dst, 1.0)]
+                .into_iter()
+                .filter(|(start, end)| end > start)
+                .map(|(start, end)| slice_linestring(&r.linestring, start, end))
+                .collect::<Vec<_>>()
+        };
+        // One-way footpaths might not be reachable from the end that was reached
+        if pieces.is_empty() {
+            continue;
+        }
+
+        let mut f = r.to_gj(&map.mercator);
+        f.geometry = Some(Geometry::from(
+            &map.mercator.to_wgs84(&MultiLineString::new(pieces)),
+        ));
+        f.set_property(
+            if req.by_distance() {
+                "cost_meters"
+            } else {
+                "cost_seconds"
+            },
+            req.cost_to_output(cost as f64),
+        );
+        f.set_property("fraction_reachable", (from_src + 1.0 - from_dst).min(1.0));
+        features.push(f);
+    }
+
+    Ok(FeatureCollection {
+        features,
+        bbox: None,
+        foreign_members: None,
+    })
+}
+
+/// Finds the area reachable within the limit before and after adding some new crossings.
+/// Returns the `before` and `after` contours and the `newly_reachable` area between them, with the
+/// number of `newly_reachable_buildings` as a foreign member.
+pub fn calculate_with_edit(
+    map: &MapModel,
+    req: IsochroneRequest,
+    edits: Vec<CrossingEdit>,
+) -> Result<FeatureCollection> {
+    let extra = crossing_edit_edges(map, &edits)?;
+    let starts: Vec<(IntersectionID, usize)> = snap_origins(map, &req)
+        .into_iter()
+        .map(|(_, i)| (i, 0))
+        .collect();
+    let time = req.time.as_deref().map(parse_time).transpose()?;
+    let limit = req.limit_cost();
+    let cost_fn = |r: &Road| edge_cost(r, time, &req);
+
+    let before_costs = search(
+        map,
+        starts.clone(),
+        limit.round() as usize,
+        &ExtraEdges::new(),
+        &req,
+        &cost_fn,
+    );
+    let after_costs = search(map, starts, limit.round() as usize, &extra, &req, &cost_fn);
+    let before = cost_grid(map, &before_costs, &cost_fn, CONTOUR_CELL_SIZE).contour(limit);
+    let after = cost_grid(map, &after_costs, &cost_fn, CONTOUR_CELL_SIZE).contour(limit);
+    let newly_reachable = after.difference(&before);
+    let newly_reachable_buildings = map
+        .buildings
+        .iter()
+        .filter(|b| {
+            b.polygon
+                .centroid()
+                .map(|pt| newly_reachable.contains(&pt))
+                .unwrap_or(false)
+        })
+        .count();
+
+    let mut features = Vec::new();
+    for (kind, area) in [
+        ("after", after),
+        ("before", before),
+        ("newly_reachable", newly_reachable),
+    ] {
+        let mut f = Feature::from(Geometry::from(&map.mercator.to_wgs84(&area)));
+        f.set_property("kind", kind);
+        f.set_property("area", area.unsigned_area());
+        features.push(f);
+    }
+    Ok(FeatureCollection {
+        features,
+        bbox: None,
+        foreign_members: Some(
+            serde_json::json!({
+                "newly_reachable_buildings": newly_reachable_buildings,
+            })
+            .as_object()
+            .unwrap()
+            .clone(),
+        ),
+    })
+}
+
+/// Every origin in Mercator, with the closest intersection to it
+fn snap_origins(map: &MapModel, req: &IsochroneRequest) -> Vec<(Coord, IntersectionID)> {
+    std::iter::once([req.x, req.y])
+        .chain(req.other_origins.iter().cloned())
+        .map(|[x, y]| {
+            let pt = map.mercator.pt_to_mercator(Coord { x, y });
+            let start = map.node_map.translate_id(
+                map.closest_intersection
+                    .nearest_neighbor(&[pt.x, pt.y])
+                    .unwrap()
+                    .data,
+            );
+            (pt, start)
+        })
+        .collect()
+}
+
+#[derive(Deserialize)]
+pub struct SurfaceRequest {
+    #[serde(flatten)]
+    isochrone: IsochroneRequest,
+    /// The width and height of each cell in meters
+    #[serde(default = "default_cell_size_meters")]
+    cell_size_meters: f64,
+}
+
+fn default_cell_size_meters() -> f64 {
+    CONTOUR_CELL_SIZE
+}
+
+/// Travel times on a regular grid
+pub struct Surface {
+    /// Seconds to reach each cell, or meters with `limit_meters`. NaN if it's not reachable
+    /// within the limit. Rows go from north to south, and each row from west to east.
+    pub values: Vec<f32>,
+    pub georef: Georef,
+}
+
+/// Describes where a `Surface` is, like the header of a GeoTIFF
+#[derive(Serialize)]
+pub struct Georef {
+    pub width: usize,
+    pub height: usize,
+    pub cell_size_meters: f64,
+    /// `[west, south, east, north]` in WGS84
+    pub bounds: [f64; 4],
+}
+
+/// Rasterizes the travel time from the origins onto a grid, walking along roads and then off the
+/// network in a straight line, like the contours
+pub fn travel_time_surface(map: &MapModel, req: SurfaceRequest) -> Result<Surface> {
+    if req.cell_size_meters < 1.0 {
+        bail!("The cell size must be at least 1m");
+    }
+    let iso = &req.isochrone;
+    let starts = snap_origins(map, iso)
+        .into_iter()
+        .map(|(_, i)| (i, 0))
+        .collect();
+    let time = iso.time.as_deref().map(parse_time).transpose()?;
+    let limit = iso.limit_cost();
+    let cost_fn = |r: &Road| edge_cost(r, time, iso);
+    let costs = search(
+        map,
+        starts,
+        limit.round() as usize,
+        &ExtraEdges::new(),
+        iso,
+        &cost_fn,
+    );
+    let grid = cost_grid(map, &costs, &cost_fn, req.cell_size_meters);
+
+    let mut values = Vec::with_capacity(grid.width() * grid.height());
+    for y in (0..grid.height()).rev() {
+        for x in 0..grid.width() {
+            let cost = grid.get(x, y);
+            values.push(if cost <= limit {
+                iso.cost_to_output(cost) as f32
+            } else {
+                f32::NAN
+            });
+        }
+    }
+    let bounds = map.mercator.to_wgs84(&grid.bounds().to_polygon());
+    let bounds = bounds.bounding_rect().unwrap();
+    Ok(Surface {
+        values,
+        georef: Georef {
+            width: grid.width(),
+            height: grid.height(),
+            cell_size_meters: req.cell_size_meters,
+            bounds: [
+                bounds.min().x,
+                bounds.min().y,
+                bounds.max().x,
+                bounds.max().y,
+            ],
+        },
+    })
+}
+
+/// Searches outwards from the origins, respecting one-way footpaths in the direction people walk
+fn search<F: Fn(&Road) -> Option<usize>>(
+    map: &MapModel,
+    starts: Vec<(IntersectionID, usize)>,
+    limit: usize,
+    extra: &ExtraEdges,
+    req: &IsochroneRequest,
+    cost_fn: F,
+) -> Costs {
+    dijkstra_directed(map, starts, Some(limit), extra, |r, forwards| {
+        // In reverse, the search goes against the direction people walk
+        if walkable_in_direction(r, forwards != req.reverse) {
+            cost_fn(r)
+        } else {
+            None
+        }
+    })
+}
+
+/// Costs are in cm, so convert the time to the distance walked at the default speed
+pub fn minutes_to_cost(minutes: f64) -> f64 {
+    100.0 * WALKING_SPEED * 60.0 * minutes
+}
+
+/// The cost to walk along a road, or `None` if it's closed or excluded
+fn edge_cost(r: &Road, time: Option<Time>, req: &IsochroneRequest) -> Option<usize> {
+    if time.map(|t| r.access.is_closed_at(t)).unwrap_or(false)
+        || req.exclude_kinds.contains(&format!("{:?}", r.kind))
+    {
+        return None;
+    }
+    if req.by_distance() {
+        return walking_cost(r);
+    }
+    let mut cost = walking_cost(r)? as f64 * hill_factor(r, req.hill_penalty);
+    if is_steps(r) {
+        cost *= 1.0 + req.steps_penalty.max(0.0);
+    }
+    let quality = crossing_quality(r);
+    if quality != "none" {
+        let mut delay = req.crossing_delay_seconds.max(0.0);
+        if req.pessimistic {
+            delay += crossing_delay_range(quality).max;
+        }
+        // Convert the delay to the distance that could've been walked instead
+        cost += 100.0 * req.speed() * delay;
+    }
+    Some(cost.round() as usize)
+}
+
+/// The cost to reach every point near the reached roads, walking along them and then off the
+/// network in a straight line
+pub fn cost_grid<F: Fn(&Road) -> Option<usize>>(
+    map: &MapModel,
+    costs: &Costs,
+    cost_fn: F,
+    cell_size: f64,
+) -> Grid {
+    let reached: MultiPoint = costs.keys().map(|i| map.intersections[i.0].point).collect();
+    // The start is always reached
+    let bounds = reached.bounding_rect().unwrap();
+    let mut grid = Grid::new(bounds, cell_size, OFF_NETWORK_METERS);
+
+    for r in &map.roads {
+        let Some(edge_cost) = cost_fn(r) else {
+            continue;
+        };
+        let edge_cost = edge_cost as f64;
+        let cost1 = costs.get(&r.src_i).map(|(c, _)| *c as f64);
+        let cost2 = costs.get(&r.dst_i).map(|(c, _)| *c as f64);
+        if cost1.is_none() && cost2.is_none() {
+            continue;
+        }
+        let length = r.linestring.euclidean_length();
+        let mut dist = 0.0;
+        let mut prev: Option<Coord> = None;
+        for pt in r.linestring.densify(cell_size / 2.0).0 {
+            if let Some(prev) = prev {
+                dist += prev.euclidean_distance(&pt);
+            }
+            prev = Some(pt);
+            let fraction = if length > 0.0 { dist / length } else { 0.0 };
+            let cost = [
+                cost1.map(|c| c + fraction * edge_cost),
+                cost2.map(|c| c + (1.0 - fraction) * edge_cost),
+            ]
+            .into_iter()
+            .flatten()
+            .fold(f64::INFINITY, f64::min);
+            grid.splat(pt, OFF_NETWORK_METERS, cost, 100.0);
+        }
+    }
+    grid
+}
+
+pub fn circle(center: Coord, radius: f64) -> Polygon {
+    let pts = (0..=64)
+        .map(|i| {
+            let angle = (i as f64 / 64.0) * std::f64::consts::TAU;
+            Coord {
+                x: center.x + radius * angle.cos(),
+                y: center.y + radius * angle.sin(),
+            }
+        })
+        .collect();
+    Polygon::new(LineString::new(pts), Vec::new())
+}
@@ -1,20 +1,64 @@
 #[macro_use]
 extern crate log;
 
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 use std::sync::Once;
 
-use fast_paths::{FastGraph, PathCalculator};
-use geo::{Coord, Line, LineString, Point, Polygon};
-use geojson::{Feature, GeoJson, Geometry};
-use rstar::{primitives::GeomWithData, RTree};
+use geo::{Coord, Intersects, Line, LineString, Point, Polygon, Rect};
+use geojson::{Feature, FeatureCollection, Geometry};
+use rstar::{primitives::GeomWithData, RTree, RTreeObject, AABB};
 use serde::{Deserialize, Serialize};
 use utils::{Mercator, NodeMap, Tags};
 use wasm_bindgen::prelude::*;
 
+mod access;
+mod adjacency;
+mod analyze;
+mod areas;
+mod boundary;
+mod buildings;
+mod compare;
+mod components;
+mod contours;
+mod cost_graph;
+mod costs;
+mod crossings;
+mod edits;
+mod elevation;
+mod export;
+mod fgb;
+mod geopackage;
 mod heatmap;
+mod ids;
+mod intersections;
+mod isochrone;
+mod merge;
+mod neighbourhoods;
+mod osc;
+mod overpass;
+mod parallel;
+mod pois;
+mod population;
+mod progress;
+mod provenance;
 mod route;
+mod router;
+mod schematic;
+mod scores;
 mod scrape;
+mod severances;
+mod shortcuts;
+mod snapshot;
+mod split;
+mod static_map;
+mod stubs;
+mod tag_index;
+mod tiles;
+mod traffic;
+mod trails;
+mod validate;
+mod walkability;
 
 static START: Once = Once::new();
 
@@ -26,15 +70,46 @@ pub struct MapModel {
     mercator: Mercator,
     // Only snaps to walkable roads
     closest_intersection: RTree<IntersectionLocation>,
+    closest_road: RTree<RoadLocation>,
+    closest_severance: RTree<RoadLocation>,
+    // Every road, for viewport queries
+    road_index: RTree<RoadLocation>,
+    edge_index: adjacency::EdgeIndex,
     node_map: NodeMap<IntersectionID>,
-    ch: FastGraph,
-    path_calc: PathCalculator,
+    routers: router::Routers,
     boundary_polygon: Polygon,
+    /// Where the input was actually cut off: the extract's own bounds, or a custom boundary roads
+    /// were clipped to. The default boundary hugs the roads, so it can't tell real dead-ends apart.
+    clip_polygon: Polygon,
+    /// Dead-ends within this distance of `clip_polygon` are treated as artifacts of clipping
+    boundary_buffer: f64,
+    tag_index: tag_index::TagIndex,
+    /// The driving router is built from this when it's first needed
+    driving: route::DrivingInput,
+    walking_routes: HashMap<osm_reader::RelationID, trails::WalkingRoute>,
+    buildings: Vec<buildings::Building>,
+    pois: Vec<pois::Poi>,
+    /// Pedestrian areas, parks, and water, from closed ways and multipolygon relations
+    areas: Vec<areas::Area>,
+    /// For applying osmChange files
+    osm: osc::OsmStore,
+    /// Changes made to the network since import, which can be undone
+    edits: edits::EditHistory,
+    score_channel: scores::ScoreChannel,
+    cost_config: costs::CostConfig,
+    provenance: provenance::Provenance,
+    population: Option<population::Population>,
+    /// Built up lazily as tiles are requested
+    tiles: tiles::TileSource,
+    /// The latest heatmap and isochrone, for `exportGeoPackage`
+    generated_layers: BTreeMap<&'static str, FeatureCollection>,
+    /// Increases every time rendered properties of some roads change
+    render_version: usize,
 }
 
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, PartialOrd, Ord)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct RoadID(pub usize);
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, PartialOrd, Ord, Serialize)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct IntersectionID(pub usize);
 
 impl fmt::Display for RoadID {
@@ -49,6 +124,7 @@ impl fmt::Display for IntersectionID {
     }
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct Road {
     id: RoadID,
     src_i: IntersectionID,
@@ -59,22 +135,37 @@ pub struct Road {
     linestring: LineString,
     tags: Tags,
     kind: RoadKind,
+    /// A cycleway shared with pedestrians
+    shared_with_cyclists: bool,
+    /// The OSM `layer`, with 0 being ground level
+    layer: i8,
+    /// When the road is closed, like a park locked at night
+    access: access::ConditionalAccess,
+    /// Annual average daily traffic, if known
+    aadt: Option<f64>,
+    /// The road leads to a dead-end that's probably only there because the extract was clipped
+    truncated: bool,
+    /// Total climb and descent divided by length, if elevation data was provided
+    gradient: Option<f64>,
+    /// The `render_version` when this road last changed
+    version: usize,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum RoadKind {
     Footway,
     Indoors,
-    BridgeOrTunnel,
+    Bridge,
+    Tunnel,
     WithTraffic,
     Crossing,
     Severance,
     // TODO other types of road?
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct Intersection {
     id: IntersectionID,
-    #[allow(dead_code)]
     node: osm_reader::NodeID,
     point: Point,
     roads: Vec<RoadID>,
@@ -82,14 +173,36 @@ pub struct Intersection {
 
 // fast_paths ID representing the OSM node ID as the data
 type IntersectionLocation = GeomWithData<[f64; 2], usize>;
+type RoadLocation = GeomWithData<LineString, RoadID>;
 
 #[wasm_bindgen]
 impl MapModel {
-    /// Call with bytes of an osm.pbf or osm.xml string
+    /// Call with bytes of an osm.pbf, osm.xml, or Overpass JSON string, and optionally an
+    /// `AnalyzeOptions` object, all of whose fields are optional. `traffic_counts` is CSV or
+    /// GeoJSON with traffic volumes per OSM way; streets with at least `severance_aadt` vehicles
+    /// per day are treated as severances, and quieter counted streets aren't. For huge areas where
+    /// only a few routes are needed, `skip_contraction_hierarchy` avoids slow preparation.
+    /// `boundary` is GeoJSON with a polygon, like an administrative area the extract was clipped
+    /// to, to use instead of a concave hull around the roads. Disconnected pieces of the walking
+    /// network shorter than `min_component_length` meters, like paths around a parking lot, are
+    /// dropped. Dead-ends within `snap_tolerance` meters of another intersection, like a crossing
+    /// that doesn't quite reach the sidewalk, are merged into it; the merges are listed in
+    /// `getProvenance` so the data can be fixed. `boundary_buffer` is passed to
+    /// `setBoundaryBuffer`, and the desire line bounds are ignored.
+    ///
+    /// The options can't be deserialized with everything else: `elevation` is a function taking
+    /// WGS84 longitude and latitude and returning elevation in meters, like a lookup in a DEM, and
+    /// `additional_inputs` is an array of more osm.pbf or osm.xml bytes, like adjacent extracts, to
+    /// merge with the first input.
+    ///
+    /// `progress` is an optional function called with the name of each import phase and the
+    /// overall percent complete. It's called synchronously, so to update a progress bar, import in
+    /// a web worker and post the updates to the page.
     #[wasm_bindgen(constructor)]
     pub fn new(
         input_bytes: &[u8],
-        import_streets_without_sidewalk_tagging: bool,
+        options: JsValue,
+        progress: Option<js_sys::Function>,
     ) -> Result<MapModel, JsValue> {
         // Panics shouldn't happen, but if they do, console.log them.
         console_error_panic_hook::set_once();
@@ -97,60 +210,840 @@ impl MapModel {
             console_log::init_with_level(log::Level::Info).unwrap();
         });
 
-        scrape::scrape_osm(input_bytes, import_streets_without_sidewalk_tagging).map_err(err_to_js)
+        let opts: Option<analyze::AnalyzeOptions> =
+            serde_wasm_bindgen::from_value(options.clone())?;
+        let opts = opts.unwrap_or_default();
+        let js_option = |key: &str| {
+            js_sys::Reflect::get(&options, &key.into())
+                .ok()
+                .filter(|x| !x.is_undefined() && !x.is_null())
+        };
+        let elevation = js_option("elevation").and_then(|x| x.dyn_into::<js_sys::Function>().ok());
+        let additional_inputs =
+            js_option("additional_inputs").and_then(|x| x.dyn_into::<js_sys::Array>().ok());
+
+        let boundary = match opts.boundary {
+            Some(input) => Some(boundary::parse_boundary(&input).map_err(err_to_js)?),
+            None => None,
+        };
+        let traffic_counts = match opts.traffic_counts {
+            Some(input) => traffic::parse_traffic_counts(&input).map_err(err_to_js)?,
+            None => HashMap::new(),
+        };
+        let converted;
+        let input_bytes = if overpass::is_overpass_json(input_bytes) {
+            converted = overpass::to_osm_xml(input_bytes).map_err(err_to_js)?;
+            &converted
+        } else {
+            input_bytes
+        };
+        let mut report = |phase: &str, percent: f64| {
+            if let Some(ref f) = progress {
+                let _ = f.call2(&JsValue::NULL, &phase.into(), &percent.into());
+            }
+        };
+        let merged;
+        let input_bytes = match additional_inputs {
+            Some(array) if array.length() > 0 => {
+                let mut additional = Vec::new();
+                for x in array.iter() {
+                    let bytes = js_sys::Uint8Array::new(&x).to_vec();
+                    if overpass::is_overpass_json(&bytes) {
+                        additional.push(overpass::to_osm_xml(&bytes).map_err(err_to_js)?);
+                    } else {
+                        additional.push(bytes);
+                    }
+                }
+                // The first input isn't copied
+                let inputs: Vec<&[u8]> = std::iter::once(input_bytes)
+                    .chain(additional.iter().map(|bytes| bytes.as_slice()))
+                    .collect();
+                merged = merge::merge_osm_inputs(&inputs, &mut report).map_err(err_to_js)?;
+                &merged
+            }
+            _ => input_bytes,
+        };
+        let mut map = scrape::scrape_osm(
+            input_bytes,
+            scrape::ImportOptions {
+                import_streets_without_sidewalk_tagging: opts
+                    .import_streets_without_sidewalk_tagging,
+                traffic_counts,
+                severance_aadt: opts
+                    .severance_aadt
+                    .unwrap_or(traffic::DEFAULT_SEVERANCE_AADT),
+                skip_contraction_hierarchy: opts.skip_contraction_hierarchy,
+                boundary,
+                min_component_length: opts.min_component_length,
+                snap_tolerance: opts.snap_tolerance,
+            },
+            &mut report,
+        )
+        .map_err(err_to_js)?;
+        if let Some(f) = elevation {
+            map.provenance.elevation = true;
+            elevation::set_gradients(&mut map, |pt| {
+                f.call2(&JsValue::NULL, &pt.x.into(), &pt.y.into())
+                    .ok()?
+                    .as_f64()
+            });
+        }
+        if let Some(meters) = opts.boundary_buffer {
+            map.set_boundary_buffer(meters);
+        }
+        Ok(map)
+    }
+
+    /// Applies an osmChange file from editing the area, rebuilding only the affected roads. Returns
+    /// JSON summarizing what changed. Road IDs are renumbered, so the summary's `ids` maps old IDs
+    /// to new ones like `applyEdit`, and rebuilt roads can be redrawn with `renderChangedSince`.
+    #[wasm_bindgen(js_name = applyOsc)]
+    pub fn apply_osc(&mut self, input_bytes: &[u8]) -> Result<String, JsValue> {
+        let summary = osc::apply_osc(self, input_bytes).map_err(err_to_js)?;
+        serde_json::to_string(&summary).map_err(err_to_js)
+    }
+
+    /// Changes the network. The edit is an object with a `type` of `AddCrossing` (with `x1`, `y1`,
+    /// `x2`, `y2`), `DeleteRoad` (with `stable_id`), or `SetKind` (with `stable_id` and `kind`).
+    /// Road IDs may change, so this returns JSON with `roads` and `intersections` arrays, giving
+    /// the new ID for each old ID, or null if it was removed. Changed roads can be redrawn with
+    /// `renderChangedSince`.
+    #[wasm_bindgen(js_name = applyEdit)]
+    pub fn apply_edit(&mut self, input: JsValue) -> Result<String, JsValue> {
+        let edit: edits::Edit = serde_wasm_bindgen::from_value(input)?;
+        let mapping = edits::apply(self, edit).map_err(err_to_js)?;
+        serde_json::to_string(&mapping).map_err(err_to_js)
+    }
+
+    /// Reverts the last edit. Returns JSON with the new IDs like `applyEdit`, or null if there
+    /// was nothing to undo.
+    #[wasm_bindgen(js_name = undoEdit)]
+    pub fn undo_edit(&mut self) -> Result<String, JsValue> {
+        serde_json::to_string(&edits::undo(self)).map_err(err_to_js)
+    }
+
+    /// Reapplies the last undone edit. Returns JSON with the new IDs like `applyEdit`, or null if
+    /// there was nothing to redo.
+    #[wasm_bindgen(js_name = redoEdit)]
+    pub fn redo_edit(&mut self) -> Result<String, JsValue> {
+        let mapping = edits::redo(self).map_err(err_to_js)?;
+        serde_json::to_string(&mapping).map_err(err_to_js)
+    }
+
+    /// Returns JSON with the list of edits applied, to save and later pass to `setEdits`
+    #[wasm_bindgen(js_name = getEdits)]
+    pub fn get_edits(&self) -> Result<String, JsValue> {
+        serde_json::to_string(&self.edits.list()).map_err(err_to_js)
+    }
+
+    /// Undoes all edits, then applies a list from `getEdits`. Returns JSON with the new IDs like
+    /// `applyEdit`. If any edit fails, the previous edits are kept.
+    #[wasm_bindgen(js_name = setEdits)]
+    pub fn set_edits(&mut self, input: JsValue) -> Result<String, JsValue> {
+        let list: Vec<edits::Edit> = serde_wasm_bindgen::from_value(input)?;
+        let mapping = edits::replace_all(self, list).map_err(err_to_js)?;
+        serde_json::to_string(&mapping).map_err(err_to_js)
+    }
+
+    /// After deleting roads, drops intersections nothing uses anymore and renumbers the rest.
+    /// Returns JSON with the new IDs like `applyEdit`.
+    #[wasm_bindgen()]
+    pub fn compact(&mut self) -> Result<String, JsValue> {
+        serde_json::to_string(&edits::compact(self)).map_err(err_to_js)
+    }
+
+    /// Loads a model saved with `toBytes`, skipping OSM parsing and, if it was included,
+    /// contraction hierarchy preparation
+    #[wasm_bindgen(js_name = fromBytes)]
+    pub fn from_bytes(input_bytes: &[u8]) -> Result<MapModel, JsValue> {
+        console_error_panic_hook::set_once();
+        START.call_once(|| {
+            console_log::init_with_level(log::Level::Info).unwrap();
+        });
+        snapshot::from_bytes(input_bytes).map_err(err_to_js)
     }
 
-    /// Returns a GeoJSON string. Just shows the full ped network
+    /// Saves the imported network as a binary snapshot for `fromBytes`. Set
+    /// `include_contraction_hierarchy` to also save routing preparation for the default costs,
+    /// making the snapshot bigger but loading much faster.
+    #[wasm_bindgen(js_name = toBytes)]
+    pub fn to_bytes(&mut self, include_contraction_hierarchy: bool) -> Result<Vec<u8>, JsValue> {
+        snapshot::to_bytes(self, include_contraction_hierarchy).map_err(err_to_js)
+    }
+
+    /// Returns a GeoJSON string. Just shows the full ped network. Optionally takes
+    /// `RenderOptions` to slim down the output. Each feature's ID is the road's `id`, and the
+    /// collection has the current `version` for `renderChangedSince`.
     #[wasm_bindgen()]
-    pub fn render(&self) -> Result<String, JsValue> {
+    pub fn render(&self, options: JsValue) -> Result<String, JsValue> {
+        let options: Option<export::RenderOptions> = serde_wasm_bindgen::from_value(options)?;
+        let options = options.unwrap_or_default();
         let mut features = Vec::new();
 
         for r in &self.roads {
-            features.push(r.to_gj(&self.mercator));
+            features.push(options.road(r, &self.mercator));
         }
 
-        let gj = GeoJson::from(features);
-        let out = serde_json::to_string(&gj).map_err(err_to_js)?;
-        Ok(out)
+        let mut gj = FeatureCollection::from_iter(features);
+        gj.foreign_members = Some(
+            serde_json::json!({
+                "version": self.render_version,
+            })
+            .as_object()
+            .unwrap()
+            .clone(),
+        );
+        self.export(gj)
     }
 
+    /// Returns a GeoJSON string with only the roads that changed after `version`, like after
+    /// `setBoundaryBuffer`, with the same IDs as `render`. The collection has the new `version`.
+    #[wasm_bindgen(js_name = renderChangedSince)]
+    pub fn render_changed_since(&self, version: usize) -> Result<String, JsValue> {
+        let mut gj = FeatureCollection::from_iter(
+            self.roads
+                .iter()
+                .filter(|r| r.version > version)
+                .map(|r| r.to_gj(&self.mercator)),
+        );
+        gj.foreign_members = Some(
+            serde_json::json!({
+                "version": self.render_version,
+            })
+            .as_object()
+            .unwrap()
+            .clone(),
+        );
+        self.export(gj)
+    }
+
+    /// Returns a GeoJSON string with only the roads intersecting a WGS84 bounding box. `kinds`
+    /// optionally limits this to some `RoadKind`s, like `["Severance", "Crossing"]`.
+    #[wasm_bindgen(js_name = renderBbox)]
+    pub fn render_bbox(
+        &self,
+        min_lon: f64,
+        min_lat: f64,
+        max_lon: f64,
+        max_lat: f64,
+        kinds: JsValue,
+    ) -> Result<String, JsValue> {
+        let kinds: Option<Vec<String>> = serde_wasm_bindgen::from_value(kinds)?;
+        let bbox = Rect::new(
+            self.mercator.pt_to_mercator(Coord {
+                x: min_lon,
+                y: min_lat,
+            }),
+            self.mercator.pt_to_mercator(Coord {
+                x: max_lon,
+                y: max_lat,
+            }),
+        );
+
+        let mut features = Vec::new();
+        for road in self.roads_within(bbox) {
+            if let Some(ref kinds) = kinds {
+                if !kinds.contains(&format!("{:?}", road.kind)) {
+                    continue;
+                }
+            }
+            features.push(road.to_gj(&self.mercator));
+        }
+
+        self.export(FeatureCollection::from_iter(features))
+    }
+
+    /// Returns a GeoJSON string with each piece of the route. The collection also has `steps`,
+    /// describing each road used in order, and totals like `route_length`, `length_with_traffic`,
+    /// and `severance_crossings`. With `format` set to "polyline" or "gpx", returns just the
+    /// route's geometry as a Google encoded polyline or a GPX track instead.
     #[wasm_bindgen(js_name = compareRoute)]
     pub fn compare_route(&mut self, input: JsValue) -> Result<String, JsValue> {
         let req: CompareRouteRequest = serde_wasm_bindgen::from_value(input)?;
-        let pt1 = self.mercator.pt_to_mercator(Coord {
-            x: req.x1,
-            y: req.y1,
-        });
-        let pt2 = self.mercator.pt_to_mercator(Coord {
-            x: req.x2,
-            y: req.y2,
-        });
-        let (_, gj) = route::do_route(
-            self,
-            CompareRouteRequest {
-                x1: pt1.x,
-                y1: pt1.y,
-                x2: pt2.x,
-                y2: pt2.y,
-            },
-        )
-        .map_err(err_to_js)?;
-        let out = serde_json::to_string(&gj).map_err(err_to_js)?;
+        let req = req.into_mercator(&self.mercator);
+        let format = req.format;
+        let (_, gj) = route::do_route(self, req).map_err(err_to_js)?;
+        let mut out = String::new();
+        match format {
+            export::RouteFormat::GeoJson => {
+                out = self.export(gj)?;
+            }
+            export::RouteFormat::Polyline => {
+                let line = export::route_linestring(&gj).map_err(err_to_js)?;
+                export::write_polyline(&line, &mut out).map_err(err_to_js)?;
+            }
+            export::RouteFormat::Gpx => {
+                let line = export::route_linestring(&gj).map_err(err_to_js)?;
+                export::write_gpx(&line, "Walking route", &mut out).map_err(err_to_js)?;
+            }
+        }
         Ok(out)
     }
 
+    /// Like `compareRoute`, but between two OSM nodes. Both must be intersections or the ends of
+    /// roads in the walking network.
+    #[wasm_bindgen(js_name = compareRouteByNodes)]
+    pub fn compare_route_by_nodes(&mut self, node1: f64, node2: f64) -> Result<String, JsValue> {
+        let pt1 = ids::node_point(self, node1 as i64).map_err(err_to_js)?;
+        let pt2 = ids::node_point(self, node2 as i64).map_err(err_to_js)?;
+        self.compare_route_between(pt1, pt2)
+    }
+
+    /// Like `compareRoute`, but between the middle of two OSM ways
+    #[wasm_bindgen(js_name = compareRouteByWays)]
+    pub fn compare_route_by_ways(&mut self, way1: f64, way2: f64) -> Result<String, JsValue> {
+        let pt1 = ids::way_point(self, way1 as i64).map_err(err_to_js)?;
+        let pt2 = ids::way_point(self, way2 as i64).map_err(err_to_js)?;
+        self.compare_route_between(pt1, pt2)
+    }
+
+    /// Like `compareRoute`, but also finds the route after adding hypothetical crossings. `edits`
+    /// is a list of `{x1, y1, x2, y2}` lines in WGS84 straddling severances. Returns a JSON string
+    /// with the `before` and `after` routes as GeoJSON and the `seconds_saved`.
+    #[wasm_bindgen(js_name = compareRouteWithEdit)]
+    pub fn compare_route_with_edit(
+        &mut self,
+        input: JsValue,
+        edits: JsValue,
+    ) -> Result<String, JsValue> {
+        let req: CompareRouteRequest = serde_wasm_bindgen::from_value(input)?;
+        let req = req.into_mercator(&self.mercator);
+        let edits: Vec<route::CrossingEdit> = serde_wasm_bindgen::from_value(edits)?;
+        let result = route::compare_route_with_edit(self, req, edits).map_err(err_to_js)?;
+        let out = serde_json::to_string(&result).map_err(err_to_js)?;
+        Ok(out)
+    }
+
+    /// Like `compareRoute`, but takes a list of requests and returns a JSON string with a list of
+    /// results in the same order. Each result is the route's GeoJSON, or `{"error": "..."}`.
+    #[wasm_bindgen(js_name = compareRoutes)]
+    pub fn compare_routes(&mut self, input: JsValue) -> Result<String, JsValue> {
+        let requests: Vec<CompareRouteRequest> = serde_wasm_bindgen::from_value(input)?;
+        let mut results = Vec::new();
+        for req in requests {
+            let req = req.into_mercator(&self.mercator);
+            results.push(match route::do_route(self, req) {
+                Ok((_, gj)) => serde_json::to_value(&gj).map_err(err_to_js)?,
+                Err(err) => serde_json::json!({ "error": err.to_string() }),
+            });
+        }
+        let out = serde_json::to_string(&results).map_err(err_to_js)?;
+        Ok(out)
+    }
+
+    /// Optionally takes a `HeatmapRequest`, choosing which desire lines to score and limiting their
+    /// length
     #[wasm_bindgen(js_name = makeHeatmap)]
-    pub fn make_heatmap(&mut self) -> Result<String, JsValue> {
-        let samples = heatmap::along_severances(self);
-        // TODO unit here is weird or wrong or something
-        //let samples = heatmap::nearby_footway_intersections(self, 500.0);
-        let out = serde_json::to_string(&samples).map_err(err_to_js)?;
+    pub fn make_heatmap(&mut self, input: JsValue) -> Result<String, JsValue> {
+        let req: Option<heatmap::HeatmapRequest> = serde_wasm_bindgen::from_value(input)?;
+        let req = req.unwrap_or_default();
+        let samples = heatmap::make_heatmap(self, &req);
+        self.score_channel.reset(&samples, &req);
+        self.tiles.set_layer("heatmap", &samples);
+        self.generated_layers.insert("heatmap", samples.clone());
+        self.export(samples)
+    }
+
+    /// Returns Mapbox Vector Tile bytes for tile `z/x/y`, with `network`, `neighbourhoods`, and
+    /// `heatmap` layers. The heatmap layer uses the request from the last `makeHeatmap` call, if
+    /// any, and is recalculated after the network or costs change.
+    #[wasm_bindgen(js_name = renderTiles)]
+    pub fn render_tiles(&mut self, z: u32, x: u32, y: u32) -> Result<Vec<u8>, JsValue> {
+        if !self.tiles.has_layer("network") {
+            let network =
+                FeatureCollection::from_iter(self.roads.iter().map(|r| r.to_gj(&self.mercator)));
+            self.tiles.set_layer("network", &network);
+        }
+        if !self.tiles.has_layer("neighbourhoods") {
+            let gj = neighbourhoods::neighbourhoods(self);
+            self.tiles.set_layer("neighbourhoods", &gj);
+        }
+        if !self.tiles.has_layer("heatmap") {
+            if let Some(req) = self.score_channel.last_request().cloned() {
+                let samples = heatmap::make_heatmap(self, &req);
+                self.tiles.set_layer("heatmap", &samples);
+            }
+        }
+        let mut out = Vec::new();
+        self.tiles.render(z, x, y, &mut out).map_err(err_to_js)?;
+        Ok(out)
+    }
+
+    /// Returns a GeoJSON string with the neighbourhoods separated by severances
+    #[wasm_bindgen()]
+    pub fn neighbourhoods(&self) -> Result<String, JsValue> {
+        let gj = neighbourhoods::neighbourhoods(self);
+        self.export(gj)
+    }
+
+    /// Returns a JSON string with `nodes` for each neighbourhood, `edges` for the walking
+    /// connections between them, and the model's `provenance`
+    #[wasm_bindgen(js_name = neighbourhoodGraph)]
+    pub fn neighbourhood_graph(&mut self) -> Result<String, JsValue> {
+        let graph = neighbourhoods::connectivity_graph(self);
+        self.export_json(&graph)
+    }
+
+    /// Returns a GeoJSON string with the neighbourhood graph laid out like a metro map, in
+    /// synthetic coordinates instead of WGS84
+    #[wasm_bindgen(js_name = neighbourhoodMetroMap)]
+    pub fn neighbourhood_metro_map(&mut self) -> Result<String, JsValue> {
+        let graph = neighbourhoods::connectivity_graph(self);
+        let gj = schematic::metro_map(self, &graph);
+        self.export(gj)
+    }
+
+    /// Optionally takes `DesireLineBounds`, and returns a GeoJSON string with detour statistics
+    /// for every severance road, worst first
+    #[wasm_bindgen(js_name = scoreSeverances)]
+    pub fn score_severances(&mut self, input: JsValue) -> Result<String, JsValue> {
+        let bounds: Option<heatmap::DesireLineBounds> = serde_wasm_bindgen::from_value(input)?;
+        let gj = severances::score_severances(self, &bounds.unwrap_or_default());
+        self.export(gj)
+    }
+
+    /// Takes "network", "crossings", "heatmap", or "neighbourhoods" and returns that layer as
+    /// FlatGeobuf bytes, with a fixed set of attributes per layer. The heatmap uses the request
+    /// from the last `makeHeatmap` call, or the default desire lines.
+    #[wasm_bindgen(js_name = exportFlatGeobuf)]
+    pub fn export_flatgeobuf(&mut self, layer: JsValue) -> Result<Vec<u8>, JsValue> {
+        let layer: export::Layer = serde_wasm_bindgen::from_value(layer)?;
+        let gj = match layer {
+            export::Layer::Network => {
+                FeatureCollection::from_iter(self.roads.iter().map(|r| r.to_gj(&self.mercator)))
+            }
+            export::Layer::Crossings => crossings::crossing_audit(self),
+            export::Layer::Heatmap => {
+                let req = self.score_channel.last_request().cloned();
+                heatmap::make_heatmap(self, &req.unwrap_or_default())
+            }
+            export::Layer::Neighbourhoods => neighbourhoods::neighbourhoods(self),
+        };
+        let mut out = Vec::new();
+        export::write_flatgeobuf(layer, &gj, &mut out).map_err(err_to_js)?;
+        Ok(out)
+    }
+
+    /// Returns a GeoPackage with the `network`, `severances`, and `crossings`, plus the last
+    /// `heatmap` and `isochrone` calculated, if any. `timestamp` is an ISO 8601 time recorded as
+    /// when the layers last changed, like `new Date().toISOString()`.
+    #[wasm_bindgen(js_name = exportGeoPackage)]
+    pub fn export_geopackage(&self, timestamp: String) -> Result<Vec<u8>, JsValue> {
+        let network =
+            FeatureCollection::from_iter(self.roads.iter().map(|r| r.to_gj(&self.mercator)));
+        let severances = FeatureCollection::from_iter(
+            self.roads
+                .iter()
+                .filter(|r| r.kind == RoadKind::Severance)
+                .map(|r| r.to_gj(&self.mercator)),
+        );
+        let crossings = crossings::crossing_audit(self);
+        let mut layers = vec![
+            ("network", &network),
+            ("severances", &severances),
+            ("crossings", &crossings),
+        ];
+        for (name, gj) in &self.generated_layers {
+            layers.push((*name, gj));
+        }
+
+        let mut out = Vec::new();
+        geopackage::write_geopackage(&layers, &timestamp, &mut out).map_err(err_to_js)?;
+        Ok(out)
+    }
+
+    /// Takes "csv" or "arrow", and returns the score of every desire line along severances as a
+    /// table, with the severance way, the desire line's endpoints in WGS84, and lengths in meters.
+    /// Desire lines are bounded and sampled like the last `makeHeatmap` call, if any. The model's
+    /// provenance is in a leading `#` comment for CSV, or the schema metadata for Arrow.
+    #[wasm_bindgen(js_name = heatmapTable)]
+    pub fn heatmap_table(&mut self, format: JsValue) -> Result<Vec<u8>, JsValue> {
+        let format: export::TableFormat = serde_wasm_bindgen::from_value(format)?;
+        let req = self.score_channel.last_request().cloned();
+        let rows = heatmap::table_rows(self, &req.unwrap_or_default());
+        let mut out = Vec::new();
+        export::write_heatmap_table(&rows, format, &self.provenance, &mut out)
+            .map_err(err_to_js)?;
+        Ok(out)
+    }
+
+    /// Returns a GeoJSON string with the `n` desire lines along severances with the most extra
+    /// walking distance, each followed by its route
+    #[wasm_bindgen(js_name = worstDetours)]
+    pub fn worst_detours(&mut self, n: usize) -> Result<String, JsValue> {
+        let gj = heatmap::worst_detours(self, n);
+        self.export(gj)
+    }
+
+    /// Takes an `ExposureRequest` and returns a GeoJSON string with every road used by routes
+    /// between desire lines, showing how much of that walking is alongside or across severances
+    #[wasm_bindgen(js_name = makeExposureHeatmap)]
+    pub fn make_exposure_heatmap(&mut self, input: JsValue) -> Result<String, JsValue> {
+        let req: heatmap::ExposureRequest = serde_wasm_bindgen::from_value(input)?;
+        let gj = heatmap::severance_exposure(self, req).map_err(err_to_js)?;
+        self.export(gj)
+    }
+
+    /// Takes `GridDesireLines` and returns a GeoJSON string scoring desire lines on a regular grid
+    /// crossing severances
+    #[wasm_bindgen(js_name = makeGridHeatmap)]
+    pub fn make_grid_heatmap(&mut self, input: JsValue) -> Result<String, JsValue> {
+        let req: heatmap::GridDesireLines = serde_wasm_bindgen::from_value(input)?;
+        let samples = heatmap::grid_across_severances(self, req).map_err(err_to_js)?;
+        self.export(samples)
+    }
+
+    /// Returns a GeoJSON string with desire lines where avoiding steps makes the route longer or
+    /// impossible
+    #[wasm_bindgen(js_name = makeStepFreeHeatmap)]
+    pub fn make_step_free_heatmap(&mut self) -> Result<String, JsValue> {
+        let gj = heatmap::step_free(self);
+        self.export(gj)
+    }
+
+    /// After something changes, recalculates the heatmap and returns only the scores that changed
+    /// since `makeHeatmap` or the last poll, as `{version, patches: {feature_id: properties}}`. A
+    /// null patch means the feature should be removed.
+    #[wasm_bindgen(js_name = pollScores)]
+    pub fn poll_scores(&mut self) -> Result<String, JsValue> {
+        let patch = scores::poll(self);
+        let out = serde_json::to_string(&patch).map_err(err_to_js)?;
+        Ok(out)
+    }
+
+    /// Compares walking along a footpath (by OSM way ID) with driving between its ends. Returns a
+    /// GeoJSON string with both routes and the ratio of driving to walking distance.
+    #[wasm_bindgen(js_name = shortcutScore)]
+    pub fn shortcut_score(&mut self, way_id: f64) -> Result<String, JsValue> {
+        let gj = shortcuts::shortcut_score(self, way_id as i64).map_err(err_to_js)?;
+        let out = serde_json::to_string(&gj).map_err(err_to_js)?;
+        Ok(out)
+    }
+
+    /// Returns a GeoJSON string with every road on a walking route relation, and a summary of each
+    /// route, including how many disconnected pieces it's in and how many severances it meets
+    #[wasm_bindgen(js_name = getWalkingRoutes)]
+    pub fn get_walking_routes(&self) -> Result<String, JsValue> {
+        self.export(trails::walking_routes_layer(self))
+    }
+
+    /// Audits a walking route relation (by OSM ID) for the severances it crosses or follows, and
+    /// scores desire lines across severances within `buffer_meters` of the route. Returns a
+    /// GeoJSON string.
+    #[wasm_bindgen(js_name = auditTrail)]
+    pub fn audit_trail(&mut self, relation_id: f64, buffer_meters: f64) -> Result<String, JsValue> {
+        let gj = trails::audit(self, relation_id as i64, buffer_meters).map_err(err_to_js)?;
+        self.export(gj)
+    }
+
+    /// Takes a `StaticMapRequest` and draws the network and the last heatmap as SVG text or PNG
+    /// bytes, for reports
+    #[wasm_bindgen(js_name = renderStaticMap)]
+    pub fn render_static_map(&self, input: JsValue) -> Result<Vec<u8>, JsValue> {
+        let req: static_map::StaticMapRequest = serde_wasm_bindgen::from_value(input)?;
+        static_map::render(self, &req, self.generated_layers.get("heatmap")).map_err(err_to_js)
+    }
+
+    /// Returns a GeoJSON string with only severances, each with the `gap_length` between crossings
+    /// and a few other properties, instead of every tag
+    #[wasm_bindgen(js_name = renderSeverances)]
+    pub fn render_severances(&self) -> Result<String, JsValue> {
+        let gj = crossings::severance_layer(self);
+        self.export(gj)
+    }
+
+    /// Returns a GeoJSON string with every walkable road and the `component` it belongs to,
+    /// numbered from the longest connected piece of the network, plus `components` statistics
+    #[wasm_bindgen()]
+    pub fn connectivity(&self) -> Result<String, JsValue> {
+        let gj = components::connectivity(self);
+        self.export(gj)
+    }
+
+    /// Returns a GeoJSON string with walkable dead-ends that almost reach another road, as lines
+    /// from the dead-end to the closest point on that road. `max_gap` defaults to 5 meters.
+    #[wasm_bindgen(js_name = findMissingLinks)]
+    pub fn find_missing_links(&self, max_gap: Option<f64>) -> Result<String, JsValue> {
+        let gj = stubs::find_missing_links(self, max_gap.unwrap_or(stubs::DEFAULT_MAX_GAP));
+        self.export(gj)
+    }
+
+    /// Returns a GeoJSON string with every intersection, its `degree`, the `road_kinds` connected,
+    /// and whether it's `on_severance` or has a `crossing`
+    #[wasm_bindgen(js_name = renderIntersections)]
+    pub fn render_intersections(&self) -> Result<String, JsValue> {
+        self.export(intersections::render_intersections(self))
+    }
+
+    /// Returns a GeoJSON string with pedestrian areas, parks, and water, each with its `kind`
+    #[wasm_bindgen(js_name = renderAreas)]
+    pub fn render_areas(&self) -> Result<String, JsValue> {
+        self.export(areas::render(self))
+    }
+
+    /// Returns a GeoJSON string with only crossings, each with its quality, skew, score, and
+    /// `gap_to_next_crossing`, instead of every tag
+    #[wasm_bindgen(js_name = renderCrossings)]
+    pub fn render_crossings(&self) -> Result<String, JsValue> {
+        let gj = crossings::crossing_layer(self);
+        self.export(gj)
+    }
+
+    /// Returns a GeoJSON string with severances split at every crossing. Each stretch has a
+    /// `gap_length` in meters, and the collection has the `max_gap`.
+    #[wasm_bindgen(js_name = crossingGaps)]
+    pub fn crossing_gaps(&self) -> Result<String, JsValue> {
+        let gj = crossings::crossing_gaps(self);
+        self.export(gj)
+    }
+
+    /// Returns a GeoJSON string with every crossing, its quality, how skewed it is from
+    /// perpendicular, and a combined score
+    #[wasm_bindgen(js_name = crossingAudit)]
+    pub fn crossing_audit(&self) -> Result<String, JsValue> {
+        let gj = crossings::crossing_audit(self);
+        self.export(gj)
+    }
+
+    /// Takes a `DelayBudgetRequest` and estimates person-minutes of delay per day at every crossing
+    /// used by heatmap desire lines. Returns a GeoJSON string.
+    #[wasm_bindgen(js_name = crossingDelayBudget)]
+    pub fn crossing_delay_budget(&mut self, input: JsValue) -> Result<String, JsValue> {
+        let req: crossings::DelayBudgetRequest = serde_wasm_bindgen::from_value(input)?;
+        let gj = crossings::delay_budget(self, req);
+        self.export(gj)
+    }
+
+    /// Returns a GeoJSON string with points where people can enter buildings, from mapped
+    /// entrances or sampled along the side facing a walkable road
+    #[wasm_bindgen(js_name = buildingAccessPoints)]
+    pub fn building_access_points(&self) -> Result<String, JsValue> {
+        let gj = buildings::access_points(self);
+        self.export(gj)
+    }
+
+    /// Takes an `IsochroneRequest` and returns a GeoJSON string of reachable roads
+    #[wasm_bindgen()]
+    pub fn isochrone(&mut self, input: JsValue) -> Result<String, JsValue> {
+        let req: isochrone::IsochroneRequest = serde_wasm_bindgen::from_value(input)?;
+        let gj = isochrone::calculate(self, req).map_err(err_to_js)?;
+
+        // Only the latest isochrone is exported
+        self.generated_layers.insert("isochrone", gj.clone());
+
+        self.export(gj)
+    }
+
+    /// Takes an `AccessGridRequest` and returns a GeoJSON string with one chunk of a grid covering
+    /// the boundary, scoring how much is walkable from each cell
+    #[wasm_bindgen(js_name = accessGrid)]
+    pub fn access_grid(&self, input: JsValue) -> Result<String, JsValue> {
+        let req: walkability::AccessGridRequest = serde_wasm_bindgen::from_value(input)?;
+        let gj = walkability::access_grid(self, req).map_err(err_to_js)?;
+        self.export(gj)
+    }
+
+    /// Takes a `SurfaceRequest` and returns an object with `values`, a `Float32Array` of the seconds
+    /// to reach each grid cell, and `georef` describing the grid
+    #[wasm_bindgen(js_name = travelTimeSurface)]
+    pub fn travel_time_surface(&self, input: JsValue) -> Result<JsValue, JsValue> {
+        let req: isochrone::SurfaceRequest = serde_wasm_bindgen::from_value(input)?;
+        let surface = isochrone::travel_time_surface(self, req).map_err(err_to_js)?;
+        let out = js_sys::Object::new();
+        js_sys::Reflect::set(
+            &out,
+            &"values".into(),
+            &js_sys::Float32Array::from(&surface.values[..]),
+        )?;
+        js_sys::Reflect::set(
+            &out,
+            &"georef".into(),
+            &serde_wasm_bindgen::to_value(&surface.georef)?,
+        )?;
+        Ok(out.into())
+    }
+
+    /// Takes an optional `HeatmapRequest` and a list of `{x1, y1, x2, y2}` lines in WGS84 for new
+    /// crossings straddling severances. Returns a GeoJSON string with every desire line's
+    /// `score_before`, `score_after`, and `improvement`.
+    #[wasm_bindgen(js_name = heatmapWithEdit)]
+    pub fn heatmap_with_edit(&mut self, input: JsValue, edits: JsValue) -> Result<String, JsValue> {
+        let req: Option<heatmap::HeatmapRequest> = serde_wasm_bindgen::from_value(input)?;
+        let edits: Vec<route::CrossingEdit> = serde_wasm_bindgen::from_value(edits)?;
+        let gj = heatmap::heatmap_with_edit(self, &req.unwrap_or_default(), &edits)
+            .map_err(err_to_js)?;
+        self.export(gj)
+    }
+
+    /// Takes an `IsochroneRequest` and a list of `{x1, y1, x2, y2}` lines in WGS84 for new crossings
+    /// straddling severances. Returns a GeoJSON string with the reachable area `before` and
+    /// `after` the crossings, and the `newly_reachable` area.
+    #[wasm_bindgen(js_name = isochroneWithEdit)]
+    pub fn isochrone_with_edit(&self, input: JsValue, edits: JsValue) -> Result<String, JsValue> {
+        let req: isochrone::IsochroneRequest = serde_wasm_bindgen::from_value(input)?;
+        let edits: Vec<route::CrossingEdit> = serde_wasm_bindgen::from_value(edits)?;
+        let gj = isochrone::calculate_with_edit(self, req, edits).map_err(err_to_js)?;
+        self.export(gj)
+    }
+
+    /// Takes a `RoutesFromRequest` and returns a GeoJSON string with the route length and detour
+    /// to every target
+    #[wasm_bindgen(js_name = routesFrom)]
+    pub fn routes_from(&self, input: JsValue) -> Result<String, JsValue> {
+        let req: route::RoutesFromRequest = serde_wasm_bindgen::from_value(input)?;
+        let gj = route::routes_from(self, req).map_err(err_to_js)?;
+        self.export(gj)
+    }
+
+    /// Takes a `MatrixRequest` and returns a JSON string with walking distances and times from
+    /// every origin to every destination, and the model's `provenance`
+    #[wasm_bindgen()]
+    pub fn matrix(&self, input: JsValue) -> Result<String, JsValue> {
+        let req: route::MatrixRequest = serde_wasm_bindgen::from_value(input)?;
+        let result = route::matrix(self, req).map_err(err_to_js)?;
+        self.export_json(&result)
+    }
+
+    /// Compare against another model imported from the same extract with different
+    /// classification options. Returns a GeoJSON string with every road whose kind differs,
+    /// with `kind1` and `kind2` properties.
+    #[wasm_bindgen(js_name = classificationDisagreements)]
+    pub fn classification_disagreements(&self, other: &MapModel) -> Result<String, JsValue> {
+        let gj = compare::classification_disagreements(self, other);
+        self.export(gj)
+    }
+
+    /// Translate between OSM way IDs, stable edge IDs, and internal road IDs in bulk. Takes a
+    /// `ResolveIdsRequest` and returns a JSON string, with the model's `provenance`.
+    #[wasm_bindgen(js_name = resolveIds)]
+    pub fn resolve_ids(&self, input: JsValue) -> Result<String, JsValue> {
+        let req: ids::ResolveIdsRequest = serde_wasm_bindgen::from_value(input)?;
+        self.export_json(&ids::resolve_ids(self, req))
+    }
+
+    /// Takes a list of times of day ("HH:MM") and scores the heatmap at each of them. Returns
+    /// a JSON string with the desire lines and delta-encoded scores per time.
+    #[wasm_bindgen(js_name = makeHeatmapOverTime)]
+    pub fn make_heatmap_over_time(&mut self, input: JsValue) -> Result<String, JsValue> {
+        let times: Vec<String> = serde_wasm_bindgen::from_value(input)?;
+        let result = heatmap::along_severances_over_time(self, times).map_err(err_to_js)?;
+        let out = serde_json::to_string(&result).map_err(err_to_js)?;
+        Ok(out)
+    }
+
+    /// Returns a JSON array of road IDs having the OSM tag `key`, with any value if `value` is
+    /// missing
+    #[wasm_bindgen(js_name = roadsWithTag)]
+    pub fn roads_with_tag(&self, key: String, value: Option<String>) -> Result<String, JsValue> {
+        let roads: Vec<usize> = self
+            .tag_index
+            .roads_with_tag(&key, value.as_deref())
+            .into_iter()
+            .map(|r| r.0)
+            .collect();
+        let out = serde_json::to_string(&roads).map_err(err_to_js)?;
         Ok(out)
     }
 
     /// Return a polygon covering the world, minus a hole for the boundary, in WGS84
     #[wasm_bindgen(js_name = getInvertedBoundary)]
     pub fn get_inverted_boundary(&self) -> Result<String, JsValue> {
+        let out = serde_json::to_string(&self.inverted_boundary()).map_err(err_to_js)?;
+        Ok(out)
+    }
+
+    /// Takes a `CostConfig` with multipliers per road kind and fixed penalties per crossing
+    /// quality. The router for these costs is prepared on the next route, unless these costs were
+    /// used before.
+    #[wasm_bindgen(js_name = setCostConfig)]
+    pub fn set_cost_config(&mut self, input: JsValue) -> Result<(), JsValue> {
+        self.cost_config = serde_wasm_bindgen::from_value(input)?;
+        self.tiles.remove_layer("heatmap");
+        Ok(())
+    }
+
+    /// Switches to one of the common profiles: "default", "wheelchair", or "night". The router
+    /// for each profile is only built the first time it's used.
+    #[wasm_bindgen(js_name = setProfile)]
+    pub fn set_profile(&mut self, name: String) -> Result<(), JsValue> {
+        let Some(config) = costs::CostConfig::profile(&name) else {
+            return Err(JsValue::from_str(&format!(
+                "Unknown profile {name}; try one of {:?}",
+                costs::PROFILES
+            )));
+        };
+        self.cost_config = config;
+        self.tiles.remove_layer("heatmap");
+        Ok(())
+    }
+
+    /// Takes the bytes of a gridded population dataset in WGS84, as an uncompressed GeoTIFF or an
+    /// ESRI ASCII grid. Isochrones with `cutoffs_minutes` then count the people living in each band.
+    #[wasm_bindgen(js_name = setPopulation)]
+    pub fn set_population(&mut self, input_bytes: &[u8]) -> Result<(), JsValue> {
+        self.population =
+            Some(population::Population::parse(self, input_bytes).map_err(err_to_js)?);
+        self.provenance.population = true;
+        self.tiles.remove_layer("neighbourhoods");
+        Ok(())
+    }
+
+    /// Dead-ends within this many meters of the boundary are treated as artifacts of clipping the
+    /// extract, and excluded from the heatmap and statistics
+    #[wasm_bindgen(js_name = setBoundaryBuffer)]
+    pub fn set_boundary_buffer(&mut self, meters: f64) {
+        self.boundary_buffer = meters;
+        boundary::mark_truncated_roads(self);
+        // Truncated roads are shown on the network and left out of the heatmap
+        self.tiles.remove_layer("network");
+        self.tiles.remove_layer("heatmap");
+    }
+
+    #[wasm_bindgen(js_name = getBounds)]
+    pub fn get_bounds(&self) -> Vec<f64> {
+        let b = &self.mercator.wgs84_bounds;
+        vec![b.min().x, b.min().y, b.max().x, b.max().y]
+    }
+
+    /// Returns a JSON string describing the input data and import options
+    #[wasm_bindgen(js_name = getProvenance)]
+    pub fn get_provenance(&self) -> Result<String, JsValue> {
+        let out = serde_json::to_string(&self.provenance).map_err(err_to_js)?;
+        Ok(out)
+    }
+
+    fn compare_route_between(&mut self, pt1: Coord, pt2: Coord) -> Result<String, JsValue> {
+        let (_, gj) = route::do_route(self, Line::new(pt1, pt2).into()).map_err(err_to_js)?;
+        let out = serde_json::to_string(&gj).map_err(err_to_js)?;
+        Ok(out)
+    }
+
+    /// Serializes analysis results, recording where they came from
+    fn export(&self, mut gj: FeatureCollection) -> Result<String, JsValue> {
+        gj.foreign_members
+            .get_or_insert_with(Default::default)
+            .insert(
+                "provenance".to_string(),
+                serde_json::to_value(&self.provenance).map_err(err_to_js)?,
+            );
+        let mut out = String::new();
+        export::write_geojson(&gj, &mut out).map_err(err_to_js)?;
+        Ok(out)
+    }
+
+    /// Like `export`, for JSON objects that aren't GeoJSON
+    fn export_json<T: Serialize>(&self, value: &T) -> Result<String, JsValue> {
+        let mut value = serde_json::to_value(value).map_err(err_to_js)?;
+        if let Some(object) = value.as_object_mut() {
+            object.insert(
+                "provenance".to_string(),
+                serde_json::to_value(&self.provenance).map_err(err_to_js)?,
+            );
+        }
+        serde_json::to_string(&value).map_err(err_to_js)
+    }
+
+    fn inverted_boundary(&self) -> Feature {
         let (boundary, _) = self.mercator.to_wgs84(&self.boundary_polygon).into_inner();
         let polygon = Polygon::new(
             LineString::from(vec![
@@ -162,52 +1055,169 @@ impl MapModel {
             ]),
             vec![boundary],
         );
-        let f = Feature::from(Geometry::from(&polygon));
-        let out = serde_json::to_string(&f).map_err(err_to_js)?;
-        Ok(out)
+        Feature::from(Geometry::from(&polygon))
     }
 
-    #[wasm_bindgen(js_name = getBounds)]
-    pub fn get_bounds(&self) -> Vec<f64> {
-        let b = &self.mercator.wgs84_bounds;
-        vec![b.min().x, b.min().y, b.max().x, b.max().y]
+    /// Rebuilds every index and forgets prepared walking routers, after roads or intersections
+    /// change
+    fn rebuild_indices(&mut self) {
+        let (closest_intersection, node_map, mut routers) = route::build_router(
+            &self.intersections,
+            &self.roads,
+            self.routers.skip_contraction_hierarchy(),
+        );
+        if let Some(driving) = self.routers.take_driving() {
+            routers.insert_driving(driving);
+        }
+        self.closest_intersection = closest_intersection;
+        self.node_map = node_map;
+        self.routers = routers;
+        self.closest_road = route::build_closest_road(&self.roads);
+        self.closest_severance = route::build_closest_severance(&self.roads);
+        self.road_index = route::build_road_index(&self.roads);
+        self.edge_index = adjacency::EdgeIndex::new(&self.roads);
+        self.tag_index = tag_index::TagIndex::new(&self.roads);
+        // Every layer depends on the roads
+        self.tiles.clear();
     }
 
+    /// The cheapest road from one intersection to another under the current costs
     fn find_edge(&self, i1: IntersectionID, i2: IntersectionID) -> &Road {
-        // TODO Store lookup table
-        for r in &self.intersections[i1.0].roads {
-            let road = &self.roads[r.0];
-            if road.src_i == i2 || road.dst_i == i2 {
-                return road;
-            }
+        match self
+            .edge_index
+            .cheapest(&self.roads, &self.cost_config, i1, i2)
+        {
+            Some(r) => &self.roads[r.0],
+            None => panic!("no road from {i1} to {i2} or vice versa"),
         }
-        panic!("no road from {i1} to {i2} or vice versa");
+    }
+
+    /// Roads with any part inside `bbox`, in Mercator
+    fn roads_within(&self, bbox: Rect) -> impl Iterator<Item = &Road> {
+        let envelope = AABB::from_corners(bbox.min().into(), bbox.max().into());
+        self.road_index
+            .locate_in_envelope_intersecting(&envelope)
+            .map(|obj| &self.roads[obj.data.0])
+            .filter(move |road| road.linestring.intersects(&bbox))
+    }
+
+    /// The closest road to `pt` matching `filter`, with the distance in meters
+    fn nearest_road<F: Fn(&Road) -> bool>(&self, pt: Coord, filter: F) -> Option<(&Road, f64)> {
+        self.road_index
+            .nearest_neighbor_iter_with_distance_2(&Point::from(pt))
+            .map(|(obj, dist_squared)| (&self.roads[obj.data.0], dist_squared.sqrt()))
+            .find(|(road, _)| filter(road))
+    }
+
+    /// Roads that `line` crosses or touches
+    fn roads_crossing<'a>(&'a self, line: &'a Line) -> impl Iterator<Item = &'a Road> {
+        self.road_index
+            .locate_in_envelope_intersecting(&line.envelope())
+            .filter(|obj| obj.geom().intersects(line))
+            .map(|obj| &self.roads[obj.data.0])
+    }
+
+    /// Every road touching an intersection, with the intersection at its other end
+    fn neighbors(&self, i: IntersectionID) -> impl Iterator<Item = (&Road, IntersectionID)> {
+        self.intersections[i.0].roads.iter().map(move |r| {
+            let road = &self.roads[r.0];
+            (road, road.other_side(i))
+        })
     }
 }
 
 impl Road {
+    /// The intersection at the opposite end from `i`
+    fn other_side(&self, i: IntersectionID) -> IntersectionID {
+        if self.src_i == i {
+            self.dst_i
+        } else {
+            self.src_i
+        }
+    }
+
+    /// Internal RoadIDs depend on the import options and the extent of the input. This identifies
+    /// the same edge across imports, as long as the OSM way and its split points are unchanged.
+    fn stable_id(&self) -> String {
+        format!("{}:{}:{}", self.way.0, self.node1.0, self.node2.0)
+    }
+
     fn to_gj(&self, mercator: &Mercator) -> Feature {
         let mut f = Feature::from(Geometry::from(&mercator.to_wgs84(&self.linestring)));
+        f.id = Some(geojson::feature::Id::Number(self.id.0.into()));
         f.set_property("id", self.id.0);
+        f.set_property("stable_id", self.stable_id());
         f.set_property("kind", format!("{:?}", self.kind));
+        f.set_property("shared_with_cyclists", self.shared_with_cyclists);
         f.set_property("way", self.way.to_string());
         f.set_property("node1", self.node1.to_string());
         f.set_property("node2", self.node2.to_string());
         for (k, v) in &self.tags.0 {
             f.set_property(k, v.to_string());
         }
+        // Overwrite the raw tag with the parsed value
+        f.set_property("layer", self.layer);
+        f.set_property("time_restricted", !self.access.is_empty());
+        f.set_property("aadt", self.aadt);
+        f.set_property("truncated", self.truncated);
+        f.set_property("gradient", self.gradient);
         f
     }
 }
 
 // Mercator worldspace internally, but not when it comes in from the app
 // TODO only use this on the boundary
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize)]
 pub struct CompareRouteRequest {
     x1: f64,
     y1: f64,
     x2: f64,
     y2: f64,
+    /// Time of day as "HH:MM". If specified, roads closed at this time are avoided.
+    #[serde(default)]
+    time: Option<String>,
+    /// Check if the route depends on a single edge that might be a mapping error
+    #[serde(default)]
+    check_robustness: bool,
+    /// Also find up to this many alternative routes
+    #[serde(default)]
+    alternatives: usize,
+    /// On one-way roads without sidewalks, assume people walk facing oncoming traffic, so they
+    /// can only walk against the flow
+    #[serde(default)]
+    face_traffic: bool,
+    /// Also find the best route crossing severances mid-block, with the length of each informal
+    /// crossing multiplied by this penalty
+    #[serde(default)]
+    jaywalk_penalty: Option<f64>,
+    /// Fail if either point is further than this many meters from the walking network, instead of
+    /// returning a route from somewhere unexpected
+    #[serde(default)]
+    max_snap_distance: Option<f64>,
+    /// Only used by `compareRoute`
+    #[serde(default)]
+    format: export::RouteFormat,
+}
+
+impl CompareRouteRequest {
+    /// Transforms a request from WGS84 into map coordinates
+    fn into_mercator(self, mercator: &Mercator) -> Self {
+        let pt1 = mercator.pt_to_mercator(Coord {
+            x: self.x1,
+            y: self.y1,
+        });
+        let pt2 = mercator.pt_to_mercator(Coord {
+            x: self.x2,
+            y: self.y2,
+        });
+        Self {
+            x1: pt1.x,
+            y1: pt1.y,
+            x2: pt2.x,
+            y2: pt2.y,
+            ..self
+        }
+    }
 }
 
 impl From<Line> for CompareRouteRequest {
@@ -217,10 +1227,28 @@ impl From<Line> for CompareRouteRequest {
             y1: line.start.y,
             x2: line.end.x,
             y2: line.end.y,
+            time: None,
+            check_robustness: false,
+            alternatives: 0,
+            face_traffic: false,
+            jaywalk_penalty: None,
+            max_snap_distance: None,
+            format: export::RouteFormat::GeoJson,
         }
     }
 }
 
+/// Builds a model from osm.pbf or osm.xml bytes and analyzes it in one call, for embedding a
+/// severance widget elsewhere. `options` is an `AnalyzeOptions`. Returns a JSON string with the
+/// `network`, `heatmap`, `boundary`, `bounds`, and summary `stats`.
+#[wasm_bindgen(js_name = analyzeArea)]
+pub fn analyze_area(input_bytes: &[u8], options: JsValue) -> Result<String, JsValue> {
+    let opts: analyze::AnalyzeOptions = serde_wasm_bindgen::from_value(options.clone())?;
+    let mut map = MapModel::new(input_bytes, options, None)?;
+    let out = serde_json::to_string(&analyze::bundle(&mut map, &opts.bounds)).map_err(err_to_js)?;
+    Ok(out)
+}
+
 fn err_to_js<E: std::fmt::Display>(err: E) -> JsValue {
     JsValue::from_str(&err.to_string())
 }
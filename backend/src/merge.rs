@@ -0,0 +1,181 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use anyhow::Result;
+use osm_reader::{Element, NodeID, OsmID, RelationID, WayID};
+
+use crate::progress::{self, Progress};
+
+/// Combines several osm.pbf or osm.xml inputs, like adjacent extracts, into one osm.xml file.
+/// Nodes, ways, and relations appearing in more than one input are only kept once, so features
+/// along the seams aren't duplicated. The bounds are the union of every input's bounds, if they
+/// all have them. Reading each input is reported to `progress`, before parsing the merged result.
+pub fn merge_osm_inputs(inputs: &[&[u8]], progress: &mut Progress) -> Result<Vec<u8>> {
+    let mut data = OsmData::default();
+    let mut bounds: Option<[f64; 4]> = None;
+    let mut missing_bounds = false;
+
+    for (idx, input) in inputs.iter().enumerate() {
+        progress(
+            &format!("merging input {} of {}", idx + 1, inputs.len()),
+            progress::MERGE
+                + (progress::PARSE_NODES - progress::MERGE) * idx as f64 / inputs.len() as f64,
+        );
+        let mut input_bounds = None;
+        osm_reader::parse(input, |elem| match elem {
+            Element::Bounds {
+                min_lon,
+                min_lat,
+                max_lon,
+                max_lat,
+            } => {
+                input_bounds = Some([min_lon, min_lat, max_lon, max_lat]);
+            }
+            Element::Node {
+                id, lon, lat, tags, ..
+            } => {
+                data.nodes
+                    .entry(id)
+                    .or_insert_with(|| (lon, lat, copy_tags(tags)));
+            }
+            Element::Way {
+                id, node_ids, tags, ..
+            } => {
+                data.ways
+                    .entry(id)
+                    .or_insert_with(|| (node_ids.to_vec(), copy_tags(tags)));
+            }
+            Element::Relation {
+                id, tags, members, ..
+            } => {
+                data.relations
+                    .entry(id)
+                    .or_insert_with(|| (members.to_vec(), copy_tags(tags)));
+            }
+            _ => {}
+        })?;
+
+        match (input_bounds, bounds) {
+            (Some(b), Some(acc)) => {
+                bounds = Some([
+                    acc[0].min(b[0]),
+                    acc[1].min(b[1]),
+                    acc[2].max(b[2]),
+                    acc[3].max(b[3]),
+                ]);
+            }
+            (Some(b), None) => {
+                bounds = Some(b);
+            }
+            (None, _) => {
+                missing_bounds = true;
+            }
+        }
+    }
+    info!(
+        "Merged {} inputs into {} nodes, {} ways, and {} relations",
+        inputs.len(),
+        data.nodes.len(),
+        data.ways.len(),
+        data.relations.len()
+    );
+
+    data.bounds = bounds.filter(|_| !missing_bounds);
+    Ok(data.to_xml())
+}
+
+/// Raw OSM elements, each with its tags
+#[derive(Default)]
+pub struct OsmData {
+    /// Longitude and latitude
+    pub nodes: BTreeMap<NodeID, (f64, f64, Vec<(String, String)>)>,
+    pub ways: BTreeMap<WayID, (Vec<NodeID>, Vec<(String, String)>)>,
+    pub relations: BTreeMap<RelationID, (Vec<(String, OsmID)>, Vec<(String, String)>)>,
+    /// `[min_lon, min_lat, max_lon, max_lat]`
+    pub bounds: Option<[f64; 4]>,
+}
+
+impl OsmData {
+    /// Writes osm.xml, which any importer can read
+    pub fn to_xml(&self) -> Vec<u8> {
+        // Writing to a String can't fail, so the results are ignored below
+        let mut out = String::new();
+        out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<osm version=\"0.6\">\n");
+        if let Some([min_lon, min_lat, max_lon, max_lat]) = self.bounds {
+            let _ = writeln!(
+                out,
+                "  <bounds minlon=\"{min_lon}\" minlat=\"{min_lat}\" maxlon=\"{max_lon}\" maxlat=\"{max_lat}\"/>"
+            );
+        }
+        for (id, (lon, lat, tags)) in &self.nodes {
+            let _ = write!(out, "  <node id=\"{}\" lon=\"{lon}\" lat=\"{lat}\"", id.0);
+            write_tags(&mut out, "node", tags, |_| {});
+        }
+        for (id, (node_ids, tags)) in &self.ways {
+            let _ = write!(out, "  <way id=\"{}\"", id.0);
+            write_tags(&mut out, "way", tags, |out| {
+                for node in node_ids {
+                    let _ = writeln!(out, "    <nd ref=\"{}\"/>", node.0);
+                }
+            });
+        }
+        for (id, (members, tags)) in &self.relations {
+            let _ = write!(out, "  <relation id=\"{}\"", id.0);
+            write_tags(&mut out, "relation", tags, |out| {
+                for (role, member) in members {
+                    let (kind, id) = match member {
+                        OsmID::Node(x) => ("node", x.0),
+                        OsmID::Way(x) => ("way", x.0),
+                        OsmID::Relation(x) => ("relation", x.0),
+                    };
+                    let _ = writeln!(
+                        out,
+                        "    <member type=\"{kind}\" ref=\"{id}\" role=\"{}\"/>",
+                        escape(role)
+                    );
+                }
+            });
+        }
+        out.push_str("</osm>\n");
+        out.into_bytes()
+    }
+}
+
+pub fn copy_tags<K: ToString, V: ToString>(
+    tags: impl IntoIterator<Item = (K, V)>,
+) -> Vec<(String, String)> {
+    tags.into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+/// Finishes the opening tag started by the caller, then writes any children and tags
+fn write_tags<F: Fn(&mut String)>(
+    out: &mut String,
+    element: &str,
+    tags: &[(String, String)],
+    children: F,
+) {
+    let mut inner = String::new();
+    children(&mut inner);
+    for (k, v) in tags {
+        let _ = writeln!(inner, "    <tag k=\"{}\" v=\"{}\"/>", escape(k), escape(v));
+    }
+    if inner.is_empty() {
+        out.push_str("/>\n");
+    } else {
+        out.push_str(">\n");
+        out.push_str(&inner);
+        let _ = writeln!(out, "  </{element}>");
+    }
+}
+
+pub fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+        .replace('\n', "&#10;")
+}
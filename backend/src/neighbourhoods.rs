@@ -0,0 +1,447 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use geo::line_intersection::{line_intersection, LineIntersection};
+use geo::{
+    Area, BoundingRect, Centroid, Contains, Coord, EuclideanDistance, EuclideanLength,
+    InteriorPoint, Line, LineString, MultiPolygon, Point, Polygon,
+};
+use geojson::{feature::Id, Feature, FeatureCollection, Geometry};
+use rstar::primitives::{GeomWithData, Rectangle};
+use rstar::{RTree, RTreeObject, AABB};
+use serde::Serialize;
+
+use crate::heatmap::{score_request, severance_desire_lines, DesireLineBounds};
+use crate::route::walking_cost;
+use crate::{MapModel, RoadID, RoadKind};
+
+/// Points closer than this many meters are merged when splitting lines where they cross
+const PRECISION: f64 = 0.001;
+/// Severances ending this many meters from another one, or from the boundary, are extended to
+/// meet it
+const SNAP_DISTANCE: f64 = 20.0;
+/// Ignore slivers smaller than this many square meters
+const MIN_AREA: f64 = 10_000.0;
+/// Also ignore places narrower than this many meters on average, like the space between two
+/// carriageways
+const MIN_WIDTH: f64 = 15.0;
+/// Points this many meters from a neighbourhood, like the ends of a crossing, still belong to it
+const SEARCH_DISTANCE: f64 = 60.0;
+
+/// The area inside the boundary, split into neighbourhoods: the places reachable from each other
+/// without crossing a severance.
+///
+/// The severances and boundary are split wherever they cross or overlap, and each neighbourhood is
+/// a face of the planar graph they form. Severances that stop just short of another are extended
+/// to meet it, and dead ends that don't enclose anything are ignored.
+pub struct Partition {
+    /// Indexed by neighbourhood
+    pub polygons: Vec<MultiPolygon>,
+    /// The bounding box of each neighbourhood
+    index: RTree<GeomWithData<Rectangle<[f64; 2]>, usize>>,
+}
+
+impl Partition {
+    pub fn new(map: &MapModel) -> Self {
+        let mut graph = PlanarGraph::new(&severance_lines(map));
+        graph.remove_dead_ends();
+
+        // Faces wound counterclockwise are bounded. The others go around the outside of a group of
+        // connected lines.
+        let mut faces = Vec::new();
+        let mut outlines = Vec::new();
+        for ring in graph.faces() {
+            let polygon = Polygon::new(ring, Vec::new());
+            if polygon.signed_area() > 0.0 {
+                faces.push(polygon);
+            } else {
+                outlines.push(polygon.into_inner().0);
+            }
+        }
+        // A group of severances not touching any others, like a ring road, leaves a hole in the
+        // smallest face around it
+        for outline in outlines {
+            let pt = Point::from(outline.0[0]);
+            if let Some(face) = faces
+                .iter_mut()
+                .filter(|face| face.contains(&pt))
+                .min_by(|a, b| a.unsigned_area().total_cmp(&b.unsigned_area()))
+            {
+                face.interiors_push(outline);
+            }
+        }
+
+        let polygons: Vec<MultiPolygon> = faces
+            .into_iter()
+            .filter(|face| {
+                let area = face.unsigned_area();
+                let perimeter = face.exterior().euclidean_length()
+                    + face
+                        .interiors()
+                        .iter()
+                        .map(|ring| ring.euclidean_length())
+                        .sum::<f64>();
+                // Faces outside the boundary are bounded by it and any severances leaving the map
+                area >= MIN_AREA
+                    && 2.0 * area / perimeter >= MIN_WIDTH
+                    && face
+                        .interior_point()
+                        .is_some_and(|pt| map.boundary_polygon.contains(&pt))
+            })
+            .map(|face| MultiPolygon::new(vec![face]))
+            .collect();
+        let index = RTree::bulk_load(
+            polygons
+                .iter()
+                .enumerate()
+                .filter_map(|(id, polygon)| {
+                    let bounds = polygon.bounding_rect()?;
+                    Some(GeomWithData::new(
+                        Rectangle::from_corners(bounds.min().into(), bounds.max().into()),
+                        id,
+                    ))
+                })
+                .collect(),
+        );
+
+        Self { polygons, index }
+    }
+
+    /// The neighbourhood containing `pt`, or the closest one a short distance away. Points on a
+    /// severance between two neighbourhoods go to the one with the lower ID.
+    pub fn neighbourhood_at(&self, pt: Coord) -> Option<usize> {
+        let envelope = AABB::from_corners(
+            [pt.x - SEARCH_DISTANCE, pt.y - SEARCH_DISTANCE],
+            [pt.x + SEARCH_DISTANCE, pt.y + SEARCH_DISTANCE],
+        );
+        let pt = Point::from(pt);
+        self.index
+            .locate_in_envelope_intersecting(&envelope)
+            .map(|obj| (pt.euclidean_distance(&self.polygons[obj.data]), obj.data))
+            .filter(|(dist, _)| *dist <= SEARCH_DISTANCE)
+            .min_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)))
+            .map(|(_, id)| id)
+    }
+}
+
+/// Every segment of the severances and the boundary, plus short links from the dead ends of
+/// severances to anything else nearby
+fn severance_lines(map: &MapModel) -> Vec<Line> {
+    let mut lines: Vec<GeomWithData<Line, Option<RoadID>>> = Vec::new();
+    for r in &map.roads {
+        if r.kind == RoadKind::Severance {
+            lines.extend(
+                r.linestring
+                    .lines()
+                    .map(|line| GeomWithData::new(line, Some(r.id))),
+            );
+        }
+    }
+    for ring in
+        std::iter::once(map.boundary_polygon.exterior()).chain(map.boundary_polygon.interiors())
+    {
+        lines.extend(ring.lines().map(|line| GeomWithData::new(line, None)));
+    }
+    let index = RTree::bulk_load(lines.clone());
+
+    // Severances are split wherever they meet another road, so an end used by only one is a dead
+    // end
+    let mut uses: HashMap<(i64, i64), usize> = HashMap::new();
+    for r in &map.roads {
+        if r.kind == RoadKind::Severance {
+            for pt in [r.linestring.0[0], *r.linestring.0.last().unwrap()] {
+                *uses.entry(key(pt)).or_default() += 1;
+            }
+        }
+    }
+    for r in &map.roads {
+        if r.kind != RoadKind::Severance {
+            continue;
+        }
+        for pt in [r.linestring.0[0], *r.linestring.0.last().unwrap()] {
+            if uses[&key(pt)] > 1 {
+                continue;
+            }
+            let Some((nearest, dist_squared)) = index
+                .nearest_neighbor_iter_with_distance_2(&[pt.x, pt.y])
+                .take_while(|(_, dist_squared)| *dist_squared <= SNAP_DISTANCE.powi(2))
+                .find(|(line, _)| line.data != Some(r.id))
+            else {
+                continue;
+            };
+            // Ends touching another line are already joined once lines are split
+            if dist_squared > 0.0 {
+                let link = Line::new(pt, closest_point(nearest.geom(), pt));
+                lines.push(GeomWithData::new(link, Some(r.id)));
+            }
+        }
+    }
+
+    lines.into_iter().map(|line| *line.geom()).collect()
+}
+
+fn closest_point(line: &Line, pt: Coord) -> Coord {
+    let length_squared = line.dx().powi(2) + line.dy().powi(2);
+    if length_squared == 0.0 {
+        return line.start;
+    }
+    let fraction =
+        ((pt.x - line.start.x) * line.dx() + (pt.y - line.start.y) * line.dy()) / length_squared;
+    line.start + line.delta() * fraction.clamp(0.0, 1.0)
+}
+
+/// Rounds a point to `PRECISION`, so the same point calculated twice is only used once
+fn key(pt: Coord) -> (i64, i64) {
+    (
+        (pt.x / PRECISION).round() as i64,
+        (pt.y / PRECISION).round() as i64,
+    )
+}
+
+/// Lines split wherever they cross, as an undirected graph
+struct PlanarGraph {
+    points: Vec<Coord>,
+    ids: HashMap<(i64, i64), usize>,
+    /// The neighbors of each point
+    adjacency: Vec<BTreeSet<usize>>,
+}
+
+impl PlanarGraph {
+    fn new(lines: &[Line]) -> Self {
+        let mut graph = Self {
+            points: Vec::new(),
+            ids: HashMap::new(),
+            adjacency: Vec::new(),
+        };
+        let index = RTree::bulk_load(
+            lines
+                .iter()
+                .enumerate()
+                .map(|(idx, line)| GeomWithData::new(*line, idx))
+                .collect(),
+        );
+
+        for (idx, line) in lines.iter().enumerate() {
+            let mut splits = vec![line.start, line.end];
+            for other in index.locate_in_envelope_intersecting(&line.envelope()) {
+                if other.data == idx {
+                    continue;
+                }
+                match line_intersection(*line, *other.geom()) {
+                    Some(LineIntersection::SinglePoint { intersection, .. }) => {
+                        splits.push(intersection);
+                    }
+                    Some(LineIntersection::Collinear { intersection }) => {
+                        splits.extend([intersection.start, intersection.end]);
+                    }
+                    None => {}
+                }
+            }
+            let along =
+                |pt: &Coord| (pt.x - line.start.x) * line.dx() + (pt.y - line.start.y) * line.dy();
+            splits.sort_by(|a, b| along(a).total_cmp(&along(b)));
+
+            for pair in splits.windows(2) {
+                let (a, b) = (graph.point(pair[0]), graph.point(pair[1]));
+                if a != b {
+                    graph.adjacency[a].insert(b);
+                    graph.adjacency[b].insert(a);
+                }
+            }
+        }
+        graph
+    }
+
+    fn point(&mut self, pt: Coord) -> usize {
+        *self.ids.entry(key(pt)).or_insert_with(|| {
+            self.points.push(pt);
+            self.adjacency.push(BTreeSet::new());
+            self.points.len() - 1
+        })
+    }
+
+    /// Repeatedly removes lines leading to a point with nothing else attached
+    fn remove_dead_ends(&mut self) {
+        let mut queue: Vec<usize> = (0..self.points.len())
+            .filter(|idx| self.adjacency[*idx].len() == 1)
+            .collect();
+        while let Some(idx) = queue.pop() {
+            if self.adjacency[idx].len() != 1 {
+                continue;
+            }
+            let next = self.adjacency[idx].pop_first().unwrap();
+            self.adjacency[next].remove(&idx);
+            if self.adjacency[next].len() == 1 {
+                queue.push(next);
+            }
+        }
+    }
+
+    /// Walks around every face, always turning as far right as possible. Each line is followed
+    /// once in each direction.
+    fn faces(&self) -> Vec<LineString> {
+        let angle = |from: usize, to: usize| {
+            let (a, b) = (self.points[from], self.points[to]);
+            (b.y - a.y).atan2(b.x - a.x)
+        };
+        // The neighbors of each point, counterclockwise
+        let sorted: Vec<Vec<usize>> = self
+            .adjacency
+            .iter()
+            .enumerate()
+            .map(|(idx, neighbors)| {
+                let mut neighbors: Vec<usize> = neighbors.iter().cloned().collect();
+                neighbors.sort_by(|a, b| angle(idx, *a).total_cmp(&angle(idx, *b)));
+                neighbors
+            })
+            .collect();
+
+        let mut visited: HashSet<(usize, usize)> = HashSet::new();
+        let mut faces = Vec::new();
+        for (start, neighbors) in sorted.iter().enumerate() {
+            for next in neighbors {
+                let (mut from, mut to) = (start, *next);
+                if !visited.insert((from, to)) {
+                    continue;
+                }
+                let mut pts = vec![self.points[from]];
+                loop {
+                    let around = &sorted[to];
+                    let back = around.iter().position(|idx| *idx == from).unwrap();
+                    (from, to) = (to, around[(back + around.len() - 1) % around.len()]);
+                    if !visited.insert((from, to)) {
+                        break;
+                    }
+                    pts.push(self.points[from]);
+                }
+                pts.push(pts[0]);
+                faces.push(LineString::new(pts));
+            }
+        }
+        faces
+    }
+}
+
+/// Each neighbourhood has an `id`, `area` in square meters, and `population` if population data
+/// is loaded
+pub fn neighbourhoods(map: &MapModel) -> FeatureCollection {
+    let partition = Partition::new(map);
+    let mut features = Vec::new();
+    for (id, polygon) in partition.polygons.iter().enumerate() {
+        let mut f = Feature::from(Geometry::from(&map.mercator.to_wgs84(polygon)));
+        f.id = Some(Id::Number(id.into()));
+        f.set_property("id", id);
+        f.set_property("area", polygon.unsigned_area());
+        if let Some(ref population) = map.population {
+            f.set_property("population", population.within(polygon));
+        }
+        features.push(f);
+    }
+
+    FeatureCollection {
+        features,
+        bbox: None,
+        foreign_members: None,
+    }
+}
+
+/// How neighbourhoods connect to each other, in a form for graph tools like Gephi
+#[derive(Serialize)]
+pub struct ConnectivityGraph {
+    pub nodes: Vec<GraphNode>,
+    pub edges: Vec<GraphEdge>,
+}
+
+#[derive(Serialize)]
+pub struct GraphNode {
+    pub id: usize,
+    /// The centroid in WGS84
+    pub x: f64,
+    pub y: f64,
+    /// In square meters
+    pub area: f64,
+    pub population: Option<f64>,
+}
+
+#[derive(Serialize)]
+pub struct GraphEdge {
+    pub from: usize,
+    pub to: usize,
+    /// How many roads lead directly from one neighbourhood to the other, like crossings, bridges,
+    /// and tunnels
+    pub crossings: usize,
+    /// The smallest ratio of route length to straight-line distance over the desire lines between
+    /// these neighbourhoods, or `None` if none could be scored
+    pub min_detour: Option<f64>,
+}
+
+/// The edge between two neighbourhoods, given in either order
+fn edge(edges: &mut BTreeMap<(usize, usize), GraphEdge>, a: usize, b: usize) -> &mut GraphEdge {
+    let (from, to) = (a.min(b), a.max(b));
+    edges.entry((from, to)).or_insert_with(|| GraphEdge {
+        from,
+        to,
+        crossings: 0,
+        min_detour: None,
+    })
+}
+
+/// Finds every walking connection between adjacent neighbourhoods. The graph is undirected, with
+/// `from` always less than `to`.
+pub fn connectivity_graph(map: &mut MapModel) -> ConnectivityGraph {
+    let partition = Partition::new(map);
+    let mut edges: BTreeMap<(usize, usize), GraphEdge> = BTreeMap::new();
+
+    for r in &map.roads {
+        // Only count links someone could walk along
+        if walking_cost(r).is_none() || r.kind == RoadKind::Severance {
+            continue;
+        }
+        let n1 = partition.neighbourhood_at(map.intersections[r.src_i.0].point.into());
+        let n2 = partition.neighbourhood_at(map.intersections[r.dst_i.0].point.into());
+        if let (Some(n1), Some(n2)) = (n1, n2) {
+            if n1 != n2 {
+                edge(&mut edges, n1, n2).crossings += 1;
+            }
+        }
+    }
+
+    for line in severance_desire_lines(map) {
+        let (Some(n1), Some(n2)) = (
+            partition.neighbourhood_at(line.line.start),
+            partition.neighbourhood_at(line.line.end),
+        ) else {
+            continue;
+        };
+        if n1 == n2 {
+            continue;
+        }
+        if let Some((_, score)) = score_request(map, line.line.into(), &DesireLineBounds::default())
+        {
+            let e = edge(&mut edges, n1, n2);
+            e.min_detour = Some(e.min_detour.map(|x| x.min(score)).unwrap_or(score));
+        }
+    }
+
+    let nodes = partition
+        .polygons
+        .iter()
+        .enumerate()
+        .map(|(id, polygon)| {
+            let centroid = polygon
+                .centroid()
+                .map(|pt| map.mercator.pt_to_wgs84(pt.into()))
+                .unwrap_or_default();
+            GraphNode {
+                id,
+                x: centroid.x,
+                y: centroid.y,
+                area: polygon.unsigned_area(),
+                population: map.population.as_ref().map(|p| p.within(polygon)),
+            }
+        })
+        .collect();
+    ConnectivityGraph {
+        nodes,
+        edges: edges.into_values().collect(),
+    }
+}
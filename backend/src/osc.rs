@@ -0,0 +1,543 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{bail, Result};
+use geo::{Coord, LineString};
+use osm_reader::{NodeID, WayID};
+use serde::{Deserialize, Serialize};
+use utils::Tags;
+
+use crate::access::ConditionalAccess;
+use crate::edits::IdMapping;
+use crate::{Intersection, IntersectionID, MapModel, Road, RoadID};
+
+/// The OSM nodes along every `highway` way, kept after import so osmChange files can be applied
+#[derive(Default, Serialize, Deserialize)]
+pub struct OsmStore {
+    pub way_nodes: HashMap<WayID, Vec<NodeID>>,
+    /// In WGS84
+    pub node_coords: HashMap<NodeID, Coord>,
+    /// Import options needed to classify changed ways the same way
+    pub import_streets_without_sidewalk_tagging: bool,
+    pub severance_aadt: f64,
+}
+
+/// What `apply_osc` changed
+#[derive(Serialize)]
+pub struct OscSummary {
+    nodes_changed: usize,
+    ways_changed: usize,
+    /// Ways rebuilt because they changed, or share a node with something that did
+    ways_rebuilt: usize,
+    roads_removed: usize,
+    roads_added: usize,
+    /// Problems with the new roads' geometry that were fixed
+    geometry: crate::validate::GeometryReport,
+    /// Elevation data isn't kept after import, so rebuilt roads only keep their gradient when
+    /// their geometry didn't change. This many roads lost it.
+    gradients_dropped: usize,
+    /// Optional import passes that aren't run again over rebuilt roads, like clipping to a custom
+    /// boundary, snapping dead-ends, or dropping small disconnected pieces. Rebuilt roads may need
+    /// checking for what these would've caught.
+    passes_skipped: Vec<&'static str>,
+    /// Edits are applied again afterwards. Some may not apply anymore.
+    edits_skipped: usize,
+    /// Where each road and intersection went. Only rebuilt roads get a new version.
+    ids: IdMapping,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Action {
+    Create,
+    Modify,
+    Delete,
+}
+
+struct ChangedNode {
+    id: NodeID,
+    action: Action,
+    pt: Option<Coord>,
+}
+
+struct ChangedWay {
+    id: WayID,
+    action: Action,
+    nodes: Vec<NodeID>,
+    tags: Vec<(String, String)>,
+}
+
+/// Applies an osmChange file to the network, rebuilding only the roads on changed ways and on
+/// ways sharing a node with anything changed. Edits are undone first, then applied again. Routers
+/// are rebuilt lazily the next time they're needed. Rebuilt ways keep their traffic counts. The
+/// optional import passes over the whole network, like pruning small components, aren't run again;
+/// the summary lists which ones were skipped. Relations, and changes to buildings, points of
+/// interest, and the driving network, are ignored.
+pub fn apply_osc(map: &mut MapModel, input: &[u8]) -> Result<OscSummary> {
+    let (nodes, ways) = parse_osc(std::str::from_utf8(input)?)?;
+    // Check everything before changing anything
+    if let Some(node) = nodes
+        .iter()
+        .find(|n| n.action != Action::Delete && n.pt.is_none())
+    {
+        bail!("Node {} has no location", node.id.0);
+    }
+    let store = &mut map.osm;
+
+    // Everything touching these nodes has to be rebuilt
+    let mut touched_nodes: HashSet<NodeID> = HashSet::new();
+    for node in &nodes {
+        touched_nodes.insert(node.id);
+        if node.action == Action::Delete {
+            store.node_coords.remove(&node.id);
+        } else if let Some(pt) = node.pt {
+            store.node_coords.insert(node.id, pt);
+        }
+    }
+    let mut changed_tags: HashMap<WayID, Option<Tags>> = HashMap::new();
+    for way in &ways {
+        if let Some(old) = store.way_nodes.remove(&way.id) {
+            touched_nodes.extend(old);
+        }
+        if way.action == Action::Delete {
+            changed_tags.insert(way.id, None);
+            continue;
+        }
+        let tags = Tags(way.tags.iter().cloned().collect());
+        if tags.has("highway") {
+            touched_nodes.extend(way.nodes.iter().cloned());
+            store.way_nodes.insert(way.id, way.nodes.clone());
+            changed_tags.insert(way.id, Some(tags));
+        } else {
+            changed_tags.insert(way.id, None);
+        }
+    }
+
+    let mut affected: HashSet<WayID> = changed_tags.keys().cloned().collect();
+    for (way, way_nodes) in &store.way_nodes {
+        if way_nodes.iter().any(|n| touched_nodes.contains(n)) {
+            affected.insert(*way);
+        }
+    }
+
+    // Edits are applied again at the end, and roads are matched up afterwards by stable ID
+    let (edits, undo_mapping) = crate::edits::take_all(map);
+    let old_roads: Vec<(String, IntersectionID, IntersectionID)> = map
+        .roads
+        .iter()
+        .map(|r| (r.stable_id(), r.src_i, r.dst_i))
+        .collect();
+    let old_nodes: Vec<NodeID> = map.intersections.iter().map(|i| i.node).collect();
+
+    // Remember what's needed to rebuild ways before removing their roads. Traffic counts are kept
+    // by way, even if the tags changed.
+    let mut old_tags: HashMap<WayID, Tags> = HashMap::new();
+    let mut old_aadt: HashMap<WayID, f64> = HashMap::new();
+    let mut old_gradients: HashMap<(WayID, NodeID, NodeID), (LineString, f64)> = HashMap::new();
+    for r in &map.roads {
+        if affected.contains(&r.way) && !changed_tags.contains_key(&r.way) {
+            old_tags.entry(r.way).or_insert_with(|| r.tags.clone());
+        }
+        if affected.contains(&r.way) {
+            if let Some(aadt) = r.aadt {
+                old_aadt.insert(r.way, aadt);
+            }
+            if let Some(gradient) = r.gradient {
+                old_gradients.insert((r.way, r.node1, r.node2), (r.linestring.clone(), gradient));
+            }
+        }
+    }
+    let roads_removed = crate::scrape::retain_roads(&mut map.intersections, &mut map.roads, |r| {
+        !affected.contains(&r.way)
+    });
+
+    // Work out what to build, then how many walkable ways use each node, to know where to split
+    let store = &map.osm;
+    let mut rebuild = Vec::new();
+    for way in &affected {
+        let tags = match changed_tags.get(way) {
+            Some(Some(tags)) => tags.clone(),
+            Some(None) => continue,
+            None => match old_tags.remove(way) {
+                Some(tags) => tags,
+                // Not walkable before, and its tags didn't change
+                None => continue,
+            },
+        };
+        let aadt = old_aadt.get(way).cloned();
+        let Some(kind) =
+            crate::scrape::classify(&tags, store.import_streets_without_sidewalk_tagging)
+        else {
+            continue;
+        };
+        let Some(way_nodes) = store.way_nodes.get(way) else {
+            continue;
+        };
+        rebuild.push((*way, way_nodes.clone(), tags, kind, aadt));
+    }
+    let mut walkable_ways: HashSet<WayID> = map.roads.iter().map(|r| r.way).collect();
+    walkable_ways.extend(rebuild.iter().map(|(way, ..)| *way));
+    let mut uses_per_node: HashMap<NodeID, usize> = HashMap::new();
+    for way in &walkable_ways {
+        for node in store.way_nodes.get(way).into_iter().flatten() {
+            *uses_per_node.entry(*node).or_insert(0) += 1;
+        }
+    }
+
+    let mut intersections_per_node: HashMap<NodeID, Vec<IntersectionID>> = HashMap::new();
+    for i in &map.intersections {
+        intersections_per_node.entry(i.node).or_default().push(i.id);
+    }
+    let version = map.render_version + 1;
+    let ways_rebuilt = rebuild.len();
+    let roads_before = map.roads.len();
+    let mut gradients_dropped = 0;
+    rebuild.sort_by_key(|(way, ..)| way.0);
+    for (way, way_nodes, tags, kind, aadt) in rebuild {
+        let Some(pts) = way_nodes
+            .iter()
+            .map(|n| map.osm.node_coords.get(n).cloned())
+            .collect::<Option<Vec<Coord>>>()
+        else {
+            warn!("Skipping way {} with missing nodes", way.0);
+            continue;
+        };
+        let layer = crate::scrape::get_layer(&tags);
+        let mut start = 0;
+        for idx in 1..way_nodes.len() {
+            let is_last = idx == way_nodes.len() - 1;
+            if !is_last && uses_per_node.get(&way_nodes[idx]).cloned().unwrap_or(0) < 2 {
+                continue;
+            }
+            let id = RoadID(map.roads.len());
+            let src_i = find_or_add_intersection(
+                map,
+                &mut intersections_per_node,
+                way_nodes[start],
+                pts[start],
+                layer,
+            );
+            let dst_i = find_or_add_intersection(
+                map,
+                &mut intersections_per_node,
+                way_nodes[idx],
+                pts[idx],
+                layer,
+            );
+            let linestring = LineString::new(
+                pts[start..=idx]
+                    .iter()
+                    .map(|pt| map.mercator.pt_to_mercator(*pt))
+                    .collect(),
+            );
+            // The elevation data is gone, so only keep the gradient of unchanged geometry
+            let gradient = old_gradients
+                .get(&(way, way_nodes[start], way_nodes[idx]))
+                .filter(|(old, _)| *old == linestring)
+                .map(|(_, gradient)| *gradient);
+            if map.provenance.elevation && gradient.is_none() {
+                gradients_dropped += 1;
+            }
+            map.intersections[src_i.0].roads.push(id);
+            if dst_i != src_i {
+                map.intersections[dst_i.0].roads.push(id);
+            }
+            map.roads.push(Road {
+                id,
+                src_i,
+                dst_i,
+                way,
+                node1: way_nodes[start],
+                node2: way_nodes[idx],
+                linestring,
+                kind: crate::scrape::apply_traffic_counts(
+                    kind.clone(),
+                    aadt,
+                    map.osm.severance_aadt,
+                ),
+                aadt,
+                shared_with_cyclists: crate::scrape::is_shared_cycleway(&tags),
+                layer,
+                access: ConditionalAccess::parse(&tags),
+                truncated: false,
+                gradient,
+                version,
+                tags: tags.clone(),
+            });
+            start = idx;
+        }
+    }
+    let geometry = crate::validate::validate(&mut map.intersections, &mut map.roads);
+    let roads_added = map.roads.len().saturating_sub(roads_before);
+
+    // Only the new roads have to be redrawn. IDs were renumbered, which callers follow with `ids`.
+    map.render_version = version;
+    let osc_mapping = match_ids(map, &old_roads, &old_nodes);
+    map.rebuild_indices();
+    let (edits_skipped, redo_mapping) = crate::edits::reapply(map, edits);
+
+    Ok(OscSummary {
+        nodes_changed: nodes.len(),
+        ways_changed: ways.len(),
+        ways_rebuilt,
+        roads_removed,
+        roads_added,
+        geometry,
+        gradients_dropped,
+        passes_skipped: map.provenance.optional_passes(),
+        edits_skipped,
+        ids: undo_mapping.then(&osc_mapping).then(&redo_mapping),
+    })
+}
+
+/// Matches roads from before a change by `Road::stable_id`, and intersections by the roads using
+/// them, or else by OSM node when only one intersection uses it before and after
+fn match_ids(
+    map: &MapModel,
+    old_roads: &[(String, IntersectionID, IntersectionID)],
+    old_nodes: &[NodeID],
+) -> IdMapping {
+    let new_roads: HashMap<String, &Road> =
+        map.roads.iter().map(|r| (r.stable_id(), r)).collect();
+    let mut intersections = vec![None; old_nodes.len()];
+    let roads = old_roads
+        .iter()
+        .map(|(stable_id, src_i, dst_i)| {
+            let r = new_roads.get(stable_id)?;
+            intersections[src_i.0] = Some(r.src_i);
+            intersections[dst_i.0] = Some(r.dst_i);
+            Some(r.id)
+        })
+        .collect();
+
+    let mut old_per_node: HashMap<NodeID, usize> = HashMap::new();
+    for node in old_nodes {
+        *old_per_node.entry(*node).or_insert(0) += 1;
+    }
+    let mut new_per_node: HashMap<NodeID, Vec<IntersectionID>> = HashMap::new();
+    for i in &map.intersections {
+        new_per_node.entry(i.node).or_default().push(i.id);
+    }
+    for (idx, node) in old_nodes.iter().enumerate() {
+        if intersections[idx].is_some() || old_per_node[node] != 1 {
+            continue;
+        }
+        if let Some([i]) = new_per_node.get(node).map(|list| list.as_slice()) {
+            intersections[idx] = Some(*i);
+        }
+    }
+    IdMapping {
+        roads,
+        intersections,
+    }
+}
+
+/// Where a node was split by layer, prefer the intersection already used by that layer
+fn find_or_add_intersection(
+    map: &mut MapModel,
+    intersections_per_node: &mut HashMap<NodeID, Vec<IntersectionID>>,
+    node: NodeID,
+    pt: Coord,
+    layer: i8,
+) -> IntersectionID {
+    let candidates = intersections_per_node.entry(node).or_default();
+    if let Some(i) = candidates
+        .iter()
+        .find(|i| {
+            map.intersections[i.0]
+                .roads
+                .iter()
+                .any(|r| map.roads[r.0].layer == layer)
+        })
+        .or(candidates.first())
+    {
+        return *i;
+    }
+    let id = IntersectionID(map.intersections.len());
+    map.intersections.push(Intersection {
+        id,
+        node,
+        point: map.mercator.pt_to_mercator(pt).into(),
+        roads: Vec::new(),
+    });
+    candidates.push(id);
+    id
+}
+
+/// Reads the nodes and ways from osmChange XML. Relations are skipped.
+fn parse_osc(input: &str) -> Result<(Vec<ChangedNode>, Vec<ChangedWay>)> {
+    let doc = roxmltree::Document::parse(input)?;
+    let root = doc.root_element();
+    if root.tag_name().name() != "osmChange" {
+        bail!("This isn't an osmChange file");
+    }
+
+    let mut nodes = Vec::new();
+    let mut ways = Vec::new();
+    for block in root.children().filter(|n| n.is_element()) {
+        let action = match block.tag_name().name() {
+            "create" => Action::Create,
+            "modify" => Action::Modify,
+            "delete" => Action::Delete,
+            _ => continue,
+        };
+        for obj in block.children().filter(|n| n.is_element()) {
+            match obj.tag_name().name() {
+                "node" => {
+                    let pt = match (obj.attribute("lon"), obj.attribute("lat")) {
+                        (Some(lon), Some(lat)) => Some(Coord {
+                            x: lon.parse()?,
+                            y: lat.parse()?,
+                        }),
+                        _ => None,
+                    };
+                    nodes.push(ChangedNode {
+                        id: NodeID(required(obj, "id")?.parse()?),
+                        action,
+                        pt,
+                    });
+                }
+                "way" => {
+                    let mut way = ChangedWay {
+                        id: WayID(required(obj, "id")?.parse()?),
+                        action,
+                        nodes: Vec::new(),
+                        tags: Vec::new(),
+                    };
+                    for child in obj.children().filter(|n| n.is_element()) {
+                        match child.tag_name().name() {
+                            "nd" => way.nodes.push(NodeID(required(child, "ref")?.parse()?)),
+                            "tag" => way.tags.push((
+                                required(child, "k")?.to_string(),
+                                required(child, "v")?.to_string(),
+                            )),
+                            _ => {}
+                        }
+                    }
+                    ways.push(way);
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok((nodes, ways))
+}
+
+fn required<'a>(element: roxmltree::Node<'a, '_>, key: &str) -> Result<&'a str> {
+    match element.attribute(key) {
+        Some(value) => Ok(value),
+        None => bail!("<{}> is missing {key}", element.tag_name().name()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scrape::{scrape_osm, ImportOptions};
+
+    /// Four corners of a block, joined by footways on three sides and a street on the fourth
+    const BLOCK: &str = r#"<osm version="0.6">
+        <bounds minlon="-0.101" minlat="51.499" maxlon="-0.097" maxlat="51.502"/>
+        <node id="1" lon="-0.100" lat="51.500"/>
+        <node id="2" lon="-0.099" lat="51.500"/>
+        <node id="3" lon="-0.098" lat="51.500"/>
+        <node id="4" lon="-0.100" lat="51.501"/>
+        <node id="5" lon="-0.098" lat="51.501"/>
+        <way id="10"><nd ref="1"/><nd ref="2"/><nd ref="3"/><tag k="highway" v="footway"/></way>
+        <way id="11"><nd ref="1"/><nd ref="4"/><tag k="highway" v="footway"/></way>
+        <way id="12"><nd ref="3"/><nd ref="5"/><tag k="highway" v="footway"/></way>
+        <way id="13"><nd ref="4"/><nd ref="5"/><tag k="highway" v="residential"/><tag k="sidewalk" v="both"/></way>
+    </osm>"#;
+
+    /// A new footway from the middle of the southern side, a retagged western side, and no more
+    /// eastern side
+    const CHANGE: &str = r#"<osmChange version="0.6">
+        <create>
+            <node id="6" lon="-0.099" lat="51.5015"/>
+            <way id="14"><nd ref="2"/><nd ref="6"/><tag k="highway" v="footway"/></way>
+        </create>
+        <modify>
+            <way id="11"><nd ref="1"/><nd ref="4"/><tag k="highway" v="path"/></way>
+        </modify>
+        <delete>
+            <way id="12"/>
+        </delete>
+    </osmChange>"#;
+
+    fn block() -> MapModel {
+        let opts = ImportOptions {
+            import_streets_without_sidewalk_tagging: false,
+            traffic_counts: [(WayID(11), 50.0)].into_iter().collect(),
+            severance_aadt: crate::traffic::DEFAULT_SEVERANCE_AADT,
+            skip_contraction_hierarchy: true,
+            boundary: None,
+            min_component_length: None,
+            snap_tolerance: None,
+        };
+        scrape_osm(BLOCK.as_bytes(), opts, &mut |_: &str, _: f64| {}).unwrap()
+    }
+
+    fn road<'a>(map: &'a MapModel, stable_id: &str) -> Option<&'a Road> {
+        map.roads.iter().find(|r| r.stable_id() == stable_id)
+    }
+
+    #[test]
+    fn test_create_modify_delete() {
+        let mut map = block();
+        let summary = apply_osc(&mut map, CHANGE.as_bytes()).unwrap();
+
+        assert_eq!(summary.nodes_changed, 1);
+        assert_eq!(summary.ways_changed, 3);
+        // Everything shares a node with something that changed
+        assert_eq!(summary.roads_removed, 4);
+        assert_eq!(summary.ways_rebuilt, 4);
+        assert_eq!(summary.roads_added, 5);
+        assert!(summary.passes_skipped.is_empty());
+
+        // The new footway splits the southern side where it starts
+        assert!(road(&map, "14:2:6").is_some());
+        assert!(road(&map, "10:1:3").is_none());
+        assert!(road(&map, "10:1:2").is_some());
+        assert!(road(&map, "10:2:3").is_some());
+        assert!(map.roads.iter().all(|r| r.way != WayID(12)));
+        let retagged = road(&map, "11:1:4").unwrap();
+        assert!(retagged.tags.is("highway", "path"));
+        assert_eq!(retagged.aadt, Some(50.0));
+        for (idx, r) in map.roads.iter().enumerate() {
+            assert_eq!(r.id, RoadID(idx));
+            for i in [r.src_i, r.dst_i] {
+                assert!(map.intersections[i.0].roads.contains(&r.id));
+            }
+        }
+    }
+
+    #[test]
+    fn test_match_ids() {
+        let mut map = block();
+        let old_roads: Vec<String> = map.roads.iter().map(|r| r.stable_id()).collect();
+        let old_nodes: Vec<NodeID> = map.intersections.iter().map(|i| i.node).collect();
+        let summary = apply_osc(&mut map, CHANGE.as_bytes()).unwrap();
+
+        for (old, new) in old_roads.iter().zip(&summary.ids.roads) {
+            match road(&map, old) {
+                Some(r) => assert_eq!(*new, Some(r.id)),
+                // Split or deleted
+                None => assert_eq!(*new, None),
+            }
+        }
+        assert_eq!(summary.ids.roads.iter().flatten().count(), 2);
+        // Every old intersection is still there, matched by its roads or else its node
+        for (node, new) in old_nodes.iter().zip(&summary.ids.intersections) {
+            assert_eq!(map.intersections[new.unwrap().0].node, *node);
+        }
+    }
+
+    #[test]
+    fn test_rejects_nodes_without_location() {
+        let mut map = block();
+        let before = map.roads.len();
+        let change = r#"<osmChange version="0.6">
+            <modify><node id="2"/></modify>
+        </osmChange>"#;
+        assert!(apply_osc(&mut map, change.as_bytes()).is_err());
+        assert_eq!(map.roads.len(), before);
+    }
+}
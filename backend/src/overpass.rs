@@ -0,0 +1,140 @@
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Result};
+use osm_reader::{NodeID, OsmID, RelationID, WayID};
+use serde::Deserialize;
+
+use crate::merge::OsmData;
+
+#[derive(Deserialize)]
+struct Response {
+    elements: Vec<Element>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Element {
+    Node {
+        id: i64,
+        // Missing for nodes output with only IDs or tags
+        lat: Option<f64>,
+        lon: Option<f64>,
+        #[serde(default)]
+        tags: BTreeMap<String, String>,
+    },
+    Way {
+        id: i64,
+        #[serde(default)]
+        nodes: Vec<i64>,
+        /// From `out geom`, matching `nodes`
+        #[serde(default)]
+        geometry: Vec<Option<LatLon>>,
+        #[serde(default)]
+        tags: BTreeMap<String, String>,
+    },
+    Relation {
+        id: i64,
+        #[serde(default)]
+        members: Vec<Member>,
+        #[serde(default)]
+        tags: BTreeMap<String, String>,
+    },
+    // Like "area" from area queries
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct LatLon {
+    lat: f64,
+    lon: f64,
+}
+
+#[derive(Deserialize)]
+struct Member {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(rename = "ref")]
+    id: i64,
+    #[serde(default)]
+    role: String,
+}
+
+/// Overpass can return JSON instead of XML, which starts with an object
+pub fn is_overpass_json(input_bytes: &[u8]) -> bool {
+    input_bytes
+        .iter()
+        .find(|b| !b.is_ascii_whitespace())
+        .map(|b| *b == b'{')
+        .unwrap_or(false)
+}
+
+/// Converts Overpass JSON (`[out:json]`) to osm.xml. Ways from `out geom` without separate node
+/// elements use the coordinates included with them.
+pub fn to_osm_xml(input_bytes: &[u8]) -> Result<Vec<u8>> {
+    let response: Response = serde_json::from_slice(input_bytes)?;
+    let mut data = OsmData::default();
+    for element in response.elements {
+        match element {
+            Element::Node {
+                id,
+                lat: Some(lat),
+                lon: Some(lon),
+                tags,
+            } => {
+                data.nodes
+                    .insert(NodeID(id), (lon, lat, tags.into_iter().collect()));
+            }
+            Element::Node { .. } | Element::Other => {}
+            Element::Way {
+                id,
+                nodes,
+                geometry,
+                tags,
+            } => {
+                if geometry.len() == nodes.len() {
+                    for (node, pt) in nodes.iter().zip(geometry) {
+                        if let Some(pt) = pt {
+                            data.nodes
+                                .entry(NodeID(*node))
+                                .or_insert_with(|| (pt.lon, pt.lat, Vec::new()));
+                        }
+                    }
+                }
+                data.ways.insert(
+                    WayID(id),
+                    (
+                        nodes.into_iter().map(NodeID).collect(),
+                        tags.into_iter().collect(),
+                    ),
+                );
+            }
+            Element::Relation { id, members, tags } => {
+                let members = members
+                    .into_iter()
+                    .filter_map(|m| {
+                        let member = match m.kind.as_str() {
+                            "node" => OsmID::Node(NodeID(m.id)),
+                            "way" => OsmID::Way(WayID(m.id)),
+                            "relation" => OsmID::Relation(RelationID(m.id)),
+                            _ => return None,
+                        };
+                        Some((m.role, member))
+                    })
+                    .collect();
+                data.relations
+                    .insert(RelationID(id), (members, tags.into_iter().collect()));
+            }
+        }
+    }
+    if data.ways.is_empty() {
+        bail!("The Overpass JSON has no ways; query with `out body` or `out geom` and `>;`");
+    }
+    info!(
+        "Converted Overpass JSON with {} nodes, {} ways, and {} relations",
+        data.nodes.len(),
+        data.ways.len(),
+        data.relations.len()
+    );
+    Ok(data.to_xml())
+}
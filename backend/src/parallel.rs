@@ -0,0 +1,17 @@
+//! Native builds can enable the `parallel` feature to spread import work across threads: splitting
+//! ways and projecting their points, turning the pieces into roads, projecting buildings and
+//! areas, and preparing the routers. Parsing stays serial. The wasm build stays single-threaded by
+//! default.
+
+/// Transforms every item, in parallel with the `parallel` feature
+pub fn map<T: Send, U: Send, F: Fn(T) -> U + Send + Sync>(items: Vec<T>, f: F) -> Vec<U> {
+    #[cfg(feature = "parallel")]
+    {
+        use rayon::prelude::*;
+        items.into_par_iter().map(f).collect()
+    }
+    #[cfg(not(feature = "parallel"))]
+    {
+        items.into_iter().map(f).collect()
+    }
+}
@@ -0,0 +1,35 @@
+use std::collections::BTreeMap;
+
+use geo::{Contains, Coord, MultiPolygon, Point};
+use serde::{Deserialize, Serialize};
+use utils::Tags;
+
+/// A point of interest, like a school or shop
+#[derive(Serialize, Deserialize)]
+pub struct Poi {
+    /// The `amenity` value, like "school", or "shop" for any kind of shop
+    pub category: String,
+    pub point: Coord,
+}
+
+/// Classifies OSM objects that people walk to, or returns `None`
+pub fn category(tags: &Tags) -> Option<String> {
+    if let Some(amenity) = tags.get("amenity") {
+        return Some(amenity.clone());
+    }
+    if tags.has("shop") {
+        return Some("shop".to_string());
+    }
+    None
+}
+
+/// Counts the POIs of each category inside `area`
+pub fn count_within(pois: &[Poi], area: &MultiPolygon) -> BTreeMap<String, usize> {
+    let mut counts = BTreeMap::new();
+    for poi in pois {
+        if area.contains(&Point::from(poi.point)) {
+            *counts.entry(poi.category.clone()).or_insert(0) += 1;
+        }
+    }
+    counts
+}
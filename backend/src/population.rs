@@ -0,0 +1,506 @@
+use anyhow::{bail, Context, Result};
+use geo::{Contains, Coord, MultiPolygon, Point, Rect};
+use geojson::{Feature, FeatureCollection, Geometry};
+use serde::{Deserialize, Serialize};
+
+use crate::MapModel;
+
+/// People living in each cell of a gridded population dataset, clipped to the map
+#[derive(Serialize, Deserialize)]
+pub struct Population {
+    /// Each cell in Mercator, with its population
+    cells: Vec<(Rect, f64)>,
+}
+
+impl Population {
+    /// Parses a single-band WGS84 raster, either an uncompressed GeoTIFF or an ESRI ASCII grid,
+    /// like those from GHSL or WorldPop. Only cells overlapping the map are kept.
+    pub fn parse(map: &MapModel, input_bytes: &[u8]) -> Result<Self> {
+        let raster = if input_bytes.starts_with(b"II") || input_bytes.starts_with(b"MM") {
+            Raster::from_geotiff(input_bytes)?
+        } else {
+            Raster::from_ascii_grid(std::str::from_utf8(input_bytes)?)?
+        };
+
+        let bounds = &map.mercator.wgs84_bounds;
+        let mut cells = Vec::new();
+        for row in 0..raster.height {
+            for col in 0..raster.width {
+                let count = raster.values[row * raster.width + col];
+                // No data and negative values are treated as empty
+                if count.is_nan() || count <= 0.0 {
+                    continue;
+                }
+                let min = Coord {
+                    x: raster.left + col as f64 * raster.cell_width,
+                    y: raster.top - (row + 1) as f64 * raster.cell_height,
+                };
+                let max = Coord {
+                    x: min.x + raster.cell_width,
+                    y: min.y + raster.cell_height,
+                };
+                if max.x < bounds.min().x
+                    || min.x > bounds.max().x
+                    || max.y < bounds.min().y
+                    || min.y > bounds.max().y
+                {
+                    continue;
+                }
+                let cell = Rect::new(
+                    map.mercator.pt_to_mercator(min),
+                    map.mercator.pt_to_mercator(max),
+                );
+                cells.push((cell, count));
+            }
+        }
+        info!("Kept {} populated cells overlapping the map", cells.len());
+        Ok(Self { cells })
+    }
+
+    /// The number of people living in cells with their center inside `area`
+    pub fn within(&self, area: &MultiPolygon) -> f64 {
+        self.cells
+            .iter()
+            .filter(|(cell, _)| area.contains(&Point::from(cell.center())))
+            .map(|(_, count)| *count)
+            .sum()
+    }
+
+    /// The center of every cell, with the number of people living there
+    pub fn cell_centers(&self) -> impl Iterator<Item = (Coord, f64)> + '_ {
+        self.cells.iter().map(|(cell, count)| (cell.center(), *count))
+    }
+
+    /// Every cell with its center inside `area`, with a `population` property
+    pub fn cells_within(&self, map: &MapModel, area: &MultiPolygon) -> FeatureCollection {
+        let mut features = Vec::new();
+        for (cell, count) in &self.cells {
+            if !area.contains(&Point::from(cell.center())) {
+                continue;
+            }
+            let mut f = Feature::from(Geometry::from(&map.mercator.to_wgs84(&cell.to_polygon())));
+            f.set_property("population", *count);
+            features.push(f);
+        }
+        FeatureCollection {
+            features,
+            bbox: None,
+            foreign_members: None,
+        }
+    }
+}
+
+/// A grid of values in WGS84, starting from the top-left and going row by row
+struct Raster {
+    left: f64,
+    top: f64,
+    cell_width: f64,
+    cell_height: f64,
+    width: usize,
+    height: usize,
+    values: Vec<f64>,
+}
+
+impl Raster {
+    fn from_ascii_grid(input: &str) -> Result<Self> {
+        let mut lines = input.lines();
+        let mut header = std::collections::HashMap::new();
+        let mut first_data_line = None;
+        for line in lines.by_ref() {
+            let mut parts = line.split_whitespace();
+            let Some(key) = parts.next() else {
+                continue;
+            };
+            if key.parse::<f64>().is_ok() {
+                first_data_line = Some(line);
+                break;
+            }
+            let value: f64 = parts
+                .next()
+                .with_context(|| format!("No value for {key}"))?
+                .parse()?;
+            header.insert(key.to_lowercase(), value);
+        }
+        let get = |key: &str| -> Result<f64> {
+            header
+                .get(key)
+                .cloned()
+                .with_context(|| format!("ASCII grid header is missing {key}"))
+        };
+
+        let width = get("ncols")? as usize;
+        let height = get("nrows")? as usize;
+        let cell_size = get("cellsize")?;
+        let left = match header.get("xllcorner") {
+            Some(x) => *x,
+            None => get("xllcenter")? - cell_size / 2.0,
+        };
+        let bottom = match header.get("yllcorner") {
+            Some(y) => *y,
+            None => get("yllcenter")? - cell_size / 2.0,
+        };
+        let no_data = header.get("nodata_value").cloned();
+
+        let mut values = Vec::with_capacity(width * height);
+        for line in first_data_line.into_iter().chain(lines) {
+            for value in line.split_whitespace() {
+                let value: f64 = value.parse()?;
+                values.push(if Some(value) == no_data { 0.0 } else { value });
+            }
+        }
+        if values.len() != width * height {
+            bail!(
+                "ASCII grid should have {} values, but has {}",
+                width * height,
+                values.len()
+            );
+        }
+        Ok(Self {
+            left,
+            top: bottom + height as f64 * cell_size,
+            cell_width: cell_size,
+            cell_height: cell_size,
+            width,
+            height,
+            values,
+        })
+    }
+
+    fn from_geotiff(bytes: &[u8]) -> Result<Self> {
+        let tiff = Tiff {
+            bytes,
+            little_endian: bytes.starts_with(b"II"),
+        };
+        if tiff.u16(2)? != 42 {
+            bail!("Not a classic TIFF; BigTIFF isn't supported");
+        }
+
+        // Just read the first image
+        let mut tags = std::collections::HashMap::new();
+        let ifd = tiff.u32(4)? as usize;
+        for idx in 0..tiff.u16(ifd)? as usize {
+            let entry = ifd + 2 + idx * 12;
+            tags.insert(tiff.u16(entry)?, tiff.tag_values(entry)?);
+        }
+        let get = |tag: u16, name: &str| -> Result<&Vec<f64>> {
+            tags.get(&tag)
+                .with_context(|| format!("GeoTIFF is missing {name}"))
+        };
+        let single = |tag: u16, name: &str| -> Result<f64> {
+            get(tag, name)?
+                .first()
+                .cloned()
+                .with_context(|| format!("GeoTIFF has no value for {name}"))
+        };
+        let optional = |tag: u16| tags.get(&tag).and_then(|x| x.first()).cloned();
+
+        let width = single(256, "ImageWidth")? as usize;
+        let height = single(257, "ImageLength")? as usize;
+        let bits = single(258, "BitsPerSample")? as usize;
+        if ![8, 16, 32, 64].contains(&bits) {
+            bail!("GeoTIFF has {bits} bits per sample; only 8, 16, 32, or 64 are supported");
+        }
+        if optional(259).unwrap_or(1.0) != 1.0 {
+            bail!("Compressed GeoTIFFs aren't supported; convert with gdal_translate -co COMPRESS=NONE or to an ASCII grid");
+        }
+        if optional(277).unwrap_or(1.0) != 1.0 {
+            bail!("GeoTIFF must have only one band");
+        }
+        let format = optional(339).unwrap_or(1.0) as u16;
+        check_crs(get(34735, "GeoKeyDirectory")?)?;
+        let scale = get(33550, "ModelPixelScale")?;
+        let tiepoint = get(33922, "ModelTiepoint")?;
+        if tiepoint.len() < 6 || scale.len() < 2 {
+            bail!("GeoTIFF georeferencing is malformed");
+        }
+        let (cell_width, cell_height) = (scale[0], scale[1]);
+        let left = tiepoint[3] - tiepoint[0] * cell_width;
+        let top = tiepoint[4] + tiepoint[1] * cell_height;
+        // GDAL_NODATA is stored as text
+        let no_data: Option<f64> = tags.get(&42113).and_then(|chars| {
+            let text: String = chars
+                .iter()
+                .map(|c| *c as u8 as char)
+                .take_while(|c| *c != '\0')
+                .collect();
+            text.trim().parse().ok()
+        });
+
+        // Pixel data is stored in strips spanning the full width, or in tiles
+        let (block_width, block_height, offsets, counts) = if tags.contains_key(&324) {
+            (
+                single(322, "TileWidth")? as usize,
+                single(323, "TileLength")? as usize,
+                get(324, "TileOffsets")?,
+                get(325, "TileByteCounts")?,
+            )
+        } else {
+            (
+                width,
+                optional(278).map(|x| x as usize).unwrap_or(height),
+                get(273, "StripOffsets")?,
+                get(279, "StripByteCounts")?,
+            )
+        };
+        if block_width == 0 || block_height == 0 {
+            bail!("GeoTIFF has empty tiles or strips");
+        }
+        let blocks_across = width.div_ceil(block_width);
+        let bytes_per_value = bits / 8;
+
+        let mut values = vec![0.0; width * height];
+        for (block, (offset, count)) in offsets.iter().zip(counts).enumerate() {
+            let (offset, count) = (*offset as usize, *count as usize);
+            let block_col = (block % blocks_across) * block_width;
+            let block_row = (block / blocks_across) * block_height;
+            for idx in 0..count / bytes_per_value {
+                let (row, col) = (block_row + idx / block_width, block_col + idx % block_width);
+                // Tiles on the edges are padded
+                if row >= height || col >= width {
+                    continue;
+                }
+                let value = tiff.sample(offset + idx * bytes_per_value, bits, format)?;
+                values[row * width + col] = if Some(value) == no_data { 0.0 } else { value };
+            }
+        }
+
+        Ok(Self {
+            left,
+            top,
+            cell_width,
+            cell_height,
+            width,
+            height,
+            values,
+        })
+    }
+}
+
+/// Fails unless the GeoKeyDirectory says the raster is in EPSG:4326. Each key is an ID, where the
+/// value is stored, a count, and the value itself, after a header with the number of keys.
+fn check_crs(directory: &[f64]) -> Result<()> {
+    let reproject = "reproject with gdalwarp -t_srs EPSG:4326";
+    let num_keys = directory.get(3).cloned().unwrap_or(0.0) as usize;
+    let mut keys = std::collections::HashMap::new();
+    for key in directory
+        .get(4..)
+        .unwrap_or_default()
+        .chunks_exact(4)
+        .take(num_keys)
+    {
+        // Only keys with short values stored inline are needed
+        if key[1] == 0.0 {
+            keys.insert(key[0] as u16, key[3] as u16);
+        }
+    }
+
+    // GTModelTypeGeoKey, then ProjectedCSTypeGeoKey or GeographicTypeGeoKey
+    match keys.get(&1024) {
+        Some(2) => {}
+        Some(1) => match keys.get(&3072) {
+            Some(code) if *code != 32767 => {
+                bail!("GeoTIFF is in the projected CRS EPSG:{code}, not WGS84; {reproject}")
+            }
+            _ => bail!("GeoTIFF is in a custom projected CRS, not WGS84; {reproject}"),
+        },
+        _ => bail!("GeoTIFF doesn't say what CRS it's in; {reproject}"),
+    }
+    match keys.get(&2048) {
+        Some(4326) => Ok(()),
+        Some(32767) | None => {
+            bail!("GeoTIFF is in a custom geographic CRS, not WGS84; {reproject}")
+        }
+        Some(code) => bail!("GeoTIFF is in EPSG:{code}, not WGS84; {reproject}"),
+    }
+}
+
+/// Reads the pieces of a TIFF file
+struct Tiff<'a> {
+    bytes: &'a [u8],
+    little_endian: bool,
+}
+
+impl Tiff<'_> {
+    fn slice<const N: usize>(&self, offset: usize) -> Result<[u8; N]> {
+        let Some(slice) = self.bytes.get(offset..offset + N) else {
+            bail!("TIFF is truncated");
+        };
+        let mut array: [u8; N] = slice.try_into()?;
+        if !self.little_endian {
+            array.reverse();
+        }
+        Ok(array)
+    }
+
+    fn u16(&self, offset: usize) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.slice(offset)?))
+    }
+
+    fn u32(&self, offset: usize) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.slice(offset)?))
+    }
+
+    /// Reads a numeric value of the given bit depth and TIFF sample format
+    fn sample(&self, offset: usize, bits: usize, format: u16) -> Result<f64> {
+        Ok(match (format, bits) {
+            (3, 32) => f32::from_le_bytes(self.slice(offset)?) as f64,
+            (3, 64) => f64::from_le_bytes(self.slice(offset)?),
+            (2, 8) => i8::from_le_bytes(self.slice(offset)?) as f64,
+            (2, 16) => i16::from_le_bytes(self.slice(offset)?) as f64,
+            (2, 32) => i32::from_le_bytes(self.slice(offset)?) as f64,
+            (_, 8) => self.slice::<1>(offset)?[0] as f64,
+            (_, 16) => self.u16(offset)? as f64,
+            (_, 32) => self.u32(offset)? as f64,
+            _ => bail!("Unsupported GeoTIFF sample format {format} with {bits} bits"),
+        })
+    }
+
+    /// All numeric values of the IFD entry at `entry`
+    fn tag_values(&self, entry: usize) -> Result<Vec<f64>> {
+        let field_type = self.u16(entry + 2)?;
+        let count = self.u32(entry + 4)? as usize;
+        let size = match field_type {
+            1 | 2 | 6 | 7 => 1,
+            3 | 8 => 2,
+            4 | 9 | 11 => 4,
+            12 => 8,
+            // Rationals and anything else aren't needed
+            _ => return Ok(Vec::new()),
+        };
+        // Values fitting in 4 bytes are stored inline
+        let start = if size * count <= 4 {
+            entry + 8
+        } else {
+            self.u32(entry + 8)? as usize
+        };
+        let mut values = Vec::with_capacity(count);
+        for idx in 0..count {
+            let offset = start + idx * size;
+            let format = match field_type {
+                6 | 8 | 9 => 2,
+                11 | 12 => 3,
+                _ => 1,
+            };
+            values.push(self.sample(offset, size * 8, format)?);
+        }
+        Ok(values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 2x2 single-band 8-bit GeoTIFF in WGS84, with 255 as no data. Cells are 0.01 degrees, and
+    /// the top-left corner is at (-0.1, 51.51).
+    fn geotiff(pixels: [u8; 4]) -> Vec<u8> {
+        let shorts = |xs: &[u16]| xs.iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<_>>();
+        let doubles = |xs: &[f64]| xs.iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<_>>();
+        // Tag, field type, count, and values
+        let entries: Vec<(u16, u16, u32, Vec<u8>)> = vec![
+            (256, 3, 1, shorts(&[2])),
+            (257, 3, 1, shorts(&[2])),
+            (258, 3, 1, shorts(&[8])),
+            (259, 3, 1, shorts(&[1])),
+            // The pixels come right after the header
+            (273, 4, 1, 8u32.to_le_bytes().to_vec()),
+            (277, 3, 1, shorts(&[1])),
+            (278, 3, 1, shorts(&[2])),
+            (279, 4, 1, 4u32.to_le_bytes().to_vec()),
+            (33550, 12, 3, doubles(&[0.01, 0.01, 0.0])),
+            (33922, 12, 6, doubles(&[0.0, 0.0, 0.0, -0.1, 51.51, 0.0])),
+            // A geographic CRS, EPSG:4326
+            (
+                34735,
+                3,
+                12,
+                shorts(&[1, 1, 0, 2, 1024, 0, 1, 2, 2048, 0, 1, 4326]),
+            ),
+            (42113, 2, 4, b"255\0".to_vec()),
+        ];
+
+        let ifd = 12;
+        let ifd_len = 2 + entries.len() * 12 + 4;
+        let mut out = b"II".to_vec();
+        out.extend(42u16.to_le_bytes());
+        out.extend((ifd as u32).to_le_bytes());
+        out.extend(pixels);
+        out.extend((entries.len() as u16).to_le_bytes());
+        // Values that don't fit inline go after the IFD
+        let mut extra = Vec::new();
+        for (tag, field_type, count, mut values) in entries {
+            out.extend(tag.to_le_bytes());
+            out.extend(field_type.to_le_bytes());
+            out.extend(count.to_le_bytes());
+            if values.len() <= 4 {
+                values.resize(4, 0);
+                out.extend(values);
+            } else {
+                out.extend(((ifd + ifd_len + extra.len()) as u32).to_le_bytes());
+                extra.extend(values);
+            }
+        }
+        out.extend(0u32.to_le_bytes());
+        out.extend(extra);
+        out
+    }
+
+    #[test]
+    fn test_geotiff() {
+        let raster = Raster::from_geotiff(&geotiff([1, 2, 3, 255])).unwrap();
+        assert_eq!((raster.width, raster.height), (2, 2));
+        assert_eq!((raster.left, raster.top), (-0.1, 51.51));
+        assert_eq!((raster.cell_width, raster.cell_height), (0.01, 0.01));
+        // No data counts as empty
+        assert_eq!(raster.values, vec![1.0, 2.0, 3.0, 0.0]);
+    }
+
+    #[test]
+    fn test_geotiff_projected() {
+        let mut bytes = geotiff([0; 4]);
+        // Change GTModelTypeGeoKey to projected and GeographicTypeGeoKey to ProjectedCSTypeGeoKey
+        // in EPSG:3857
+        let directory: Vec<u8> = [1u16, 1, 0, 2, 1024, 0, 1, 2, 2048, 0, 1, 4326]
+            .iter()
+            .flat_map(|x| x.to_le_bytes())
+            .collect();
+        let start = bytes
+            .windows(directory.len())
+            .position(|w| w == directory)
+            .unwrap();
+        for (idx, value) in [(7, 1u16), (8, 3072), (11, 3857)] {
+            bytes[start + idx * 2..start + idx * 2 + 2].copy_from_slice(&value.to_le_bytes());
+        }
+        let err = Raster::from_geotiff(&bytes).err().unwrap();
+        assert!(err.to_string().contains("EPSG:3857"));
+    }
+
+    #[test]
+    fn test_ascii_grid() {
+        let input = "ncols 3
+nrows 2
+xllcorner -0.1
+yllcorner 51.49
+cellsize 0.01
+NODATA_value -9999
+1 2 3
+4 -9999 6
+";
+        let raster = Raster::from_ascii_grid(input).unwrap();
+        assert_eq!((raster.width, raster.height), (3, 2));
+        assert_eq!(raster.left, -0.1);
+        assert!((raster.top - 51.51).abs() < 1e-9);
+        assert_eq!((raster.cell_width, raster.cell_height), (0.01, 0.01));
+        assert_eq!(raster.values, vec![1.0, 2.0, 3.0, 4.0, 0.0, 6.0]);
+
+        // Corners can also be given by the center of the corner cell
+        let centered = input
+            .replace("xllcorner -0.1", "xllcenter -0.095")
+            .replace("yllcorner 51.49", "yllcenter 51.495");
+        let raster = Raster::from_ascii_grid(&centered).unwrap();
+        assert!((raster.left + 0.1).abs() < 1e-9);
+        assert!((raster.top - 51.51).abs() < 1e-9);
+
+        assert!(Raster::from_ascii_grid(&input.replace("4 -9999 6", "4 5")).is_err());
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+
+use geo::Coord;
+use osm_reader::{NodeID, OsmID, RelationID, WayID};
+use utils::osm2graph::OsmReader;
+use utils::Tags;
+
+/// Called with the name of the current import phase and the overall percent complete
+pub type Progress<'a> = dyn FnMut(&str, f64) + 'a;
+
+// Where each phase starts, as an overall percent. Parsing dominates for big inputs. Merging
+// only happens with several inputs.
+pub const MERGE: f64 = 0.0;
+pub const PARSE_NODES: f64 = 10.0;
+pub const PARSE_WAYS: f64 = 20.0;
+pub const PARSE_RELATIONS: f64 = 35.0;
+pub const SPLIT: f64 = 40.0;
+pub const DRIVING: f64 = 45.0;
+pub const CONTRACTION_HIERARCHY: f64 = 55.0;
+pub const INDEX: f64 = 95.0;
+pub const DONE: f64 = 100.0;
+
+/// How often to report while reading one kind of OSM element
+const REPORT_EVERY: usize = 100_000;
+
+/// Wraps another reader, reporting progress as the parser moves from nodes to ways to relations.
+/// Within each kind, the percent can't advance without knowing how many elements there are, but
+/// the callback is still called regularly with the count so far.
+pub struct ReportProgress<'a, 'b, R> {
+    pub inner: &'a mut R,
+    pub progress: &'a mut Progress<'b>,
+    phase: &'static str,
+    count: usize,
+}
+
+impl<'a, 'b, R> ReportProgress<'a, 'b, R> {
+    pub fn new(inner: &'a mut R, progress: &'a mut Progress<'b>) -> Self {
+        Self {
+            inner,
+            progress,
+            phase: "",
+            count: 0,
+        }
+    }
+
+    fn tick(&mut self, phase: &'static str, percent: f64) {
+        if self.phase != phase {
+            self.phase = phase;
+            self.count = 0;
+            (self.progress)(phase, percent);
+        }
+        self.count += 1;
+        if self.count % REPORT_EVERY == 0 {
+            (self.progress)(&format!("{phase} ({} so far)", self.count), percent);
+        }
+    }
+}
+
+impl<R: OsmReader> OsmReader for ReportProgress<'_, '_, R> {
+    fn node(&mut self, id: NodeID, pt: Coord, tags: Tags) {
+        self.tick("parsing nodes", PARSE_NODES);
+        self.inner.node(id, pt, tags);
+    }
+
+    fn way(
+        &mut self,
+        id: WayID,
+        node_ids: &Vec<NodeID>,
+        node_mapping: &HashMap<NodeID, Coord>,
+        tags: &Tags,
+    ) {
+        self.tick("parsing ways", PARSE_WAYS);
+        self.inner.way(id, node_ids, node_mapping, tags);
+    }
+
+    fn relation(&mut self, id: RelationID, members: &Vec<(String, OsmID)>, tags: &Tags) {
+        self.tick("parsing relations", PARSE_RELATIONS);
+        self.inner.relation(id, members, tags);
+    }
+}
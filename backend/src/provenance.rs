@@ -0,0 +1,106 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::scrape::ImportOptions;
+use crate::validate::GeometryReport;
+use crate::{Intersection, Road};
+
+/// What a model was built from, so published results can state exactly which data and options
+/// produced them
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Provenance {
+    crate_version: String,
+    /// "xml" or "pbf"
+    input_format: String,
+    input_bytes: usize,
+    /// The tool that produced the OSM extract, if recorded in an XML header
+    generator: Option<String>,
+    /// When the OSM data was current, if recorded in an XML header (like Overpass's `osm_base`)
+    osm_timestamp: Option<String>,
+    import_streets_without_sidewalk_tagging: bool,
+    severance_aadt: f64,
+    ways_with_traffic_counts: usize,
+    /// Whether a boundary was provided, instead of using a concave hull around the roads
+    custom_boundary: bool,
+    /// Disconnected pieces of the walking network shorter than this were dropped, in meters
+    min_component_length: Option<f64>,
+    /// Dead-ends within this many meters of another intersection were merged into it
+    snap_tolerance: Option<f64>,
+    /// Duplicate points, zero-length roads, and loops fixed while importing
+    pub geometry: GeometryReport,
+    /// Whether elevation data was provided
+    pub elevation: bool,
+    /// Whether a population dataset was provided
+    pub population: bool,
+    roads: usize,
+    intersections: usize,
+    road_kinds: BTreeMap<String, usize>,
+}
+
+impl Provenance {
+    pub fn new(
+        input_bytes: &[u8],
+        opts: &ImportOptions,
+        roads: &Vec<Road>,
+        intersections: &Vec<Intersection>,
+    ) -> Self {
+        // Only look at the start of the file for header metadata
+        let header = String::from_utf8_lossy(&input_bytes[..input_bytes.len().min(4096)]);
+        let is_xml = header.trim_start().starts_with('<');
+
+        let mut road_kinds = BTreeMap::new();
+        for r in roads {
+            *road_kinds.entry(format!("{:?}", r.kind)).or_insert(0) += 1;
+        }
+
+        Self {
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            input_format: if is_xml { "xml" } else { "pbf" }.to_string(),
+            input_bytes: input_bytes.len(),
+            generator: is_xml
+                .then(|| find_attribute(&header, "generator"))
+                .flatten(),
+            osm_timestamp: is_xml
+                .then(|| {
+                    find_attribute(&header, "osm_base")
+                        .or_else(|| find_attribute(&header, "timestamp"))
+                })
+                .flatten(),
+            import_streets_without_sidewalk_tagging: opts.import_streets_without_sidewalk_tagging,
+            severance_aadt: opts.severance_aadt,
+            ways_with_traffic_counts: opts.traffic_counts.len(),
+            custom_boundary: opts.boundary.is_some(),
+            min_component_length: opts.min_component_length,
+            snap_tolerance: opts.snap_tolerance,
+            geometry: GeometryReport::default(),
+            elevation: false,
+            population: false,
+            roads: roads.len(),
+            intersections: intersections.len(),
+            road_kinds,
+        }
+    }
+
+    /// The optional import passes that were used, which only run over the whole network
+    pub fn optional_passes(&self) -> Vec<&'static str> {
+        let mut passes = Vec::new();
+        if self.custom_boundary {
+            passes.push("clip_to_boundary");
+        }
+        if self.snap_tolerance.is_some() {
+            passes.push("snap_intersections");
+        }
+        if self.min_component_length.is_some() {
+            passes.push("prune_components");
+        }
+        passes
+    }
+}
+
+/// Finds the first `key="value"` in some XML
+fn find_attribute(xml: &str, key: &str) -> Option<String> {
+    let start = xml.find(&format!("{key}=\""))? + key.len() + 2;
+    let len = xml[start..].find('"')?;
+    Some(xml[start..start + len].to_string())
+}
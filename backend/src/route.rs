@@ -1,45 +1,69 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
 use anyhow::{bail, Result};
-use fast_paths::{FastGraph, InputGraph};
-use geo::{EuclideanLength, LineString};
+use fast_paths::{FastGraph, PathCalculator};
+use geo::{
+    Coord, Densify, EuclideanDistance, EuclideanLength, Intersects, Line, LineInterpolatePoint,
+    LineLocatePoint, LineString, MultiLineString, Point,
+};
 use geojson::{Feature, FeatureCollection};
-use rstar::RTree;
-use utils::NodeMap;
+use osm_reader::{NodeID, OsmID, WayID};
+use rstar::{primitives::GeomWithData, RTree, RTreeObject};
+use serde::{Deserialize, Serialize};
+use utils::{Mercator, NodeMap, Tags};
 
+use crate::access::Time;
+use crate::cost_graph::{self, CostFunction, Turn};
+use crate::costs::CostConfig;
+use crate::crossings::{crossing_delay_range, crossing_quality, crossing_score, DelayRange};
+use crate::isochrone::WALKING_SPEED;
+use crate::router::{RouterGraph, Routers};
 use crate::{
     CompareRouteRequest, Intersection, IntersectionID, IntersectionLocation, MapModel, Road,
-    RoadKind,
+    RoadID, RoadKind, RoadLocation,
 };
 
+/// Prepares routing over the walking network. Routers for each set of costs are built lazily. The
+/// contraction hierarchy is slow to build for huge areas, so callers only needing a few routes can
+/// skip it.
 pub fn build_router(
     intersections: &Vec<Intersection>,
     roads: &Vec<Road>,
+    skip_contraction_hierarchy: bool,
 ) -> (
     RTree<IntersectionLocation>,
     NodeMap<IntersectionID>,
-    FastGraph,
+    Routers,
 ) {
-    let mut input_graph = InputGraph::new();
+    // Every `CostConfig` starts from `walking_cost`, so these roads are the union of those any
+    // costs allow, and routers for all of them can share one node map
     let mut node_map = NodeMap::new();
-
     for r in roads {
-        if r.kind == RoadKind::Severance {
-            continue;
+        if walking_cost(r).is_some() {
+            node_map.get_or_insert(r.src_i);
+            node_map.get_or_insert(r.dst_i);
         }
-        let node1 = node_map.get_or_insert(r.src_i);
-        let node2 = node_map.get_or_insert(r.dst_i);
-        // Use units of cm for comparing edges
-        let cost = (100.0 * r.linestring.euclidean_length()).round() as usize;
-        input_graph.add_edge(node1, node2, cost);
-        input_graph.add_edge(node2, node1, cost);
     }
-    input_graph.freeze();
-    let ch = fast_paths::prepare(&input_graph);
 
     let closest_intersection = build_closest_intersection(intersections, &node_map);
-    (closest_intersection, node_map, ch)
+    (
+        closest_intersection,
+        node_map,
+        Routers::new(skip_contraction_hierarchy),
+    )
 }
 
-fn build_closest_intersection(
+/// The cost of walking along a road, in units of cm, or `None` if it's not walkable at all. One-way
+/// footpaths are walkable; `CostConfig::cost_in_direction` checks which way.
+pub fn walking_cost(road: &Road) -> Option<usize> {
+    if road.kind == RoadKind::Severance {
+        return None;
+    }
+    Some((100.0 * road.linestring.euclidean_length()).round() as usize)
+}
+
+pub fn build_closest_intersection(
     intersections: &Vec<Intersection>,
     node_map: &NodeMap<IntersectionID>,
 ) -> RTree<IntersectionLocation> {
@@ -53,64 +77,1827 @@ fn build_closest_intersection(
     RTree::bulk_load(points)
 }
 
+/// Walkable roads only
+pub fn build_closest_road(roads: &Vec<Road>) -> RTree<RoadLocation> {
+    RTree::bulk_load(
+        roads
+            .iter()
+            .filter(|r| walking_cost(r).is_some())
+            .map(|r| RoadLocation::new(r.linestring.clone(), r.id))
+            .collect(),
+    )
+}
+
+/// Every road, walkable or not, for finding what's in view
+pub fn build_road_index(roads: &Vec<Road>) -> RTree<RoadLocation> {
+    RTree::bulk_load(
+        roads
+            .iter()
+            .map(|r| RoadLocation::new(r.linestring.clone(), r.id))
+            .collect(),
+    )
+}
+
+/// Severances only
+pub fn build_closest_severance(roads: &Vec<Road>) -> RTree<RoadLocation> {
+    RTree::bulk_load(
+        roads
+            .iter()
+            .filter(|r| r.kind == RoadKind::Severance)
+            .map(|r| RoadLocation::new(r.linestring.clone(), r.id))
+            .collect(),
+    )
+}
+
+/// Walking within this many meters of a severance exposes people to its noise and fumes
+const SEVERANCE_BUFFER: f64 = 15.0;
+
+/// How many meters of a linestring are within `SEVERANCE_BUFFER` of any severance
+pub fn length_alongside_severance(map: &MapModel, linestring: &LineString) -> f64 {
+    let mut total = 0.0;
+    for line in linestring.densify(5.0).lines() {
+        let midpoint = Point::from(line.start + (line.end - line.start) / 2.0);
+        if map
+            .closest_severance
+            .nearest_neighbor(&midpoint)
+            .map(|obj| obj.geom().euclidean_distance(&midpoint) <= SEVERANCE_BUFFER)
+            .unwrap_or(false)
+        {
+            total += line.euclidean_length();
+        }
+    }
+    total
+}
+
+/// A point projected onto the nearest walkable road
+#[derive(Clone, Copy)]
+struct Snap {
+    road: RoadID,
+    /// Fraction along the road's linestring, in [0, 1]
+    fraction: f64,
+    pt: Coord,
+    /// The point originally requested
+    requested: Coord,
+}
+
+impl Snap {
+    fn new(map: &MapModel, pt: Coord) -> Result<Self> {
+        let Some(obj) = map.closest_road.nearest_neighbor(&Point::from(pt)) else {
+            bail!("No walkable road to snap to");
+        };
+        let road = &map.roads[obj.data.0];
+        let fraction = road
+            .linestring
+            .line_locate_point(&Point::from(pt))
+            .unwrap_or(0.0);
+        let snapped = road
+            .linestring
+            .line_interpolate_point(fraction)
+            .map(|pt| pt.into())
+            .unwrap_or(pt);
+        Ok(Self {
+            road: road.id,
+            fraction,
+            pt: snapped,
+            requested: pt,
+        })
+    }
+
+    /// How far the requested point moved to reach the walking network
+    fn distance(&self) -> f64 {
+        self.requested.euclidean_distance(&self.pt)
+    }
+
+    /// Describes how the requested point was moved, to spot points snapped to the wrong side of a
+    /// severance
+    fn diagnostics(&self, map: &MapModel) -> SnapDiagnostics {
+        let line = Line::new(self.requested, self.pt);
+        let crosses_severance = map
+            .closest_severance
+            .locate_in_envelope_intersecting(&line.envelope())
+            .any(|obj| obj.geom().intersects(&line));
+        SnapDiagnostics {
+            distance: self.distance(),
+            crosses_severance,
+        }
+    }
+
+    /// The cost to walk between the snapped point and each end of the road, using some cost
+    /// function that depends on the direction walked. `leaving` means walking from the point to
+    /// the ends, like at the start of a route, rather than from the ends to the point. Ends the
+    /// cost function rules out in that direction are skipped.
+    fn costs_to_ends<F: Fn(&Road, bool) -> Option<usize>>(
+        &self,
+        map: &MapModel,
+        leaving: bool,
+        cost: F,
+    ) -> Vec<(IntersectionID, usize)> {
+        let road = &map.roads[self.road.0];
+        // Leaving towards src_i walks backwards along the road
+        [
+            (road.src_i, !leaving, self.fraction),
+            (road.dst_i, leaving, 1.0 - self.fraction),
+        ]
+        .into_iter()
+        .filter_map(|(i, forwards, fraction)| {
+            let cost = cost(road, forwards)? as f64;
+            Some((i, (fraction * cost).round() as usize))
+        })
+        .collect()
+    }
+
+    /// The cost to walk directly from this point to `end` on the same road, or `None` if they're
+    /// on different roads or the cost function rules out that direction
+    fn cost_along_to<F: Fn(&Road, bool) -> Option<usize>>(
+        &self,
+        map: &MapModel,
+        end: &Snap,
+        cost: F,
+    ) -> Option<usize> {
+        if self.road != end.road {
+            return None;
+        }
+        let full = cost(&map.roads[self.road.0], end.fraction >= self.fraction)? as f64;
+        Some(((end.fraction - self.fraction).abs() * full).round() as usize)
+    }
+
+    /// The fraction along the road of one of its endpoints
+    fn fraction_at(&self, map: &MapModel, i: IntersectionID) -> f64 {
+        if map.roads[self.road.0].src_i == i {
+            0.0
+        } else {
+            1.0
+        }
+    }
+}
+
+/// How a requested point was snapped to the walking network
+#[derive(Serialize)]
+struct SnapDiagnostics {
+    /// Meters between the requested point and the snapped point
+    distance: f64,
+    /// The straight line to the snapped point crosses a severance, so the route probably starts
+    /// or ends on the wrong side of it
+    crosses_severance: bool,
+}
+
+/// A route between two snapped points. The first and last roads may only be partly used.
+struct Path {
+    start: Snap,
+    end: Snap,
+    /// The full roads in between, if the route doesn't start and end on the same road
+    middle: Option<(IntersectionID, Vec<RoadID>, IntersectionID)>,
+}
+
+impl Path {
+    /// Every road used, with the geometry of the part used, in order
+    fn pieces(&self, map: &MapModel) -> Vec<(RoadID, LineString)> {
+        let start_road = &map.roads[self.start.road.0];
+        let Some((exit, ref middle, entry)) = self.middle else {
+            return vec![(
+                start_road.id,
+                slice_linestring(
+                    &start_road.linestring,
+                    self.start.fraction,
+                    self.end.fraction,
+                ),
+            )];
+        };
+
+        let mut pieces = vec![(
+            start_road.id,
+            slice_linestring(
+                &start_road.linestring,
+                self.start.fraction,
+                self.start.fraction_at(map, exit),
+            ),
+        )];
+        for r in middle {
+            pieces.push((*r, map.roads[r.0].linestring.clone()));
+        }
+        let end_road = &map.roads[self.end.road.0];
+        pieces.push((
+            end_road.id,
+            slice_linestring(
+                &end_road.linestring,
+                self.end.fraction_at(map, entry),
+                self.end.fraction,
+            ),
+        ));
+        pieces
+    }
+
+    /// The cost `find_path` minimized to choose this path, in the active costs' units
+    fn cost(&self, map: &MapModel, query_costs: &QueryCosts) -> usize {
+        let cost = |r: &Road, forwards| query_costs.cost(&map.cost_config, r, forwards);
+        let Some((exit, ref middle, entry)) = self.middle else {
+            return self.start.cost_along_to(map, &self.end, cost).unwrap_or(0);
+        };
+        let to_end = |snap: &Snap, leaving: bool, i: IntersectionID| {
+            snap.costs_to_ends(map, leaving, cost)
+                .into_iter()
+                .find(|(end, _)| *end == i)
+                .map_or(0, |(_, cost)| cost)
+        };
+        let mut total = to_end(&self.start, true, exit) + to_end(&self.end, false, entry);
+        let mut i = exit;
+        for r in middle {
+            let road = &map.roads[r.0];
+            total += cost(road, road.src_i == i).unwrap_or(0);
+            i = road.other_side(i);
+        }
+        total
+    }
+
+    fn length(&self, map: &MapModel) -> f64 {
+        self.pieces(map)
+            .into_iter()
+            .map(|(_, linestring)| linestring.euclidean_length())
+            .sum()
+    }
+
+    /// The full roads used, excluding the partly used start and end
+    fn middle_roads(&self) -> &[RoadID] {
+        match self.middle {
+            Some((_, ref middle, _)) => middle,
+            None => &[],
+        }
+    }
+}
+
+/// Changes to the normal walking costs for one query. When nothing changes, the contraction
+/// hierarchy can be used.
+#[derive(Clone, Default)]
+struct QueryCosts {
+    /// Roads closed at this time of day can't be used
+    time: Option<Time>,
+    /// On one-way roads without sidewalks, only walk facing oncoming traffic
+    face_traffic: bool,
+    /// These roads can't be used
+    avoid: HashSet<RoadID>,
+    /// Multiply the normal cost of these roads
+    penalties: HashMap<RoadID, f64>,
+}
+
+impl QueryCosts {
+    /// The cost of walking along a road, forwards meaning from `src_i` to `dst_i`
+    fn cost(&self, config: &CostConfig, road: &Road, forwards: bool) -> Option<usize> {
+        if (self.face_traffic && !allowed_facing_traffic(road, forwards))
+            || self.avoid.contains(&road.id)
+            || self
+                .time
+                .map(|t| road.access.is_closed_at(t))
+                .unwrap_or(false)
+        {
+            return None;
+        }
+        let cost = config.cost_in_direction(road, forwards)?;
+        Some(match self.penalties.get(&road.id) {
+            Some(factor) => (factor * cost as f64).round() as usize,
+            None => cost,
+        })
+    }
+
+    fn changes_anything(&self, map: &MapModel) -> bool {
+        !self.avoid.is_empty()
+            || !self.penalties.is_empty()
+            || (self.face_traffic
+                && map
+                    .roads
+                    .iter()
+                    .any(|r| !allowed_facing_traffic(r, true) || !allowed_facing_traffic(r, false)))
+            || self
+                .time
+                .map(|t| map.roads.iter().any(|r| r.access.is_closed_at(t)))
+                .unwrap_or(false)
+    }
+}
+
+/// On a one-way road without sidewalks, pedestrians facing oncoming traffic can only walk against
+/// the flow. Returns true if walking in this direction is allowed, with forwards meaning from
+/// `src_i` to `dst_i`, the direction of the OSM way.
+fn allowed_facing_traffic(road: &Road, forwards: bool) -> bool {
+    if road.kind != RoadKind::WithTraffic
+        || road
+            .tags
+            .is_any("sidewalk", vec!["both", "left", "right", "yes", "separate"])
+    {
+        return true;
+    }
+    if road.tags.is_any("oneway", vec!["yes", "true", "1"]) {
+        !forwards
+    } else if road.tags.is("oneway", "-1") {
+        forwards
+    } else {
+        true
+    }
+}
+
+/// Some footpaths can only be walked one way, like one-way passages through stations and
+/// escalators. Returns true if walking in this direction is allowed, with forwards meaning from
+/// `src_i` to `dst_i`, the direction of the OSM way.
+pub fn walkable_in_direction(road: &Road, forwards: bool) -> bool {
+    let direction = road
+        .tags
+        .get("oneway:foot")
+        .or_else(|| road.tags.get("conveying"))
+        .map(|x| x.as_str());
+    match direction {
+        Some("yes" | "true" | "1" | "forward") => forwards,
+        Some("-1" | "backward") => !forwards,
+        _ => true,
+    }
+}
+
+/// Snaps the request and finds the best path. Also returns the costs used, so related queries can
+/// make the same assumptions.
+fn snap_and_find_path(map: &mut MapModel, req: &CompareRouteRequest) -> Result<(Path, QueryCosts)> {
+    let start = Snap::new(
+        map,
+        Coord {
+            x: req.x1,
+            y: req.y1,
+        },
+    )?;
+    let end = Snap::new(
+        map,
+        Coord {
+            x: req.x2,
+            y: req.y2,
+        },
+    )?;
+    if let Some(max) = req.max_snap_distance {
+        for (snap, which) in [(&start, "start"), (&end, "end")] {
+            if snap.distance() > max {
+                bail!(
+                    "The {which} is {:.1}m from the walking network, more than the maximum of {max}m",
+                    snap.distance()
+                );
+            }
+        }
+    }
+    if start.road == end.road && start.fraction == end.fraction {
+        bail!("start = end");
+    }
+
+    let time = req.time.as_deref().map(crate::access::parse_time).transpose()?;
+    let query_costs = QueryCosts {
+        time,
+        face_traffic: req.face_traffic,
+        ..Default::default()
+    };
+    let Some(path) = find_path(map, start, end, &query_costs) else {
+        bail!("No path");
+    };
+    Ok((path, query_costs))
+}
+
+/// The roads used by the best route, with the geometry of the part used, for analyses that don't
+/// need GeoJSON
+pub fn route_pieces(
+    map: &mut MapModel,
+    req: &CompareRouteRequest,
+) -> Result<Vec<(RoadID, LineString)>> {
+    let (path, _) = snap_and_find_path(map, req)?;
+    Ok(path.pieces(map))
+}
+
+/// Compares the best route with the best route avoiding steps. Returns the length of each, with
+/// `None` if there's no step-free route.
+pub fn step_free_comparison(
+    map: &mut MapModel,
+    req: &CompareRouteRequest,
+) -> Result<(f64, Option<f64>)> {
+    let (path, base_costs) = snap_and_find_path(map, req)?;
+    let length = path.length(map);
+    let uses_steps = path
+        .pieces(map)
+        .into_iter()
+        .any(|(r, _)| is_steps(&map.roads[r.0]));
+    if !uses_steps {
+        return Ok((length, Some(length)));
+    }
+
+    let query_costs = QueryCosts {
+        avoid: map
+            .roads
+            .iter()
+            .filter(|r| is_steps(r))
+            .map(|r| r.id)
+            .collect(),
+        ..base_costs
+    };
+    let step_free = find_path(map, path.start, path.end, &query_costs)
+        .filter(|path| {
+            // If the start and end are on the same road, find_path doesn't search
+            !path
+                .pieces(map)
+                .into_iter()
+                .any(|(r, _)| query_costs.avoid.contains(&r))
+        })
+        .map(|path| path.length(map));
+    Ok((length, step_free))
+}
+
+/// Synthetic edges between intersections that aren't connected by a road, with the distance in
+/// meters. Each edge is listed from both ends.
+pub type ExtraEdges = HashMap<IntersectionID, Vec<(IntersectionID, f64)>>;
+
+/// Synthetic edges for crossing severances mid-block, away from any formal crossing. Each
+/// intersection maps to the intersections directly across a severance and the distance in meters.
+/// Only intersections with a road allowed under the current costs are connected.
+fn jaywalk_edges(map: &MapModel) -> ExtraEdges {
+    let allowed = |i: IntersectionID| {
+        map.intersections[i.0]
+            .roads
+            .iter()
+            .any(|r| map.cost_config.cost(&map.roads[r.0]).is_some())
+    };
+    let snap = |pt: Coord| -> Option<IntersectionID> {
+        map.closest_intersection
+            .nearest_neighbor_iter(&[pt.x, pt.y])
+            .map(|obj| map.node_map.translate_id(obj.data))
+            .take_while(|i| {
+                let i_pt: Coord = map.intersections[i.0].point.into();
+                i_pt.euclidean_distance(&pt) <= JAYWALK_SNAP_DISTANCE
+            })
+            .find(|i| allowed(*i))
+    };
+
+    let mut edges = ExtraEdges::new();
+    for r in &map.roads {
+        if r.kind != RoadKind::Severance {
+            continue;
+        }
+        for line in crate::heatmap::make_perpendicular_offsets(&r.linestring, 25.0, 15.0) {
+            let (Some(i1), Some(i2)) = (snap(line.start), snap(line.end)) else {
+                continue;
+            };
+            if i1 == i2 {
+                continue;
+            }
+            let dist = map.intersections[i1.0]
+                .point
+                .euclidean_distance(&map.intersections[i2.0].point);
+            edges.entry(i1).or_default().push((i2, dist));
+            edges.entry(i2).or_default().push((i1, dist));
+        }
+    }
+    edges
+}
+
+/// Only connect jaywalking edges to intersections this close to either side of a severance
+const JAYWALK_SNAP_DISTANCE: f64 = 20.0;
+
+/// The best route that may cross severances mid-block, with each jaywalk's cost multiplied by
+/// `penalty`. Returns the length actually walked and the penalized cost, if jaywalking beats the
+/// normal route. The jaywalking edges are found once per set of costs.
+fn jaywalk_route(
+    map: &mut MapModel,
+    path: &Path,
+    query_costs: &QueryCosts,
+    penalty: f64,
+) -> Option<(f64, f64)> {
+    if path.middle.is_none() {
+        return None;
+    }
+    if map.routers.jaywalk_edges(&map.cost_config).is_none() {
+        let edges = jaywalk_edges(map);
+        map.routers.insert_jaywalk_edges(map.cost_config.clone(), edges);
+    }
+    let extra = map.routers.jaywalk_edges(&map.cost_config)?;
+    let route = route_with_extra_edges(map, path, query_costs, extra, penalty)?;
+    (route.cost < path.cost(map, query_costs))
+        .then_some((route.length, route.cost as f64 / 100.0))
+}
+
+/// A route that may use extra edges between intersections
+pub struct ExtraEdgeRoute {
+    /// In the same units as `Path::cost`, with extra edges penalized
+    cost: usize,
+    /// Meters actually walked
+    pub length: f64,
+    /// Each road or extra edge used, with the geometry of the part used, in order
+    pieces: Vec<(Option<RoadID>, LineString)>,
+}
+
+/// The best route between the ends of `path` over the same costs `find_path` used, also allowing
+/// `extra` edges costing their length in cm multiplied by `penalty`
+fn route_with_extra_edges(
+    map: &MapModel,
+    path: &Path,
+    query_costs: &QueryCosts,
+    extra: &ExtraEdges,
+    penalty: f64,
+) -> Option<ExtraEdgeRoute> {
+    let snap_cost = |r: &Road, forwards| query_costs.cost(&map.cost_config, r, forwards);
+    let length_cm = |r: &Road| Some((100.0 * r.linestring.euclidean_length()).round() as usize);
+    // Skip the same ends as snap_cost, so the two lists line up
+    let snap_length = |r: &Road, forwards| snap_cost(r, forwards).and(length_cm(r));
+    // Track (cost, walked length in cm), and how each intersection was reached
+    let mut best: HashMap<IntersectionID, (usize, usize)> = HashMap::new();
+    let mut parent: HashMap<IntersectionID, (IntersectionID, Option<RoadID>)> = HashMap::new();
+    let mut queue = BinaryHeap::new();
+    for ((i, cost), (_, length)) in path
+        .start
+        .costs_to_ends(map, true, snap_cost)
+        .into_iter()
+        .zip(path.start.costs_to_ends(map, true, snap_length))
+    {
+        queue.push(Reverse((cost, length, i, None)));
+    }
+    while let Some(Reverse((cost, length, i, via))) = queue.pop() {
+        if best.contains_key(&i) {
+            continue;
+        }
+        best.insert(i, (cost, length));
+        if let Some(via) = via {
+            parent.insert(i, via);
+        }
+
+        for (road, next) in map.neighbors(i) {
+            let Some(edge_cost) = query_costs.cost(&map.cost_config, road, road.src_i == i) else {
+                continue;
+            };
+            queue.push(Reverse((
+                cost + edge_cost,
+                length + length_cm(road).unwrap(),
+                next,
+                Some((i, Some(road.id))),
+            )));
+        }
+        for (next, dist) in extra.get(&i).into_iter().flatten() {
+            let edge_length = (100.0 * dist).round() as usize;
+            let edge_cost = (100.0 * dist * penalty.max(1.0)).round() as usize;
+            queue.push(Reverse((
+                cost + edge_cost,
+                length + edge_length,
+                *next,
+                Some((i, None)),
+            )));
+        }
+    }
+
+    let (cost, length, entry) = path
+        .end
+        .costs_to_ends(map, false, snap_cost)
+        .into_iter()
+        .zip(path.end.costs_to_ends(map, false, snap_length))
+        .filter_map(|((i, cost), (_, length))| {
+            best.get(&i).map(|(c, l)| (c + cost, l + length, i))
+        })
+        .min()?;
+
+    let mut middle = Vec::new();
+    let mut i = entry;
+    while let Some((prev, r)) = parent.get(&i) {
+        let geometry = match r {
+            Some(r) => map.roads[r.0].linestring.clone(),
+            None => LineString::new(vec![
+                map.intersections[prev.0].point.into(),
+                map.intersections[i.0].point.into(),
+            ]),
+        };
+        middle.push((*r, geometry));
+        i = *prev;
+    }
+    middle.reverse();
+    let exit = i;
+
+    let start_road = &map.roads[path.start.road.0];
+    let end_road = &map.roads[path.end.road.0];
+    let mut pieces = vec![(
+        Some(start_road.id),
+        slice_linestring(
+            &start_road.linestring,
+            path.start.fraction,
+            path.start.fraction_at(map, exit),
+        ),
+    )];
+    pieces.extend(middle);
+    pieces.push((
+        Some(end_road.id),
+        slice_linestring(
+            &end_road.linestring,
+            path.end.fraction_at(map, entry),
+            path.end.fraction,
+        ),
+    ));
+
+    Some(ExtraEdgeRoute {
+        cost,
+        length: length as f64 / 100.0,
+        pieces,
+    })
+}
+
+/// A hypothetical crossing, as a WGS84 line straddling a severance
+#[derive(Deserialize)]
+pub struct CrossingEdit {
+    x1: f64,
+    y1: f64,
+    x2: f64,
+    y2: f64,
+}
+
+/// The route for a request before and after adding hypothetical crossings
+#[derive(Serialize)]
+pub struct EditComparison {
+    before: FeatureCollection,
+    /// The same as `before` if no new crossing helps. New crossings have the kind "NewCrossing".
+    after: FeatureCollection,
+    /// Walking time saved by the new crossings, ignoring any delay waiting to cross
+    seconds_saved: f64,
+}
+
+/// Compares the route for a request with and without some new crossings. Each end of a crossing
+/// snaps to the nearest intersection and costs its length. Both routes use the same costs and
+/// options, like the time of day.
+pub fn compare_route_with_edit(
+    map: &mut MapModel,
+    req: CompareRouteRequest,
+    edits: Vec<CrossingEdit>,
+) -> Result<EditComparison> {
+    let extra = crossing_edit_edges(map, &edits)?;
+    let ((_, before), after_route) = do_route_with_extra_edges(map, req, &extra)?;
+    let before_length = before.foreign_members.as_ref().unwrap()["route_length"]
+        .as_f64()
+        .unwrap();
+    let Some(after_route) = after_route else {
+        return Ok(EditComparison {
+            after: before.clone(),
+            before,
+            seconds_saved: 0.0,
+        });
+    };
+
+    let mut features = Vec::new();
+    let mut new_crossings = 0;
+    for (r, linestring) in after_route.pieces {
+        let geometry = geojson::Geometry::from(&map.mercator.to_wgs84(&linestring));
+        let mut f = match r {
+            Some(r) => map.roads[r.0].to_gj(&map.mercator),
+            None => {
+                new_crossings += 1;
+                let mut f = Feature::from(geometry.clone());
+                f.set_property("kind", "NewCrossing");
+                f
+            }
+        };
+        f.geometry = Some(geometry);
+        features.push(f);
+    }
+    let after = FeatureCollection {
+        features,
+        bbox: None,
+        foreign_members: Some(
+            serde_json::json!({
+                "route_length": after_route.length,
+                "new_crossings": new_crossings,
+            })
+            .as_object()
+            .unwrap()
+            .clone(),
+        ),
+    };
+    Ok(EditComparison {
+        before,
+        after,
+        seconds_saved: (before_length - after_route.length) / WALKING_SPEED,
+    })
+}
+
+/// Snaps one end of a new crossing, in WGS84, to the nearest walkable intersection
+pub fn snap_crossing_end(map: &MapModel, x: f64, y: f64) -> Result<IntersectionID> {
+    let pt = map.mercator.pt_to_mercator(Coord { x, y });
+    if let Some(obj) = map.closest_intersection.nearest_neighbor(&[pt.x, pt.y]) {
+        let i = map.node_map.translate_id(obj.data);
+        let i_pt: Coord = map.intersections[i.0].point.into();
+        if i_pt.euclidean_distance(&pt) <= JAYWALK_SNAP_DISTANCE {
+            return Ok(i);
+        }
+    }
+    bail!("New crossing end at ({x}, {y}) isn't within {JAYWALK_SNAP_DISTANCE}m of the walking network");
+}
+
+/// Snaps each end of some new crossings to the nearest intersection
+pub fn crossing_edit_edges(map: &MapModel, edits: &[CrossingEdit]) -> Result<ExtraEdges> {
+    let mut extra = ExtraEdges::new();
+    for edit in edits {
+        let i1 = snap_crossing_end(map, edit.x1, edit.y1)?;
+        let i2 = snap_crossing_end(map, edit.x2, edit.y2)?;
+        if i1 == i2 {
+            bail!("Both ends of a new crossing snap to the same intersection");
+        }
+        let dist = map.intersections[i1.0]
+            .point
+            .euclidean_distance(&map.intersections[i2.0].point);
+        extra.entry(i1).or_default().push((i2, dist));
+        extra.entry(i2).or_default().push((i1, dist));
+    }
+    Ok(extra)
+}
+
+pub fn is_steps(road: &Road) -> bool {
+    road.tags.is("highway", "steps")
+}
+
+#[derive(Deserialize)]
+pub struct RoutesFromRequest {
+    /// WGS84 `[x, y]`
+    origin: [f64; 2],
+    targets: Vec<[f64; 2]>,
+}
+
+/// Routes from one origin to many targets using a single search over the active costs. Returns
+/// one straight line feature per target, in the same order, with `route_length` and `detour` (the
+/// ratio of the route length to the straight line), or nulls if the target can't be reached.
+pub fn routes_from(map: &MapModel, req: RoutesFromRequest) -> Result<FeatureCollection> {
+    let to_mercator = |pt: [f64; 2]| map.mercator.pt_to_mercator(Coord { x: pt[0], y: pt[1] });
+    let start = Snap::new(map, to_mercator(req.origin))?;
+    let cost = |r: &Road, forwards| map.cost_config.cost_in_direction(r, forwards);
+    let costs = dijkstra_directed(
+        map,
+        start.costs_to_ends(map, true, cost),
+        None,
+        &ExtraEdges::new(),
+        cost,
+    );
+
+    let mut features = Vec::new();
+    let mut reached = 0;
+    for target in req.targets {
+        let end = Snap::new(map, to_mercator(target))?;
+        let best = path_to_snap(map, &costs, &start, &end);
+
+        let direct_line = LineString::new(vec![start.pt, end.pt]);
+        let direct_length = direct_line.euclidean_length();
+        let route_length = best.map(|path| path.length(map));
+        let mut f = Feature::from(geojson::Geometry::from(
+            &map.mercator.to_wgs84(&direct_line),
+        ));
+        f.set_property("direct_length", direct_length);
+        f.set_property("route_length", route_length);
+        f.set_property(
+            "detour",
+            route_length
+                .filter(|_| direct_length > 0.0)
+                .map(|route| route / direct_length),
+        );
+        if route_length.is_some() {
+            reached += 1;
+        }
+        features.push(f);
+    }
+
+    Ok(FeatureCollection {
+        features,
+        bbox: None,
+        foreign_members: Some(
+            serde_json::json!({
+                "reached": reached,
+            })
+            .as_object()
+            .unwrap()
+            .clone(),
+        ),
+    })
+}
+
+/// Given a `dijkstra_directed` search from `start` over the active costs, the cheapest path to
+/// `end`, like `find_path` would choose
+fn path_to_snap(map: &MapModel, costs: &Costs, start: &Snap, end: &Snap) -> Option<Path> {
+    let best = end
+        .costs_to_ends(map, false, |r, forwards| {
+            map.cost_config.cost_in_direction(r, forwards)
+        })
+        .into_iter()
+        .filter_map(|(i, cost)| costs.get(&i).map(|(total, _)| (total + cost, i)))
+        .min();
+    // Walking directly along the same road might be cheaper
+    if let Some(along) = start.cost_along_to(map, end, |r, forwards| {
+        map.cost_config.cost_in_direction(r, forwards)
+    }) {
+        if best.is_none_or(|(cost, _)| along <= cost) {
+            return Some(Path {
+                start: *start,
+                end: *end,
+                middle: None,
+            });
+        }
+    }
+    let (_, entry) = best?;
+    let middle = trace_path(map, costs, entry)?;
+    Some(Path {
+        start: *start,
+        end: *end,
+        middle: Some((trace_start(map, &middle, entry), middle, entry)),
+    })
+}
+
+#[derive(Deserialize)]
+pub struct MatrixRequest {
+    /// WGS84 `[x, y]`
+    origins: Vec<[f64; 2]>,
+    destinations: Vec<[f64; 2]>,
+}
+
+/// Walking distances and times from every origin to every destination. Rows are origins and
+/// columns are destinations. Unreachable pairs are null.
+#[derive(Serialize)]
+pub struct Matrix {
+    distances_meters: Vec<Vec<Option<f64>>>,
+    durations_seconds: Vec<Vec<Option<f64>>>,
+}
+
+/// Calculates a travel matrix over the walking network, with one search per origin. Paths are
+/// chosen by the active costs, like `route`.
+pub fn matrix(map: &MapModel, req: MatrixRequest) -> Result<Matrix> {
+    let to_mercator = |pt: [f64; 2]| map.mercator.pt_to_mercator(Coord { x: pt[0], y: pt[1] });
+    let destinations = req
+        .destinations
+        .into_iter()
+        .map(|pt| Snap::new(map, to_mercator(pt)))
+        .collect::<Result<Vec<_>>>()?;
+    let cost = |r: &Road, forwards| map.cost_config.cost_in_direction(r, forwards);
+
+    let mut distances_meters = Vec::new();
+    let mut durations_seconds = Vec::new();
+    for origin in req.origins {
+        let start = Snap::new(map, to_mercator(origin))?;
+        let costs = dijkstra_directed(
+            map,
+            start.costs_to_ends(map, true, cost),
+            None,
+            &ExtraEdges::new(),
+            cost,
+        );
+        let row: Vec<Option<f64>> = destinations
+            .iter()
+            .map(|end| path_to_snap(map, &costs, &start, end).map(|path| path.length(map)))
+            .collect();
+        durations_seconds.push(
+            row.iter()
+                .map(|dist| dist.map(|dist| dist / WALKING_SPEED))
+                .collect(),
+        );
+        distances_meters.push(row);
+    }
+    Ok(Matrix {
+        distances_meters,
+        durations_seconds,
+    })
+}
+
 // Also returns the line of the snapped request (in WGS84)
 pub fn do_route(
     map: &mut MapModel,
     req: CompareRouteRequest,
 ) -> Result<(Feature, FeatureCollection)> {
-    let start = map
-        .closest_intersection
-        .nearest_neighbor(&[req.x1, req.y1])
-        .unwrap()
-        .data;
-    let end = map
-        .closest_intersection
-        .nearest_neighbor(&[req.x2, req.y2])
-        .unwrap()
-        .data;
-    if start == end {
-        bail!("start = end");
+    let (path, query_costs) = snap_and_find_path(map, &req)?;
+    describe_route(map, &req, &path, &query_costs)
+}
+
+/// Like `do_route`, and also returns the route after adding `extra` edges costing their length,
+/// if any are used and make the route cheaper under the same costs
+pub fn do_route_with_extra_edges(
+    map: &mut MapModel,
+    req: CompareRouteRequest,
+    extra: &ExtraEdges,
+) -> Result<((Feature, FeatureCollection), Option<ExtraEdgeRoute>)> {
+    let (path, query_costs) = snap_and_find_path(map, &req)?;
+    let before = describe_route(map, &req, &path, &query_costs)?;
+    let after = if path.middle.is_some() {
+        route_with_extra_edges(map, &path, &query_costs, extra, 1.0)
+            .filter(|r| r.cost < path.cost(map, &query_costs))
+    } else {
+        None
+    };
+    Ok((before, after))
+}
+
+/// The GeoJSON response for a route found by `snap_and_find_path`
+fn describe_route(
+    map: &mut MapModel,
+    req: &CompareRouteRequest,
+    path: &Path,
+    query_costs: &QueryCosts,
+) -> Result<(Feature, FeatureCollection)> {
+    let robustness = if req.check_robustness {
+        Some(check_robustness(map, path, query_costs))
+    } else {
+        None
+    };
+    let jaywalking = req.jaywalk_penalty.and_then(|penalty| {
+        let (length, penalized_length) = jaywalk_route(map, path, query_costs, penalty)?;
+        Some(serde_json::json!({
+            "route_length": length,
+            "penalized_length": penalized_length,
+            "seconds_saved": (path.length(map) - length) / WALKING_SPEED,
+        }))
+    });
+    let alternatives = if req.alternatives > 0 {
+        Some(find_alternatives(map, path, query_costs, req.alternatives))
+    } else {
+        None
+    };
+
+    let direct_line = LineString::new(vec![path.start.pt, path.end.pt]);
+    let direct_feature = Feature::from(geojson::Geometry::from(
+        &map.mercator.to_wgs84(&direct_line),
+    ));
+
+    let mut features = Vec::new();
+    let mut steps = Vec::new();
+    let mut route_length = 0.0;
+    let mut length_with_traffic = 0.0;
+    let crossings = crossings_along(map, path);
+    let mut crossing_delays = DelayRange {
+        min: 0.0,
+        typical: 0.0,
+        max: 0.0,
+    };
+    for quality in &crossings {
+        let delay = crossing_delay_range(quality);
+        crossing_delays.min += delay.min;
+        crossing_delays.typical += delay.typical;
+        crossing_delays.max += delay.max;
     }
+    for (r, linestring) in path.pieces(map) {
+        let road = &map.roads[r.0];
+        let mut f = road.to_gj(&map.mercator);
+        f.geometry = Some(geojson::Geometry::from(&map.mercator.to_wgs84(&linestring)));
+        features.push(f);
 
-    if let Some(path) = map.path_calc.calc_path(&map.ch, start, end) {
-        let direct_line = LineString::new(vec![
-            map.intersections[map.node_map.translate_id(start).0]
-                .point
-                .into(),
-            map.intersections[map.node_map.translate_id(end).0]
-                .point
-                .into(),
-        ]);
-        let direct_feature = Feature::from(geojson::Geometry::from(
-            &map.mercator.to_wgs84(&direct_line),
-        ));
+        let length = linestring.euclidean_length();
+        route_length += length;
+        if road.kind == RoadKind::WithTraffic {
+            length_with_traffic += length;
+        }
+        steps.push(RouteStep {
+            road: r.0,
+            way: road.way.0,
+            kind: format!("{:?}", road.kind),
+            length,
+            crosses_severance: crossing_quality(road) != "none",
+        });
+    }
+
+    let direct_length = direct_line.euclidean_length();
+    let route_score = RouteScore::new(map, path, direct_length);
+    let walking_seconds = route_length / WALKING_SPEED;
+    let walking_time = DelayRange {
+        min: walking_seconds + crossing_delays.min,
+        typical: walking_seconds + crossing_delays.typical,
+        max: walking_seconds + crossing_delays.max,
+    };
+    Ok((
+        direct_feature,
+        FeatureCollection {
+            features,
+            bbox: None,
+            foreign_members: Some(
+                serde_json::json!({
+                    "direct_length": direct_length,
+                    "route_length": route_length,
+                    "length_with_traffic": length_with_traffic,
+                    "severance_crossings": crossings.len(),
+                    "route_score": route_score,
+                    "walking_time": walking_time,
+                    "jaywalking": jaywalking,
+                    "snapping": {
+                        "start": path.start.diagnostics(map),
+                        "end": path.end.diagnostics(map),
+                    },
+                    "steps": steps,
+                    "robustness": robustness,
+                    "alternatives": alternatives,
+                })
+                .as_object()
+                .unwrap()
+                .clone(),
+            ),
+        },
+    ))
+}
 
-        let mut features = Vec::new();
+/// Summarizes how pleasant a route is, combining the detour with time spent next to traffic, the
+/// crossings used, and steps. A short route can still be horrible.
+#[derive(Serialize)]
+pub struct RouteScore {
+    /// The ratio of the route length to the straight line
+    detour: f64,
+    /// Meters walked alongside traffic
+    severance_exposure: f64,
+    /// Meters walked near a severance, like along a sidewalk next to a dual carriageway, not
+    /// counting crossings of it
+    alongside_severance: f64,
+    crossings: usize,
+    /// The lowest `crossing_score` of any crossing used
+    worst_crossing: Option<f64>,
+    steps: usize,
+    /// From 0 (worst) to 1 (a direct route with nothing unpleasant)
+    score: f64,
+}
+
+impl RouteScore {
+    fn new(map: &MapModel, path: &Path, direct_length: f64) -> Self {
         let mut route_length = 0.0;
-        for pair in path.get_nodes().windows(2) {
-            let i1 = map.node_map.translate_id(pair[0]);
-            let i2 = map.node_map.translate_id(pair[1]);
-            let road = map.find_edge(i1, i2);
-            features.push(road.to_gj(&map.mercator));
-            route_length += road.linestring.euclidean_length();
+        let mut severance_exposure = 0.0;
+        let mut alongside_severance = 0.0;
+        let mut crossing_scores: Vec<f64> = Vec::new();
+        let mut on_crossing = false;
+        let mut steps = 0;
+        for (r, linestring) in path.pieces(map) {
+            let road = &map.roads[r.0];
+            let length = linestring.euclidean_length();
+            route_length += length;
+            if road.kind == RoadKind::WithTraffic {
+                severance_exposure += length;
+            }
+            if crossing_quality(road) == "none" {
+                alongside_severance += length_alongside_severance(map, &linestring);
+                on_crossing = false;
+            } else {
+                // Like `crossings_along`, a crossing split into several roads counts once, as
+                // good as its worst part
+                let score = crossing_score(map, road);
+                match crossing_scores.last_mut() {
+                    Some(last) if on_crossing => *last = last.min(score),
+                    _ => crossing_scores.push(score),
+                }
+                on_crossing = true;
+            }
+            if is_steps(road) {
+                steps += 1;
+            }
         }
-        let direct_length = direct_line.euclidean_length();
-        return Ok((
-            direct_feature,
-            FeatureCollection {
-                features,
-                bbox: None,
-                foreign_members: Some(
-                    serde_json::json!({
-                        "direct_length": direct_length,
-                        "route_length": route_length,
-                    })
-                    .as_object()
-                    .unwrap()
-                    .clone(),
-                ),
-            },
-        ));
+
+        let detour = if direct_length > 0.0 {
+            route_length / direct_length
+        } else {
+            1.0
+        };
+        // Each factor is in (0, 1]. 100m next to traffic halves the score, every crossing costs
+        // up to half, and each flight of steps costs a tenth.
+        let mut score = (1.0 / detour.max(1.0)) * (1.0 / (1.0 + severance_exposure / 100.0));
+        for crossing in &crossing_scores {
+            score *= 0.5 + 0.5 * crossing;
+        }
+        score *= 0.9_f64.powi(steps as i32);
+
+        Self {
+            detour,
+            severance_exposure,
+            alongside_severance,
+            crossings: crossing_scores.len(),
+            worst_crossing: crossing_scores.iter().cloned().reduce(f64::min),
+            steps,
+            score,
+        }
+    }
+}
+
+/// One road along a route, in order
+#[derive(Serialize)]
+struct RouteStep {
+    road: usize,
+    way: i64,
+    kind: String,
+    /// The length of the part of the road used, in meters
+    length: f64,
+    /// Crosses a severance somehow, possibly over a bridge or through a tunnel
+    crosses_severance: bool,
+}
+
+/// Finds the shortest path between two snapped points
+fn find_path(map: &mut MapModel, start: Snap, end: Snap, query_costs: &QueryCosts) -> Option<Path> {
+    let cost = |r: &Road, forwards| query_costs.cost(&map.cost_config, r, forwards);
+    let sources = start.costs_to_ends(map, true, cost);
+    let targets = end.costs_to_ends(map, false, cost);
+    let along = start.cost_along_to(map, &end, cost);
+    let direct = Path {
+        start,
+        end,
+        middle: None,
+    };
+
+    // The router can't change costs, so fall back to Dijkstra when needed
+    let (exit, middle, entry) = if query_costs.changes_anything(map) {
+        let costs = dijkstra_to_targets(map, sources, &targets, cost);
+        let best = targets
+            .iter()
+            .filter_map(|(i, extra)| costs.get(i).map(|(cost, _)| (*i, cost + extra)))
+            .min_by_key(|(_, cost)| *cost);
+        // Closures, avoided roads, and penalties might make going around cheaper
+        if let Some(along) = along {
+            if best.is_none_or(|(_, cost)| along <= cost) {
+                return Some(direct);
+            }
+        }
+        let (entry, _) = best?;
+        let middle = trace_path(map, &costs, entry)?;
+        (trace_start(map, &middle, entry), middle, entry)
+    } else {
+        // The best route might go around the block and come back, but just walking along the
+        // road directly is reasonable, unless it's a one-way footpath the wrong way
+        if along.is_some() {
+            return Some(direct);
+        }
+        let graph = RouterGraph {
+            intersections: &map.intersections,
+            roads: &map.roads,
+            node_map: &map.node_map,
+            cost_config: &map.cost_config,
+        };
+        let nodes = map.routers.get(&graph).route(&graph, sources, targets)?;
+        let middle: Vec<RoadID> = nodes
+            .windows(2)
+            .map(|pair| map.find_edge(pair[0], pair[1]).id)
+            .collect();
+        (nodes[0], middle, *nodes.last().unwrap())
+    };
+    Some(Path {
+        start,
+        end,
+        middle: Some((exit, middle, entry)),
+    })
+}
+
+/// Each time a road is used by a route, its cost for finding the next alternative is multiplied by
+/// this
+const ALTERNATIVE_PENALTY: f64 = 1.5;
+
+/// Find up to `k` routes besides the best one, by repeatedly penalizing roads already used. Each
+/// alternative is a feature with stats as properties.
+fn find_alternatives(
+    map: &mut MapModel,
+    best: &Path,
+    base_costs: &QueryCosts,
+    k: usize,
+) -> Vec<Feature> {
+    let mut query_costs = base_costs.clone();
+    let penalize = |query_costs: &mut QueryCosts, path: &Path| {
+        for r in path.middle_roads() {
+            *query_costs.penalties.entry(*r).or_insert(1.0) *= ALTERNATIVE_PENALTY;
+        }
+    };
+    penalize(&mut query_costs, best);
+    let mut seen = vec![best.middle_roads().to_vec()];
+
+    let mut alternatives = Vec::new();
+    // Penalizing might keep finding the same route; don't try forever
+    for _ in 0..3 * k {
+        if alternatives.len() == k {
+            break;
+        }
+        let Some(path) = find_path(map, best.start, best.end, &query_costs) else {
+            break;
+        };
+        penalize(&mut query_costs, &path);
+        let roads = path.middle_roads().to_vec();
+        if seen.contains(&roads) {
+            continue;
+        }
+        seen.push(roads);
+        alternatives.push(path_summary(map, &path));
+    }
+    alternatives
+}
+
+/// One feature covering the whole path, with its length, the number of severance crossings, and
+/// the length walked alongside traffic, counted the same way as `describe_route`
+fn path_summary(map: &MapModel, path: &Path) -> Feature {
+    let mut lines = Vec::new();
+    let mut route_length = 0.0;
+    let mut length_with_traffic = 0.0;
+    for (r, linestring) in path.pieces(map) {
+        let length = linestring.euclidean_length();
+        route_length += length;
+        if map.roads[r.0].kind == RoadKind::WithTraffic {
+            length_with_traffic += length;
+        }
+        lines.push(map.mercator.to_wgs84(&linestring));
+    }
+    let mut f = Feature::from(geojson::Geometry::from(&MultiLineString::new(lines)));
+    f.set_property("route_length", route_length);
+    f.set_property("severance_crossings", crossings_along(map, path).len());
+    f.set_property("length_with_traffic", length_with_traffic);
+    f
+}
+
+/// The `crossing_quality` of every severance crossed along a path, in order. Bridges and tunnels
+/// count, not just crossings at grade. Consecutive crossing roads, like a footbridge split into
+/// several ways, are one crossing, described by the first road.
+fn crossings_along(map: &MapModel, path: &Path) -> Vec<&'static str> {
+    let mut crossings = Vec::new();
+    let mut on_crossing = false;
+    for (r, _) in path.pieces(map) {
+        let quality = crossing_quality(&map.roads[r.0]);
+        if quality != "none" && !on_crossing {
+            crossings.push(quality);
+        }
+        on_crossing = quality != "none";
+    }
+    crossings
+}
+
+/// A route is fragile if it depends on a single edge that might be a mapping error, and avoiding
+/// that edge makes the route much longer.
+#[derive(Serialize)]
+struct Robustness {
+    fragile: bool,
+    suspicious_edges: Vec<SuspiciousEdge>,
+}
+
+#[derive(Serialize)]
+struct SuspiciousEdge {
+    road: usize,
+    way: String,
+    reason: &'static str,
+    /// The length of the best route avoiding this edge, or `None` if there's no other route
+    route_length_without: Option<f64>,
+}
+
+/// Avoiding a suspicious edge must make the route this much longer to be considered fragile
+const FRAGILE_RATIO: f64 = 1.5;
+
+/// For every suspicious edge along the path, find the next-best route avoiding it
+fn check_robustness(map: &mut MapModel, path: &Path, base_costs: &QueryCosts) -> Robustness {
+    let best_length = path.length(map);
+    let mut suspicious_edges = Vec::new();
+    let mut fragile = false;
+    for r in path.middle_roads() {
+        let Some(reason) = suspicious_edge(&map.roads[r.0]) else {
+            continue;
+        };
+        let query_costs = QueryCosts {
+            avoid: HashSet::from([*r]),
+            ..base_costs.clone()
+        };
+        let route_length_without =
+            find_path(map, path.start, path.end, &query_costs).map(|alt| alt.length(map));
+        if route_length_without
+            .map(|length| length > FRAGILE_RATIO * best_length)
+            .unwrap_or(true)
+        {
+            fragile = true;
+        }
+        suspicious_edges.push(SuspiciousEdge {
+            road: r.0,
+            way: map.roads[r.0].way.to_string(),
+            reason,
+            route_length_without,
+        });
+    }
+    Robustness {
+        fragile,
+        suspicious_edges,
+    }
+}
+
+/// Edges that are often mapping errors
+fn suspicious_edge(road: &Road) -> Option<&'static str> {
+    if road.linestring.euclidean_length() < 3.0 {
+        return Some("very short connector");
+    }
+    if road.tags.is_any("highway", vec!["path", "footway"]) && road.tags.0.len() == 1 {
+        return Some("untagged path");
+    }
+    None
+}
+
+/// Given a path ending at `end`, find the intersection it starts from
+fn trace_start(map: &MapModel, path: &Vec<RoadID>, end: IntersectionID) -> IntersectionID {
+    let mut current = end;
+    for r in path.iter().rev() {
+        current = map.roads[r.0].other_side(current);
+    }
+    current
+}
+
+/// The part of a linestring between two fractions of its length. If `start` is after `end`, the
+/// result points backwards.
+pub fn slice_linestring(linestring: &LineString, start: f64, end: f64) -> LineString {
+    if start > end {
+        let mut result = slice_linestring(linestring, end, start);
+        result.0.reverse();
+        return result;
+    }
+
+    let total = linestring.euclidean_length();
+    let (start_dist, end_dist) = (start * total, end * total);
+    let mut pts = Vec::new();
+    let mut dist_so_far = 0.0;
+    for line in linestring.lines() {
+        let length = line.euclidean_length();
+        let next_dist = dist_so_far + length;
+        let interpolate = |dist: f64| {
+            let pct = if length == 0.0 {
+                0.0
+            } else {
+                (dist - dist_so_far) / length
+            };
+            Coord {
+                x: line.start.x + pct * (line.end.x - line.start.x),
+                y: line.start.y + pct * (line.end.y - line.start.y),
+            }
+        };
+
+        if pts.is_empty() && start_dist <= next_dist {
+            pts.push(interpolate(start_dist));
+        }
+        if !pts.is_empty() {
+            if end_dist <= next_dist {
+                pts.push(interpolate(end_dist));
+                break;
+            }
+            pts.push(line.end);
+        }
+        dist_so_far = next_dist;
+    }
+    // Handle floating point issues and degenerate input
+    if pts.len() < 2 {
+        pts = vec![linestring.0[0], *linestring.0.last().unwrap()];
+    }
+    LineString::new(pts)
+}
+
+/// For every intersection reached by `dijkstra_directed`, the total cost to reach it and the road
+/// used to get there (`None` for the start)
+pub type Costs = HashMap<IntersectionID, (usize, Option<RoadID>)>;
+
+/// Dijkstra over the full graph, for queries the contraction hierarchy can't answer. The search
+/// begins from each start with some initial cost. `cost` returns `None` for roads that can't be
+/// used, and is passed true when walking from `src_i` to `dst_i`. Stops searching past `limit`, if
+/// specified. Also allows `extra` edges, costing their length. They're recorded in the costs
+/// without a road, so paths through them can't be traced.
+pub fn dijkstra_directed<F: Fn(&Road, bool) -> Option<usize>>(
+    map: &MapModel,
+    starts: Vec<(IntersectionID, usize)>,
+    limit: Option<usize>,
+    extra: &ExtraEdges,
+    cost: F,
+) -> Costs {
+    search(map, starts, limit, extra, &[], cost)
+}
+
+/// Like `dijkstra_directed`, but stops once no cheaper way to reach any of the targets, each with
+/// some extra cost to finish, is possible. Intersections beyond that aren't in the costs.
+pub fn dijkstra_to_targets<F: Fn(&Road, bool) -> Option<usize>>(
+    map: &MapModel,
+    starts: Vec<(IntersectionID, usize)>,
+    targets: &[(IntersectionID, usize)],
+    cost: F,
+) -> Costs {
+    search(map, starts, None, &ExtraEdges::new(), targets, cost)
+}
+
+fn search<F: Fn(&Road, bool) -> Option<usize>>(
+    map: &MapModel,
+    starts: Vec<(IntersectionID, usize)>,
+    limit: Option<usize>,
+    extra: &ExtraEdges,
+    targets: &[(IntersectionID, usize)],
+    cost: F,
+) -> Costs {
+    let mut costs: Costs = HashMap::new();
+    let mut queue = BinaryHeap::new();
+    for (i, initial_cost) in starts {
+        queue.push(Reverse((initial_cost, i, None)));
+    }
+    // The cheapest way found so far to finish at a target
+    let mut best_target: Option<usize> = None;
+
+    while let Some(Reverse((total, i, via))) = queue.pop() {
+        if best_target.map(|best| best <= total).unwrap_or(false) {
+            break;
+        }
+        if costs.contains_key(&i) {
+            continue;
+        }
+        costs.insert(i, (total, via));
+        for (target, finish) in targets {
+            if *target == i {
+                best_target = Some(best_target.map_or(total + finish, |b| b.min(total + finish)));
+            }
+        }
+
+        for (road, next) in map.neighbors(i) {
+            let Some(edge_cost) = cost(road, road.src_i == i) else {
+                continue;
+            };
+            let next_total = total + edge_cost;
+            if limit.map(|limit| next_total > limit).unwrap_or(false) || costs.contains_key(&next) {
+                continue;
+            }
+            queue.push(Reverse((next_total, next, Some(road.id))));
+        }
+        for (next, dist) in extra.get(&i).into_iter().flatten() {
+            let next_total = total + (100.0 * dist).round() as usize;
+            if limit.map(|limit| next_total > limit).unwrap_or(false) || costs.contains_key(next) {
+                continue;
+            }
+            queue.push(Reverse((next_total, *next, None)));
+        }
+    }
+
+    costs
+}
+
+/// Returns the roads from the start of a `dijkstra_directed` search to `end`, or `None` if it
+/// wasn't reached
+pub fn trace_path(map: &MapModel, costs: &Costs, end: IntersectionID) -> Option<Vec<RoadID>> {
+    let mut path = Vec::new();
+    let mut current = end;
+    while let (_, Some(r)) = costs.get(&current)? {
+        path.push(*r);
+        current = map.roads[r.0].other_side(current);
+    }
+    path.reverse();
+    Some(path)
+}
+
+/// A separate graph over roads that cars can use, only for comparing against walking routes. It's
+/// built from driveable ways seen while importing the walking network, but split on its own, so it
+/// has no relation to `Road`s and `Intersection`s.
+pub struct DrivingRouter {
+    ch: FastGraph,
+    path_calc: PathCalculator,
+    // Indexed by the driving graph's intersection ID, in the MapModel's Mercator
+    points: Vec<Coord>,
+    closest_intersection: RTree<GeomWithData<[f64; 2], usize>>,
+    /// Each directed edge is a node in the contraction hierarchy, so that turns between edges can
+    /// be banned. Indexed by the fast_paths node ID.
+    directed_edges: Vec<DirectedEdge>,
+    /// Per intersection, the directed edges leaving it
+    outgoing: Vec<Vec<usize>>,
+    /// Per intersection, the directed edges arriving at it
+    incoming: Vec<Vec<usize>>,
+}
+
+/// The parts of a `DrivingRouter` that are slow to recalculate, for snapshots
+#[derive(Deserialize)]
+struct DrivingRouterParts {
+    ch: FastGraph,
+    points: Vec<Coord>,
+    directed_edges: Vec<DirectedEdge>,
+    outgoing: Vec<Vec<usize>>,
+    incoming: Vec<Vec<usize>>,
+}
+
+/// Borrows the same fields as `DrivingRouterParts`, to serialize without copying
+#[derive(Serialize)]
+struct DrivingRouterPartsRef<'a> {
+    ch: &'a FastGraph,
+    points: &'a Vec<Coord>,
+    directed_edges: &'a Vec<DirectedEdge>,
+    outgoing: &'a Vec<Vec<usize>>,
+    incoming: &'a Vec<Vec<usize>>,
+}
+
+impl Serialize for DrivingRouter {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        DrivingRouterPartsRef {
+            ch: &self.ch,
+            points: &self.points,
+            directed_edges: &self.directed_edges,
+            outgoing: &self.outgoing,
+            incoming: &self.incoming,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for DrivingRouter {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        DrivingRouterParts::deserialize(deserializer).map(Self::from_parts)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct DirectedEdge {
+    /// The undirected edge in the driving graph
+    edge: usize,
+    way: WayID,
+    dst: usize,
+    cost: usize,
+    /// Pointing from `src` to `dst`, in the MapModel's Mercator
+    linestring: LineString,
+}
+
+/// Everything needed to build a `DrivingRouter`, remembered while reading the OSM input for the
+/// walking network
+#[derive(Default, Serialize, Deserialize)]
+pub struct DrivingInput {
+    pub ways: Vec<DrivingWay>,
+    pub turn_restrictions: Vec<TurnRestriction>,
+}
+
+/// A driveable way
+#[derive(Serialize, Deserialize)]
+pub struct DrivingWay {
+    pub way: WayID,
+    pub nodes: Vec<NodeID>,
+    pub tags: Tags,
+}
+
+/// Part of a driveable way between two intersections of the driving graph
+struct DrivingEdge {
+    way: WayID,
+    src: usize,
+    dst: usize,
+    forwards: bool,
+    backwards: bool,
+    /// In the MapModel's Mercator
+    linestring: LineString,
+}
+
+/// Driving costs are the plain distance in cm, following one-way streets. U-turns are only allowed
+/// at dead-ends, and turn restrictions are obeyed.
+struct DrivingCosts<'a> {
+    /// The OSM node of each intersection in the driving graph
+    nodes: &'a [NodeID],
+    turn_restrictions: &'a Vec<TurnRestriction>,
+}
+
+impl CostFunction for DrivingCosts<'_> {
+    type Edge = DrivingEdge;
+    type Node = usize;
+
+    fn endpoints(&self, edge: &DrivingEdge) -> (usize, usize) {
+        (edge.src, edge.dst)
+    }
+
+    fn forwards_cost(&self, edge: &DrivingEdge) -> Option<usize> {
+        edge.forwards
+            .then(|| (100.0 * edge.linestring.euclidean_length()).round() as usize)
+    }
+
+    fn backwards_cost(&self, edge: &DrivingEdge) -> Option<usize> {
+        edge.backwards
+            .then(|| (100.0 * edge.linestring.euclidean_length()).round() as usize)
+    }
+
+    fn turn_cost(&self, edges: &[DrivingEdge], turn: &Turn<usize>) -> Option<usize> {
+        if turn.from.edge == turn.to.edge && turn.options > 1 {
+            return None;
+        }
+        let from_way = edges[turn.from.edge].way;
+        let to_way = edges[turn.to.edge].way;
+        let via = self.nodes[turn.via];
+        for restriction in self.turn_restrictions {
+            if restriction.via == via
+                && restriction.from == from_way
+                && (restriction.to == to_way) != restriction.only
+            {
+                return None;
+            }
+        }
+        Some(0)
+    }
+}
+
+/// A `type=restriction` relation between two ways at a node
+#[derive(Serialize, Deserialize)]
+pub struct TurnRestriction {
+    from: WayID,
+    via: NodeID,
+    to: WayID,
+    /// If true, this is the only turn allowed from `from` at `via`. Otherwise this turn is banned.
+    only: bool,
+}
+
+impl DrivingRouter {
+    /// Builds the driving graph from ways remembered during the walking import, so the OSM input
+    /// is only read once. `node_coords` are in WGS84.
+    pub fn new(
+        input: &DrivingInput,
+        node_coords: &HashMap<NodeID, Coord>,
+        mercator: &Mercator,
+    ) -> Self {
+        let (nodes, points, edges) = split_driving_ways(&input.ways, node_coords, mercator);
+        let costs = DrivingCosts {
+            nodes: &nodes,
+            turn_restrictions: &input.turn_restrictions,
+        };
+        let turn_graph = cost_graph::build_turn_graph(&edges[..], &costs);
+
+        let mut directed_edges = Vec::new();
+        let mut outgoing = vec![Vec::new(); points.len()];
+        let mut incoming = vec![Vec::new(); points.len()];
+        for directed in turn_graph.directed_edges {
+            let e = &edges[directed.edge];
+            let mut linestring = e.linestring.clone();
+            if !directed.forwards {
+                linestring.0.reverse();
+            }
+            outgoing[directed.src].push(directed_edges.len());
+            incoming[directed.dst].push(directed_edges.len());
+            directed_edges.push(DirectedEdge {
+                edge: directed.edge,
+                way: e.way,
+                dst: directed.dst,
+                cost: directed.cost,
+                linestring,
+            });
+        }
+
+        Self::from_parts(DrivingRouterParts {
+            ch: turn_graph.ch,
+            points,
+            directed_edges,
+            outgoing,
+            incoming,
+        })
+    }
+
+    /// Builds the indices that are cheap to recalculate
+    fn from_parts(parts: DrivingRouterParts) -> Self {
+        let path_calc = fast_paths::create_calculator(&parts.ch);
+        let closest_intersection = RTree::bulk_load(
+            parts
+                .points
+                .iter()
+                .enumerate()
+                .map(|(idx, pt)| GeomWithData::new([pt.x, pt.y], idx))
+                .collect(),
+        );
+        Self {
+            ch: parts.ch,
+            path_calc,
+            points: parts.points,
+            closest_intersection,
+            directed_edges: parts.directed_edges,
+            outgoing: parts.outgoing,
+            incoming: parts.incoming,
+        }
+    }
+
+    /// Drive between the driving intersections closest to each point. Returns the route and the
+    /// distance from each point to the intersection used.
+    pub fn route(&mut self, pt1: Coord, pt2: Coord) -> Result<(LineString, f64, f64)> {
+        let Some(start) = self.closest_intersection.nearest_neighbor(&[pt1.x, pt1.y]) else {
+            bail!("No driveable roads");
+        };
+        let start = start.data;
+        let end = self
+            .closest_intersection
+            .nearest_neighbor(&[pt2.x, pt2.y])
+            .unwrap()
+            .data;
+        let snap1 = Point::from(pt1).euclidean_distance(&Point::from(self.points[start]));
+        let snap2 = Point::from(pt2).euclidean_distance(&Point::from(self.points[end]));
+        if start == end {
+            return Ok((LineString::new(vec![self.points[start]]), snap1, snap2));
+        }
+
+        // Start by driving along any edge leaving the start, and finish after arriving at the end
+        let sources = self.outgoing[start]
+            .iter()
+            .map(|d| (*d, self.directed_edges[*d].cost))
+            .collect();
+        let targets = self.incoming[end].iter().map(|d| (*d, 0)).collect();
+        let Some(path) = self
+            .path_calc
+            .calc_path_multiple_sources_and_targets(&self.ch, sources, targets)
+        else {
+            bail!("No driving route");
+        };
+        let mut pts = Vec::new();
+        for d in path.get_nodes() {
+            pts.extend(self.directed_edges[*d].linestring.0.iter().cloned());
+        }
+        pts.dedup();
+        Ok((LineString::new(pts), snap1, snap2))
+    }
+}
+
+/// Splits driveable ways into edges wherever they share a node. Returns the OSM node and
+/// Mercator point of each intersection, and the edges between them. Ways with missing nodes are
+/// skipped.
+fn split_driving_ways(
+    ways: &[DrivingWay],
+    node_coords: &HashMap<NodeID, Coord>,
+    mercator: &Mercator,
+) -> (Vec<NodeID>, Vec<Coord>, Vec<DrivingEdge>) {
+    let mut uses_per_node: HashMap<NodeID, usize> = HashMap::new();
+    for way in ways {
+        for node in &way.nodes {
+            *uses_per_node.entry(*node).or_insert(0) += 1;
+        }
+    }
+
+    let mut intersections: HashMap<NodeID, usize> = HashMap::new();
+    let mut nodes = Vec::new();
+    let mut points = Vec::new();
+    let mut edges = Vec::new();
+    for way in ways {
+        let Some(pts) = way
+            .nodes
+            .iter()
+            .map(|n| node_coords.get(n).map(|pt| mercator.pt_to_mercator(*pt)))
+            .collect::<Option<Vec<Coord>>>()
+        else {
+            warn!("Skipping driveable way {} with missing nodes", way.way.0);
+            continue;
+        };
+        if pts.len() < 2 {
+            continue;
+        }
+        let mut intersection = |idx: usize| {
+            *intersections.entry(way.nodes[idx]).or_insert_with(|| {
+                nodes.push(way.nodes[idx]);
+                points.push(pts[idx]);
+                nodes.len() - 1
+            })
+        };
+        let (forwards, backwards) = driving_directions(&way.tags);
+        let mut start = 0;
+        for idx in 1..way.nodes.len() {
+            if idx != way.nodes.len() - 1 && uses_per_node[&way.nodes[idx]] < 2 {
+                continue;
+            }
+            let src = intersection(start);
+            let dst = intersection(idx);
+            edges.push(DrivingEdge {
+                way: way.way,
+                src,
+                dst,
+                forwards,
+                backwards,
+                linestring: LineString::new(pts[start..=idx].to_vec()),
+            });
+            start = idx;
+        }
+    }
+    (nodes, points, edges)
+}
+
+/// Parses a `type=restriction` relation with a single via node
+pub fn parse_turn_restriction(members: &[(String, OsmID)], tags: &Tags) -> Option<TurnRestriction> {
+    if !tags.is("type", "restriction") {
+        return None;
+    }
+    let restriction = tags.get("restriction")?;
+    let only = restriction.starts_with("only_");
+    if !only && !restriction.starts_with("no_") {
+        return None;
+    }
+
+    let (mut from, mut via, mut to) = (None, None, None);
+    for (role, member) in members {
+        match (role.as_str(), member) {
+            ("from", OsmID::Way(way)) => from = Some(*way),
+            ("via", OsmID::Node(node)) => via = Some(*node),
+            ("to", OsmID::Way(way)) => to = Some(*way),
+            _ => {}
+        }
+    }
+    // Restrictions with via ways aren't supported yet
+    Some(TurnRestriction {
+        from: from?,
+        via: via?,
+        to: to?,
+        only,
+    })
+}
+
+pub fn is_driveable(tags: &Tags) -> bool {
+    tags.is_any(
+        "highway",
+        vec![
+            "motorway",
+            "motorway_link",
+            "trunk",
+            "trunk_link",
+            "primary",
+            "primary_link",
+            "secondary",
+            "secondary_link",
+            "tertiary",
+            "tertiary_link",
+            "residential",
+            "unclassified",
+            "living_street",
+            "service",
+            "road",
+        ],
+    ) && !tags.is_any("access", vec!["no", "private"])
+        && !tags.is("motor_vehicle", "no")
+        && !tags.is("area", "yes")
+}
+
+/// Can vehicles drive forwards and backwards along the way?
+fn driving_directions(tags: &Tags) -> (bool, bool) {
+    if tags.is_any("oneway", vec!["yes", "true", "1"])
+        || tags.is("junction", "roundabout")
+        || (tags.is_any("highway", vec!["motorway", "motorway_link"]) && !tags.is("oneway", "no"))
+    {
+        return (true, false);
+    }
+    if tags.is_any("oneway", vec!["-1", "reverse"]) {
+        return (false, true);
     }
-    bail!("No path");
+    (true, true)
 }
@@ -0,0 +1,361 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use fast_paths::{FastGraph, PathCalculator};
+use geo::{Coord, EuclideanDistance, Point};
+use osm_reader::NodeID;
+use utils::{Mercator, NodeMap};
+
+use crate::costs::CostConfig;
+use crate::route::{DrivingInput, DrivingRouter, ExtraEdges};
+use crate::{Intersection, IntersectionID, Road};
+
+/// Everything a `Router` needs from the model
+pub struct RouterGraph<'a> {
+    pub intersections: &'a Vec<Intersection>,
+    pub roads: &'a Vec<Road>,
+    pub node_map: &'a NodeMap<IntersectionID>,
+    pub cost_config: &'a CostConfig,
+}
+
+/// Answers shortest path queries over the walking network using the normal costs
+pub trait Router {
+    /// Finds the cheapest path from any source to any target, each with an initial cost in the
+    /// units of `walking_cost`. Returns the intersections along the path.
+    fn route(
+        &mut self,
+        graph: &RouterGraph,
+        sources: Vec<(IntersectionID, usize)>,
+        targets: Vec<(IntersectionID, usize)>,
+    ) -> Option<Vec<IntersectionID>>;
+
+    /// The prepared contraction hierarchy, if this router uses one
+    fn contraction_hierarchy(&self) -> Option<&FastGraph> {
+        None
+    }
+}
+
+/// A walking router for every set of costs used so far, and the driving router. Each one is built
+/// the first time it's needed, so switching between profiles only pays for preparation once.
+pub struct Routers {
+    skip_contraction_hierarchy: bool,
+    /// Keyed by `CostConfig::profile_hash`. Different costs could share a hash, so each entry
+    /// remembers its exact costs.
+    walking: HashMap<u64, Vec<(CostConfig, Box<dyn Router>)>>,
+    /// Edges for crossing severances mid-block, keyed like `walking`
+    jaywalk_edges: HashMap<u64, Vec<(CostConfig, ExtraEdges)>>,
+    driving: Option<DrivingRouter>,
+}
+
+impl Routers {
+    pub fn new(skip_contraction_hierarchy: bool) -> Self {
+        Self {
+            skip_contraction_hierarchy,
+            walking: HashMap::new(),
+            jaywalk_edges: HashMap::new(),
+            driving: None,
+        }
+    }
+
+    /// The router for `graph.cost_config`, building it if needed
+    pub fn get(&mut self, graph: &RouterGraph) -> &mut dyn Router {
+        let entries = self
+            .walking
+            .entry(graph.cost_config.profile_hash())
+            .or_default();
+        let idx = match entries.iter().position(|(costs, _)| costs == graph.cost_config) {
+            Some(idx) => idx,
+            None => {
+                let router: Box<dyn Router> = if self.skip_contraction_hierarchy {
+                    Box::new(BidirectionalAStar)
+                } else {
+                    info!("Preparing a contraction hierarchy for new costs");
+                    Box::new(ContractionHierarchy::new(graph))
+                };
+                entries.push((graph.cost_config.clone(), router));
+                entries.len() - 1
+            }
+        };
+        entries[idx].1.as_mut()
+    }
+
+    /// The driving router, building it from `input` if needed. `node_coords` are in WGS84.
+    pub fn driving(
+        &mut self,
+        input: &DrivingInput,
+        node_coords: &HashMap<NodeID, Coord>,
+        mercator: &Mercator,
+    ) -> &mut DrivingRouter {
+        self.driving.get_or_insert_with(|| {
+            info!("Building the driving network");
+            DrivingRouter::new(input, node_coords, mercator)
+        })
+    }
+
+    pub fn skip_contraction_hierarchy(&self) -> bool {
+        self.skip_contraction_hierarchy
+    }
+
+    /// The contraction hierarchy already prepared for some costs, if any
+    pub fn prepared(&self, cost_config: &CostConfig) -> Option<&FastGraph> {
+        self.walking
+            .get(&cost_config.profile_hash())?
+            .iter()
+            .find(|(costs, _)| costs == cost_config)?
+            .1
+            .contraction_hierarchy()
+    }
+
+    /// Uses a contraction hierarchy prepared earlier for some costs, like from a snapshot
+    pub fn insert_prepared(&mut self, cost_config: CostConfig, ch: FastGraph) {
+        let path_calc = fast_paths::create_calculator(&ch);
+        let router: Box<dyn Router> = Box::new(ContractionHierarchy { ch, path_calc });
+        let entries = self.walking.entry(cost_config.profile_hash()).or_default();
+        entries.retain(|(costs, _)| *costs != cost_config);
+        entries.push((cost_config, router));
+    }
+
+    /// The jaywalking edges already found for some costs, if any
+    pub fn jaywalk_edges(&self, cost_config: &CostConfig) -> Option<&ExtraEdges> {
+        self.jaywalk_edges
+            .get(&cost_config.profile_hash())?
+            .iter()
+            .find(|(costs, _)| costs == cost_config)
+            .map(|(_, edges)| edges)
+    }
+
+    pub fn insert_jaywalk_edges(&mut self, cost_config: CostConfig, edges: ExtraEdges) {
+        let entries = self
+            .jaywalk_edges
+            .entry(cost_config.profile_hash())
+            .or_default();
+        entries.retain(|(costs, _)| *costs != cost_config);
+        entries.push((cost_config, edges));
+    }
+
+    /// The driving router, if it's been built
+    pub fn prepared_driving(&self) -> Option<&DrivingRouter> {
+        self.driving.as_ref()
+    }
+
+    /// Uses a driving router built earlier. It doesn't depend on the walking network, so it
+    /// survives edits.
+    pub fn insert_driving(&mut self, driving: DrivingRouter) {
+        self.driving = Some(driving);
+    }
+
+    /// Gives up the driving router, to carry it over to new `Routers`
+    pub fn take_driving(&mut self) -> Option<DrivingRouter> {
+        self.driving.take()
+    }
+}
+
+/// Slow to prepare, but very fast to query
+pub struct ContractionHierarchy {
+    ch: FastGraph,
+    path_calc: PathCalculator,
+}
+
+impl ContractionHierarchy {
+    pub fn new(graph: &RouterGraph) -> Self {
+        let ch = prepare_ch(graph);
+        let path_calc = fast_paths::create_calculator(&ch);
+        Self { ch, path_calc }
+    }
+}
+
+fn prepare_ch(graph: &RouterGraph) -> FastGraph {
+    // Every walkable road is in the node map, which is shared by all costs
+    crate::cost_graph::build_graph(&graph.roads[..], graph.cost_config, graph.node_map)
+}
+
+impl Router for ContractionHierarchy {
+    fn route(
+        &mut self,
+        graph: &RouterGraph,
+        sources: Vec<(IntersectionID, usize)>,
+        targets: Vec<(IntersectionID, usize)>,
+    ) -> Option<Vec<IntersectionID>> {
+        let to_node =
+            |(i, cost): (IntersectionID, usize)| graph.node_map.get(i).map(|node| (node, cost));
+        let path = self.path_calc.calc_path_multiple_sources_and_targets(
+            &self.ch,
+            sources.into_iter().filter_map(to_node).collect(),
+            targets.into_iter().filter_map(to_node).collect(),
+        )?;
+        Some(
+            path.get_nodes()
+                .iter()
+                .map(|node| graph.node_map.translate_id(*node))
+                .collect(),
+        )
+    }
+
+    fn contraction_hierarchy(&self) -> Option<&FastGraph> {
+        Some(&self.ch)
+    }
+}
+
+/// Needs no preparation, so it's better for huge areas when only a few routes are needed. Searches
+/// from both ends at once, guided by straight-line distance.
+pub struct BidirectionalAStar;
+
+impl Router for BidirectionalAStar {
+    fn route(
+        &mut self,
+        graph: &RouterGraph,
+        sources: Vec<(IntersectionID, usize)>,
+        targets: Vec<(IntersectionID, usize)>,
+    ) -> Option<Vec<IntersectionID>> {
+        // Walking costs are at least the straight-line distance in cm, unless some kind of road
+        // is made cheaper
+        let scale = 100.0
+            * graph
+                .cost_config
+                .kind_multipliers
+                .values()
+                .fold(1.0_f64, |acc, x| acc.min(x.max(0.0)));
+        let point = |i: IntersectionID| graph.intersections[i.0].point;
+        let lower_bound = |from: Point, to: Point| (scale * from.euclidean_distance(&to)) as i64;
+        // A lower bound on the cost to reach any target, and from any source
+        let to_target = |i: IntersectionID| {
+            targets
+                .iter()
+                .map(|(t, extra)| lower_bound(point(i), point(*t)) + *extra as i64)
+                .min()
+                .unwrap_or(0)
+        };
+        let from_source = |i: IntersectionID| {
+            sources
+                .iter()
+                .map(|(s, initial)| lower_bound(point(*s), point(i)) + *initial as i64)
+                .min()
+                .unwrap_or(0)
+        };
+        // Average the two bounds, so both searches use consistent potentials. Keys are doubled to
+        // stay in integers.
+        let potential = |i: IntersectionID| to_target(i) - from_source(i);
+
+        let mut searches = [Search::default(), Search::default()];
+        for (i, cost) in &sources {
+            searches[0].visit(*i, *cost as i64, None, 2 * *cost as i64 + potential(*i));
+        }
+        for (i, cost) in &targets {
+            searches[1].visit(*i, *cost as i64, None, 2 * *cost as i64 - potential(*i));
+        }
+
+        let mut best: Option<(i64, IntersectionID)> = None;
+        for i in searches[0].cost.keys() {
+            if let Some(other) = searches[1].cost.get(i) {
+                let total = searches[0].cost[i] + other;
+                if best.map(|(b, _)| total < b).unwrap_or(true) {
+                    best = Some((total, *i));
+                }
+            }
+        }
+
+        loop {
+            let (Some(top0), Some(top1)) = (searches[0].peek(), searches[1].peek()) else {
+                break;
+            };
+            if best.map(|(b, _)| top0 + top1 >= 2 * b).unwrap_or(false) {
+                break;
+            }
+            // Expand the smaller side
+            let side = if top0 <= top1 { 0 } else { 1 };
+            let Some(i) = searches[side].pop() else {
+                continue;
+            };
+            let sign = if side == 0 { 1 } else { -1 };
+            for r in &graph.intersections[i.0].roads {
+                let road = &graph.roads[r.0];
+                // The search from the targets walks roads backwards
+                let forwards = (road.src_i == i) == (side == 0);
+                let Some(edge_cost) = graph.cost_config.cost_in_direction(road, forwards) else {
+                    continue;
+                };
+                let next = road.other_side(i);
+                let next_cost = searches[side].cost[&i] + edge_cost as i64;
+                if searches[side].visit(
+                    next,
+                    next_cost,
+                    Some(i),
+                    2 * next_cost + sign * potential(next),
+                ) {
+                    if let Some(other) = searches[1 - side].cost.get(&next) {
+                        let total = next_cost + other;
+                        if best.map(|(b, _)| total < b).unwrap_or(true) {
+                            best = Some((total, next));
+                        }
+                    }
+                }
+            }
+        }
+
+        let (_, meet) = best?;
+        let mut path = searches[0].trace(meet);
+        path.reverse();
+        path.extend(searches[1].trace(meet).into_iter().skip(1));
+        Some(path)
+    }
+}
+
+/// One direction of a bidirectional search
+#[derive(Default)]
+struct Search {
+    cost: HashMap<IntersectionID, i64>,
+    parent: HashMap<IntersectionID, IntersectionID>,
+    queue: BinaryHeap<Reverse<(i64, IntersectionID)>>,
+    settled: HashSet<IntersectionID>,
+}
+
+impl Search {
+    /// Returns true if this is the cheapest way found so far to reach `i`
+    fn visit(
+        &mut self,
+        i: IntersectionID,
+        cost: i64,
+        via: Option<IntersectionID>,
+        key: i64,
+    ) -> bool {
+        if self.settled.contains(&i) || self.cost.get(&i).map(|c| *c <= cost).unwrap_or(false) {
+            return false;
+        }
+        self.cost.insert(i, cost);
+        match via {
+            Some(via) => self.parent.insert(i, via),
+            None => self.parent.remove(&i),
+        };
+        self.queue.push(Reverse((key, i)));
+        true
+    }
+
+    /// The smallest key still queued, skipping anything already settled
+    fn peek(&mut self) -> Option<i64> {
+        while let Some(Reverse((key, i))) = self.queue.peek() {
+            if self.settled.contains(i) {
+                self.queue.pop();
+            } else {
+                return Some(*key);
+            }
+        }
+        None
+    }
+
+    fn pop(&mut self) -> Option<IntersectionID> {
+        self.peek()?;
+        let Reverse((_, i)) = self.queue.pop()?;
+        self.settled.insert(i);
+        Some(i)
+    }
+
+    /// The intersections from `i` back to where this search began
+    fn trace(&self, mut i: IntersectionID) -> Vec<IntersectionID> {
+        let mut path = vec![i];
+        while let Some(prev) = self.parent.get(&i) {
+            i = *prev;
+            path.push(i);
+        }
+        path
+    }
+}
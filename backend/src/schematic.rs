@@ -0,0 +1,203 @@
+use std::collections::HashSet;
+
+use geo::{Coord, LineString, Point};
+use geojson::{Feature, FeatureCollection, Geometry};
+
+use crate::neighbourhoods::ConnectivityGraph;
+use crate::MapModel;
+
+/// The length of every edge in the layout, in synthetic units
+const EDGE_LENGTH: f64 = 100.0;
+const ITERATIONS: usize = 300;
+/// How strongly nodes are pulled back towards their geographic position each iteration, from 0 to
+/// 1
+const ANCHOR_STRENGTH: f64 = 0.05;
+/// Nodes closer than this are pushed apart
+const MIN_SEPARATION: f64 = 0.75 * EDGE_LENGTH;
+
+/// Lays out the neighbourhood connectivity graph like a metro map. Edges are bent towards
+/// multiples of 45 degrees and similar lengths, while nodes stay close to where they really are
+/// relative to each other. Nodes are then snapped onto a grid, and each edge is drawn as a
+/// diagonal and a straight piece.
+///
+/// The output is GeoJSON, but in a synthetic coordinate space with y pointing north, not WGS84.
+/// Nodes are points with the `id`, `area`, and `population` from the graph. Edges are
+/// linestrings with `from`, `to`, `crossings`, and `min_detour`.
+pub fn metro_map(map: &MapModel, graph: &ConnectivityGraph) -> FeatureCollection {
+    let geo: Vec<Coord> = graph
+        .nodes
+        .iter()
+        .map(|n| map.mercator.pt_to_mercator(Coord { x: n.x, y: n.y }))
+        .collect();
+    let positions = layout(&geo, graph);
+
+    let mut features = Vec::new();
+    for (node, pt) in graph.nodes.iter().zip(&positions) {
+        let mut f = Feature::from(Geometry::from(&Point::from(*pt)));
+        f.set_property("id", node.id);
+        f.set_property("area", node.area);
+        f.set_property("population", node.population);
+        features.push(f);
+    }
+    for edge in &graph.edges {
+        let line = octilinear_line(positions[edge.from], positions[edge.to]);
+        let mut f = Feature::from(Geometry::from(&line));
+        f.set_property("from", edge.from);
+        f.set_property("to", edge.to);
+        f.set_property("crossings", edge.crossings);
+        f.set_property("min_detour", edge.min_detour);
+        features.push(f);
+    }
+
+    FeatureCollection {
+        features,
+        bbox: None,
+        foreign_members: Some(
+            serde_json::json!({
+                "coordinates": "synthetic",
+            })
+            .as_object()
+            .unwrap()
+            .clone(),
+        ),
+    }
+}
+
+/// Returns the position of every node, indexed like `geo`
+fn layout(geo: &[Coord], graph: &ConnectivityGraph) -> Vec<Coord> {
+    if geo.is_empty() {
+        return Vec::new();
+    }
+
+    // Scale the real positions so that the typical edge has about the right length
+    let mut lengths: Vec<f64> = graph
+        .edges
+        .iter()
+        .map(|e| distance(geo[e.from], geo[e.to]))
+        .filter(|x| *x > 0.0)
+        .collect();
+    lengths.sort_by(f64::total_cmp);
+    let scale = lengths
+        .get(lengths.len() / 2)
+        .map(|median| EDGE_LENGTH / median)
+        .unwrap_or(1.0);
+    let anchors: Vec<Coord> = geo
+        .iter()
+        .map(|pt| Coord {
+            x: (pt.x - geo[0].x) * scale,
+            y: (pt.y - geo[0].y) * scale,
+        })
+        .collect();
+    let mut positions = anchors.clone();
+
+    for iteration in 0..ITERATIONS {
+        let cooling = 1.0 - iteration as f64 / ITERATIONS as f64;
+        let mut moves = vec![Coord { x: 0.0, y: 0.0 }; positions.len()];
+
+        for (idx, pt) in positions.iter().enumerate() {
+            moves[idx] = moves[idx] + (anchors[idx] - *pt) * ANCHOR_STRENGTH;
+        }
+
+        // Pull both ends of each edge towards the nearest octilinear direction
+        for e in &graph.edges {
+            let (a, b) = (positions[e.from], positions[e.to]);
+            let angle = snap_angle((b.y - a.y).atan2(b.x - a.x));
+            let target = a + Coord {
+                x: EDGE_LENGTH * angle.cos(),
+                y: EDGE_LENGTH * angle.sin(),
+            };
+            let correction = (target - b) * 0.25;
+            moves[e.to] = moves[e.to] + correction;
+            moves[e.from] = moves[e.from] - correction;
+        }
+
+        for i in 0..positions.len() {
+            for j in (i + 1)..positions.len() {
+                let diff = positions[j] - positions[i];
+                let dist = diff.x.hypot(diff.y);
+                if dist >= MIN_SEPARATION {
+                    continue;
+                }
+                // Nodes in exactly the same place are pushed apart in an arbitrary direction
+                let direction = if dist > 0.0 {
+                    diff / dist
+                } else {
+                    Coord { x: 1.0, y: 0.0 }
+                };
+                let push = direction * ((MIN_SEPARATION - dist) / 2.0);
+                moves[j] = moves[j] + push;
+                moves[i] = moves[i] - push;
+            }
+        }
+
+        for (pt, change) in positions.iter_mut().zip(moves) {
+            *pt = *pt + change * cooling;
+        }
+    }
+
+    snap_to_grid(positions)
+}
+
+/// Rounds every position to a grid with half the edge length, moving nodes that land on the same
+/// spot to the closest free one
+fn snap_to_grid(positions: Vec<Coord>) -> Vec<Coord> {
+    let spacing = EDGE_LENGTH / 2.0;
+    let mut taken: HashSet<(i64, i64)> = HashSet::new();
+    let mut result = Vec::new();
+    for pt in positions {
+        let (x, y) = (
+            (pt.x / spacing).round() as i64,
+            (pt.y / spacing).round() as i64,
+        );
+        let mut radius = 0;
+        let spot = loop {
+            let mut candidates: Vec<(i64, i64)> = Vec::new();
+            for dy in -radius..=radius {
+                for dx in -radius..=radius {
+                    if dx.abs().max(dy.abs()) == radius && !taken.contains(&(x + dx, y + dy)) {
+                        candidates.push((x + dx, y + dy));
+                    }
+                }
+            }
+            if let Some(spot) = candidates
+                .into_iter()
+                .min_by_key(|(cx, cy)| (cx - x).pow(2) + (cy - y).pow(2))
+            {
+                break spot;
+            }
+            radius += 1;
+        };
+        taken.insert(spot);
+        result.push(Coord {
+            x: spot.0 as f64 * spacing,
+            y: spot.1 as f64 * spacing,
+        });
+    }
+    result
+}
+
+/// A diagonal piece followed by a horizontal or vertical piece, the usual way metro maps connect
+/// two points
+fn octilinear_line(a: Coord, b: Coord) -> LineString {
+    let (dx, dy) = (b.x - a.x, b.y - a.y);
+    let diagonal = dx.abs().min(dy.abs());
+    let bend = Coord {
+        x: a.x + diagonal * dx.signum(),
+        y: a.y + diagonal * dy.signum(),
+    };
+    if bend == a || bend == b {
+        LineString::new(vec![a, b])
+    } else {
+        LineString::new(vec![a, bend, b])
+    }
+}
+
+/// Rounds an angle in radians to the nearest multiple of 45 degrees
+fn snap_angle(angle: f64) -> f64 {
+    let step = std::f64::consts::FRAC_PI_4;
+    (angle / step).round() * step
+}
+
+fn distance(a: Coord, b: Coord) -> f64 {
+    (b.x - a.x).hypot(b.y - a.y)
+}
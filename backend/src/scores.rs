@@ -0,0 +1,81 @@
+use std::collections::{BTreeMap, HashMap};
+
+use geojson::{feature::Id, FeatureCollection};
+use serde::Serialize;
+
+use crate::heatmap::{heatmap_requests, score_request, HeatmapRequest};
+use crate::MapModel;
+
+/// Remembers the heatmap scores last sent to the frontend, so that after something changes, only
+/// the differences need to be sent. The frontend can then restyle features in place.
+#[derive(Default)]
+pub struct ScoreChannel {
+    version: usize,
+    // Keyed by desire line ID
+    scores: HashMap<usize, f64>,
+    /// From the last full heatmap, if one was made
+    request: Option<HeatmapRequest>,
+}
+
+/// Changes since the last version. Each desire line ID maps to its new properties, or null if the
+/// line no longer has a score.
+#[derive(Serialize)]
+pub struct ScorePatch {
+    version: usize,
+    patches: BTreeMap<usize, Option<serde_json::Value>>,
+}
+
+impl ScoreChannel {
+    /// Remember the full heatmap just sent to the frontend
+    pub fn reset(&mut self, heatmap: &FeatureCollection, request: &HeatmapRequest) {
+        self.version += 1;
+        self.request = Some(request.clone());
+        self.scores.clear();
+        for f in &heatmap.features {
+            if let (Some(Id::Number(id)), Some(score)) =
+                (&f.id, f.property("score").and_then(|x| x.as_f64()))
+            {
+                if let Some(id) = id.as_u64() {
+                    self.scores.insert(id as usize, score);
+                }
+            }
+        }
+    }
+
+    /// The request for the last full heatmap sent, if any
+    pub fn last_request(&self) -> Option<&HeatmapRequest> {
+        self.request.as_ref()
+    }
+}
+
+/// Recalculate all heatmap scores, and return only the ones that changed since the last poll
+pub fn poll(map: &mut MapModel) -> ScorePatch {
+    let request = map.score_channel.request.clone().unwrap_or_default();
+    let mut latest = HashMap::new();
+    for (id, req) in heatmap_requests(map, &request) {
+        if let Some((_, score)) = score_request(map, req, &request.bounds) {
+            latest.insert(id, score);
+        }
+    }
+
+    let mut patches = BTreeMap::new();
+    for (id, score) in &latest {
+        if map.score_channel.scores.get(id) != Some(score) {
+            patches.insert(*id, Some(serde_json::json!({ "score": score })));
+        }
+    }
+    for id in map.score_channel.scores.keys() {
+        if !latest.contains_key(id) {
+            patches.insert(*id, None);
+        }
+    }
+
+    if !patches.is_empty() {
+        map.score_channel.version += 1;
+        map.score_channel.scores = latest;
+    }
+    ScorePatch {
+        version: map.score_channel.version,
+        patches,
+    }
+}
@@ -1,67 +1,280 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
 use anyhow::Result;
-use utils::Tags;
+use fast_paths::FastGraph;
+use geo::{
+    Centroid, ConcaveHull, Contains, Coord, Intersects, LineString, MultiPoint, MultiPolygon,
+    Polygon,
+};
+use osm_reader::{NodeID, OsmID, RelationID, WayID};
+use utils::{Mercator, NodeMap, Tags};
 
+use crate::access::ConditionalAccess;
+use crate::areas::{Area, AreaKind};
+use crate::buildings::Building;
+use crate::costs::CostConfig;
+use crate::pois::Poi;
+use crate::progress::{self, Progress, ReportProgress};
+use crate::provenance::Provenance;
+use crate::route::{DrivingInput, DrivingRouter, DrivingWay, TurnRestriction};
+use crate::trails::WalkingRoute;
 use crate::{Intersection, IntersectionID, MapModel, Road, RoadID, RoadKind};
 
+pub struct ImportOptions {
+    pub import_streets_without_sidewalk_tagging: bool,
+    /// Annual average daily traffic per OSM way, if known
+    pub traffic_counts: HashMap<WayID, f64>,
+    /// Streets carrying at least this much traffic are always severances
+    pub severance_aadt: f64,
+    /// Route with bidirectional A* instead of preparing a contraction hierarchy
+    pub skip_contraction_hierarchy: bool,
+    /// A WGS84 boundary to use instead of a concave hull around the roads
+    pub boundary: Option<Polygon>,
+    /// Drop disconnected pieces of the walking network with less than this total length, in
+    /// meters
+    pub min_component_length: Option<f64>,
+    /// Merge dead-ends into intersections within this many meters
+    pub snap_tolerance: Option<f64>,
+}
+
+/// Builds the model, reporting each phase to `progress`
 pub fn scrape_osm(
     input_bytes: &[u8],
-    import_streets_without_sidewalk_tagging: bool,
+    opts: ImportOptions,
+    progress: &mut Progress,
 ) -> Result<MapModel> {
-    let graph = utils::osm2graph::Graph::new(
+    let import_streets_without_sidewalk_tagging = opts.import_streets_without_sidewalk_tagging;
+    let mut extras = ReadExtras::default();
+    let scraped = crate::split::read(
         input_bytes,
         |tags| classify(tags, import_streets_without_sidewalk_tagging).is_some(),
-        &mut utils::osm2graph::NullReader,
+        &mut ReportProgress::new(&mut extras, progress),
     )?;
+    // Only needed while reading relations
+    extras.area_ways = HashMap::new();
+    progress("splitting the network", progress::SPLIT);
+    let graph = scraped.split()?;
 
     // Copy all the fields
-    let intersections = graph
+    let mut intersections: Vec<Intersection> = graph
         .intersections
         .into_iter()
         .map(|i| Intersection {
-            id: IntersectionID(i.id.0),
+            id: i.id,
             point: i.point,
             node: i.osm_node,
-            roads: i.edges.into_iter().map(|e| RoadID(e.0)).collect(),
+            roads: i.edges,
         })
         .collect();
 
     // Add in a bit
-    let roads = graph
-        .edges
+    let mercator = graph.mercator;
+    let mut roads: Vec<Road> = crate::parallel::map(graph.edges, |e| Road {
+        access: extras.access_for_edge(&e),
+        id: e.id,
+        src_i: e.src,
+        dst_i: e.dst,
+        way: e.osm_way,
+        node1: e.osm_node1,
+        node2: e.osm_node2,
+        linestring: e.linestring,
+        kind: apply_traffic_counts(
+            classify(&e.osm_tags, import_streets_without_sidewalk_tagging).unwrap(),
+            opts.traffic_counts.get(&e.osm_way).cloned(),
+            opts.severance_aadt,
+        ),
+        aadt: opts.traffic_counts.get(&e.osm_way).cloned(),
+        // Calculated below
+        truncated: false,
+        // Set later, if elevation data is provided
+        gradient: None,
+        version: 0,
+        shared_with_cyclists: is_shared_cycleway(&e.osm_tags),
+        layer: get_layer(&e.osm_tags),
+        tags: e.osm_tags,
+    });
+    let mut geometry = crate::validate::validate(&mut intersections, &mut roads);
+    if let Some(tolerance) = opts.snap_tolerance {
+        geometry.snapped = crate::validate::snap_intersections(
+            &mut intersections,
+            &mut roads,
+            tolerance,
+            &mercator,
+        );
+    }
+    split_layered_intersections(&mut intersections, &mut roads);
+    let (boundary_polygon, clip_polygon) = match opts.boundary {
+        Some(ref polygon) => {
+            let polygon = mercator.to_mercator(polygon);
+            clip_to_boundary(&mut intersections, &mut roads, &polygon);
+            (polygon.clone(), polygon)
+        }
+        // The hull passes through the outermost roads, so only the extract's bounds say where
+        // roads were really cut off
+        None => (
+            concave_boundary(&roads).unwrap_or_else(|| graph.boundary_polygon.clone()),
+            graph.boundary_polygon,
+        ),
+    };
+    if let Some(min_length) = opts.min_component_length {
+        crate::components::prune(&mut intersections, &mut roads, min_length);
+    }
+
+    let buildings = crate::parallel::map(extras.buildings, |(way, ring, entrances)| Building {
+        way,
+        polygon: Polygon::new(mercator.to_mercator(&ring), Vec::new()),
+        entrances: entrances
+            .into_iter()
+            .map(|pt| mercator.pt_to_mercator(pt))
+            .collect(),
+    })
+    .into_iter()
+    .filter(|b| b.polygon.intersects(&boundary_polygon))
+    .collect();
+    let pois = extras
+        .pois
         .into_iter()
-        .map(|e| Road {
-            id: RoadID(e.id.0),
-            src_i: IntersectionID(e.src.0),
-            dst_i: IntersectionID(e.dst.0),
-            way: e.osm_way,
-            node1: e.osm_node1,
-            node2: e.osm_node2,
-            linestring: e.linestring,
-            kind: classify(&e.osm_tags, import_streets_without_sidewalk_tagging).unwrap(),
-            tags: e.osm_tags,
+        .map(|(category, pt)| Poi {
+            category,
+            point: mercator.pt_to_mercator(pt),
         })
+        .filter(|poi| boundary_polygon.contains(&poi.point))
         .collect();
+    for i in &mut intersections {
+        i.roads.sort();
+    }
+    let areas = crate::parallel::map(extras.areas, |(osm_id, kind, polygon)| Area {
+        osm_id,
+        kind,
+        polygon: mercator.to_mercator(&polygon),
+    })
+    .into_iter()
+    .filter(|a| a.polygon.intersects(&boundary_polygon))
+    .collect();
+    let osm = crate::osc::OsmStore {
+        import_streets_without_sidewalk_tagging,
+        severance_aadt: opts.severance_aadt,
+        ..extras.osm
+    };
+    let mut provenance = Provenance::new(input_bytes, &opts, &roads, &intersections);
+    provenance.geometry = geometry;
+    let (closest_intersection, node_map, mut routers) =
+        crate::route::build_router(&intersections, &roads, opts.skip_contraction_hierarchy);
+    let driving = DrivingInput {
+        ways: extras.driving_ways,
+        turn_restrictions: extras.turn_restrictions,
+    };
+    let (driving_router, ch) = prepare_routers(
+        &driving,
+        &osm.node_coords,
+        &mercator,
+        &roads,
+        &node_map,
+        opts.skip_contraction_hierarchy,
+        progress,
+    );
+    routers.insert_driving(driving_router);
+    if let Some(ch) = ch {
+        routers.insert_prepared(CostConfig::default(), ch);
+    }
+    progress("building indices", progress::INDEX);
+    let closest_road = crate::route::build_closest_road(&roads);
+    let closest_severance = crate::route::build_closest_severance(&roads);
+    let road_index = crate::route::build_road_index(&roads);
+    let edge_index = crate::adjacency::EdgeIndex::new(&roads);
+    let tag_index = crate::tag_index::TagIndex::new(&roads);
 
-    let (closest_intersection, node_map, ch) = crate::route::build_router(&intersections, &roads);
-    let path_calc = fast_paths::create_calculator(&ch);
-
-    Ok(MapModel {
+    let mut map = MapModel {
         roads,
         intersections,
-        mercator: graph.mercator,
+        mercator,
         closest_intersection,
+        closest_road,
+        closest_severance,
+        road_index,
+        edge_index,
         node_map,
-        ch,
-        path_calc,
-        boundary_polygon: graph.boundary_polygon,
-    })
+        routers,
+        boundary_polygon,
+        clip_polygon,
+        boundary_buffer: crate::boundary::DEFAULT_BOUNDARY_BUFFER,
+        tag_index,
+        driving,
+        walking_routes: extras.walking_routes,
+        buildings,
+        pois,
+        areas,
+        osm,
+        edits: Default::default(),
+        score_channel: Default::default(),
+        cost_config: CostConfig::default(),
+        provenance,
+        population: None,
+        tiles: Default::default(),
+        generated_layers: Default::default(),
+        render_version: 0,
+    };
+    crate::boundary::mark_truncated_roads(&mut map);
+    progress("done", progress::DONE);
+    Ok(map)
+}
+
+/// Builds the driving router and, unless skipped, the contraction hierarchy for the default
+/// walking costs. Otherwise the contraction hierarchy is prepared the first time anything routes,
+/// with no feedback.
+#[cfg(not(feature = "parallel"))]
+fn prepare_routers(
+    driving: &DrivingInput,
+    node_coords: &HashMap<NodeID, Coord>,
+    mercator: &Mercator,
+    roads: &Vec<Road>,
+    node_map: &NodeMap<IntersectionID>,
+    skip_contraction_hierarchy: bool,
+    progress: &mut Progress,
+) -> (DrivingRouter, Option<FastGraph>) {
+    progress("building the driving network", progress::DRIVING);
+    let driving = DrivingRouter::new(driving, node_coords, mercator);
+    if skip_contraction_hierarchy {
+        return (driving, None);
+    }
+    progress(
+        "preparing a contraction hierarchy",
+        progress::CONTRACTION_HIERARCHY,
+    );
+    let ch = crate::cost_graph::build_graph(&roads[..], &CostConfig::default(), node_map);
+    (driving, Some(ch))
+}
+
+/// The two graphs are independent, so they're prepared at the same time
+#[cfg(feature = "parallel")]
+fn prepare_routers(
+    driving: &DrivingInput,
+    node_coords: &HashMap<NodeID, Coord>,
+    mercator: &Mercator,
+    roads: &Vec<Road>,
+    node_map: &NodeMap<IntersectionID>,
+    skip_contraction_hierarchy: bool,
+    progress: &mut Progress,
+) -> (DrivingRouter, Option<FastGraph>) {
+    progress(
+        "building the driving network and preparing a contraction hierarchy",
+        progress::DRIVING,
+    );
+    rayon::join(
+        || DrivingRouter::new(driving, node_coords, mercator),
+        || {
+            (!skip_contraction_hierarchy).then(|| {
+                crate::cost_graph::build_graph(&roads[..], &CostConfig::default(), node_map)
+            })
+        },
+    )
 }
 
 /// This function classifies an OSM way as a RoadKind. If it returns `None`, then the way is
 /// totally excluded from the walking graph.
 // TODO This should probably be configurable per region. In Hong Kong, primary and above are
 // severances. In some places, maybe secondary or tertiary should also be considered severances.
-fn classify(tags: &Tags, import_streets_without_sidewalk_tagging: bool) -> Option<RoadKind> {
+pub fn classify(tags: &Tags, import_streets_without_sidewalk_tagging: bool) -> Option<RoadKind> {
     if !tags.has("highway") || tags.is("highway", "proposed") || tags.is("area", "yes") {
         return None;
     }
@@ -75,8 +288,12 @@ fn classify(tags: &Tags, import_streets_without_sidewalk_tagging: bool) -> Optio
         if tags.has("indoor") || tags.is("highway", "corridor") {
             return Some(RoadKind::Indoors);
         }
-        if tags.has_any(vec!["layer", "bridge", "tunnel"]) {
-            return Some(RoadKind::BridgeOrTunnel);
+        let layer = get_layer(tags);
+        if (tags.has("tunnel") && !tags.is("tunnel", "no")) || layer < 0 {
+            return Some(RoadKind::Tunnel);
+        }
+        if (tags.has("bridge") && !tags.is("bridge", "no")) || layer > 0 {
+            return Some(RoadKind::Bridge);
         }
         if tags.is("footway", "crossing") {
             return Some(RoadKind::Crossing);
@@ -88,6 +305,15 @@ fn classify(tags: &Tags, import_streets_without_sidewalk_tagging: bool) -> Optio
         return Some(RoadKind::Crossing);
     }
 
+    // Cycleways shared with pedestrians are walkable like any other path. Cycle-only ones are
+    // excluded, regardless of import_streets_without_sidewalk_tagging.
+    if tags.is("highway", "cycleway") {
+        if is_shared_cycleway(tags) {
+            return Some(RoadKind::Footway);
+        }
+        return None;
+    }
+
     // Big roads are always severances.
     // TODO Big roads without separate sidewalks aren't walkable at all right now.
     // https://github.com/dabreegster/severance_snape/issues/5
@@ -134,7 +360,6 @@ fn classify(tags: &Tags, import_streets_without_sidewalk_tagging: bool) -> Optio
             "unclassified",
             "service",
             "living_street",
-            "cycleway",
         ],
     ) && !tags.is("foot", "no")
     {
@@ -152,3 +377,419 @@ fn classify(tags: &Tags, import_streets_without_sidewalk_tagging: bool) -> Optio
     // TODO wait, why's this the fallback case?
     Some(RoadKind::Severance)
 }
+
+/// Where a street has a traffic count, the count decides whether it's a severance, regardless of
+/// how it's tagged: busy streets become severances, and quiet ones are walkable with traffic.
+/// Footways, crossings, and streets without a count keep their kind.
+pub fn apply_traffic_counts(kind: RoadKind, aadt: Option<f64>, severance_aadt: f64) -> RoadKind {
+    match (aadt, kind) {
+        (Some(aadt), RoadKind::WithTraffic | RoadKind::Severance) => {
+            if aadt >= severance_aadt {
+                RoadKind::Severance
+            } else {
+                RoadKind::WithTraffic
+            }
+        }
+        (_, kind) => kind,
+    }
+}
+
+/// A cycleway that pedestrians may also use, either mixed or segregated
+pub fn is_shared_cycleway(tags: &Tags) -> bool {
+    tags.is("highway", "cycleway")
+        && (tags.is_any("foot", vec!["yes", "designated"]) || tags.has("segregated"))
+}
+
+/// Ways at different layers or levels sometimes share a node where they pass over each other, like
+/// a footbridge over a path. Split those intersections, so routes can't jump between levels. Where
+/// every way at one layer or level ends at the shared node, like a ramp up to a bridge, it's a real
+/// connection. A way ending beside others passing through, like a stub off the path under a
+/// bridge, only connects to those at its own layer or level.
+fn split_layered_intersections(intersections: &mut Vec<Intersection>, roads: &mut Vec<Road>) {
+    let mut splits = 0;
+    for idx in 0..intersections.len() {
+        let i = intersections[idx].id;
+        let mut groups: BTreeMap<(i8, String), Vec<RoadID>> = BTreeMap::new();
+        let mut roads_per_way: HashMap<WayID, usize> = HashMap::new();
+        for r in &intersections[idx].roads {
+            let road = &roads[r.0];
+            let level = road.tags.get("level").cloned().unwrap_or_default();
+            groups.entry((road.layer, level)).or_default().push(*r);
+            *roads_per_way.entry(road.way).or_insert(0) += 1;
+        }
+        let connector = groups
+            .values()
+            .any(|group| group.iter().all(|r| roads_per_way[&roads[r.0].way] == 1));
+        if groups.len() < 2 || connector {
+            continue;
+        }
+
+        // The first group keeps the original intersection
+        for group in groups.into_values().skip(1) {
+            let new_i = IntersectionID(intersections.len());
+            for r in &group {
+                let road = &mut roads[r.0];
+                if road.src_i == i {
+                    road.src_i = new_i;
+                }
+                if road.dst_i == i {
+                    road.dst_i = new_i;
+                }
+            }
+            intersections[idx].roads.retain(|r| !group.contains(r));
+            intersections.push(Intersection {
+                id: new_i,
+                point: intersections[idx].point,
+                node: intersections[idx].node,
+                roads: group,
+            });
+        }
+        splits += 1;
+    }
+    info!("Split {splits} intersections between ways at different layers or levels");
+}
+
+/// How closely the default boundary follows the road network. Higher values approach the convex
+/// hull; lower values hug the network more tightly, but risk cutting into sparse areas.
+const BOUNDARY_CONCAVITY: f64 = 2.0;
+
+/// A concave hull around every road point, so coastlines and irregularly shaped extracts don't
+/// count large empty areas. Returns `None` when there are too few roads to form a polygon.
+fn concave_boundary(roads: &[Road]) -> Option<Polygon> {
+    let points: MultiPoint = roads
+        .iter()
+        .flat_map(|r| r.linestring.0.iter().copied())
+        .map(geo::Point::from)
+        .collect();
+    if points.0.len() < 3 {
+        return None;
+    }
+    let hull = points.concave_hull(BOUNDARY_CONCAVITY);
+    if hull.exterior().0.len() < 4 {
+        return None;
+    }
+    Some(hull)
+}
+
+/// Drops roads entirely outside the boundary. Roads crossing the boundary are kept whole, and
+/// later treated as truncated if they dead-end just outside.
+fn clip_to_boundary(
+    intersections: &mut Vec<Intersection>,
+    roads: &mut Vec<Road>,
+    boundary: &Polygon,
+) {
+    let dropped = retain_roads(intersections, roads, |r| r.linestring.intersects(boundary));
+    info!("Dropped {dropped} roads outside the boundary");
+}
+
+/// Keeps only roads matching `keep`, dropping intersections left without any roads and
+/// renumbering IDs. Returns the number of roads dropped.
+pub fn retain_roads<F: Fn(&Road) -> bool>(
+    intersections: &mut Vec<Intersection>,
+    roads: &mut Vec<Road>,
+    keep: F,
+) -> usize {
+    let before = roads.len();
+    let mut road_ids: HashMap<RoadID, RoadID> = HashMap::new();
+    for mut road in std::mem::take(roads) {
+        if !keep(&road) {
+            continue;
+        }
+        let id = RoadID(roads.len());
+        road_ids.insert(road.id, id);
+        road.id = id;
+        roads.push(road);
+    }
+
+    let mut intersection_ids: HashMap<IntersectionID, IntersectionID> = HashMap::new();
+    for mut i in std::mem::take(intersections) {
+        i.roads = i
+            .roads
+            .into_iter()
+            .filter_map(|r| road_ids.get(&r).copied())
+            .collect();
+        if i.roads.is_empty() {
+            continue;
+        }
+        let id = IntersectionID(intersections.len());
+        intersection_ids.insert(i.id, id);
+        i.id = id;
+        intersections.push(i);
+    }
+    for road in roads.iter_mut() {
+        road.src_i = intersection_ids[&road.src_i];
+        road.dst_i = intersection_ids[&road.dst_i];
+    }
+    before - roads.len()
+}
+
+/// Parses the OSM `layer` tag, defaulting to ground level
+pub fn get_layer(tags: &Tags) -> i8 {
+    tags.get("layer")
+        .and_then(|x| x.parse::<i8>().ok())
+        .unwrap_or(0)
+}
+
+/// Remembers things from the OSM input besides the walkable ways: gates with time restrictions,
+/// walking route relations, buildings, points of interest, and the driveable network
+#[derive(Default)]
+struct ReadExtras {
+    gates: HashMap<NodeID, ConditionalAccess>,
+    gates_per_way: HashMap<WayID, Vec<NodeID>>,
+    walking_routes: HashMap<RelationID, WalkingRoute>,
+    entrances: HashSet<NodeID>,
+    // In WGS84, with the entrances
+    buildings: Vec<(WayID, LineString, Vec<Coord>)>,
+    // In WGS84, with the category. Areas are represented by their centroid.
+    pois: Vec<(String, Coord)>,
+    // In WGS84. Relations come after every way, and any way could be a multipolygon member, tagged
+    // or not, so remember them all until relations are read.
+    area_ways: HashMap<WayID, Vec<Coord>>,
+    // In WGS84
+    areas: Vec<(OsmID, AreaKind, MultiPolygon)>,
+    osm: crate::osc::OsmStore,
+    driving_ways: Vec<DrivingWay>,
+    turn_restrictions: Vec<TurnRestriction>,
+}
+
+impl utils::osm2graph::OsmReader for ReadExtras {
+    fn node(&mut self, id: NodeID, pt: Coord, tags: Tags) {
+        if tags.has("entrance") || tags.has("door") {
+            self.entrances.insert(id);
+        }
+        if let Some(category) = crate::pois::category(&tags) {
+            self.pois.push((category, pt));
+        }
+        if tags.has("barrier") {
+            let access = ConditionalAccess::parse(&tags);
+            if !access.is_empty() {
+                self.gates.insert(id, access);
+            }
+        }
+    }
+
+    fn way(
+        &mut self,
+        id: WayID,
+        node_ids: &Vec<NodeID>,
+        node_mapping: &HashMap<NodeID, Coord>,
+        tags: &Tags,
+    ) {
+        if tags.has("building") && node_ids.len() >= 4 && node_ids[0] == *node_ids.last().unwrap() {
+            let pts: Option<Vec<Coord>> = node_ids
+                .iter()
+                .map(|node| node_mapping.get(node).cloned())
+                .collect();
+            if let Some(pts) = pts {
+                let entrances = node_ids
+                    .iter()
+                    .filter(|node| self.entrances.contains(node))
+                    .map(|node| node_mapping[node])
+                    .collect();
+                self.buildings.push((id, LineString::new(pts), entrances));
+            }
+        }
+
+        if let Some(category) = crate::pois::category(tags) {
+            let pts: Option<Vec<Coord>> = node_ids
+                .iter()
+                .map(|node| node_mapping.get(node).cloned())
+                .collect();
+            if let Some(centroid) = pts.and_then(|pts| LineString::new(pts).centroid()) {
+                self.pois.push((category, centroid.into()));
+            }
+        }
+
+        if tags.has("highway") {
+            for node in node_ids {
+                if let Some(pt) = node_mapping.get(node) {
+                    self.osm.node_coords.insert(*node, *pt);
+                }
+            }
+            self.osm.way_nodes.insert(id, node_ids.clone());
+            if crate::route::is_driveable(tags) {
+                self.driving_ways.push(DrivingWay {
+                    way: id,
+                    nodes: node_ids.clone(),
+                    tags: tags.clone(),
+                });
+            }
+        }
+
+        let pts: Option<Vec<Coord>> = node_ids
+            .iter()
+            .map(|node| node_mapping.get(node).cloned())
+            .collect();
+        if let Some(pts) = pts {
+            if let Some(kind) = crate::areas::kind(tags) {
+                if let Some(polygon) =
+                    crate::areas::assemble_multipolygon(vec![pts.clone()], Vec::new())
+                {
+                    self.areas.push((OsmID::Way(id), kind, polygon));
+                }
+            }
+            self.area_ways.insert(id, pts);
+        }
+
+        for node in node_ids {
+            if self.gates.contains_key(node) {
+                self.gates_per_way.entry(id).or_default().push(*node);
+            }
+        }
+    }
+
+    fn relation(&mut self, id: RelationID, members: &Vec<(String, OsmID)>, tags: &Tags) {
+        if let Some(restriction) = crate::route::parse_turn_restriction(members, tags) {
+            self.turn_restrictions.push(restriction);
+        }
+
+        if tags.is("type", "multipolygon") {
+            if let Some(kind) = crate::areas::kind(tags) {
+                let mut outers = Vec::new();
+                let mut inners = Vec::new();
+                for (role, member) in members {
+                    let OsmID::Way(way) = member else {
+                        continue;
+                    };
+                    let Some(pts) = self.area_ways.get(way) else {
+                        continue;
+                    };
+                    if role == "inner" {
+                        inners.push(pts.clone());
+                    } else {
+                        outers.push(pts.clone());
+                    }
+                }
+                if let Some(polygon) = crate::areas::assemble_multipolygon(outers, inners) {
+                    self.areas.push((OsmID::Relation(id), kind, polygon));
+                }
+            }
+        }
+
+        if tags.is("type", "route") && tags.is_any("route", vec!["foot", "hiking", "walking"]) {
+            self.walking_routes.insert(
+                id,
+                WalkingRoute {
+                    name: tags.get("name").cloned(),
+                    ways: members
+                        .iter()
+                        .filter_map(|(_, member)| match member {
+                            OsmID::Way(way) => Some(*way),
+                            _ => None,
+                        })
+                        .collect(),
+                },
+            );
+        }
+    }
+}
+
+impl ReadExtras {
+    /// Combines restrictions on the way with those from any gates along this piece of it
+    fn access_for_edge(&self, edge: &crate::split::Edge) -> ConditionalAccess {
+        let mut access = ConditionalAccess::parse(&edge.osm_tags);
+        let (Some(gates), Some(nodes)) = (
+            self.gates_per_way.get(&edge.osm_way),
+            self.osm.way_nodes.get(&edge.osm_way),
+        ) else {
+            return access;
+        };
+        // The edge covers the way's nodes from node1 to node2
+        let Some(start) = nodes.iter().position(|n| *n == edge.osm_node1) else {
+            return access;
+        };
+        let Some(len) = nodes[start + 1..].iter().position(|n| *n == edge.osm_node2) else {
+            return access;
+        };
+        for node in &nodes[start..=start + 1 + len] {
+            if gates.contains(node) {
+                access.extend(&self.gates[node]);
+            }
+        }
+        access
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use geo::Point;
+
+    use super::*;
+
+    fn road(id: usize, way: i64, layer: i8, src_i: usize, dst_i: usize) -> Road {
+        Road {
+            id: RoadID(id),
+            src_i: IntersectionID(src_i),
+            dst_i: IntersectionID(dst_i),
+            way: WayID(way),
+            node1: NodeID(src_i as i64),
+            node2: NodeID(dst_i as i64),
+            linestring: LineString::new(vec![Coord { x: 0.0, y: 0.0 }; 2]),
+            tags: Tags(Default::default()),
+            kind: RoadKind::Footway,
+            shared_with_cyclists: false,
+            layer,
+            access: ConditionalAccess::default(),
+            aadt: None,
+            truncated: false,
+            gradient: None,
+            version: 0,
+        }
+    }
+
+    /// Intersection 0 is shared by every road; each road's other end gets its own intersection
+    fn intersections(roads: &[Road]) -> Vec<Intersection> {
+        (0..=roads.len())
+            .map(|idx| Intersection {
+                id: IntersectionID(idx),
+                node: NodeID(idx as i64),
+                point: Point::new(0.0, 0.0),
+                roads: roads
+                    .iter()
+                    .filter(|r| r.src_i.0 == idx || r.dst_i.0 == idx)
+                    .map(|r| r.id)
+                    .collect(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_bridge_over_path_with_stub() {
+        let mut roads = vec![
+            // The bridge and the path both pass through the shared node
+            road(0, 1, 1, 1, 0),
+            road(1, 1, 1, 0, 2),
+            road(2, 2, 0, 3, 0),
+            road(3, 2, 0, 0, 4),
+            // A stub off the path ends there
+            road(4, 3, 0, 0, 5),
+        ];
+        let mut intersections = intersections(&roads);
+        split_layered_intersections(&mut intersections, &mut roads);
+
+        assert_eq!(intersections.len(), 7);
+        assert_eq!(
+            intersections[0].roads,
+            vec![RoadID(2), RoadID(3), RoadID(4)]
+        );
+        assert_eq!(intersections[6].roads, vec![RoadID(0), RoadID(1)]);
+        assert_eq!(roads[0].dst_i, IntersectionID(6));
+        assert_eq!(roads[1].src_i, IntersectionID(6));
+        assert_eq!(roads[4].src_i, IntersectionID(0));
+    }
+
+    #[test]
+    fn test_ramp_up_to_bridge() {
+        let mut roads = vec![
+            road(0, 1, 1, 1, 0),
+            road(1, 1, 1, 0, 2),
+            // The ramp is the only way at ground level, and ends on the bridge
+            road(2, 2, 0, 3, 0),
+        ];
+        let mut intersections = intersections(&roads);
+        split_layered_intersections(&mut intersections, &mut roads);
+
+        assert_eq!(intersections.len(), 4);
+        assert_eq!(intersections[0].roads.len(), 3);
+    }
+}
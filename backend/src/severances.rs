@@ -0,0 +1,51 @@
+use std::collections::HashMap;
+
+use geo::EuclideanLength;
+use geojson::FeatureCollection;
+
+use crate::crossings::gap_per_road;
+use crate::heatmap::{score_request, severance_desire_lines, DesireLineBounds};
+use crate::{MapModel, RoadKind};
+
+/// Summarizes every severance road, so the worst can be ranked. Each has its `length` and the
+/// `gap_length` between crossings in meters, and the `mean_detour` and `max_detour` ratio of the
+/// desire lines crossing it, both null if none could be scored. The worst severances on average
+/// come first.
+pub fn score_severances(map: &mut MapModel, bounds: &DesireLineBounds) -> FeatureCollection {
+    let mut detours: HashMap<_, Vec<f64>> = HashMap::new();
+    for line in severance_desire_lines(map) {
+        if let Some((_, score)) = score_request(map, line.line.into(), bounds) {
+            detours.entry(line.road).or_default().push(score);
+        }
+    }
+    let gaps = gap_per_road(map);
+
+    let mut scored = Vec::new();
+    for r in &map.roads {
+        if r.kind != RoadKind::Severance {
+            continue;
+        }
+        let scores = detours.remove(&r.id).unwrap_or_default();
+        let mean = (!scores.is_empty()).then(|| scores.iter().sum::<f64>() / scores.len() as f64);
+        let max = scores.iter().cloned().reduce(f64::max);
+
+        let mut f = r.to_gj(&map.mercator);
+        f.set_property("way", r.way.to_string());
+        f.set_property("length", r.linestring.euclidean_length());
+        f.set_property("gap_length", gaps.get(&r.id).cloned());
+        f.set_property("desire_lines", scores.len());
+        f.set_property("mean_detour", mean);
+        f.set_property("max_detour", max);
+        scored.push((mean, f));
+    }
+    scored.sort_by(|a, b| {
+        b.0.unwrap_or(f64::NEG_INFINITY)
+            .total_cmp(&a.0.unwrap_or(f64::NEG_INFINITY))
+    });
+
+    FeatureCollection {
+        features: scored.into_iter().map(|(_, f)| f).collect(),
+        bbox: None,
+        foreign_members: None,
+    }
+}
@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use geo::EuclideanLength;
+use geojson::{Feature, FeatureCollection, Geometry};
+
+use crate::{IntersectionID, MapModel};
+
+/// How much further would somebody have to drive between the two ends of a footpath? High ratios
+/// mark valuable shortcuts for pedestrians. Returns the footpath and the driving route, with the
+/// lengths and ratio as foreign members.
+pub fn shortcut_score(map: &mut MapModel, way: i64) -> Result<FeatureCollection> {
+    let roads: Vec<_> = map
+        .roads
+        .iter()
+        .filter(|r| r.way.0 == way && crate::route::walking_cost(r).is_some())
+        .collect();
+    if roads.is_empty() {
+        bail!("No walkable way {way}");
+    }
+
+    // The way may be split into many roads. Its ends are intersections used by only one of them.
+    let mut count: HashMap<IntersectionID, usize> = HashMap::new();
+    for r in &roads {
+        *count.entry(r.src_i).or_insert(0) += 1;
+        *count.entry(r.dst_i).or_insert(0) += 1;
+    }
+    let mut ends: Vec<IntersectionID> = count
+        .into_iter()
+        .filter(|(_, count)| *count == 1)
+        .map(|(i, _)| i)
+        .collect();
+    ends.sort();
+    if ends.len() != 2 {
+        bail!("Way {way} is a loop or has gaps, so it has no clear endpoints");
+    }
+
+    let walking_length: f64 = roads.iter().map(|r| r.linestring.euclidean_length()).sum();
+    let mut features: Vec<Feature> = roads.iter().map(|r| r.to_gj(&map.mercator)).collect();
+
+    let pt1 = map.intersections[ends[0].0].point.into();
+    let pt2 = map.intersections[ends[1].0].point.into();
+    let (driving_route, snap1, snap2) = map
+        .routers
+        .driving(&map.driving, &map.osm.node_coords, &map.mercator)
+        .route(pt1, pt2)?;
+    let driving_length = driving_route.euclidean_length();
+    let mut f = Feature::from(Geometry::from(&map.mercator.to_wgs84(&driving_route)));
+    f.set_property("kind", "driving");
+    features.push(f);
+
+    Ok(FeatureCollection {
+        features,
+        bbox: None,
+        foreign_members: Some(
+            serde_json::json!({
+                "walking_length": walking_length,
+                "driving_length": driving_length,
+                "ratio": driving_length / walking_length,
+                // How far each end of the footpath is from the nearest driveable intersection
+                "snap_distances": [snap1, snap2],
+            })
+            .as_object()
+            .unwrap()
+            .clone(),
+        ),
+    })
+}
@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use fast_paths::FastGraph;
+use geo::Polygon;
+use osm_reader::RelationID;
+use serde::{Deserialize, Serialize};
+use utils::{Mercator, NodeMap};
+
+use crate::areas::Area;
+use crate::buildings::Building;
+use crate::costs::CostConfig;
+use crate::osc::OsmStore;
+use crate::pois::Poi;
+use crate::population::Population;
+use crate::provenance::Provenance;
+use crate::route::{DrivingInput, DrivingRouter};
+use crate::router::RouterGraph;
+use crate::trails::WalkingRoute;
+use crate::{Intersection, IntersectionID, MapModel, Road};
+
+/// Every snapshot starts with this, then a little-endian `VERSION`
+const MAGIC: &[u8; 8] = b"SEVSNAP\0";
+/// Bump whenever anything serialized changes, so old snapshots are rejected instead of
+/// misread
+const VERSION: u32 = 10;
+
+/// Everything in a `MapModel` that's slow to rebuild. Indices, caches, results, and the current
+/// costs are not included.
+#[derive(Deserialize)]
+struct Snapshot {
+    mercator: Mercator,
+    roads: Vec<Road>,
+    intersections: Vec<Intersection>,
+    boundary_polygon: Polygon,
+    clip_polygon: Polygon,
+    boundary_buffer: f64,
+    walking_routes: HashMap<RelationID, WalkingRoute>,
+    buildings: Vec<Building>,
+    pois: Vec<Poi>,
+    areas: Vec<Area>,
+    osm: OsmStore,
+    provenance: Provenance,
+    population: Option<Population>,
+    driving_input: DrivingInput,
+    /// Only if it was built before saving
+    driving: Option<DrivingRouter>,
+    render_version: usize,
+    skip_contraction_hierarchy: bool,
+    contraction_hierarchy: Option<PreparedRouter>,
+}
+
+/// The contraction hierarchy for the default costs, with the intersection for each of its nodes,
+/// so the `NodeMap` it was prepared with can be restored exactly
+#[derive(Deserialize)]
+struct PreparedRouter {
+    node_order: Vec<IntersectionID>,
+    ch: FastGraph,
+}
+
+#[derive(Serialize)]
+struct PreparedRouterRef<'a> {
+    node_order: Vec<IntersectionID>,
+    ch: &'a FastGraph,
+}
+
+/// Borrows the same fields as `Snapshot`, to serialize without copying
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+    mercator: &'a Mercator,
+    roads: &'a Vec<Road>,
+    intersections: &'a Vec<Intersection>,
+    boundary_polygon: &'a Polygon,
+    clip_polygon: &'a Polygon,
+    boundary_buffer: f64,
+    walking_routes: &'a HashMap<RelationID, WalkingRoute>,
+    buildings: &'a Vec<Building>,
+    pois: &'a Vec<Poi>,
+    areas: &'a Vec<Area>,
+    osm: &'a OsmStore,
+    provenance: &'a Provenance,
+    population: &'a Option<Population>,
+    driving_input: &'a DrivingInput,
+    driving: Option<&'a DrivingRouter>,
+    render_version: usize,
+    skip_contraction_hierarchy: bool,
+    contraction_hierarchy: Option<PreparedRouterRef<'a>>,
+}
+
+/// Serializes the network, with a versioned header. If `include_contraction_hierarchy` is true,
+/// the contraction hierarchy for the default costs is prepared if needed and included, so loading
+/// doesn't have to prepare it again. The driving router is included if it's been built.
+pub fn to_bytes(map: &mut MapModel, include_contraction_hierarchy: bool) -> Result<Vec<u8>> {
+    let default_costs = CostConfig::default();
+    let skip_contraction_hierarchy = map.routers.skip_contraction_hierarchy();
+    if include_contraction_hierarchy {
+        if skip_contraction_hierarchy {
+            bail!("This model routes without a contraction hierarchy");
+        }
+        if map.routers.prepared(&default_costs).is_none() {
+            let graph = RouterGraph {
+                intersections: &map.intersections,
+                roads: &map.roads,
+                node_map: &map.node_map,
+                cost_config: &default_costs,
+            };
+            map.routers.get(&graph);
+        }
+    }
+
+    let snapshot = SnapshotRef {
+        mercator: &map.mercator,
+        roads: &map.roads,
+        intersections: &map.intersections,
+        boundary_polygon: &map.boundary_polygon,
+        clip_polygon: &map.clip_polygon,
+        boundary_buffer: map.boundary_buffer,
+        walking_routes: &map.walking_routes,
+        buildings: &map.buildings,
+        pois: &map.pois,
+        areas: &map.areas,
+        osm: &map.osm,
+        provenance: &map.provenance,
+        population: &map.population,
+        driving_input: &map.driving,
+        driving: map.routers.prepared_driving(),
+        render_version: map.render_version,
+        skip_contraction_hierarchy,
+        contraction_hierarchy: if include_contraction_hierarchy {
+            map.routers
+                .prepared(&default_costs)
+                .map(|ch| PreparedRouterRef {
+                    node_order: node_order(map),
+                    ch,
+                })
+        } else {
+            None
+        },
+    };
+
+    let mut out = MAGIC.to_vec();
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    bincode::serialize_into(&mut out, &snapshot)?;
+    Ok(out)
+}
+
+/// Loads a model written by `to_bytes`, rebuilding the indices
+pub fn from_bytes(input_bytes: &[u8]) -> Result<MapModel> {
+    let Some(rest) = input_bytes.strip_prefix(MAGIC.as_slice()) else {
+        bail!("This isn't a severance snapshot");
+    };
+    if rest.len() < 4 {
+        bail!("The snapshot is truncated");
+    }
+    let version = u32::from_le_bytes(rest[0..4].try_into().unwrap());
+    if version != VERSION {
+        bail!("The snapshot is version {version}, but only version {VERSION} can be loaded; please import again");
+    }
+    let snapshot: Snapshot = bincode::deserialize(&rest[4..])?;
+
+    let (mut closest_intersection, mut node_map, mut routers) = crate::route::build_router(
+        &snapshot.intersections,
+        &snapshot.roads,
+        snapshot.skip_contraction_hierarchy,
+    );
+    if let Some(prepared) = snapshot.contraction_hierarchy {
+        if prepared.ch.get_num_nodes() != prepared.node_order.len() {
+            bail!("The snapshot's contraction hierarchy doesn't match its network");
+        }
+        // Use exactly the node IDs the contraction hierarchy was prepared with
+        node_map = NodeMap::new();
+        for i in prepared.node_order {
+            node_map.get_or_insert(i);
+        }
+        closest_intersection =
+            crate::route::build_closest_intersection(&snapshot.intersections, &node_map);
+        routers.insert_prepared(CostConfig::default(), prepared.ch);
+    }
+    if let Some(driving) = snapshot.driving {
+        routers.insert_driving(driving);
+    }
+    let roads = snapshot.roads;
+    let closest_road = crate::route::build_closest_road(&roads);
+    let closest_severance = crate::route::build_closest_severance(&roads);
+    let road_index = crate::route::build_road_index(&roads);
+    let edge_index = crate::adjacency::EdgeIndex::new(&roads);
+    let tag_index = crate::tag_index::TagIndex::new(&roads);
+
+    Ok(MapModel {
+        roads,
+        intersections: snapshot.intersections,
+        mercator: snapshot.mercator,
+        closest_intersection,
+        closest_road,
+        closest_severance,
+        road_index,
+        edge_index,
+        node_map,
+        routers,
+        boundary_polygon: snapshot.boundary_polygon,
+        clip_polygon: snapshot.clip_polygon,
+        boundary_buffer: snapshot.boundary_buffer,
+        tag_index,
+        driving: snapshot.driving_input,
+        walking_routes: snapshot.walking_routes,
+        buildings: snapshot.buildings,
+        pois: snapshot.pois,
+        areas: snapshot.areas,
+        osm: snapshot.osm,
+        edits: Default::default(),
+        score_channel: Default::default(),
+        cost_config: CostConfig::default(),
+        provenance: snapshot.provenance,
+        population: snapshot.population,
+        tiles: Default::default(),
+        generated_layers: Default::default(),
+        render_version: snapshot.render_version,
+    })
+}
+
+/// The intersection for each node of the router, in order
+fn node_order(map: &MapModel) -> Vec<IntersectionID> {
+    let mut nodes: Vec<(usize, IntersectionID)> = map
+        .intersections
+        .iter()
+        .filter_map(|i| Some((map.node_map.get(i.id)?, i.id)))
+        .collect();
+    nodes.sort();
+    nodes.into_iter().map(|(_, i)| i).collect()
+}
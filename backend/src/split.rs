@@ -0,0 +1,221 @@
+//! Splits OSM ways into a graph of roads and intersections. Parsing is serial, but with the
+//! `parallel` feature, each way is split and projected on its own thread.
+
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use geo::{ConvexHull, Coord, LineString, MultiPoint, Point, Polygon, Rect};
+use osm_reader::{Element, NodeID, WayID};
+use utils::osm2graph::OsmReader;
+use utils::{Mercator, Tags};
+
+use crate::{IntersectionID, RoadID};
+
+/// Every way kept while parsing, not split yet
+pub struct Scraped {
+    ways: Vec<Way>,
+    /// In WGS84
+    node_coords: HashMap<NodeID, Coord>,
+    /// The extract's own bounds, if recorded, in WGS84
+    bounds: Option<Rect>,
+}
+
+struct Way {
+    id: WayID,
+    nodes: Vec<NodeID>,
+    tags: Tags,
+}
+
+/// Ways split at every node they share with another, projected to Mercator
+pub struct Graph {
+    pub edges: Vec<Edge>,
+    pub intersections: Vec<GraphIntersection>,
+    pub mercator: Mercator,
+    /// The extract's bounds if recorded, or else a convex hull around the ways
+    pub boundary_polygon: Polygon,
+}
+
+/// The piece of a way between two intersections
+pub struct Edge {
+    pub id: RoadID,
+    pub src: IntersectionID,
+    pub dst: IntersectionID,
+    pub osm_way: WayID,
+    pub osm_node1: NodeID,
+    pub osm_node2: NodeID,
+    pub osm_tags: Tags,
+    pub linestring: LineString,
+}
+
+pub struct GraphIntersection {
+    pub id: IntersectionID,
+    pub osm_node: NodeID,
+    pub point: Point,
+    pub edges: Vec<RoadID>,
+}
+
+/// Parses osm.pbf or osm.xml, passing every element to `reader`, and keeps the ways where
+/// `is_road` is true
+pub fn read<F: Fn(&Tags) -> bool, R: OsmReader>(
+    input_bytes: &[u8],
+    is_road: F,
+    reader: &mut R,
+) -> Result<Scraped> {
+    let mut node_coords: HashMap<NodeID, Coord> = HashMap::new();
+    let mut ways = Vec::new();
+    let mut bounds = None;
+    osm_reader::parse(input_bytes, |elem| match elem {
+        Element::Bounds {
+            min_lon,
+            min_lat,
+            max_lon,
+            max_lat,
+        } => {
+            bounds = Some(Rect::new(
+                Coord {
+                    x: min_lon,
+                    y: min_lat,
+                },
+                Coord {
+                    x: max_lon,
+                    y: max_lat,
+                },
+            ));
+        }
+        Element::Node {
+            id, lon, lat, tags, ..
+        } => {
+            let pt = Coord { x: lon, y: lat };
+            node_coords.insert(id, pt);
+            reader.node(id, pt, to_tags(tags));
+        }
+        Element::Way {
+            id, node_ids, tags, ..
+        } => {
+            let nodes = node_ids.to_vec();
+            let tags = to_tags(tags);
+            reader.way(id, &nodes, &node_coords, &tags);
+            if is_road(&tags) {
+                ways.push(Way { id, nodes, tags });
+            }
+        }
+        Element::Relation {
+            id, tags, members, ..
+        } => {
+            reader.relation(id, &members.to_vec(), &to_tags(tags));
+        }
+        _ => {}
+    })?;
+    Ok(Scraped {
+        ways,
+        node_coords,
+        bounds,
+    })
+}
+
+impl Scraped {
+    /// Splits ways at every node used more than once, by different ways or by a way looping back on
+    /// itself. Nodes missing from the input, like those just outside a clipped extract, are
+    /// skipped.
+    pub fn split(mut self) -> Result<Graph> {
+        let node_coords = &self.node_coords;
+        for way in &mut self.ways {
+            way.nodes.retain(|n| node_coords.contains_key(n));
+        }
+        self.ways.retain(|way| way.nodes.len() >= 2);
+
+        let boundary = match self.bounds {
+            Some(rect) => rect.to_polygon(),
+            None => MultiPoint::from(
+                self.ways
+                    .iter()
+                    .flat_map(|way| &way.nodes)
+                    .map(|n| node_coords[n])
+                    .collect::<Vec<_>>(),
+            )
+            .convex_hull(),
+        };
+        let Some(mercator) = Mercator::from(boundary.clone()) else {
+            bail!("No roads or bounds in the input");
+        };
+
+        let mut uses_per_node: HashMap<NodeID, usize> = HashMap::new();
+        for way in &self.ways {
+            for node in &way.nodes {
+                *uses_per_node.entry(*node).or_insert(0) += 1;
+            }
+        }
+        info!("Splitting {} ways into roads", self.ways.len());
+        let pieces = crate::parallel::map(self.ways, |way| {
+            let mut pieces = Vec::new();
+            let mut start = 0;
+            for idx in 1..way.nodes.len() {
+                let is_last = idx == way.nodes.len() - 1;
+                if !is_last && uses_per_node[&way.nodes[idx]] < 2 {
+                    continue;
+                }
+                let linestring = LineString::new(
+                    way.nodes[start..=idx]
+                        .iter()
+                        .map(|n| mercator.pt_to_mercator(node_coords[n]))
+                        .collect(),
+                );
+                pieces.push((way.nodes[start], way.nodes[idx], linestring));
+                start = idx;
+            }
+            (way, pieces)
+        });
+
+        // Number everything in the order of the input
+        let mut intersection_per_node: HashMap<NodeID, IntersectionID> = HashMap::new();
+        let mut intersections: Vec<(NodeID, Vec<RoadID>)> = Vec::new();
+        let mut edges = Vec::new();
+        for (way, pieces) in pieces {
+            for (node1, node2, linestring) in pieces {
+                let id = RoadID(edges.len());
+                let mut endpoint = |node: NodeID| {
+                    *intersection_per_node.entry(node).or_insert_with(|| {
+                        intersections.push((node, Vec::new()));
+                        IntersectionID(intersections.len() - 1)
+                    })
+                };
+                let src = endpoint(node1);
+                let dst = endpoint(node2);
+                intersections[src.0].1.push(id);
+                if dst != src {
+                    intersections[dst.0].1.push(id);
+                }
+                edges.push(Edge {
+                    id,
+                    src,
+                    dst,
+                    osm_way: way.id,
+                    osm_node1: node1,
+                    osm_node2: node2,
+                    osm_tags: way.tags.clone(),
+                    linestring,
+                });
+            }
+        }
+        let intersections = crate::parallel::map(
+            intersections.into_iter().enumerate().collect(),
+            |(idx, (osm_node, edges))| GraphIntersection {
+                id: IntersectionID(idx),
+                osm_node,
+                point: mercator.pt_to_mercator(node_coords[&osm_node]).into(),
+                edges,
+            },
+        );
+
+        Ok(Graph {
+            edges,
+            intersections,
+            boundary_polygon: mercator.to_mercator(&boundary),
+            mercator,
+        })
+    }
+}
+
+fn to_tags<K: ToString, V: ToString>(tags: impl IntoIterator<Item = (K, V)>) -> Tags {
+    Tags(crate::merge::copy_tags(tags).into_iter().collect())
+}
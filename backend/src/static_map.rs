@@ -0,0 +1,225 @@
+use anyhow::{bail, Result};
+use geo::{BoundingRect, Coord, Intersects, LineString, Rect};
+use geojson::FeatureCollection;
+use serde::Deserialize;
+use tiny_skia::{LineCap, Paint, PathBuilder, Pixmap, Stroke, Transform};
+
+use crate::{MapModel, RoadKind};
+
+/// The input to `renderStaticMap`
+#[derive(Deserialize)]
+pub struct StaticMapRequest {
+    /// In pixels
+    width: u32,
+    height: u32,
+    /// WGS84 `[min_lon, min_lat, max_lon, max_lat]` to show, defaulting to the whole map
+    #[serde(default)]
+    bbox: Option<[f64; 4]>,
+    #[serde(default)]
+    format: ImageFormat,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    #[default]
+    Svg,
+    Png,
+}
+
+// Matching the web app's styling
+const HEATMAP_LIMITS: [f64; 6] = [1.0, 4.0, 7.0, 10.0, 13.0, 15.0];
+const HEATMAP_COLORS: [&str; 5] = ["#CDE594", "#80C6A3", "#1F9EB7", "#186290", "#080C54"];
+
+fn kind_color(kind: &RoadKind) -> &'static str {
+    match kind {
+        RoadKind::Footway | RoadKind::Bridge | RoadKind::Tunnel => "#000000",
+        RoadKind::Indoors => "#808080",
+        RoadKind::WithTraffic => "#4C3926",
+        RoadKind::Crossing => "#008000",
+        RoadKind::Severance => "#FF0000",
+    }
+}
+
+fn score_color(score: Option<f64>) -> &'static str {
+    let Some(score) = score else {
+        return "#000000";
+    };
+    let bucket = HEATMAP_LIMITS[1..]
+        .iter()
+        .position(|limit| score < *limit)
+        .unwrap_or(HEATMAP_COLORS.len() - 1);
+    HEATMAP_COLORS[bucket]
+}
+
+/// A line to draw, in pixels
+struct Line {
+    pts: Vec<Coord>,
+    color: &'static str,
+    width: f32,
+}
+
+/// Draws the classified network, and the heatmap on top if there is one, without a browser. Returns
+/// SVG text or PNG bytes.
+pub fn render(
+    map: &MapModel,
+    req: &StaticMapRequest,
+    heatmap: Option<&FeatureCollection>,
+) -> Result<Vec<u8>> {
+    if req.width == 0 || req.height == 0 {
+        bail!("The image must be at least 1 pixel wide and tall");
+    }
+    let bbox = match req.bbox {
+        Some([x1, y1, x2, y2]) => Rect::new(Coord { x: x1, y: y1 }, Coord { x: x2, y: y2 }),
+        None => map.mercator.wgs84_bounds,
+    };
+    let projection = Projection::new(bbox, req.width, req.height);
+
+    let mut lines = Vec::new();
+    let mut add = |linestring: &LineString, color: &'static str, width: f32| {
+        let visible = linestring
+            .bounding_rect()
+            .map(|r| r.intersects(&bbox))
+            .unwrap_or(false);
+        if visible {
+            lines.push(Line {
+                pts: linestring
+                    .0
+                    .iter()
+                    .map(|pt| projection.project(*pt))
+                    .collect(),
+                color,
+                width,
+            });
+        }
+    };
+    for r in &map.roads {
+        let width = if r.kind == RoadKind::Severance {
+            3.0
+        } else {
+            1.5
+        };
+        add(
+            &map.mercator.to_wgs84(&r.linestring),
+            kind_color(&r.kind),
+            width,
+        );
+    }
+    for f in heatmap.map(|gj| &gj.features).into_iter().flatten() {
+        let Some(linestring) = f
+            .geometry
+            .as_ref()
+            .and_then(|g| LineString::try_from(g.value.clone()).ok())
+        else {
+            continue;
+        };
+        let score = f.property("score").and_then(|x| x.as_f64());
+        add(&linestring, score_color(score), 5.0);
+    }
+
+    match req.format {
+        ImageFormat::Svg => Ok(to_svg(&lines, req.width, req.height).into_bytes()),
+        ImageFormat::Png => to_png(&lines, req.width, req.height),
+    }
+}
+
+/// Fits a WGS84 box into the image with Web Mercator, keeping the aspect ratio
+struct Projection {
+    center: Coord,
+    scale: f64,
+    width: f64,
+    height: f64,
+}
+
+impl Projection {
+    fn new(bbox: Rect, width: u32, height: u32) -> Self {
+        let min = web_mercator(bbox.min());
+        let max = web_mercator(bbox.max());
+        let (width, height) = (width as f64, height as f64);
+        let scale = (width / (max.x - min.x)).min(height / (max.y - min.y));
+        Self {
+            center: Coord {
+                x: (min.x + max.x) / 2.0,
+                y: (min.y + max.y) / 2.0,
+            },
+            scale: if scale.is_finite() { scale } else { 1.0 },
+            width,
+            height,
+        }
+    }
+
+    fn project(&self, pt: Coord) -> Coord {
+        let pt = web_mercator(pt);
+        Coord {
+            x: self.width / 2.0 + (pt.x - self.center.x) * self.scale,
+            // y points down in images
+            y: self.height / 2.0 - (pt.y - self.center.y) * self.scale,
+        }
+    }
+}
+
+fn web_mercator(pt: Coord) -> Coord {
+    Coord {
+        x: pt.x.to_radians(),
+        y: pt.y.to_radians().tan().asinh(),
+    }
+}
+
+fn to_svg(lines: &[Line], width: u32, height: u32) -> String {
+    let mut out = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}">"#
+    );
+    out.push_str(r#"<rect width="100%" height="100%" fill="white"/>"#);
+    for line in lines {
+        let pts: Vec<String> = line
+            .pts
+            .iter()
+            .map(|pt| format!("{:.1},{:.1}", pt.x, pt.y))
+            .collect();
+        out.push_str(&format!(
+            r#"<polyline points="{}" fill="none" stroke="{}" stroke-width="{}" stroke-linecap="round" stroke-linejoin="round"/>"#,
+            pts.join(" "),
+            line.color,
+            line.width
+        ));
+    }
+    out.push_str("</svg>");
+    out
+}
+
+fn to_png(lines: &[Line], width: u32, height: u32) -> Result<Vec<u8>> {
+    let Some(mut pixmap) = Pixmap::new(width, height) else {
+        bail!("Can't make a {width}x{height} image");
+    };
+    pixmap.fill(tiny_skia::Color::WHITE);
+    for line in lines {
+        let mut path = PathBuilder::new();
+        for (idx, pt) in line.pts.iter().enumerate() {
+            if idx == 0 {
+                path.move_to(pt.x as f32, pt.y as f32);
+            } else {
+                path.line_to(pt.x as f32, pt.y as f32);
+            }
+        }
+        let Some(path) = path.finish() else {
+            continue;
+        };
+        let mut paint = Paint::default();
+        let (r, g, b) = parse_hex(line.color);
+        paint.set_color_rgba8(r, g, b, 255);
+        paint.anti_alias = true;
+        let stroke = Stroke {
+            width: line.width,
+            line_cap: LineCap::Round,
+            ..Default::default()
+        };
+        pixmap.stroke_path(&path, &paint, &stroke, Transform::identity(), None);
+    }
+    Ok(pixmap.encode_png()?)
+}
+
+/// Parses a "#RRGGBB" color
+fn parse_hex(color: &str) -> (u8, u8, u8) {
+    let channel = |idx: usize| u8::from_str_radix(&color[idx..idx + 2], 16).unwrap_or(0);
+    (channel(1), channel(3), channel(5))
+}
@@ -0,0 +1,67 @@
+use geo::{Closest, ClosestPoint, Coord, LineString, Point};
+use geojson::{Feature, FeatureCollection, Geometry};
+
+use crate::route::walking_cost;
+use crate::{MapModel, RoadKind};
+
+/// Dead-ends this close to another road are reported, by default, in meters
+pub const DEFAULT_MAX_GAP: f64 = 5.0;
+
+/// Finds walkable dead-ends ending within `max_gap` meters of a road they don't connect to. Each
+/// is a LineString from the dead-end to the closest point on that road, with the dead-end `road`
+/// and the `other_road` (as stable IDs), the `distance`, and a `kind` of `gap` for another
+/// walkable road, usually a digitization mistake, or `severance` for a possible missing crossing.
+/// Dead-ends caused by clipping the extract are skipped.
+pub fn find_missing_links(map: &MapModel, max_gap: f64) -> FeatureCollection {
+    let mut features = Vec::new();
+    for i in &map.intersections {
+        if i.roads.len() != 1 {
+            continue;
+        }
+        let stub = &map.roads[i.roads[0].0];
+        if stub.truncated || walking_cost(stub).is_none() {
+            continue;
+        }
+        let other_end = stub.other_side(i.id);
+        let pt: Coord = i.point.into();
+
+        let Some((other, distance)) = map
+            .road_index
+            .nearest_neighbor_iter_with_distance_2(&i.point)
+            .map(|(obj, dist_squared)| (&map.roads[obj.data.0], dist_squared.sqrt()))
+            .take_while(|(_, distance)| *distance <= max_gap)
+            // Roads at the other end are already connected
+            .find(|(road, _)| {
+                road.id != stub.id
+                    && road.src_i != other_end
+                    && road.dst_i != other_end
+                    && road.layer == stub.layer
+                    && (road.kind == RoadKind::Severance || walking_cost(road).is_some())
+            })
+        else {
+            continue;
+        };
+        let closest = match other.linestring.closest_point(&Point::from(pt)) {
+            Closest::Intersection(pt) | Closest::SinglePoint(pt) => pt,
+            Closest::Indeterminate => continue,
+        };
+
+        let mut f = Feature::from(Geometry::from(
+            &map.mercator
+                .to_wgs84(&LineString::new(vec![pt, closest.into()])),
+        ));
+        f.set_property("road", stub.stable_id());
+        f.set_property("other_road", other.stable_id());
+        f.set_property("distance", distance);
+        f.set_property(
+            "kind",
+            if other.kind == RoadKind::Severance {
+                "severance"
+            } else {
+                "gap"
+            },
+        );
+        features.push(f);
+    }
+    FeatureCollection::from_iter(features)
+}
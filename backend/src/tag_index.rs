@@ -0,0 +1,50 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::{Road, RoadID};
+
+/// An inverted index from OSM tags to the roads that have them. Edits renumber roads, so it's
+/// rebuilt from scratch after any change.
+#[derive(Default)]
+pub struct TagIndex {
+    // key => value => roads
+    index: HashMap<String, HashMap<String, BTreeSet<RoadID>>>,
+}
+
+impl TagIndex {
+    pub fn new(roads: &Vec<Road>) -> Self {
+        let mut index = Self::default();
+        for r in roads {
+            for (k, v) in &r.tags.0 {
+                index
+                    .index
+                    .entry(k.to_string())
+                    .or_default()
+                    .entry(v.to_string())
+                    .or_default()
+                    .insert(r.id);
+            }
+        }
+        index
+    }
+
+    /// Roads with the tag `key`. If `value` is specified, the tag must have that value. Sorted by
+    /// ID.
+    pub fn roads_with_tag(&self, key: &str, value: Option<&str>) -> Vec<RoadID> {
+        let Some(values) = self.index.get(key) else {
+            return Vec::new();
+        };
+        match value {
+            Some(value) => values
+                .get(value)
+                .map(|roads| roads.iter().cloned().collect())
+                .unwrap_or_default(),
+            None => {
+                let mut roads: BTreeSet<RoadID> = BTreeSet::new();
+                for set in values.values() {
+                    roads.extend(set.iter().cloned());
+                }
+                roads.into_iter().collect()
+            }
+        }
+    }
+}
@@ -0,0 +1,722 @@
+use std::collections::{BTreeMap, HashMap};
+use std::f64::consts::PI;
+
+use anyhow::Result;
+use geo::{
+    BooleanOps, BoundingRect, Contains, Coord, Geometry, Intersects, LineString, MapCoords,
+    MultiLineString, MultiPolygon, Point, Polygon, Rect, Simplify,
+};
+use geojson::{feature::Id, FeatureCollection};
+use serde_json::Value;
+
+use crate::export::ExportSink;
+
+/// Tiles are this many units across, the usual MVT extent
+const EXTENT: f64 = 4096.0;
+/// Geometry is kept this many units past the edge of each tile, so lines and outlines don't show
+/// seams between tiles
+const BUFFER: f64 = 64.0;
+/// How far simplified geometry can stray from the original, in tile units
+const SIMPLIFY_TOLERANCE: f64 = 1.0;
+
+/// Layers of features that can be cut into Mapbox Vector Tiles on demand, so big areas don't have
+/// to be sent to the frontend as one huge GeoJSON blob
+#[derive(Default)]
+pub struct TileSource {
+    layers: BTreeMap<String, Vec<SourceFeature>>,
+}
+
+struct SourceFeature {
+    id: Option<u64>,
+    /// In WGS84
+    geometry: Geometry,
+    bounds: Rect,
+    properties: Vec<(String, Value)>,
+}
+
+impl TileSource {
+    pub fn has_layer(&self, name: &str) -> bool {
+        self.layers.contains_key(name)
+    }
+
+    /// Forgets every layer, so they're rebuilt the next time they're needed
+    pub fn clear(&mut self) {
+        self.layers.clear();
+    }
+
+    /// Forgets one layer, so it's rebuilt the next time it's needed
+    pub fn remove_layer(&mut self, name: &str) {
+        self.layers.remove(name);
+    }
+
+    /// Replaces a layer with the features from some WGS84 GeoJSON. Null properties are dropped.
+    pub fn set_layer(&mut self, name: &str, gj: &FeatureCollection) {
+        let mut features = Vec::new();
+        for f in &gj.features {
+            let Some(geometry) = f
+                .geometry
+                .as_ref()
+                .and_then(|g| Geometry::try_from(g.value.clone()).ok())
+            else {
+                continue;
+            };
+            let Some(bounds) = geometry.bounding_rect() else {
+                continue;
+            };
+            let id = match f.id {
+                Some(Id::Number(ref n)) => n.as_u64(),
+                _ => None,
+            };
+            let properties = f
+                .properties
+                .iter()
+                .flatten()
+                .filter(|(_, v)| !v.is_null())
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect();
+            features.push(SourceFeature {
+                id,
+                geometry,
+                bounds,
+                properties,
+            });
+        }
+        self.layers.insert(name.to_string(), features);
+    }
+
+    /// Encodes tile `z/x/y` in the usual Web Mercator tiling scheme. Geometry is clipped to the
+    /// tile and simplified. Layers with nothing in the tile are left out, so an empty tile writes
+    /// nothing.
+    pub fn render(&self, z: u32, x: u32, y: u32, sink: &mut dyn ExportSink) -> Result<()> {
+        let tile = TileCoords { z, x, y };
+        let bounds = tile.wgs84_bounds();
+        let clip = Rect::new(
+            Coord {
+                x: -BUFFER,
+                y: -BUFFER,
+            },
+            Coord {
+                x: EXTENT + BUFFER,
+                y: EXTENT + BUFFER,
+            },
+        )
+        .to_polygon();
+
+        for (name, features) in &self.layers {
+            let mut layer = LayerEncoder::default();
+            for f in features {
+                if !f.bounds.intersects(&bounds) {
+                    continue;
+                }
+                let projected = f.geometry.map_coords(|c| tile.project(c));
+                if let Some((geometry_type, commands)) = encode_geometry(&projected, &clip) {
+                    layer.add_feature(f.id, &f.properties, geometry_type, commands);
+                }
+            }
+            if !layer.features.is_empty() {
+                let mut out = Vec::new();
+                write_bytes(&mut out, 3, &layer.finish(name));
+                sink.write_bytes(&out)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+struct TileCoords {
+    z: u32,
+    x: u32,
+    y: u32,
+}
+
+impl TileCoords {
+    fn tiles_across(&self) -> f64 {
+        2.0_f64.powi(self.z as i32)
+    }
+
+    /// From WGS84 to tile units, with y pointing down
+    fn project(&self, pt: Coord) -> Coord {
+        let n = self.tiles_across();
+        let lat = pt.y.to_radians();
+        Coord {
+            x: ((pt.x + 180.0) / 360.0 * n - self.x as f64) * EXTENT,
+            y: ((1.0 - lat.tan().asinh() / PI) / 2.0 * n - self.y as f64) * EXTENT,
+        }
+    }
+
+    /// The tile's area in WGS84, including the buffer
+    fn wgs84_bounds(&self) -> Rect {
+        let n = self.tiles_across();
+        let pad = BUFFER / EXTENT;
+        let unproject = |tx: f64, ty: f64| Coord {
+            x: tx / n * 360.0 - 180.0,
+            y: (PI * (1.0 - 2.0 * ty / n)).sinh().atan().to_degrees(),
+        };
+        Rect::new(
+            unproject(self.x as f64 - pad, self.y as f64 - pad),
+            unproject(self.x as f64 + 1.0 + pad, self.y as f64 + 1.0 + pad),
+        )
+    }
+}
+
+const POINT: u32 = 1;
+const LINESTRING: u32 = 2;
+const POLYGON: u32 = 3;
+
+/// Clips, simplifies, and rounds geometry in tile units, then encodes it as MVT drawing commands.
+/// Returns the geometry type and commands, or `None` if nothing is left.
+fn encode_geometry(geometry: &Geometry, clip: &Polygon) -> Option<(u32, Vec<u32>)> {
+    let encoder = GeometryEncoder::default();
+    match geometry {
+        Geometry::Point(pt) => encoder.points(&[*pt], clip),
+        Geometry::MultiPoint(pts) => encoder.points(&pts.0, clip),
+        Geometry::LineString(ls) => encoder.lines(&MultiLineString::new(vec![ls.clone()]), clip),
+        Geometry::MultiLineString(mls) => encoder.lines(mls, clip),
+        Geometry::Polygon(p) => encoder.polygons(&MultiPolygon::new(vec![p.clone()]), clip),
+        Geometry::MultiPolygon(mp) => encoder.polygons(mp, clip),
+        _ => None,
+    }
+}
+
+#[derive(Default)]
+struct GeometryEncoder {
+    commands: Vec<u32>,
+    /// Coordinates are relative to the previous one, even across parts
+    cursor: (i32, i32),
+}
+
+impl GeometryEncoder {
+    fn points(mut self, pts: &[Point], clip: &Polygon) -> Option<(u32, Vec<u32>)> {
+        let pts: Vec<(i32, i32)> = pts
+            .iter()
+            .filter(|pt| clip.contains(*pt))
+            .map(|pt| round(pt.0))
+            .collect();
+        if pts.is_empty() {
+            return None;
+        }
+        self.commands.push(command(1, pts.len()));
+        for pt in pts {
+            self.push_point(pt);
+        }
+        Some((POINT, self.commands))
+    }
+
+    fn lines(mut self, lines: &MultiLineString, clip: &Polygon) -> Option<(u32, Vec<u32>)> {
+        for line in clip.clip(lines, false) {
+            let pts = simplify_and_round(&line);
+            if pts.len() < 2 {
+                continue;
+            }
+            self.draw(&pts);
+        }
+        if self.commands.is_empty() {
+            return None;
+        }
+        Some((LINESTRING, self.commands))
+    }
+
+    fn polygons(mut self, polygons: &MultiPolygon, clip: &Polygon) -> Option<(u32, Vec<u32>)> {
+        for polygon in polygons.intersection(clip) {
+            let mut exterior = simplify_and_round(polygon.exterior());
+            if !self.ring(&mut exterior, true) {
+                // Holes without their exterior would be drawn as exteriors
+                continue;
+            }
+            for interior in polygon.interiors() {
+                let mut hole = simplify_and_round(interior);
+                self.ring(&mut hole, false);
+            }
+        }
+        if self.commands.is_empty() {
+            return None;
+        }
+        Some((POLYGON, self.commands))
+    }
+
+    /// Draws a closed ring, which must wind one way for exteriors and the other for holes. Returns
+    /// false if the ring collapsed when rounded.
+    fn ring(&mut self, pts: &mut Vec<(i32, i32)>, exterior: bool) -> bool {
+        // The last point repeats the first; ClosePath draws that edge
+        if pts.len() > 1 && pts.first() == pts.last() {
+            pts.pop();
+        }
+        if pts.len() < 3 {
+            return false;
+        }
+        let area = signed_area(pts);
+        if area == 0 {
+            return false;
+        }
+        // With y pointing down, exteriors have positive area by this formula
+        if (area > 0) != exterior {
+            pts.reverse();
+        }
+        self.draw(pts);
+        self.commands.push(command(7, 1));
+        true
+    }
+
+    fn draw(&mut self, pts: &[(i32, i32)]) {
+        self.commands.push(command(1, 1));
+        self.push_point(pts[0]);
+        self.commands.push(command(2, pts.len() - 1));
+        for pt in &pts[1..] {
+            self.push_point(*pt);
+        }
+    }
+
+    fn push_point(&mut self, pt: (i32, i32)) {
+        self.commands.push(zigzag(pt.0 - self.cursor.0));
+        self.commands.push(zigzag(pt.1 - self.cursor.1));
+        self.cursor = pt;
+    }
+}
+
+fn simplify_and_round(line: &LineString) -> Vec<(i32, i32)> {
+    let mut pts: Vec<(i32, i32)> = line
+        .simplify(&SIMPLIFY_TOLERANCE)
+        .0
+        .into_iter()
+        .map(round)
+        .collect();
+    pts.dedup();
+    pts
+}
+
+fn round(pt: Coord) -> (i32, i32) {
+    (pt.x.round() as i32, pt.y.round() as i32)
+}
+
+fn signed_area(pts: &[(i32, i32)]) -> i64 {
+    let mut sum = 0;
+    for (idx, (x1, y1)) in pts.iter().enumerate() {
+        let (x2, y2) = pts[(idx + 1) % pts.len()];
+        sum += *x1 as i64 * y2 as i64 - x2 as i64 * *y1 as i64;
+    }
+    sum
+}
+
+fn command(id: u32, count: usize) -> u32 {
+    (id & 0x7) | ((count as u32) << 3)
+}
+
+fn zigzag(x: i32) -> u32 {
+    ((x << 1) ^ (x >> 31)) as u32
+}
+
+/// A property value, hashable so each distinct value is only stored once per layer
+#[derive(PartialEq, Eq, Hash)]
+enum TileValue {
+    String(String),
+    /// The bits of an f64
+    Double(u64),
+    Int(i64),
+    Uint(u64),
+    Bool(bool),
+}
+
+impl TileValue {
+    fn new(value: &Value) -> Self {
+        match value {
+            Value::String(x) => Self::String(x.clone()),
+            Value::Bool(x) => Self::Bool(*x),
+            Value::Number(x) => {
+                if let Some(x) = x.as_u64() {
+                    Self::Uint(x)
+                } else if let Some(x) = x.as_i64() {
+                    Self::Int(x)
+                } else {
+                    Self::Double(x.as_f64().unwrap_or(0.0).to_bits())
+                }
+            }
+            // Tiles can't nest values, so keep arrays and objects as JSON
+            _ => Self::String(value.to_string()),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        match self {
+            Self::String(x) => write_bytes(&mut out, 1, x.as_bytes()),
+            Self::Double(bits) => {
+                write_key(&mut out, 3, 1);
+                out.extend_from_slice(&bits.to_le_bytes());
+            }
+            Self::Uint(x) => {
+                write_key(&mut out, 5, 0);
+                write_varint(&mut out, *x);
+            }
+            Self::Int(x) => {
+                write_key(&mut out, 6, 0);
+                write_varint(&mut out, ((x << 1) ^ (x >> 63)) as u64);
+            }
+            Self::Bool(x) => {
+                write_key(&mut out, 7, 0);
+                write_varint(&mut out, *x as u64);
+            }
+        }
+        out
+    }
+}
+
+#[derive(Default)]
+struct LayerEncoder {
+    keys: HashMap<String, u32>,
+    values: HashMap<TileValue, u32>,
+    features: Vec<Vec<u8>>,
+}
+
+impl LayerEncoder {
+    fn add_feature(
+        &mut self,
+        id: Option<u64>,
+        properties: &[(String, Value)],
+        geometry_type: u32,
+        commands: Vec<u32>,
+    ) {
+        let mut tags = Vec::new();
+        for (key, value) in properties {
+            let next_key = self.keys.len() as u32;
+            tags.push(*self.keys.entry(key.clone()).or_insert(next_key));
+            let next_value = self.values.len() as u32;
+            tags.push(
+                *self
+                    .values
+                    .entry(TileValue::new(value))
+                    .or_insert(next_value),
+            );
+        }
+
+        let mut out = Vec::new();
+        if let Some(id) = id {
+            write_key(&mut out, 1, 0);
+            write_varint(&mut out, id);
+        }
+        write_packed(&mut out, 2, &tags);
+        write_key(&mut out, 3, 0);
+        write_varint(&mut out, geometry_type as u64);
+        write_packed(&mut out, 4, &commands);
+        self.features.push(out);
+    }
+
+    fn finish(self, name: &str) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_key(&mut out, 15, 0);
+        write_varint(&mut out, 2);
+        write_bytes(&mut out, 1, name.as_bytes());
+        for feature in &self.features {
+            write_bytes(&mut out, 2, feature);
+        }
+
+        let mut keys: Vec<(String, u32)> = self.keys.into_iter().collect();
+        keys.sort_by_key(|(_, idx)| *idx);
+        for (key, _) in keys {
+            write_bytes(&mut out, 3, key.as_bytes());
+        }
+        let mut values: Vec<(TileValue, u32)> = self.values.into_iter().collect();
+        values.sort_by_key(|(_, idx)| *idx);
+        for (value, _) in values {
+            write_bytes(&mut out, 4, &value.encode());
+        }
+
+        write_key(&mut out, 5, 0);
+        write_varint(&mut out, EXTENT as u64);
+        out
+    }
+}
+
+// Just enough protobuf encoding for vector tiles
+
+fn write_varint(out: &mut Vec<u8>, mut x: u64) {
+    while x >= 0x80 {
+        out.push((x as u8 & 0x7f) | 0x80);
+        x >>= 7;
+    }
+    out.push(x as u8);
+}
+
+fn write_key(out: &mut Vec<u8>, field: u32, wire_type: u32) {
+    write_varint(out, ((field << 3) | wire_type) as u64);
+}
+
+fn write_bytes(out: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    write_key(out, field, 2);
+    write_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn write_packed(out: &mut Vec<u8>, field: u32, values: &[u32]) {
+    let mut packed = Vec::new();
+    for x in values {
+        write_varint(&mut packed, *x as u64);
+    }
+    write_bytes(out, field, &packed);
+}
+
+#[cfg(test)]
+mod tests {
+    use geojson::{Feature, Geometry as GeoJsonGeometry, Value as GeoJsonValue};
+
+    use super::*;
+
+    enum Field {
+        Varint(u64),
+        Bytes(Vec<u8>),
+    }
+
+    fn read_varint(bytes: &[u8], pos: &mut usize) -> u64 {
+        let mut x = 0;
+        let mut shift = 0;
+        loop {
+            let byte = bytes[*pos];
+            *pos += 1;
+            x |= ((byte & 0x7f) as u64) << shift;
+            if byte < 0x80 {
+                return x;
+            }
+            shift += 7;
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Vec<(u32, Field)> {
+        let mut fields = Vec::new();
+        let mut pos = 0;
+        while pos < bytes.len() {
+            let key = read_varint(bytes, &mut pos);
+            let field = match key & 0x7 {
+                0 => Field::Varint(read_varint(bytes, &mut pos)),
+                // Doubles aren't checked
+                1 => {
+                    pos += 8;
+                    continue;
+                }
+                2 => {
+                    let len = read_varint(bytes, &mut pos) as usize;
+                    pos += len;
+                    Field::Bytes(bytes[pos - len..pos].to_vec())
+                }
+                wire_type => panic!("unexpected wire type {wire_type}"),
+            };
+            fields.push(((key >> 3) as u32, field));
+        }
+        fields
+    }
+
+    fn bytes_fields(fields: &[(u32, Field)], field: u32) -> Vec<&[u8]> {
+        fields
+            .iter()
+            .filter_map(|(f, x)| match x {
+                Field::Bytes(bytes) if *f == field => Some(bytes.as_slice()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn varint_field(fields: &[(u32, Field)], field: u32) -> Option<u64> {
+        fields.iter().find_map(|(f, x)| match x {
+            Field::Varint(x) if *f == field => Some(*x),
+            _ => None,
+        })
+    }
+
+    fn unpack(bytes: &[u8]) -> Vec<u32> {
+        let mut values = Vec::new();
+        let mut pos = 0;
+        while pos < bytes.len() {
+            values.push(read_varint(bytes, &mut pos) as u32);
+        }
+        values
+    }
+
+    fn unzigzag(x: u32) -> i32 {
+        ((x >> 1) as i32) ^ -((x & 1) as i32)
+    }
+
+    /// Each MoveTo starts a new part, in absolute tile units. Returns the parts and whether each
+    /// one was closed.
+    fn decode_commands(commands: &[u32]) -> Vec<(Vec<(i32, i32)>, bool)> {
+        let mut parts: Vec<(Vec<(i32, i32)>, bool)> = Vec::new();
+        let mut cursor = (0, 0);
+        let mut idx = 0;
+        while idx < commands.len() {
+            let (id, count) = (commands[idx] & 0x7, commands[idx] >> 3);
+            idx += 1;
+            if id == 7 {
+                parts.last_mut().unwrap().1 = true;
+                continue;
+            }
+            for _ in 0..count {
+                cursor.0 += unzigzag(commands[idx]);
+                cursor.1 += unzigzag(commands[idx + 1]);
+                idx += 2;
+                if id == 1 {
+                    parts.push((Vec::new(), false));
+                }
+                parts.last_mut().unwrap().0.push(cursor);
+            }
+        }
+        parts
+    }
+
+    fn feature(value: GeoJsonValue, id: u64, properties: serde_json::Value) -> Feature {
+        Feature {
+            bbox: None,
+            geometry: Some(GeoJsonGeometry::new(value)),
+            id: Some(Id::Number(id.into())),
+            properties: properties.as_object().cloned(),
+            foreign_members: None,
+        }
+    }
+
+    fn render(source: &TileSource, z: u32, x: u32, y: u32) -> Vec<u8> {
+        let mut out = Vec::new();
+        source.render(z, x, y, &mut out).unwrap();
+        out
+    }
+
+    fn clip() -> Polygon {
+        Rect::new(
+            Coord {
+                x: -BUFFER,
+                y: -BUFFER,
+            },
+            Coord {
+                x: EXTENT + BUFFER,
+                y: EXTENT + BUFFER,
+            },
+        )
+        .to_polygon()
+    }
+
+    #[test]
+    fn test_zigzag_and_commands() {
+        assert_eq!(zigzag(0), 0);
+        assert_eq!(zigzag(-1), 1);
+        assert_eq!(zigzag(1), 2);
+        assert_eq!(zigzag(-2), 3);
+        assert_eq!(zigzag(2048), 4096);
+        assert_eq!(command(1, 1), 9);
+        assert_eq!(command(2, 3), 26);
+        assert_eq!(command(7, 1), 15);
+    }
+
+    #[test]
+    fn test_point_feature() {
+        let mut source = TileSource::default();
+        let gj = FeatureCollection::from_iter(vec![
+            feature(
+                GeoJsonValue::Point(vec![0.0, 0.0]),
+                7,
+                serde_json::json!({ "kind": "a", "missing": null }),
+            ),
+            feature(
+                GeoJsonValue::Point(vec![0.0, 0.0]),
+                8,
+                serde_json::json!({ "kind": "a" }),
+            ),
+        ]);
+        source.set_layer("test", &gj);
+        let tile = decode(&render(&source, 0, 0, 0));
+
+        let layers = bytes_fields(&tile, 3);
+        assert_eq!(layers.len(), 1);
+        let layer = decode(layers[0]);
+        assert_eq!(varint_field(&layer, 15), Some(2));
+        assert_eq!(bytes_fields(&layer, 1), vec![b"test".as_slice()]);
+        assert_eq!(varint_field(&layer, 5), Some(4096));
+        // The null property is dropped, and the repeated value is only stored once
+        assert_eq!(bytes_fields(&layer, 3), vec![b"kind".as_slice()]);
+        let values = bytes_fields(&layer, 4);
+        assert_eq!(values.len(), 1);
+        assert_eq!(bytes_fields(&decode(values[0]), 1), vec![b"a".as_slice()]);
+
+        let features = bytes_fields(&layer, 2);
+        assert_eq!(features.len(), 2);
+        for (f, id) in features.into_iter().zip([7, 8]) {
+            let f = decode(f);
+            assert_eq!(varint_field(&f, 1), Some(id));
+            assert_eq!(unpack(bytes_fields(&f, 2)[0]), vec![0, 0]);
+            assert_eq!(varint_field(&f, 3), Some(POINT as u64));
+            // The middle of the only tile at zoom 0
+            assert_eq!(unpack(bytes_fields(&f, 4)[0]), vec![9, 4096, 4096]);
+        }
+    }
+
+    #[test]
+    fn test_empty_tile() {
+        let mut source = TileSource::default();
+        let gj = FeatureCollection::from_iter(vec![feature(
+            GeoJsonValue::Point(vec![0.0, 0.0]),
+            1,
+            serde_json::json!({}),
+        )]);
+        source.set_layer("test", &gj);
+        // Far away from the point
+        assert!(render(&source, 10, 0, 0).is_empty());
+    }
+
+    #[test]
+    fn test_line_clipped_to_buffer() {
+        let line = LineString::from(vec![(-1000.0, 2048.0), (5000.0, 2048.0)]);
+        let (geometry_type, commands) =
+            encode_geometry(&Geometry::LineString(line), &clip()).unwrap();
+        assert_eq!(geometry_type, LINESTRING);
+        let parts = decode_commands(&commands);
+        assert_eq!(parts.len(), 1);
+        let (mut pts, closed) = parts.into_iter().next().unwrap();
+        assert!(!closed);
+        pts.sort();
+        assert_eq!(pts, vec![(-64, 2048), (4160, 2048)]);
+    }
+
+    #[test]
+    fn test_polygon_winding() {
+        let square = vec![
+            (100.0, 100.0),
+            (200.0, 100.0),
+            (200.0, 200.0),
+            (100.0, 200.0),
+            (100.0, 100.0),
+        ];
+        let hole = vec![
+            (120.0, 120.0),
+            (180.0, 120.0),
+            (180.0, 180.0),
+            (120.0, 180.0),
+            (120.0, 120.0),
+        ];
+        // Exteriors and holes must wind opposite ways, whichever way the input winds
+        for reverse in [false, true] {
+            let mut exterior = square.clone();
+            let mut interior = hole.clone();
+            if reverse {
+                exterior.reverse();
+            } else {
+                interior.reverse();
+            }
+            let polygon = Polygon::new(
+                LineString::from(exterior),
+                vec![LineString::from(interior)],
+            );
+            let (geometry_type, commands) =
+                encode_geometry(&Geometry::Polygon(polygon), &clip()).unwrap();
+            assert_eq!(geometry_type, POLYGON);
+            let parts = decode_commands(&commands);
+            assert_eq!(parts.len(), 2);
+            assert!(parts.iter().all(|(pts, closed)| *closed && pts.len() == 4));
+            assert!(signed_area(&parts[0].0) > 0);
+            assert!(signed_area(&parts[1].0) < 0);
+        }
+    }
+
+    #[test]
+    fn test_collapsed_polygon_dropped() {
+        // Smaller than one tile unit, so it rounds away to nothing
+        let tiny = Polygon::new(
+            LineString::from(vec![(10.1, 10.1), (10.2, 10.1), (10.2, 10.2), (10.1, 10.1)]),
+            vec![],
+        );
+        assert!(encode_geometry(&Geometry::Polygon(tiny), &clip()).is_none());
+    }
+}
@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use geojson::GeoJson;
+use osm_reader::WayID;
+
+/// Roads carrying at least this many vehicles per day are severances, unless overridden
+pub const DEFAULT_SEVERANCE_AADT: f64 = 10_000.0;
+
+/// Parses annual average daily traffic (AADT) counts per OSM way. The input is either CSV with
+/// `way_id,aadt` rows (and an optional header), or GeoJSON with `way_id` and `aadt` properties.
+pub fn parse_traffic_counts(input: &str) -> Result<HashMap<WayID, f64>> {
+    if input.trim_start().starts_with('{') {
+        parse_geojson(input)
+    } else {
+        parse_csv(input)
+    }
+}
+
+fn parse_csv(input: &str) -> Result<HashMap<WayID, f64>> {
+    let mut counts = HashMap::new();
+    for (idx, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let Some((way, aadt)) = line.split_once(',') else {
+            bail!("Line {} of traffic counts isn't way_id,aadt: {line}", idx + 1);
+        };
+        let (Ok(way), Ok(aadt)) = (way.trim().parse::<i64>(), aadt.trim().parse::<f64>()) else {
+            if idx == 0 {
+                // Assume this is a header
+                continue;
+            }
+            bail!("Line {} of traffic counts isn't way_id,aadt: {line}", idx + 1);
+        };
+        counts.insert(WayID(way), aadt);
+    }
+    Ok(counts)
+}
+
+fn parse_geojson(input: &str) -> Result<HashMap<WayID, f64>> {
+    let GeoJson::FeatureCollection(fc) = input.parse::<GeoJson>()? else {
+        bail!("Traffic counts GeoJSON must be a FeatureCollection");
+    };
+    let mut counts = HashMap::new();
+    for f in fc.features {
+        // Tolerate way IDs as numbers or strings
+        let way = match f.property("way_id") {
+            Some(serde_json::Value::Number(n)) => n.as_i64(),
+            Some(serde_json::Value::String(s)) => s.parse::<i64>().ok(),
+            _ => None,
+        };
+        let aadt = f.property("aadt").and_then(|x| x.as_f64());
+        let (Some(way), Some(aadt)) = (way, aadt) else {
+            bail!("Every traffic count feature needs numeric way_id and aadt properties");
+        };
+        counts.insert(WayID(way), aadt);
+    }
+    Ok(counts)
+}
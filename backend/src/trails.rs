@@ -0,0 +1,258 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{bail, Result};
+use geo::{Coord, EuclideanDistance, EuclideanLength, Point};
+use geojson::{Feature, FeatureCollection, Geometry};
+use osm_reader::{RelationID, WayID};
+use serde::{Deserialize, Serialize};
+
+use crate::crossings::{crossing_quality, crossing_skew};
+use crate::heatmap::{score_request, severance_requests, DesireLineBounds};
+use crate::{IntersectionID, MapModel, Road, RoadKind};
+
+/// A `type=route` relation for walking, like a national trail
+#[derive(Serialize, Deserialize)]
+pub struct WalkingRoute {
+    pub name: Option<String>,
+    pub ways: Vec<WayID>,
+}
+
+/// Walk along a route relation, reporting every place it crosses a severance, the stretches where
+/// it follows a severance, and scored desire lines across severances within `buffer_meters` of the
+/// route.
+pub fn audit(map: &mut MapModel, relation: i64, buffer_meters: f64) -> Result<FeatureCollection> {
+    let Some(route) = map.walking_routes.get(&RelationID(relation)) else {
+        bail!("No walking route relation {relation}");
+    };
+    let name = route.name.clone();
+    let ways: HashSet<WayID> = route.ways.iter().cloned().collect();
+
+    let mut features = Vec::new();
+    let mut trail_length = 0.0;
+    let mut length_along_severances = 0.0;
+    let mut trail_intersections: HashSet<IntersectionID> = HashSet::new();
+    let mut trail_geometry = Vec::new();
+    for r in &map.roads {
+        if !ways.contains(&r.way) {
+            continue;
+        }
+        let length = r.linestring.euclidean_length();
+        trail_length += length;
+        trail_intersections.insert(r.src_i);
+        trail_intersections.insert(r.dst_i);
+        trail_geometry.push(r.linestring.clone());
+
+        if matches!(r.kind, RoadKind::Severance | RoadKind::WithTraffic) {
+            length_along_severances += length;
+            let mut f = r.to_gj(&map.mercator);
+            f.set_property("audit", "along severance");
+            features.push(f);
+        }
+    }
+    if trail_geometry.is_empty() {
+        bail!("None of the walking route's ways are in the network");
+    }
+
+    // Where the route meets a severance, judge it by the route's own roads there
+    let mut crossings: HashMap<&'static str, usize> = HashMap::new();
+    for i in &trail_intersections {
+        let intersection = &map.intersections[i.0];
+        let severances: Vec<String> = intersection
+            .roads
+            .iter()
+            .map(|r| &map.roads[r.0])
+            .filter(|r| r.kind == RoadKind::Severance && !ways.contains(&r.way))
+            .map(|r| r.way.to_string())
+            .collect();
+        if severances.is_empty() {
+            continue;
+        }
+        let crossing = intersection
+            .roads
+            .iter()
+            .map(|r| &map.roads[r.0])
+            .filter(|r| ways.contains(&r.way))
+            .find(|r| crossing_quality(r) != "none");
+        let quality = crossing.map(crossing_quality).unwrap_or("none");
+        let skew = crossing.and_then(|r| crossing_skew(map, r));
+        *crossings.entry(quality).or_insert(0) += 1;
+
+        let mut f = Feature::from(Geometry::from(&map.mercator.to_wgs84(&intersection.point)));
+        f.set_property("audit", "crossing");
+        f.set_property("quality", quality);
+        f.set_property("skew_degrees", skew);
+        f.set_property("severances", severances);
+        features.push(f);
+    }
+
+    // Score desire lines across nearby severances
+    let mut hotspots = 0;
+    for req in severance_requests(map) {
+        let midpoint = Point::from(Coord {
+            x: (req.x1 + req.x2) / 2.0,
+            y: (req.y1 + req.y2) / 2.0,
+        });
+        if !trail_geometry
+            .iter()
+            .any(|ls| midpoint.euclidean_distance(ls) <= buffer_meters)
+        {
+            continue;
+        }
+        if let Some((mut f, score)) = score_request(map, req, &DesireLineBounds::default()) {
+            hotspots += 1;
+            f.set_property("audit", "desire line");
+            f.set_property("score", score);
+            features.push(f);
+        }
+    }
+
+    Ok(FeatureCollection {
+        features,
+        bbox: None,
+        foreign_members: Some(
+            serde_json::json!({
+                "name": name,
+                "trail_length": trail_length,
+                "length_along_severances": length_along_severances,
+                "crossings_by_quality": crossings,
+                "desire_lines": hotspots,
+            })
+            .as_object()
+            .unwrap()
+            .clone(),
+        ),
+    })
+}
+
+#[derive(Serialize)]
+struct RouteSummary {
+    relation: i64,
+    name: Option<String>,
+    /// Meters of the route that are in the network
+    length: f64,
+    /// How many disconnected stretches the route's roads form. More than 1 means the route is
+    /// interrupted, or leaves the network.
+    pieces: usize,
+    /// Places where the route meets a severance that isn't part of the route
+    severance_crossings: usize,
+}
+
+/// Every road on a walking route relation, with the `walking_routes` names and `relations` using
+/// it. The collection has `routes`, summarizing each one, for spotting routes interrupted by
+/// severances.
+pub fn walking_routes_layer(map: &MapModel) -> FeatureCollection {
+    let mut relations: Vec<&RelationID> = map.walking_routes.keys().collect();
+    relations.sort_by_key(|r| r.0);
+
+    let mut routes_per_way: HashMap<WayID, Vec<&RelationID>> = HashMap::new();
+    for relation in &relations {
+        for way in &map.walking_routes[*relation].ways {
+            routes_per_way
+                .entry(*way)
+                .or_default()
+                .push(*relation);
+        }
+    }
+
+    let mut features = Vec::new();
+    let mut summaries: Vec<RouteSummary> = relations
+        .iter()
+        .map(|relation| RouteSummary {
+            relation: relation.0,
+            name: map.walking_routes[*relation].name.clone(),
+            length: 0.0,
+            pieces: 0,
+            severance_crossings: 0,
+        })
+        .collect();
+    let mut roads_per_route: Vec<Vec<&Road>> = vec![Vec::new(); relations.len()];
+    for r in &map.roads {
+        let Some(on_routes) = routes_per_way.get(&r.way) else {
+            continue;
+        };
+        let mut f = r.to_gj(&map.mercator);
+        f.set_property(
+            "walking_routes",
+            on_routes
+                .iter()
+                .filter_map(|relation| map.walking_routes[*relation].name.clone())
+                .collect::<Vec<_>>(),
+        );
+        f.set_property(
+            "relations",
+            on_routes
+                .iter()
+                .map(|relation| relation.0)
+                .collect::<Vec<_>>(),
+        );
+        features.push(f);
+
+        for relation in on_routes {
+            let idx = relations
+                .binary_search_by_key(&relation.0, |r| r.0)
+                .unwrap();
+            roads_per_route[idx].push(r);
+        }
+    }
+
+    for (summary, roads) in summaries.iter_mut().zip(roads_per_route) {
+        let ways: HashSet<WayID> = roads.iter().map(|r| r.way).collect();
+        summary.length = roads.iter().map(|r| r.linestring.euclidean_length()).sum();
+        summary.pieces = count_pieces(&roads);
+        let intersections: HashSet<IntersectionID> =
+            roads.iter().flat_map(|r| [r.src_i, r.dst_i]).collect();
+        summary.severance_crossings = intersections
+            .into_iter()
+            .filter(|i| {
+                map.intersections[i.0]
+                    .roads
+                    .iter()
+                    .map(|r| &map.roads[r.0])
+                    .any(|r| r.kind == RoadKind::Severance && !ways.contains(&r.way))
+            })
+            .count();
+    }
+
+    let mut gj = FeatureCollection::from_iter(features);
+    gj.foreign_members = Some(
+        serde_json::json!({
+            "routes": summaries,
+        })
+        .as_object()
+        .unwrap()
+        .clone(),
+    );
+    gj
+}
+
+/// How many groups of roads are connected through shared intersections
+fn count_pieces(roads: &[&Road]) -> usize {
+    let mut neighbors: HashMap<IntersectionID, Vec<IntersectionID>> = HashMap::new();
+    for r in roads {
+        neighbors
+            .entry(r.src_i)
+            .or_default()
+            .push(r.dst_i);
+        neighbors
+            .entry(r.dst_i)
+            .or_default()
+            .push(r.src_i);
+    }
+    let mut seen: HashSet<IntersectionID> = HashSet::new();
+    let mut pieces = 0;
+    for start in neighbors.keys() {
+        if !seen.insert(*start) {
+            continue;
+        }
+        pieces += 1;
+        let mut queue = vec![*start];
+        while let Some(i) = queue.pop() {
+            for next in &neighbors[&i] {
+                if seen.insert(*next) {
+                    queue.push(*next);
+                }
+            }
+        }
+    }
+    pieces
+}
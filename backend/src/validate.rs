@@ -0,0 +1,404 @@
+use std::collections::{BTreeSet, HashSet};
+
+use geo::{EuclideanDistance, EuclideanLength, LineString};
+use osm_reader::NodeID;
+use rstar::{primitives::GeomWithData, RTree};
+use serde::{Deserialize, Serialize};
+use utils::Mercator;
+
+use crate::{Intersection, IntersectionID, Road, RoadID};
+
+/// Consecutive points closer than this, in meters, are treated as the same point
+const DUPLICATE_POINT_THRESHOLD: f64 = 0.01;
+
+/// Problems with road geometry found and fixed while importing
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct GeometryReport {
+    /// Duplicate consecutive points removed from roads
+    pub duplicate_points: usize,
+    /// Zero-length roads between two intersections at the same place, merged into one
+    pub merged_intersections: usize,
+    /// Roads starting and ending at the same intersection, split into two
+    pub split_loops: usize,
+    /// Roads dropped for having no usable length, by `stable_id`
+    pub dropped: Vec<String>,
+    /// Nearly-coincident intersections merged by `snap_intersections`
+    pub snapped: Vec<SnappedIntersection>,
+}
+
+/// A dead-end merged into a nearby intersection, usually a crossing that doesn't quite reach the
+/// sidewalk. The OSM data should be fixed to connect them.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SnappedIntersection {
+    pub kept_node: i64,
+    pub merged_node: i64,
+    /// In meters
+    pub distance: f64,
+    /// WGS84 position of the merged node
+    pub x: f64,
+    pub y: f64,
+}
+
+/// Zero-length roads and self-loops break routing weights and adjacency, so this removes
+/// duplicate points, merges intersections joined by zero-length roads, and splits loops in half.
+/// Roads and intersections may be renumbered.
+pub fn validate(intersections: &mut Vec<Intersection>, roads: &mut Vec<Road>) -> GeometryReport {
+    let mut report = GeometryReport::default();
+    let mut drop: HashSet<RoadID> = HashSet::new();
+
+    for road in roads.iter_mut() {
+        let pts = &mut road.linestring.0;
+        let before = pts.len();
+        let last = *pts.last().unwrap();
+        pts.dedup_by(|pt, prev| pt.euclidean_distance(prev) < DUPLICATE_POINT_THRESHOLD);
+        // Keep the real end, so the road still meets its intersection
+        if pts.len() >= 2 {
+            *pts.last_mut().unwrap() = last;
+        }
+        report.duplicate_points += before - pts.len();
+    }
+
+    for idx in 0..roads.len() {
+        if roads[idx].linestring.0.len() >= 2
+            && roads[idx].linestring.euclidean_length() >= DUPLICATE_POINT_THRESHOLD
+        {
+            continue;
+        }
+        let (keep, gone) = (roads[idx].src_i, roads[idx].dst_i);
+        drop.insert(roads[idx].id);
+        if keep == gone {
+            report.dropped.push(roads[idx].stable_id());
+            continue;
+        }
+        // Both intersections are in the same place, so every road using one uses the other
+        for road in roads.iter_mut() {
+            if road.src_i == gone {
+                road.src_i = keep;
+            }
+            if road.dst_i == gone {
+                road.dst_i = keep;
+            }
+        }
+        let moved = std::mem::take(&mut intersections[gone.0].roads);
+        for r in moved {
+            if !intersections[keep.0].roads.contains(&r) {
+                intersections[keep.0].roads.push(r);
+            }
+        }
+        report.merged_intersections += 1;
+    }
+
+    for idx in 0..roads.len() {
+        if roads[idx].src_i != roads[idx].dst_i || drop.contains(&roads[idx].id) {
+            continue;
+        }
+        let pts = roads[idx].linestring.0.clone();
+        if pts.len() < 3 {
+            drop.insert(roads[idx].id);
+            report.dropped.push(roads[idx].stable_id());
+            continue;
+        }
+        split_loop(intersections, roads, idx, pts.len() / 2);
+        report.split_loops += 1;
+    }
+
+    crate::scrape::retain_roads(intersections, roads, |r| !drop.contains(&r.id));
+    for i in intersections.iter_mut() {
+        i.roads.sort();
+    }
+
+    info!(
+        "Removed {} duplicate points, merged {} intersections, split {} loops, and dropped {} roads with no length",
+        report.duplicate_points,
+        report.merged_intersections,
+        report.split_loops,
+        report.dropped.len()
+    );
+    report
+}
+
+/// Splits a road that starts and ends at the same intersection at one of its points. The new
+/// intersection isn't an OSM node, so it gets a negative node ID.
+fn split_loop(
+    intersections: &mut Vec<Intersection>,
+    roads: &mut Vec<Road>,
+    idx: usize,
+    split_at: usize,
+) {
+    let new_i = IntersectionID(intersections.len());
+    let new_r = RoadID(roads.len());
+    let node = NodeID(-(new_i.0 as i64) - 1);
+    let pts = std::mem::take(&mut roads[idx].linestring.0);
+
+    let road = &mut roads[idx];
+    road.linestring = LineString::new(pts[..=split_at].to_vec());
+    road.dst_i = new_i;
+    let old_node2 = std::mem::replace(&mut road.node2, node);
+    let second = Road {
+        id: new_r,
+        src_i: new_i,
+        dst_i: road.src_i,
+        way: road.way,
+        node1: node,
+        node2: old_node2,
+        linestring: LineString::new(pts[split_at..].to_vec()),
+        tags: road.tags.clone(),
+        kind: road.kind.clone(),
+        shared_with_cyclists: road.shared_with_cyclists,
+        layer: road.layer,
+        access: road.access.clone(),
+        aadt: road.aadt,
+        truncated: road.truncated,
+        gradient: road.gradient,
+        version: road.version,
+    };
+    intersections[second.dst_i.0].roads.push(new_r);
+    intersections.push(Intersection {
+        id: new_i,
+        node,
+        point: pts[split_at].into(),
+        roads: vec![roads[idx].id, new_r],
+    });
+    roads.push(second);
+}
+
+/// Merges each dead-end into the closest intersection within `tolerance` meters, keeping the
+/// intersection with more roads. Intersections already joined by a road or at different layers
+/// aren't merged. Intersections may be renumbered.
+pub fn snap_intersections(
+    intersections: &mut Vec<Intersection>,
+    roads: &mut Vec<Road>,
+    tolerance: f64,
+    mercator: &Mercator,
+) -> Vec<SnappedIntersection> {
+    let rtree = RTree::bulk_load(
+        intersections
+            .iter()
+            .map(|i| GeomWithData::new(i.point.into(), i.id))
+            .collect(),
+    );
+    let mut gone: HashSet<IntersectionID> = HashSet::new();
+    let mut snapped = Vec::new();
+    for idx in 0..intersections.len() {
+        let i1 = IntersectionID(idx);
+        if gone.contains(&i1) {
+            continue;
+        }
+        let pt: [f64; 2] = intersections[idx].point.into();
+        let mut candidates: Vec<(IntersectionID, f64)> = rtree
+            .locate_within_distance(pt, tolerance * tolerance)
+            .filter(|obj| obj.data != i1 && !gone.contains(&obj.data))
+            .map(|obj| {
+                let distance = intersections[obj.data.0]
+                    .point
+                    .euclidean_distance(&intersections[idx].point);
+                (obj.data, distance)
+            })
+            .collect();
+        candidates.sort_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)));
+
+        for (i2, distance) in candidates {
+            if !can_merge(intersections, roads, i1, i2) {
+                continue;
+            }
+            let (keep, merge) = if intersections[i2.0].roads.len() > intersections[i1.0].roads.len()
+            {
+                (i2, i1)
+            } else {
+                (i1, i2)
+            };
+            let merged_pt = mercator.pt_to_wgs84(intersections[merge.0].point.into());
+            snapped.push(SnappedIntersection {
+                kept_node: intersections[keep.0].node.0,
+                merged_node: intersections[merge.0].node.0,
+                distance,
+                x: merged_pt.x,
+                y: merged_pt.y,
+            });
+            merge_intersection(intersections, roads, keep, merge);
+            gone.insert(merge);
+            if merge == i1 {
+                break;
+            }
+        }
+    }
+
+    // Drops the merged intersections, which have no roads left
+    crate::scrape::retain_roads(intersections, roads, |_| true);
+    info!(
+        "Snapped {} dead-ends to nearby intersections",
+        snapped.len()
+    );
+    snapped
+}
+
+fn can_merge(
+    intersections: &[Intersection],
+    roads: &[Road],
+    i1: IntersectionID,
+    i2: IntersectionID,
+) -> bool {
+    let roads1 = &intersections[i1.0].roads;
+    let roads2 = &intersections[i2.0].roads;
+    if roads1.len() != 1 && roads2.len() != 1 {
+        return false;
+    }
+    // Merging would turn the road between them into a loop
+    if roads1.iter().any(|r| roads2.contains(r)) {
+        return false;
+    }
+    let layers1: BTreeSet<i8> = roads1.iter().map(|r| roads[r.0].layer).collect();
+    roads2.iter().any(|r| layers1.contains(&roads[r.0].layer))
+}
+
+/// Moves every road from `merge` to `keep`, leaving `merge` with no roads
+fn merge_intersection(
+    intersections: &mut [Intersection],
+    roads: &mut [Road],
+    keep: IntersectionID,
+    merge: IntersectionID,
+) {
+    let pt = intersections[keep.0].point.into();
+    for r in std::mem::take(&mut intersections[merge.0].roads) {
+        let road = &mut roads[r.0];
+        if road.src_i == merge {
+            road.src_i = keep;
+            road.linestring.0[0] = pt;
+        }
+        if road.dst_i == merge {
+            road.dst_i = keep;
+            *road.linestring.0.last_mut().unwrap() = pt;
+        }
+        intersections[keep.0].roads.push(r);
+    }
+    intersections[keep.0].roads.sort();
+}
+
+#[cfg(test)]
+mod tests {
+    use geo::{Coord, Point};
+    use osm_reader::WayID;
+    use utils::Tags;
+
+    use super::*;
+    use crate::access::ConditionalAccess;
+    use crate::RoadKind;
+
+    fn road(id: usize, src_i: usize, dst_i: usize, pts: Vec<(f64, f64)>) -> Road {
+        Road {
+            id: RoadID(id),
+            src_i: IntersectionID(src_i),
+            dst_i: IntersectionID(dst_i),
+            way: WayID(id as i64),
+            node1: NodeID(src_i as i64),
+            node2: NodeID(dst_i as i64),
+            linestring: LineString::from(pts),
+            tags: Tags(Default::default()),
+            kind: RoadKind::Footway,
+            shared_with_cyclists: false,
+            layer: 0,
+            access: ConditionalAccess::default(),
+            aadt: None,
+            truncated: false,
+            gradient: None,
+            version: 0,
+        }
+    }
+
+    /// One intersection per point, with the roads ending there
+    fn intersections(pts: Vec<(f64, f64)>, roads: &[Road]) -> Vec<Intersection> {
+        pts.into_iter()
+            .enumerate()
+            .map(|(idx, (x, y))| Intersection {
+                id: IntersectionID(idx),
+                node: NodeID(idx as i64),
+                point: Point::new(x, y),
+                roads: roads
+                    .iter()
+                    .filter(|r| r.src_i.0 == idx || r.dst_i.0 == idx)
+                    .map(|r| r.id)
+                    .collect(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_duplicate_points() {
+        let mut roads = vec![road(
+            0,
+            0,
+            1,
+            vec![(0.0, 0.0), (5.0, 0.0), (5.0, 0.001), (10.0, 0.001)],
+        )];
+        let mut intersections = intersections(vec![(0.0, 0.0), (10.0, 0.001)], &roads);
+        let report = validate(&mut intersections, &mut roads);
+
+        assert_eq!(report.duplicate_points, 1);
+        assert_eq!(roads.len(), 1);
+        // The real end is kept
+        assert_eq!(
+            roads[0].linestring.0,
+            vec![
+                Coord { x: 0.0, y: 0.0 },
+                Coord { x: 5.0, y: 0.0 },
+                Coord { x: 10.0, y: 0.001 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_zero_length_road() {
+        let mut roads = vec![
+            road(0, 0, 1, vec![(0.0, 0.0), (0.0, 0.0)]),
+            road(1, 1, 2, vec![(0.0, 0.0), (10.0, 0.0)]),
+        ];
+        let mut intersections = intersections(vec![(0.0, 0.0), (0.0, 0.0), (10.0, 0.0)], &roads);
+        let report = validate(&mut intersections, &mut roads);
+
+        assert_eq!(report.merged_intersections, 1);
+        assert!(report.dropped.is_empty());
+        // Only the road with some length is left, starting from the merged intersection
+        assert_eq!(roads.len(), 1);
+        assert_eq!(roads[0].way, WayID(1));
+        assert_eq!(intersections.len(), 2);
+        assert_eq!(roads[0].src_i, IntersectionID(0));
+        assert_eq!(roads[0].dst_i, IntersectionID(1));
+        for i in &intersections {
+            assert_eq!(i.roads, vec![RoadID(0)]);
+        }
+    }
+
+    #[test]
+    fn test_split_loop() {
+        let mut roads = vec![road(
+            0,
+            0,
+            0,
+            vec![(0.0, 0.0), (10.0, 0.0), (10.0, 10.0), (0.0, 0.0)],
+        )];
+        let mut intersections = intersections(vec![(0.0, 0.0)], &roads);
+        let report = validate(&mut intersections, &mut roads);
+
+        assert_eq!(report.split_loops, 1);
+        assert_eq!(roads.len(), 2);
+        assert_eq!(intersections.len(), 2);
+        // The new intersection is at the middle point, with a node ID that can't clash with OSM
+        let new_i = &intersections[1];
+        assert_eq!(new_i.point, Point::new(10.0, 10.0));
+        assert_eq!(new_i.node, NodeID(-2));
+        assert_eq!(new_i.roads, vec![RoadID(0), RoadID(1)]);
+        assert_eq!(intersections[0].roads, vec![RoadID(0), RoadID(1)]);
+
+        assert_eq!(
+            (roads[0].src_i, roads[0].dst_i),
+            (IntersectionID(0), new_i.id)
+        );
+        assert_eq!(
+            (roads[1].src_i, roads[1].dst_i),
+            (new_i.id, IntersectionID(0))
+        );
+        assert_eq!(roads[0].node2, roads[1].node1);
+        assert_eq!(roads[1].way, roads[0].way);
+        assert_eq!(roads[1].linestring.0.len(), 2);
+    }
+}
@@ -0,0 +1,138 @@
+use anyhow::{bail, Result};
+use geo::{Area, BooleanOps, BoundingRect, Contains, Coord, EuclideanDistance, Point, Rect};
+use geojson::{Feature, FeatureCollection, Geometry};
+use serde::Deserialize;
+
+use crate::isochrone::{
+    circle, cost_grid, minutes_to_cost, CONTOUR_CELL_SIZE, OFF_NETWORK_METERS, WALKING_SPEED,
+};
+use crate::pois::count_within;
+use crate::route::{dijkstra_directed, ExtraEdges};
+use crate::{MapModel, Road};
+
+#[derive(Deserialize)]
+pub struct AccessGridRequest {
+    /// The width and height of each cell in meters
+    #[serde(default = "default_cell_size_meters")]
+    cell_size_meters: f64,
+    /// How long to walk for from the center of each cell
+    #[serde(default = "default_limit_minutes")]
+    limit_minutes: f64,
+    /// The index of the first cell to calculate
+    #[serde(default)]
+    start: usize,
+    /// How many cells to calculate in this call. The whole grid can take a long time, so callers
+    /// can repeatedly ask for the `next` chunk and show progress in between.
+    #[serde(default = "default_chunk_size")]
+    chunk_size: usize,
+}
+
+fn default_cell_size_meters() -> f64 {
+    200.0
+}
+
+fn default_limit_minutes() -> f64 {
+    15.0
+}
+
+fn default_chunk_size() -> usize {
+    50
+}
+
+/// Covers the boundary with a grid and finds the area walkable within the time limit from the
+/// center of each cell, using the current `CostConfig`. Each cell has the `reachable_area` in
+/// square meters, the `area_ratio` against walking in a straight line, the `amenities` reached,
+/// and the `population` reached if population data is loaded. Cells whose center is too far from
+/// the walking network have none of these.
+///
+/// Only one chunk of cells is calculated per call. The foreign members `total_cells` and `next`
+/// say how many cells there are and where the following chunk starts, or null when done.
+pub fn access_grid(map: &MapModel, req: AccessGridRequest) -> Result<FeatureCollection> {
+    if req.cell_size_meters < CONTOUR_CELL_SIZE {
+        bail!("The cell size must be at least {CONTOUR_CELL_SIZE}m");
+    }
+    let cells = grid_cells(map, req.cell_size_meters);
+    let end = (req.start + req.chunk_size.max(1)).min(cells.len());
+    let limit = minutes_to_cost(req.limit_minutes);
+    let cost_fn = |r: &Road| map.cost_config.cost(r);
+
+    let mut features = Vec::new();
+    for cell in cells.get(req.start..end).unwrap_or(&[]) {
+        let mut f = Feature::from(Geometry::from(&map.mercator.to_wgs84(&cell.to_polygon())));
+        let center = cell.center();
+        let nearest = map
+            .closest_intersection
+            .nearest_neighbor(&[center.x, center.y])
+            .filter(|obj| {
+                Point::from(center).euclidean_distance(&Point::from(*obj.geom()))
+                    <= OFF_NETWORK_METERS
+            });
+        if let Some(obj) = nearest {
+            let start = map.node_map.translate_id(obj.data);
+            // Like isochrones, respect one-way footpaths in the direction people walk
+            let costs = dijkstra_directed(
+                map,
+                vec![(start, 0)],
+                Some(limit.round() as usize),
+                &ExtraEdges::new(),
+                |r, forwards| map.cost_config.cost_in_direction(r, forwards),
+            );
+            let reachable = cost_grid(map, &costs, cost_fn, CONTOUR_CELL_SIZE)
+                .contour(limit)
+                .intersection(&map.boundary_polygon);
+            let area = reachable.unsigned_area();
+            let circle_area =
+                circle(center, WALKING_SPEED * 60.0 * req.limit_minutes).unsigned_area();
+
+            f.set_property("reachable_area", area);
+            f.set_property("area_ratio", area / circle_area);
+            f.set_property(
+                "amenities",
+                count_within(&map.pois, &reachable).values().sum::<usize>(),
+            );
+            if let Some(ref population) = map.population {
+                f.set_property("population", population.within(&reachable));
+            }
+        }
+        features.push(f);
+    }
+
+    Ok(FeatureCollection {
+        features,
+        bbox: None,
+        foreign_members: Some(
+            serde_json::json!({
+                "total_cells": cells.len(),
+                "next": (end < cells.len()).then_some(end),
+            })
+            .as_object()
+            .unwrap()
+            .clone(),
+        ),
+    })
+}
+
+/// Every cell with its center inside the boundary, in a stable order
+fn grid_cells(map: &MapModel, cell_size: f64) -> Vec<Rect> {
+    let bounds = map.boundary_polygon.bounding_rect().unwrap();
+    let mut cells = Vec::new();
+    let mut y = bounds.min().y;
+    while y < bounds.max().y {
+        let mut x = bounds.min().x;
+        while x < bounds.max().x {
+            let cell = Rect::new(
+                Coord { x, y },
+                Coord {
+                    x: x + cell_size,
+                    y: y + cell_size,
+                },
+            );
+            if map.boundary_polygon.contains(&Point::from(cell.center())) {
+                cells.push(cell);
+            }
+            x += cell_size;
+        }
+        y += cell_size;
+    }
+    cells
+}
@@ -2,7 +2,8 @@ export const kindToColor = {
   Footway: "black",
   Indoors: "grey",
   // TODO dashed
-  BridgeOrTunnel: "black",
+  Bridge: "black",
+  Tunnel: "black",
   WithTraffic: "#4C3926",
   Crossing: "green",
   Severance: "red",
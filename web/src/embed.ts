@@ -0,0 +1,53 @@
+// A minimal wrapper for embedding severance analysis in another site. Call
+// `analyze` with the bytes of an osm.pbf or osm.xml file, then draw the
+// returned layers however you like.
+import init, { analyzeArea } from "backend";
+import type { Feature, FeatureCollection, Polygon } from "geojson";
+
+export interface AnalyzeOptions {
+  import_streets_without_sidewalk_tagging?: boolean;
+  // CSV or GeoJSON with traffic volumes per OSM way
+  traffic_counts?: string;
+  severance_aadt?: number;
+  boundary_buffer?: number;
+  // Route with on-demand A* instead of preparing a contraction hierarchy. Faster
+  // to load huge areas, but each route is slower.
+  skip_contraction_hierarchy?: boolean;
+  // GeoJSON with a polygon, like an administrative area the extract was clipped
+  // to, to use instead of a concave hull around the roads
+  boundary?: string;
+  // Drop disconnected pieces of the walking network shorter than this, in meters
+  min_component_length?: number;
+  // Merge dead-ends within this many meters of another intersection into it
+  snap_tolerance?: number;
+  // Only score desire lines with a straight-line length in this range, in meters
+  min_length?: number;
+  max_length?: number;
+}
+
+export interface AnalysisBundle {
+  network: FeatureCollection;
+  heatmap: FeatureCollection;
+  boundary: Feature<Polygon>;
+  bounds: [number, number, number, number];
+  // The input data and import options, to cite alongside published results
+  provenance: { [key: string]: unknown };
+  stats: {
+    road_kinds: { [kind: string]: number };
+    desire_lines: number;
+    mean_score: number | null;
+    max_score: number | null;
+    max_gap: number | null;
+  };
+}
+
+let ready: Promise<unknown> | null = null;
+
+export async function analyze(
+  bytes: Uint8Array,
+  options: AnalyzeOptions = {},
+): Promise<AnalysisBundle> {
+  ready ||= init();
+  await ready;
+  return JSON.parse(analyzeArea(bytes, options));
+}